@@ -1,11 +1,17 @@
 use std::env::var;
-use std::path::PathBuf;
+use std::fs::{self, Metadata};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Size of tar headers and the unit file contents are padded to
+const BLOCK: usize = 512;
 
 fn main() {
     println!("cargo:rerun-if-changed=build.rs");
     println!("cargo:rerun-if-changed=Cargo.toml");
     println!("cargo:rerun-if-changed=Cargo.lock");
     println!("cargo:rerun-if-changed=kernel");
+    println!("cargo:rerun-if-changed=initrd");
 
     let kernel_path = {
         let path = var("CARGO_BIN_FILE_AK_OS_KERNEL").expect("kernel binary not found");
@@ -23,11 +29,19 @@ fn main() {
         PathBuf::from(path).join("pxe")
     };
 
-    let uefi_boot = bootloader::UefiBoot::new(&kernel_path);
+    let initrd_path = {
+        let path = var("OUT_DIR").expect("no target dir");
+        PathBuf::from(path).join("initrd.tar")
+    };
+    pack_initrd(Path::new("initrd"), &initrd_path).expect("failed to pack initrd");
+
+    let mut uefi_boot = bootloader::UefiBoot::new(&kernel_path);
+    uefi_boot.set_ramdisk(&initrd_path);
     uefi_boot
         .create_disk_image(&disk_path)
         .expect("failed to create boot partition");
-    let bios_boot = bootloader::BiosBoot::new(&kernel_path);
+    let mut bios_boot = bootloader::BiosBoot::new(&kernel_path);
+    bios_boot.set_ramdisk(&initrd_path);
     bios_boot
         .create_disk_image(&disk_path_bios)
         .expect("failed to create boot partition");
@@ -39,3 +53,104 @@ fn main() {
     println!("cargo:rustc-env=UEFI_PATH={}", disk_path.display());
     println!("cargo:rustc-env=BIOS_PATH={}", disk_path_bios.display());
 }
+
+/// Packs the contents of `dir` into an uncompressed ustar archive, which the kernel reads as its
+/// initial RAM disk. Entries are sorted and timestamps left at 0, so the archive only changes
+/// with the files.
+fn pack_initrd(dir: &Path, archive: &Path) -> io::Result<()> {
+    let mut out = Vec::new();
+    if dir.is_dir() {
+        append_dir(&mut out, dir, "")?;
+    }
+    // end of archive marker
+    out.resize(out.len() + 2 * BLOCK, 0);
+    fs::write(archive, out)
+}
+
+fn append_dir(out: &mut Vec<u8>, dir: &Path, prefix: &str) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let file_name = entry.file_name();
+        let name = file_name.to_str().expect("initrd file names must be UTF-8");
+        let path = format!("{prefix}{name}");
+        let metadata = fs::symlink_metadata(entry.path())?;
+
+        if metadata.file_type().is_symlink() {
+            let target = fs::read_link(entry.path())?;
+            let target = target.to_str().expect("initrd link targets must be UTF-8");
+            append_entry(out, &path, b'2', target, &[], 0o777);
+        } else if metadata.is_dir() {
+            let path = format!("{path}/");
+            append_entry(out, &path, b'5', "", &[], 0o755);
+            append_dir(out, &entry.path(), &path)?;
+        } else {
+            let data = fs::read(entry.path())?;
+            append_entry(out, &path, b'0', "", &data, mode(&metadata));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn mode(metadata: &Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o777
+}
+
+#[cfg(not(unix))]
+fn mode(_metadata: &Metadata) -> u32 {
+    0o644
+}
+
+fn append_entry(out: &mut Vec<u8>, path: &str, kind: u8, link: &str, data: &[u8], mode: u32) {
+    let mut header = [0u8; BLOCK];
+    let (prefix, name) = split_path(path);
+    assert!(link.len() <= 100, "link target too long for ustar: {link}");
+
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    octal(&mut header[100..108], mode as u64);
+    octal(&mut header[108..116], 0);
+    octal(&mut header[116..124], 0);
+    octal(&mut header[124..136], data.len() as u64);
+    octal(&mut header[136..148], 0);
+    header[156] = kind;
+    header[157..157 + link.len()].copy_from_slice(link.as_bytes());
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+
+    // the checksum is calculated with its own field filled with spaces
+    header[148..156].fill(b' ');
+    let checksum: u64 = header.iter().map(|&b| b as u64).sum();
+    octal(&mut header[148..155], checksum);
+
+    out.extend_from_slice(&header);
+    out.extend_from_slice(data);
+    out.resize(out.len() + (BLOCK - data.len() % BLOCK) % BLOCK, 0);
+}
+
+/// Writes `value` as zero padded octal digits followed by a NUL.
+fn octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    assert_eq!(
+        digits.len(),
+        field.len() - 1,
+        "{value} is too large for ustar"
+    );
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+    field[digits.len()] = 0;
+}
+
+/// Splits paths longer than 100 bytes into the prefix and name fields at a `/`.
+fn split_path(path: &str) -> (&str, &str) {
+    if path.len() <= 100 {
+        return ("", path);
+    }
+    path.match_indices('/')
+        .map(|(i, _)| i)
+        .find(|&i| i <= 155 && path.len() - i - 1 <= 100 && i + 1 < path.len())
+        .map(|i| (&path[..i], &path[i + 1..]))
+        .unwrap_or_else(|| panic!("path too long for ustar: {path}"))
+}
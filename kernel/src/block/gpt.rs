@@ -0,0 +1,267 @@
+//! GUID partition table
+//!
+//! The primary header is in LBA 1, followed by the partition entries. A backup of both is at the
+//! end of the disk, with the header in the last LBA. Both headers and the entries are protected
+//! by CRC32s. When the primary copy is damaged, the backup is used.
+
+use alloc::{string::String, vec::Vec};
+use core::fmt;
+
+use super::{
+    bytes, check_sector_size, read_sectors, read_u32, read_u64, BlockDevice, PartitionError,
+};
+
+const SIGNATURE: &[u8; 8] = b"EFI PART";
+const PRIMARY_LBA: u64 = 1;
+/// The smallest header, everything after it up to the end of the sector is reserved
+const MIN_HEADER_SIZE: usize = 92;
+const MIN_ENTRY_SIZE: usize = 128;
+/// The entries are read at once, this is far more than any disk has (the usual is 16 KiB)
+const MAX_ENTRIES_SIZE: usize = 1024 * 1024;
+/// UTF-16 code units in the name of a partition
+const NAME_LENGTH: usize = 36;
+
+/// A GUID in its on-disk layout, the first three fields are little-endian
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    /// Marks unused partition entries
+    pub const UNUSED: Guid = Guid([0; 16]);
+    pub const EFI_SYSTEM: Guid = Guid::from_fields(
+        0xc12a7328,
+        0xf81f,
+        0x11d2,
+        [0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e, 0xc9, 0x3b],
+    );
+    pub const BASIC_DATA: Guid = Guid::from_fields(
+        0xebd0a0a2,
+        0xb9e5,
+        0x4433,
+        [0x87, 0xc0, 0x68, 0xb6, 0xb7, 0x26, 0x99, 0xc7],
+    );
+    pub const LINUX_FILESYSTEM: Guid = Guid::from_fields(
+        0x0fc63daf,
+        0x8483,
+        0x4772,
+        [0x8e, 0x79, 0x3d, 0x69, 0xd8, 0x47, 0x7d, 0xe4],
+    );
+
+    /// The GUID written as `a-b-c-d[..2]-d[2..]`
+    pub const fn from_fields(a: u32, b: u16, c: u16, d: [u8; 8]) -> Self {
+        let [a0, a1, a2, a3] = a.to_le_bytes();
+        let [b0, b1] = b.to_le_bytes();
+        let [c0, c1] = c.to_le_bytes();
+        Guid([
+            a0, a1, a2, a3, b0, b1, c0, c1, d[0], d[1], d[2], d[3], d[4], d[5], d[6], d[7],
+        ])
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let g = &self.0;
+        write!(
+            f,
+            "{:08X}-{:04X}-{:04X}-",
+            u32::from_le_bytes([g[0], g[1], g[2], g[3]]),
+            u16::from_le_bytes([g[4], g[5]]),
+            u16::from_le_bytes([g[6], g[7]])
+        )?;
+        for (i, byte) in g[8..].iter().enumerate() {
+            if i == 2 {
+                f.write_str("-")?;
+            }
+            write!(f, "{:02X}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Guid({})", self)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GptPartition {
+    /// Index of the entry in the table, starting at 1
+    pub number: u32,
+    /// What the partition is used for, like [`Guid::EFI_SYSTEM`]
+    pub type_guid: Guid,
+    pub unique_guid: Guid,
+    pub first_lba: u64,
+    /// Last sector of the partition, inclusive
+    pub last_lba: u64,
+    pub attributes: u64,
+    pub name: String,
+}
+
+impl GptPartition {
+    pub fn sectors(&self) -> u64 {
+        self.last_lba - self.first_lba + 1
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Gpt {
+    pub disk_guid: Guid,
+    /// Where the header that was used is, 1 unless the backup was needed
+    pub header_lba: u64,
+    /// The sectors partitions can use, inclusive
+    pub first_usable_lba: u64,
+    pub last_usable_lba: u64,
+    /// Partitions in the order of their entries, unused entries are left out
+    pub partitions: Vec<GptPartition>,
+}
+
+/// Reads the GPT of `device`, from the backup if the primary header or entries are damaged.
+///
+/// Fails with [`PartitionError::NoTable`] if neither header has a GPT signature, or with what is
+/// wrong about the primary table if both are damaged.
+pub async fn read(device: &dyn BlockDevice) -> Result<Gpt, PartitionError> {
+    check_sector_size(device)?;
+    let primary = match read_at(device, PRIMARY_LBA).await {
+        Ok(table) => return Ok(table),
+        Err(e @ PartitionError::Device(_)) => return Err(e),
+        Err(e) => e,
+    };
+
+    let backup_lba = device.sector_count().saturating_sub(1);
+    if backup_lba <= PRIMARY_LBA {
+        return Err(primary);
+    }
+    match read_at(device, backup_lba).await {
+        Ok(table) => {
+            if primary != PartitionError::NoTable {
+                log::warn!("primary GPT is damaged ({}), using the backup", primary);
+            }
+            Ok(table)
+        }
+        Err(_) => Err(primary),
+    }
+}
+
+/// Reads the table with the header at `lba`.
+async fn read_at(device: &dyn BlockDevice, lba: u64) -> Result<Gpt, PartitionError> {
+    let sector_size = device.sector_size();
+    let header = read_sectors(device, lba, 1).await?;
+    if &bytes(&header, 0) != SIGNATURE {
+        return Err(PartitionError::NoTable);
+    }
+
+    let header_size = read_u32(&header, 12) as usize;
+    if !(MIN_HEADER_SIZE..=sector_size).contains(&header_size) {
+        return Err(PartitionError::Invalid("header size"));
+    }
+    let mut checked = header[..header_size].to_vec();
+    checked[16..20].fill(0);
+    if crc32(&checked) != read_u32(&header, 16) {
+        return Err(PartitionError::HeaderChecksum);
+    }
+    if read_u64(&header, 24) != lba {
+        return Err(PartitionError::Invalid("header is not where it says"));
+    }
+
+    let first_usable_lba = read_u64(&header, 40);
+    let last_usable_lba = read_u64(&header, 48);
+    if first_usable_lba > last_usable_lba || last_usable_lba >= device.sector_count() {
+        return Err(PartitionError::Invalid("usable blocks outside the disk"));
+    }
+
+    let entries_lba = read_u64(&header, 72);
+    let entry_count = read_u32(&header, 80) as usize;
+    let entry_size = read_u32(&header, 84) as usize;
+    // sizes are 128 times a power of two
+    if entry_size % MIN_ENTRY_SIZE != 0 || !(entry_size / MIN_ENTRY_SIZE).is_power_of_two() {
+        return Err(PartitionError::Invalid("entry size"));
+    }
+    let entries_size = entry_count
+        .checked_mul(entry_size)
+        .filter(|&size| size <= MAX_ENTRIES_SIZE)
+        .ok_or(PartitionError::Invalid("too many entries"))?;
+    let entries_sectors = (entries_size + sector_size - 1) / sector_size;
+    if !matches!(
+        entries_lba.checked_add(entries_sectors as u64),
+        Some(end) if end <= device.sector_count()
+    ) {
+        return Err(PartitionError::Invalid("entries outside the disk"));
+    }
+
+    let entries = read_sectors(device, entries_lba, entries_sectors).await?;
+    let entries = &entries[..entries_size];
+    if crc32(entries) != read_u32(&header, 88) {
+        return Err(PartitionError::EntriesChecksum);
+    }
+
+    let mut partitions = Vec::new();
+    for (index, entry) in entries.chunks_exact(entry_size).enumerate() {
+        let type_guid = Guid(bytes(entry, 0));
+        if type_guid == Guid::UNUSED {
+            continue;
+        }
+        let first_lba = read_u64(entry, 32);
+        let last_lba = read_u64(entry, 40);
+        if first_lba > last_lba || first_lba < first_usable_lba || last_lba > last_usable_lba {
+            return Err(PartitionError::Invalid(
+                "partition outside the usable blocks",
+            ));
+        }
+        partitions.push(GptPartition {
+            number: index as u32 + 1,
+            type_guid,
+            unique_guid: Guid(bytes(entry, 16)),
+            first_lba,
+            last_lba,
+            attributes: read_u64(entry, 48),
+            name: decode_name(&entry[56..56 + NAME_LENGTH * 2]),
+        });
+    }
+
+    Ok(Gpt {
+        disk_guid: Guid(bytes(&header, 56)),
+        header_lba: lba,
+        first_usable_lba,
+        last_usable_lba,
+        partitions,
+    })
+}
+
+/// Decodes a UTF-16LE name padded with zeros
+fn decode_name(data: &[u8]) -> String {
+    let units = data
+        .chunks_exact(2)
+        .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+        .take_while(|&unit| unit != 0);
+    char::decode_utf16(units)
+        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+        .collect()
+}
+
+/// CRC32 (IEEE 802.3) lookup table, by the low byte of the remainder
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, &byte| {
+        CRC32_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
@@ -0,0 +1,68 @@
+//! Master boot record partition table, as used by BIOS boot images
+//!
+//! Only the four primary partitions are read. Extended partitions are returned as they are,
+//! the logical partitions inside of them are not followed.
+
+use alloc::vec::Vec;
+
+use super::{check_sector_size, read_sectors, read_u32, BlockDevice, PartitionError};
+
+const TABLE_OFFSET: usize = 446;
+const ENTRY_SIZE: usize = 16;
+const BOOT_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+const BOOTABLE: u8 = 0x80;
+
+/// System ID of the single partition covering a GPT disk, see [`super::gpt`]
+pub const PROTECTIVE: u8 = 0xee;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MbrPartition {
+    /// Index of the entry in the table, from 1 to 4
+    pub number: u8,
+    pub bootable: bool,
+    /// What the partition is used for, like `0x0c` for FAT32 or `0x83` for Linux
+    pub system_id: u8,
+    pub first_lba: u64,
+    pub sectors: u64,
+}
+
+/// Reads the primary partitions of `device`, unused entries are left out.
+pub async fn read(device: &dyn BlockDevice) -> Result<Vec<MbrPartition>, PartitionError> {
+    check_sector_size(device)?;
+    let sector = read_sectors(device, 0, 1).await?;
+    if sector[510..512] != BOOT_SIGNATURE {
+        return Err(PartitionError::NoTable);
+    }
+
+    let mut partitions = Vec::new();
+    for (index, entry) in sector[TABLE_OFFSET..TABLE_OFFSET + 4 * ENTRY_SIZE]
+        .chunks_exact(ENTRY_SIZE)
+        .enumerate()
+    {
+        // boot sectors of unpartitioned disks have code here instead
+        let bootable = match entry[0] {
+            BOOTABLE => true,
+            0 => false,
+            _ => return Err(PartitionError::NoTable),
+        };
+        let system_id = entry[4];
+        let first_lba = read_u32(entry, 8) as u64;
+        let sectors = read_u32(entry, 12) as u64;
+        if system_id == 0 || sectors == 0 {
+            continue;
+        }
+        // the protective partition may claim more than the disk has, which GPT doesn't mind
+        let outside = first_lba == 0 || first_lba + sectors > device.sector_count();
+        if outside && system_id != PROTECTIVE {
+            return Err(PartitionError::Invalid("partition outside the disk"));
+        }
+        partitions.push(MbrPartition {
+            number: index as u8 + 1,
+            bootable,
+            system_id,
+            first_lba,
+            sectors,
+        });
+    }
+    Ok(partitions)
+}
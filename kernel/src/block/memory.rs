@@ -0,0 +1,71 @@
+//! A block device in memory
+
+use alloc::{boxed::Box, vec, vec::Vec};
+
+use super::{check_access, BlockDevice, BlockError, BlockFuture};
+use crate::util::Spinlock;
+
+/// A disk kept on the heap, like a disk image loaded by the bootloader
+pub struct MemoryDisk {
+    sector_size: usize,
+    data: Spinlock<Vec<u8>>,
+}
+
+impl MemoryDisk {
+    /// A disk of `sectors` zeroed sectors, `sector_size` must not be 0
+    pub fn new(sector_size: usize, sectors: usize) -> Self {
+        assert!(sector_size != 0, "sectors of 0 bytes");
+        Self {
+            sector_size,
+            data: Spinlock::new(vec![0; sector_size * sectors]),
+        }
+    }
+
+    /// A disk with the contents of `data`, which has to be a whole number of sectors
+    pub fn from_bytes(sector_size: usize, data: Vec<u8>) -> Result<Self, BlockError> {
+        if sector_size == 0 || data.len() % sector_size != 0 {
+            return Err(BlockError::Unaligned);
+        }
+        Ok(Self {
+            sector_size,
+            data: Spinlock::new(data),
+        })
+    }
+
+    /// A copy of the whole disk
+    pub fn contents(&self) -> Vec<u8> {
+        self.data.lock_sync().clone()
+    }
+}
+
+impl BlockDevice for MemoryDisk {
+    fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    fn sector_count(&self) -> u64 {
+        (self.data.lock_sync().len() / self.sector_size) as u64
+    }
+
+    fn read<'a>(&'a self, lba: u64, buf: &'a mut [u8]) -> BlockFuture<'a, ()> {
+        Box::pin(async move {
+            check_access(self, lba, buf.len())?;
+            let start = lba as usize * self.sector_size;
+            buf.copy_from_slice(&self.data.lock().await[start..start + buf.len()]);
+            Ok(())
+        })
+    }
+
+    fn write<'a>(&'a self, lba: u64, buf: &'a [u8]) -> BlockFuture<'a, ()> {
+        Box::pin(async move {
+            check_access(self, lba, buf.len())?;
+            let start = lba as usize * self.sector_size;
+            self.data.lock().await[start..start + buf.len()].copy_from_slice(buf);
+            Ok(())
+        })
+    }
+
+    fn flush(&self) -> BlockFuture<'_, ()> {
+        Box::pin(async { Ok(()) })
+    }
+}
@@ -0,0 +1,149 @@
+//! Block devices and their partitions
+//!
+//! A [`BlockDevice`] reads and writes whole sectors, addressed by their logical block address
+//! (LBA). Disks are split into partitions by a GPT, see [`gpt`], or by the MBR of older BIOS
+//! images, see [`mbr`]. [`scan`] reads whichever table a disk has and returns every partition as
+//! a [`Partition`], which is a block device itself.
+
+use alloc::{boxed::Box, sync::Arc, vec, vec::Vec};
+use core::{future::Future, pin::Pin};
+use thiserror_no_std::Error;
+
+pub mod gpt;
+pub mod mbr;
+mod memory;
+mod partition;
+
+pub use memory::MemoryDisk;
+pub use partition::{Partition, PartitionInfo};
+
+/// The future returned by block device operations
+pub type BlockFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, BlockError>> + Send + 'a>>;
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    #[error("access past the end of the device")]
+    OutOfRange,
+    #[error("buffer is not a whole number of sectors")]
+    Unaligned,
+    #[error("device is read-only")]
+    ReadOnly,
+    #[error("i/o error")]
+    Io,
+}
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionError {
+    #[error("no partition table")]
+    NoTable,
+    #[error("sectors of {0} bytes are not supported")]
+    UnsupportedSectorSize(usize),
+    #[error("invalid partition table: {0}")]
+    Invalid(&'static str),
+    #[error("GPT header checksum mismatch")]
+    HeaderChecksum,
+    #[error("GPT partition entries checksum mismatch")]
+    EntriesChecksum,
+    #[error("failed to read the partition table: {0}")]
+    Device(#[from] BlockError),
+}
+
+/// A device storing data in sectors of a fixed size
+///
+/// Buffers passed to [`read`](BlockDevice::read) and [`write`](BlockDevice::write) cover one or
+/// more whole sectors, starting at `lba`.
+pub trait BlockDevice: Send + Sync {
+    /// Size of a sector in bytes
+    fn sector_size(&self) -> usize;
+
+    fn sector_count(&self) -> u64;
+
+    fn read<'a>(&'a self, lba: u64, buf: &'a mut [u8]) -> BlockFuture<'a, ()>;
+
+    fn write<'a>(&'a self, lba: u64, buf: &'a [u8]) -> BlockFuture<'a, ()>;
+
+    /// Waits until everything written so far is stored on the device.
+    fn flush(&self) -> BlockFuture<'_, ()>;
+}
+
+/// Checks that `len` bytes are whole sectors of `device` and that they end inside of it when
+/// starting at `lba`. For implementations of [`BlockDevice`].
+pub fn check_access(device: &dyn BlockDevice, lba: u64, len: usize) -> Result<(), BlockError> {
+    let sector_size = device.sector_size();
+    if len % sector_size != 0 {
+        return Err(BlockError::Unaligned);
+    }
+    let end = lba.checked_add((len / sector_size) as u64);
+    if !matches!(end, Some(end) if end <= device.sector_count()) {
+        return Err(BlockError::OutOfRange);
+    }
+    Ok(())
+}
+
+/// Reads the partition table of `device`: the GPT, or the MBR if there is none.
+///
+/// A protective MBR without a readable GPT is an error, a damaged GPT is never read as MBR.
+pub async fn scan(device: Arc<dyn BlockDevice>) -> Result<Vec<Partition>, PartitionError> {
+    let infos: Vec<PartitionInfo> = match gpt::read(&*device).await {
+        Ok(table) => table
+            .partitions
+            .into_iter()
+            .map(PartitionInfo::Gpt)
+            .collect(),
+        Err(PartitionError::NoTable) => {
+            let partitions = mbr::read(&*device).await?;
+            if partitions
+                .iter()
+                .any(|partition| partition.system_id == mbr::PROTECTIVE)
+            {
+                return Err(PartitionError::Invalid("protective MBR without a GPT"));
+            }
+            partitions.into_iter().map(PartitionInfo::Mbr).collect()
+        }
+        Err(e) => return Err(e),
+    };
+
+    #[cfg(feature = "dbg-block")]
+    for info in infos.iter() {
+        log::trace!("partition {:?}", info);
+    }
+
+    Ok(infos
+        .into_iter()
+        .map(|info| Partition::new(device.clone(), info))
+        .collect())
+}
+
+/// Partition tables are read in sectors of at least 512 bytes, the size of the MBR
+fn check_sector_size(device: &dyn BlockDevice) -> Result<(), PartitionError> {
+    let size = device.sector_size();
+    if size < 512 || !size.is_power_of_two() {
+        return Err(PartitionError::UnsupportedSectorSize(size));
+    }
+    Ok(())
+}
+
+async fn read_sectors(
+    device: &dyn BlockDevice,
+    lba: u64,
+    count: usize,
+) -> Result<Vec<u8>, BlockError> {
+    let mut data = vec![0; count * device.sector_size()];
+    device.read(lba, &mut data).await?;
+    Ok(data)
+}
+
+/// The `N` bytes at `offset`, which the caller made sure are in `data`
+fn bytes<const N: usize>(data: &[u8], offset: usize) -> [u8; N] {
+    data[offset..offset + N]
+        .try_into()
+        .expect("slice has the length of the array")
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes(data, offset))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes(data, offset))
+}
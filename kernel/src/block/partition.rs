@@ -0,0 +1,108 @@
+//! Partitions as block devices
+
+use alloc::{boxed::Box, sync::Arc};
+
+use super::{
+    check_access, gpt::GptPartition, mbr::MbrPartition, BlockDevice, BlockError, BlockFuture,
+};
+
+/// The table entry a partition was found in
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PartitionInfo {
+    Gpt(GptPartition),
+    Mbr(MbrPartition),
+}
+
+impl PartitionInfo {
+    /// Index of the entry in its table, starting at 1
+    pub fn number(&self) -> u32 {
+        match self {
+            PartitionInfo::Gpt(partition) => partition.number,
+            PartitionInfo::Mbr(partition) => partition.number as u32,
+        }
+    }
+
+    pub fn first_lba(&self) -> u64 {
+        match self {
+            PartitionInfo::Gpt(partition) => partition.first_lba,
+            PartitionInfo::Mbr(partition) => partition.first_lba,
+        }
+    }
+
+    pub fn sectors(&self) -> u64 {
+        match self {
+            PartitionInfo::Gpt(partition) => partition.sectors(),
+            PartitionInfo::Mbr(partition) => partition.sectors,
+        }
+    }
+}
+
+/// A range of sectors of another device, LBA 0 is the first sector of the partition
+pub struct Partition {
+    device: Arc<dyn BlockDevice>,
+    info: PartitionInfo,
+}
+
+impl Partition {
+    /// The partition of `device` described by `info`, which has to be inside of it.
+    pub fn new(device: Arc<dyn BlockDevice>, info: PartitionInfo) -> Self {
+        assert!(
+            matches!(
+                info.first_lba().checked_add(info.sectors()),
+                Some(end) if end <= device.sector_count()
+            ),
+            "partition outside of its device"
+        );
+        Self { device, info }
+    }
+
+    pub fn info(&self) -> &PartitionInfo {
+        &self.info
+    }
+
+    /// The device the partition is on
+    pub fn device(&self) -> &Arc<dyn BlockDevice> {
+        &self.device
+    }
+
+    fn device_lba(&self, lba: u64, len: usize) -> Result<u64, BlockError> {
+        check_access(self, lba, len)?;
+        Ok(self.info.first_lba() + lba)
+    }
+}
+
+impl BlockDevice for Partition {
+    fn sector_size(&self) -> usize {
+        self.device.sector_size()
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.info.sectors()
+    }
+
+    fn read<'a>(&'a self, lba: u64, buf: &'a mut [u8]) -> BlockFuture<'a, ()> {
+        Box::pin(async move {
+            let lba = self.device_lba(lba, buf.len())?;
+            self.device.read(lba, buf).await
+        })
+    }
+
+    fn write<'a>(&'a self, lba: u64, buf: &'a [u8]) -> BlockFuture<'a, ()> {
+        Box::pin(async move {
+            let lba = self.device_lba(lba, buf.len())?;
+            self.device.write(lba, buf).await
+        })
+    }
+
+    fn flush(&self) -> BlockFuture<'_, ()> {
+        self.device.flush()
+    }
+}
+
+impl core::fmt::Debug for Partition {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Partition")
+            .field("info", &self.info)
+            .finish()
+    }
+}
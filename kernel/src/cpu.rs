@@ -0,0 +1,430 @@
+//! CPU identification and feature detection
+//!
+//! Every CPU parses its CPUID leaves during startup with [`init`], the result is kept per
+//! logical CPU (see [`crate::smp::Cpu::id`]) and can be queried with [`get`].
+//!
+//! Code that depends on optional hardware features should check [`features`] instead of
+//! assuming they are present.
+
+use alloc::{boxed::Box, collections::BTreeMap, string::String, vec::Vec};
+use bitflags::bitflags;
+use core::arch::asm;
+use lazy_static::lazy_static;
+use raw_cpuid::{CacheType, CpuId, TopologyType};
+use x86_64::registers::model_specific::Msr;
+
+use crate::{smp::MAX_CPUS, util::Spinlock};
+
+const IA32_TSC_AUX: u32 = 0xC000_0103;
+
+static CPUS: Spinlock<BTreeMap<u32, &'static CpuInfo>> = Spinlock::new(BTreeMap::new());
+
+lazy_static! {
+    /// Features of the boot processor, detected on first use so it is available before the heap
+    static ref BOOT_FEATURES: Features = detect_features(&CpuId::new());
+}
+
+bitflags! {
+    /// Optional CPU features the kernel cares about
+    pub struct Features: u64 {
+        const FPU = 1 << 0;
+        const TSC = 1 << 1;
+        const MSR = 1 << 2;
+        const APIC = 1 << 3;
+        const PGE = 1 << 4;
+        const PAT = 1 << 5;
+        const FXSR = 1 << 6;
+        const SSE = 1 << 7;
+        const SSE2 = 1 << 8;
+        const SSE3 = 1 << 9;
+        const SSSE3 = 1 << 10;
+        const SSE4_1 = 1 << 11;
+        const SSE4_2 = 1 << 12;
+        const AVX = 1 << 13;
+        const AVX2 = 1 << 14;
+        const AVX512F = 1 << 15;
+        const XSAVE = 1 << 16;
+        const X2APIC = 1 << 17;
+        const TSC_DEADLINE = 1 << 18;
+        const INVARIANT_TSC = 1 << 19;
+        const RDRAND = 1 << 20;
+        const RDSEED = 1 << 21;
+        const PCID = 1 << 22;
+        const INVPCID = 1 << 23;
+        const SMEP = 1 << 24;
+        const SMAP = 1 << 25;
+        const UMIP = 1 << 26;
+        const FSGSBASE = 1 << 27;
+        const NX = 1 << 28;
+        const PAGE_1GB = 1 << 29;
+        const SYSCALL = 1 << 30;
+        const RDTSCP = 1 << 31;
+        const HYPERVISOR = 1 << 32;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheKind {
+    Data,
+    Instruction,
+    Unified,
+}
+
+#[derive(Debug, Clone)]
+pub struct Cache {
+    pub level: u8,
+    pub kind: CacheKind,
+    /// Size in bytes
+    pub size: usize,
+    pub line_size: usize,
+    pub ways: usize,
+    /// Maximum number of logical CPUs sharing this cache
+    pub shared_by: usize,
+}
+
+/// Position of a logical CPU in the package/core/thread hierarchy
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Topology {
+    pub package: u32,
+    pub core: u32,
+    pub thread: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct CpuInfo {
+    pub vendor: String,
+    pub brand: Option<String>,
+    pub family: u8,
+    pub model: u8,
+    pub stepping: u8,
+    pub features: Features,
+    /// Initial (x2)APIC ID as reported by CPUID
+    pub apic_id: u32,
+    pub topology: Topology,
+    pub caches: Vec<Cache>,
+    pub physical_address_bits: u8,
+    pub linear_address_bits: u8,
+    /// TSC frequency in Hz, if the CPU reports it
+    pub tsc_frequency: Option<u64>,
+    /// Frequency the local APIC timer counts at in Hz, if the CPU reports it
+    pub apic_timer_frequency: Option<u64>,
+}
+
+impl CpuInfo {
+    /// Parses the CPUID leaves of the CPU this is running on.
+    pub fn detect() -> Self {
+        let cpuid = CpuId::new();
+
+        let vendor = cpuid
+            .get_vendor_info()
+            .map(|v| v.as_str().into())
+            .unwrap_or_else(|| String::from("unknown"));
+        let brand = cpuid
+            .get_processor_brand_string()
+            .map(|b| b.as_str().trim().into());
+
+        let feature_info = cpuid.get_feature_info();
+        let (family, model, stepping) = feature_info
+            .as_ref()
+            .map(|f| (f.family_id(), f.model_id(), f.stepping_id()))
+            .unwrap_or_default();
+
+        let (apic_id, topology) = detect_topology(&cpuid);
+
+        let (physical_address_bits, linear_address_bits) = cpuid
+            .get_processor_capacity_feature_info()
+            .map(|c| (c.physical_address_bits(), c.linear_address_bits()))
+            .unwrap_or((36, 48));
+
+        Self {
+            vendor,
+            brand,
+            family,
+            model,
+            stepping,
+            features: detect_features(&cpuid),
+            apic_id,
+            topology,
+            caches: detect_caches(&cpuid),
+            physical_address_bits,
+            linear_address_bits,
+            tsc_frequency: detect_tsc_frequency(&cpuid),
+            apic_timer_frequency: detect_apic_timer_frequency(&cpuid),
+        }
+    }
+
+    fn log_summary(&self, id: u32) {
+        let level = if id == 0 {
+            log::Level::Info
+        } else {
+            log::Level::Debug
+        };
+
+        log::log!(
+            level,
+            "cpu {}: {} {} (family {:#x}, model {:#x}, stepping {})",
+            id,
+            self.vendor,
+            self.brand.as_deref().unwrap_or("unknown model"),
+            self.family,
+            self.model,
+            self.stepping
+        );
+        log::log!(
+            level,
+            "cpu {}: apic id {}, package {}, core {}, thread {}",
+            id,
+            self.apic_id,
+            self.topology.package,
+            self.topology.core,
+            self.topology.thread
+        );
+        log::log!(level, "cpu {}: features: {:?}", id, self.features);
+
+        for cache in self.caches.iter() {
+            log::debug!(
+                "cpu {}: L{} {:?} cache: {} KiB, {}-way, {} byte lines, shared by {} threads",
+                id,
+                cache.level,
+                cache.kind,
+                cache.size / 1024,
+                cache.ways,
+                cache.line_size,
+                cache.shared_by
+            );
+        }
+    }
+}
+
+/// The TSC frequency from leaf 0x15, or else the nominal frequency from leaf 0x16, which the
+/// TSC runs at on CPUs without leaf 0x15.
+fn detect_tsc_frequency(cpuid: &CpuId) -> Option<u64> {
+    cpuid
+        .get_tsc_info()
+        .and_then(|t| t.tsc_frequency())
+        .or_else(|| {
+            let mhz = cpuid
+                .get_processor_frequency_info()?
+                .processor_base_frequency();
+            (mhz != 0).then(|| mhz as u64 * 1_000_000)
+        })
+}
+
+/// The local APIC timer counts at the core crystal clock from leaf 0x15, or at the bus clock
+/// from leaf 0x16 on CPUs without it.
+fn detect_apic_timer_frequency(cpuid: &CpuId) -> Option<u64> {
+    let crystal = cpuid.get_tsc_info().map(|t| t.nominal_frequency() as u64);
+    let bus = cpuid
+        .get_processor_frequency_info()
+        .map(|f| f.bus_frequency() as u64 * 1_000_000);
+    crystal.filter(|&hz| hz != 0).or(bus.filter(|&hz| hz != 0))
+}
+
+fn detect_features(cpuid: &CpuId) -> Features {
+    let mut features = Features::empty();
+
+    if let Some(f) = cpuid.get_feature_info() {
+        features.set(Features::FPU, f.has_fpu());
+        features.set(Features::TSC, f.has_tsc());
+        features.set(Features::MSR, f.has_msr());
+        features.set(Features::APIC, f.has_apic());
+        features.set(Features::PGE, f.has_pge());
+        features.set(Features::PAT, f.has_pat());
+        features.set(Features::FXSR, f.has_fxsave_fxstor());
+        features.set(Features::SSE, f.has_sse());
+        features.set(Features::SSE2, f.has_sse2());
+        features.set(Features::SSE3, f.has_sse3());
+        features.set(Features::SSSE3, f.has_ssse3());
+        features.set(Features::SSE4_1, f.has_sse41());
+        features.set(Features::SSE4_2, f.has_sse42());
+        features.set(Features::AVX, f.has_avx());
+        features.set(Features::XSAVE, f.has_xsave());
+        features.set(Features::X2APIC, f.has_x2apic());
+        features.set(Features::TSC_DEADLINE, f.has_tsc_deadline());
+        features.set(Features::RDRAND, f.has_rdrand());
+        features.set(Features::PCID, f.has_pcid());
+        features.set(Features::HYPERVISOR, f.has_hypervisor());
+    }
+
+    if let Some(f) = cpuid.get_extended_feature_info() {
+        features.set(Features::AVX2, f.has_avx2());
+        features.set(Features::AVX512F, f.has_avx512f());
+        features.set(Features::RDSEED, f.has_rdseed());
+        features.set(Features::INVPCID, f.has_invpcid());
+        features.set(Features::SMEP, f.has_smep());
+        features.set(Features::SMAP, f.has_smap());
+        features.set(Features::UMIP, f.has_umip());
+        features.set(Features::FSGSBASE, f.has_fsgsbase());
+    }
+
+    if let Some(f) = cpuid.get_extended_processor_and_feature_identifiers() {
+        features.set(Features::NX, f.has_execute_disable());
+        features.set(Features::PAGE_1GB, f.has_1gib_pages());
+        features.set(Features::SYSCALL, f.has_syscall_sysret());
+        features.set(Features::RDTSCP, f.has_rdtscp());
+    }
+
+    if let Some(f) = cpuid.get_advanced_power_mgmt_info() {
+        features.set(Features::INVARIANT_TSC, f.has_invariant_tsc());
+    }
+
+    features
+}
+
+/// Splits the x2APIC ID into package/core/thread IDs using the extended topology leaf,
+/// falling back to treating every CPU as its own core.
+fn detect_topology(cpuid: &CpuId) -> (u32, Topology) {
+    let initial_apic_id = cpuid
+        .get_feature_info()
+        .map(|f| f.initial_local_apic_id() as u32)
+        .unwrap_or(0);
+
+    let levels = match cpuid.get_extended_topology_info() {
+        Some(levels) => levels,
+        None => {
+            return (
+                initial_apic_id,
+                Topology {
+                    core: initial_apic_id,
+                    ..Default::default()
+                },
+            )
+        }
+    };
+
+    let mut apic_id = initial_apic_id;
+    let mut smt_shift = 0;
+    let mut core_shift = 0;
+    for level in levels {
+        apic_id = level.x2apic_id();
+        match level.level_type() {
+            TopologyType::SMT => smt_shift = level.shift_right_for_next_apic_id(),
+            TopologyType::Invalid => break,
+            _ => core_shift = level.shift_right_for_next_apic_id(),
+        }
+    }
+    let core_shift = core_shift.max(smt_shift);
+
+    let mask = |bits: u32| (1u32 << bits) - 1;
+    let topology = Topology {
+        package: apic_id.checked_shr(core_shift).unwrap_or(0),
+        core: (apic_id >> smt_shift) & mask(core_shift - smt_shift),
+        thread: apic_id & mask(smt_shift),
+    };
+    (apic_id, topology)
+}
+
+fn detect_caches(cpuid: &CpuId) -> Vec<Cache> {
+    let params = match cpuid.get_cache_parameters() {
+        Some(params) => params,
+        None => return Vec::new(),
+    };
+
+    params
+        .filter_map(|c| {
+            let kind = match c.cache_type() {
+                CacheType::Data => CacheKind::Data,
+                CacheType::Instruction => CacheKind::Instruction,
+                CacheType::Unified => CacheKind::Unified,
+                _ => return None,
+            };
+            Some(Cache {
+                level: c.level(),
+                kind,
+                size: c.associativity()
+                    * c.physical_line_partitions()
+                    * c.coherency_line_size()
+                    * c.sets(),
+                line_size: c.coherency_line_size(),
+                ways: c.associativity(),
+                shared_by: c.max_cores_for_cache(),
+            })
+        })
+        .collect()
+}
+
+/// Detects and stores the CPU information for the CPU with the given logical ID.
+///
+/// This should be called once on every CPU, as early as possible after the heap is
+/// initialized, since [`current_id`] depends on it.
+pub fn init(id: u32) {
+    if has(Features::RDTSCP) {
+        unsafe { Msr::new(IA32_TSC_AUX).write(id as u64) };
+    }
+
+    let info = Box::leak::<'static>(Box::new(CpuInfo::detect()));
+    info.log_summary(id);
+
+    if !info.features.contains(*BOOT_FEATURES) {
+        log::warn!(
+            "cpu {} is missing features of the boot cpu: {:?}",
+            id,
+            *BOOT_FEATURES - info.features
+        );
+    }
+
+    CPUS.lock_sync().insert(id, info);
+}
+
+/// Returns the information of the CPU with the given logical ID, if it has been initialized.
+pub fn get(id: u32) -> Option<&'static CpuInfo> {
+    CPUS.lock_sync().get(&id).copied()
+}
+
+/// Returns the features of the boot processor.
+///
+/// Application processors are assumed to be symmetric, a warning is logged on startup if they
+/// are not.
+pub fn features() -> Features {
+    *BOOT_FEATURES
+}
+
+/// Shorthand for checking whether the boot processor supports all of the given `features`.
+#[inline]
+pub fn has(features: Features) -> bool {
+    self::features().contains(features)
+}
+
+/// Returns the logical ID of the CPU this is running on.
+///
+/// The ID is kept in `IA32_TSC_AUX` and read with `RDTSCP`, or else read from the GDT of the
+/// CPU, see [`crate::gdt::current_cpu`]. Both are cheap enough for hot paths like the
+/// allocator. Before either is set up, the local APIC ID is looked up in the SMP CPU table
+/// instead. Before [`init`] ran on the current CPU, the result is meaningless, but it's always
+/// below [`MAX_CPUS`] so it can index per CPU data.
+pub fn current_id() -> u32 {
+    if let Some(id) = try_current_id() {
+        return id;
+    }
+
+    let apic_id = CpuId::new()
+        .get_feature_info()
+        .map(|f| f.initial_local_apic_id() as u32)
+        .unwrap_or(0);
+    crate::smp::cpus()
+        .iter()
+        .find(|c| c.local_apic_id == apic_id)
+        .map_or(0, |c| c.id)
+}
+
+/// Like [`current_id`], but `None` instead of looking up the local APIC ID, which is too slow
+/// for interrupt handlers.
+pub fn try_current_id() -> Option<u32> {
+    if has(Features::RDTSCP) {
+        let id: u32;
+        unsafe {
+            asm!(
+                "rdtscp",
+                out("eax") _,
+                out("edx") _,
+                out("ecx") id,
+                options(nomem, nostack, preserves_flags)
+            );
+        }
+        // whatever the firmware left in there before `init`
+        if (id as usize) < MAX_CPUS {
+            return Some(id);
+        }
+    }
+    crate::gdt::current_cpu().filter(|&id| (id as usize) < MAX_CPUS)
+}
@@ -0,0 +1,305 @@
+//! Loading programs into user address spaces
+//!
+//! Segments are mapped eagerly with the rights from their flags, a memory area is added for
+//! each so the layout shows up in [`AddressSpace::vmas`]. Position independent executables
+//! are loaded at a random base and their relative relocations applied. The stack is populated
+//! on demand, below it is a guard area.
+//!
+//! The initial stack follows the System V ABI: `argc` at the stack pointer, followed by the
+//! `argv` and `envp` pointer arrays, each terminated by a null pointer, and the auxiliary
+//! vector. The strings and 16 random bytes for `AT_RANDOM` are at the top of the stack.
+
+use alloc::vec::Vec;
+use x86_64::{
+    align_down, align_up,
+    structures::paging::{Page, PageSize, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+use super::{Elf, ElfError, FileType, ProgramHeader, SegmentFlags, SegmentType};
+use crate::{
+    mem::{
+        address_space::{activate_kernel, USER_END},
+        vma::{Vma, VmaFlags},
+        AddressSpace,
+    },
+    random,
+    user::{self, Exit},
+};
+
+/// Position independent executables are loaded somewhere in the `PIE_RANGE` bytes above this
+const PIE_BASE: u64 = 0x_1000_0000_0000;
+const PIE_RANGE: u64 = 1 << 40;
+const PIE_ATTEMPTS: usize = 16;
+
+const STACK_PAGES: u64 = 64;
+const STACK_GUARD_PAGES: u64 = 16;
+/// The stack is moved down by up to this many pages from the top of free memory
+const STACK_RANDOM_PAGES: u64 = 1 << 16;
+/// At most this much of the stack may be taken by arguments and the environment
+const MAX_ARGUMENTS_SIZE: u64 = STACK_PAGES * Size4KiB::SIZE / 4;
+
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_BASE: u64 = 7;
+const AT_FLAGS: u64 = 8;
+const AT_ENTRY: u64 = 9;
+const AT_SECURE: u64 = 23;
+const AT_RANDOM: u64 = 25;
+const AT_EXECFN: u64 = 31;
+
+/// A program loaded into its own address space, ready to run
+pub struct Program {
+    pub space: AddressSpace,
+    pub entry: VirtAddr,
+    /// Initial stack pointer, pointing at `argc`
+    pub stack_pointer: VirtAddr,
+    /// What was added to the addresses in the file, 0 unless position independent
+    pub base: u64,
+}
+
+impl Program {
+    /// Runs the program on the current CPU until it exits.
+    ///
+    /// # Safety
+    ///
+    /// No other user program may be running on this CPU, see [`user::run`].
+    pub unsafe fn run(&self) -> Exit {
+        self.space.activate();
+        let exit = user::run(self.entry, self.stack_pointer);
+        activate_kernel();
+        exit
+    }
+}
+
+/// Loads the ELF executable in `data` into a new address space and prepares its stack.
+pub fn load(data: &[u8], argv: &[&str], envp: &[&str]) -> Result<Program, ElfError> {
+    let elf = Elf::parse(data)?;
+    let space = AddressSpace::new()?;
+
+    let base = match elf.kind {
+        FileType::Executable => 0,
+        FileType::Dynamic => choose_base(&space, &elf)?,
+    };
+    map_segments(&space, &elf, base)?;
+    for (offset, addend) in elf.relative_relocations()? {
+        let in_segment = elf
+            .segments()
+            .any(|h| h.vaddr <= offset && offset.saturating_add(8) <= h.memory().end);
+        if !in_segment {
+            return Err(ElfError::BadRelocation(offset));
+        }
+        space.write(
+            VirtAddr::new(base + offset),
+            &base.wrapping_add(addend).to_le_bytes(),
+        )?;
+    }
+
+    let entry = user_addr(base, elf.entry)?;
+    let stack_pointer = setup_stack(&space, &elf, base, entry, argv, envp)?;
+
+    #[cfg(feature = "dbg-mem")]
+    log::debug!(
+        "loaded program at {:#x}, entry at {:?}, stack at {:?}",
+        base,
+        entry,
+        stack_pointer
+    );
+
+    Ok(Program {
+        space,
+        entry,
+        stack_pointer,
+        base,
+    })
+}
+
+/// `base + vaddr`, if that is a user address
+fn user_addr(base: u64, vaddr: u64) -> Result<VirtAddr, ElfError> {
+    base.checked_add(vaddr)
+        .filter(|&addr| addr < USER_END)
+        .map(VirtAddr::new)
+        .ok_or(ElfError::NoSpace)
+}
+
+/// Pages spanning `memory`, relocated by `base`
+fn pages(base: u64, header: &ProgramHeader) -> Result<(Page, Page), ElfError> {
+    let memory = header.memory();
+    let start = user_addr(base, memory.start)?;
+    let end = user_addr(base, align_up(memory.end, Size4KiB::SIZE))?;
+    Ok((
+        Page::containing_address(start),
+        Page::containing_address(end),
+    ))
+}
+
+/// A random load address for a position independent executable
+fn choose_base(space: &AddressSpace, elf: &Elf) -> Result<u64, ElfError> {
+    let align = elf
+        .segments()
+        .map(|h| h.align)
+        .fold(Size4KiB::SIZE, u64::max);
+    let low = align_down(elf.segments().map(|h| h.vaddr).min().unwrap_or(0), align);
+    let high = elf.segments().map(|h| h.memory().end).max().unwrap_or(0);
+    let size = align_up(high - low, Size4KiB::SIZE);
+    if size > PIE_RANGE || !align.is_power_of_two() {
+        return Err(ElfError::NoSpace);
+    }
+
+    for _ in 0..PIE_ATTEMPTS {
+        let start = PIE_BASE + random::below((PIE_RANGE - size) / align + 1) * align;
+        let first = Page::containing_address(VirtAddr::new(start));
+        if space.is_free(Page::range(first, first + size / Size4KiB::SIZE)) {
+            return Ok(start - low);
+        }
+    }
+    Err(ElfError::NoSpace)
+}
+
+fn map_segments(space: &AddressSpace, elf: &Elf, base: u64) -> Result<(), ElfError> {
+    // end of the memory areas added so far, segments may share their first and last page
+    let mut covered = None;
+
+    for header in elf.segments() {
+        let (start, end) = pages(base, header)?;
+        let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        if header.flags.contains(SegmentFlags::WRITE) {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if !header.flags.contains(SegmentFlags::EXECUTE) {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+
+        for page in Page::range(start, end) {
+            match space.translate(page.start_address()) {
+                Some((_, old)) => {
+                    // shared with the previous segment, allow what either allows
+                    let mut merged = old | (flags & PageTableFlags::WRITABLE);
+                    if !flags.contains(PageTableFlags::NO_EXECUTE) {
+                        merged -= PageTableFlags::NO_EXECUTE;
+                    }
+                    space.update_flags(page, merged)?
+                }
+                None => space.map(Page::range(page, page + 1), flags)?,
+            }
+        }
+        space.write(VirtAddr::new(base + header.vaddr), elf.contents(header))?;
+
+        let area_start = covered.map_or(start, |c: Page| c.max(start));
+        if area_start < end {
+            space.add_vma(Vma::anonymous(
+                Page::range(area_start, end),
+                vma_flags(header.flags),
+            ))?;
+        }
+        covered = Some(end);
+    }
+    Ok(())
+}
+
+fn vma_flags(flags: SegmentFlags) -> VmaFlags {
+    let mut vma = VmaFlags::empty();
+    vma.set(VmaFlags::READ, flags.contains(SegmentFlags::READ));
+    vma.set(VmaFlags::WRITE, flags.contains(SegmentFlags::WRITE));
+    vma.set(VmaFlags::EXECUTE, flags.contains(SegmentFlags::EXECUTE));
+    vma
+}
+
+/// Where the program headers are in memory, if they are loaded
+fn program_headers_addr(elf: &Elf, base: u64) -> Option<u64> {
+    if let Some(header) = elf
+        .program_headers
+        .iter()
+        .find(|h| h.kind == SegmentType::ProgramHeaders)
+    {
+        return Some(base + header.vaddr);
+    }
+    let offset = elf.program_header_offset;
+    elf.segments()
+        .find(|h| h.offset <= offset && offset < h.offset + h.file_size)
+        .map(|h| base + h.vaddr + offset - h.offset)
+}
+
+/// Maps the stack and writes the arguments, environment and auxiliary vector to it. Returns
+/// the initial stack pointer.
+fn setup_stack(
+    space: &AddressSpace,
+    elf: &Elf,
+    base: u64,
+    entry: VirtAddr,
+    argv: &[&str],
+    envp: &[&str],
+) -> Result<VirtAddr, ElfError> {
+    let gap = random::below(STACK_RANDOM_PAGES);
+    let area = space
+        .find_free(STACK_GUARD_PAGES + STACK_PAGES + gap)
+        .ok_or(ElfError::NoSpace)?;
+    let guard = Page::range(area.start, area.start + STACK_GUARD_PAGES);
+    let stack = Page::range(guard.end, guard.end + STACK_PAGES);
+    space.map_guard(guard)?;
+    space.map_anonymous(stack, VmaFlags::READ | VmaFlags::WRITE)?;
+    let top = stack.end.start_address().as_u64();
+
+    // strings and random bytes, at the top of the stack
+    let mut info = Vec::new();
+    let mut random_bytes = [0u8; 16];
+    random::fill(&mut random_bytes);
+    info.extend_from_slice(&random_bytes);
+    let mut string_offsets = Vec::new();
+    for s in argv.iter().chain(envp) {
+        string_offsets.push(info.len() as u64);
+        info.extend_from_slice(s.as_bytes());
+        info.push(0);
+    }
+    let info_start = align_down(top - info.len() as u64, 16);
+    let (argv_addrs, envp_addrs) = string_offsets.split_at(argv.len());
+
+    let mut auxv = Vec::new();
+    if let Some(phdr) = program_headers_addr(elf, base) {
+        auxv.extend_from_slice(&[AT_PHDR, phdr]);
+    }
+    auxv.extend_from_slice(&[
+        AT_PHENT,
+        super::PROGRAM_HEADER_SIZE as u64,
+        AT_PHNUM,
+        elf.program_headers.len() as u64,
+        AT_PAGESZ,
+        Size4KiB::SIZE,
+        AT_BASE,
+        0,
+        AT_FLAGS,
+        0,
+        AT_ENTRY,
+        entry.as_u64(),
+        AT_SECURE,
+        0,
+        AT_RANDOM,
+        info_start,
+    ]);
+    if let Some(&execfn) = argv_addrs.first() {
+        auxv.extend_from_slice(&[AT_EXECFN, info_start + execfn]);
+    }
+    auxv.extend_from_slice(&[AT_NULL, 0]);
+
+    let mut words = Vec::new();
+    words.push(argv.len() as u64);
+    words.extend(argv_addrs.iter().map(|o| info_start + o));
+    words.push(0);
+    words.extend(envp_addrs.iter().map(|o| info_start + o));
+    words.push(0);
+    words.extend_from_slice(&auxv);
+
+    let size = top - info_start + words.len() as u64 * 8;
+    if size > MAX_ARGUMENTS_SIZE {
+        return Err(ElfError::ArgumentsTooLarge);
+    }
+    let stack_pointer = align_down(info_start - words.len() as u64 * 8, 16);
+    let bytes: Vec<u8> = words.iter().flat_map(|w| w.to_le_bytes()).collect();
+    space.write(VirtAddr::new(stack_pointer), &bytes)?;
+    space.write(VirtAddr::new(info_start), &info)?;
+
+    Ok(VirtAddr::new(stack_pointer))
+}
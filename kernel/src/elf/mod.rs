@@ -0,0 +1,323 @@
+//! ELF64 executables
+//!
+//! Only what's needed to load statically linked x86_64 programs is parsed: the file header,
+//! the program headers and, for position independent executables, the `RELA` relocations
+//! found through the dynamic segment. Section headers are ignored. [`load`] maps a program
+//! into a new [`AddressSpace`](crate::mem::AddressSpace), see [`loader`].
+
+mod loader;
+
+pub use loader::{load, Program};
+
+use alloc::vec::Vec;
+use bitflags::bitflags;
+use core::ops::Range;
+use thiserror_no_std::Error;
+
+use crate::mem::AddressSpaceError;
+
+const MAGIC: [u8; 4] = *b"\x7fELF";
+const CLASS_64: u8 = 2;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+const VERSION_CURRENT: u8 = 1;
+const MACHINE_X86_64: u16 = 62;
+
+const FILE_HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+const RELA_SIZE: usize = 24;
+
+const DT_NULL: u64 = 0;
+const DT_RELA: u64 = 7;
+const DT_RELASZ: u64 = 8;
+const DT_RELAENT: u64 = 9;
+
+const R_X86_64_NONE: u32 = 0;
+const R_X86_64_RELATIVE: u32 = 8;
+
+#[derive(Error, Debug)]
+pub enum ElfError {
+    #[error("file is truncated")]
+    Truncated,
+    #[error("not an ELF file")]
+    BadMagic,
+    #[error("unsupported ELF file: {0}")]
+    Unsupported(&'static str),
+    #[error("invalid segment {0}")]
+    BadSegment(usize),
+    #[error("unsupported relocation type {0}")]
+    UnsupportedRelocation(u32),
+    #[error("relocation at {0:#x} is outside the program")]
+    BadRelocation(u64),
+    #[error("no space for the program")]
+    NoSpace,
+    #[error("arguments and environment don't fit on the stack")]
+    ArgumentsTooLarge,
+    #[error("failed to map program: {0}")]
+    Map(#[from] AddressSpaceError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    /// Linked to a fixed address
+    Executable,
+    /// Position independent, loaded at a random address
+    Dynamic,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SegmentType {
+    Null,
+    Load,
+    Dynamic,
+    /// Path of the dynamic linker, which isn't supported
+    Interpreter,
+    Note,
+    /// The program headers themselves
+    ProgramHeaders,
+    /// Thread local storage template
+    Tls,
+    Other(u32),
+}
+
+impl From<u32> for SegmentType {
+    fn from(value: u32) -> Self {
+        match value {
+            0 => SegmentType::Null,
+            1 => SegmentType::Load,
+            2 => SegmentType::Dynamic,
+            3 => SegmentType::Interpreter,
+            4 => SegmentType::Note,
+            6 => SegmentType::ProgramHeaders,
+            7 => SegmentType::Tls,
+            other => SegmentType::Other(other),
+        }
+    }
+}
+
+bitflags! {
+    pub struct SegmentFlags: u32 {
+        const EXECUTE = 1 << 0;
+        const WRITE = 1 << 1;
+        const READ = 1 << 2;
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ProgramHeader {
+    pub kind: SegmentType,
+    pub flags: SegmentFlags,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub file_size: u64,
+    pub mem_size: u64,
+    pub align: u64,
+}
+
+impl ProgramHeader {
+    /// Range of the segment in memory, before relocation
+    pub fn memory(&self) -> Range<u64> {
+        self.vaddr..self.vaddr + self.mem_size
+    }
+
+    /// Range of the segment's contents in the file
+    pub fn file(&self) -> Range<usize> {
+        self.offset as usize..(self.offset + self.file_size) as usize
+    }
+}
+
+/// A parsed and validated ELF file
+#[derive(Debug)]
+pub struct Elf<'a> {
+    data: &'a [u8],
+    pub kind: FileType,
+    pub entry: u64,
+    pub program_headers: Vec<ProgramHeader>,
+    /// Offset of the program headers in the file
+    pub program_header_offset: u64,
+}
+
+fn read<const N: usize>(data: &[u8], offset: usize) -> Result<[u8; N], ElfError> {
+    data.get(offset..offset.checked_add(N).ok_or(ElfError::Truncated)?)
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or(ElfError::Truncated)
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, ElfError> {
+    read(data, offset).map(u16::from_le_bytes)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, ElfError> {
+    read(data, offset).map(u32::from_le_bytes)
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Result<u64, ElfError> {
+    read(data, offset).map(u64::from_le_bytes)
+}
+
+impl<'a> Elf<'a> {
+    /// Parses the headers of `data` and checks that it is a program this kernel can run.
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        if data.len() < FILE_HEADER_SIZE {
+            return Err(ElfError::Truncated);
+        }
+        let ident: [u8; 16] = read(data, 0)?;
+        if ident[..4] != MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if ident[4] != CLASS_64 {
+            return Err(ElfError::Unsupported("not 64 bit"));
+        }
+        if ident[5] != DATA_LITTLE_ENDIAN {
+            return Err(ElfError::Unsupported("not little endian"));
+        }
+        if ident[6] != VERSION_CURRENT {
+            return Err(ElfError::Unsupported("unknown version"));
+        }
+
+        let kind = match read_u16(data, 16)? {
+            2 => FileType::Executable,
+            3 => FileType::Dynamic,
+            _ => return Err(ElfError::Unsupported("not an executable")),
+        };
+        if read_u16(data, 18)? != MACHINE_X86_64 {
+            return Err(ElfError::Unsupported("not x86_64"));
+        }
+
+        let entry = read_u64(data, 24)?;
+        let program_header_offset = read_u64(data, 32)?;
+        let entry_size = read_u16(data, 54)? as usize;
+        let count = read_u16(data, 56)? as usize;
+        if entry_size != PROGRAM_HEADER_SIZE {
+            return Err(ElfError::Unsupported("unknown program header size"));
+        }
+
+        let program_headers = (0..count)
+            .map(|i| {
+                let offset = usize::try_from(program_header_offset)
+                    .ok()
+                    .and_then(|o| o.checked_add(i * PROGRAM_HEADER_SIZE))
+                    .ok_or(ElfError::Truncated)?;
+                Ok(ProgramHeader {
+                    kind: read_u32(data, offset)?.into(),
+                    flags: SegmentFlags::from_bits_truncate(read_u32(data, offset + 4)?),
+                    offset: read_u64(data, offset + 8)?,
+                    vaddr: read_u64(data, offset + 16)?,
+                    file_size: read_u64(data, offset + 32)?,
+                    mem_size: read_u64(data, offset + 40)?,
+                    align: read_u64(data, offset + 48)?,
+                })
+            })
+            .collect::<Result<Vec<_>, ElfError>>()?;
+
+        let elf = Elf {
+            data,
+            kind,
+            entry,
+            program_headers,
+            program_header_offset,
+        };
+        elf.validate()?;
+        Ok(elf)
+    }
+
+    fn validate(&self) -> Result<(), ElfError> {
+        let mut loaded = false;
+        for (i, header) in self.program_headers.iter().enumerate() {
+            match header.kind {
+                SegmentType::Interpreter => {
+                    return Err(ElfError::Unsupported("dynamically linked"))
+                }
+                SegmentType::Tls => return Err(ElfError::Unsupported("thread local storage")),
+                SegmentType::Load => loaded = true,
+                SegmentType::Dynamic => {}
+                _ => continue,
+            }
+
+            let file_end = header.offset.checked_add(header.file_size);
+            let mem_end = header.vaddr.checked_add(header.mem_size);
+            let valid = header.file_size <= header.mem_size
+                && matches!(file_end, Some(end) if end <= self.data.len() as u64)
+                && mem_end.is_some()
+                && (header.align <= 1
+                    || header.align.is_power_of_two()
+                        && header.vaddr % header.align == header.offset % header.align);
+            if !valid {
+                return Err(ElfError::BadSegment(i));
+            }
+        }
+        if !loaded {
+            return Err(ElfError::Unsupported("nothing to load"));
+        }
+        Ok(())
+    }
+
+    /// The `PT_LOAD` segments
+    pub fn segments(&self) -> impl Iterator<Item = &ProgramHeader> {
+        self.program_headers
+            .iter()
+            .filter(|h| h.kind == SegmentType::Load && h.mem_size > 0)
+    }
+
+    /// Contents of a segment in the file
+    pub fn contents(&self, header: &ProgramHeader) -> &'a [u8] {
+        // checked by `validate`
+        &self.data[header.file()]
+    }
+
+    /// The file contents at the virtual address `vaddr`, before relocation
+    fn at_vaddr(&self, vaddr: u64, len: usize) -> Result<&'a [u8], ElfError> {
+        let header = self
+            .segments()
+            .find(|h| (h.vaddr..h.vaddr + h.file_size).contains(&vaddr))
+            .ok_or(ElfError::Truncated)?;
+        let offset = (header.offset + vaddr - header.vaddr) as usize;
+        self.data
+            .get(offset..offset.checked_add(len).ok_or(ElfError::Truncated)?)
+            .ok_or(ElfError::Truncated)
+    }
+
+    /// The `R_X86_64_RELATIVE` relocations, as pairs of the address to patch and the addend,
+    /// both relative to the load address.
+    pub fn relative_relocations(&self) -> Result<Vec<(u64, u64)>, ElfError> {
+        let Some(dynamic) = self
+            .program_headers
+            .iter()
+            .find(|h| h.kind == SegmentType::Dynamic)
+        else {
+            return Ok(Vec::new());
+        };
+
+        let (mut rela, mut rela_size, mut rela_entry) = (None, 0, RELA_SIZE as u64);
+        for entry in self.contents(dynamic).chunks_exact(16) {
+            let tag = read_u64(entry, 0)?;
+            let value = read_u64(entry, 8)?;
+            match tag {
+                DT_NULL => break,
+                DT_RELA => rela = Some(value),
+                DT_RELASZ => rela_size = value,
+                DT_RELAENT => rela_entry = value,
+                _ => {}
+            }
+        }
+        let Some(rela) = rela else {
+            return Ok(Vec::new());
+        };
+        if rela_entry != RELA_SIZE as u64 {
+            return Err(ElfError::Unsupported("unknown relocation entry size"));
+        }
+
+        let table = self.at_vaddr(rela, rela_size as usize)?;
+        let mut relocations = Vec::new();
+        for entry in table.chunks_exact(RELA_SIZE) {
+            let offset = read_u64(entry, 0)?;
+            let kind = read_u64(entry, 8)? as u32;
+            let addend = read_u64(entry, 16)?;
+            match kind {
+                R_X86_64_NONE => {}
+                R_X86_64_RELATIVE => relocations.push((offset, addend)),
+                other => return Err(ElfError::UnsupportedRelocation(other)),
+            }
+        }
+        Ok(relocations)
+    }
+}
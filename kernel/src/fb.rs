@@ -1,11 +1,16 @@
 //! Simple VGA framebuffer driver
 
-use crate::util::Spinlock;
+use crate::{mem::pat::CacheMode, util::Spinlock};
 use bootloader_api::info::{FrameBufferInfo, PixelFormat};
 use conquer_once::{spin::OnceCell, TryGetError};
 use noto_sans_mono_bitmap::{
     get_raster, get_raster_width, FontWeight, RasterHeight, RasterizedChar,
 };
+use x86_64::{
+    instructions::interrupts,
+    structures::paging::{Page, Size4KiB},
+    VirtAddr,
+};
 
 const VSPACE: usize = noto_sans_mono_bitmap::RasterHeight::Size16 as usize;
 const BITMAP_WIDTH: usize = get_raster_width(FontWeight::Regular, RasterHeight::Size16);
@@ -21,6 +26,71 @@ pub fn init(buf: &'static mut [u8], info: FrameBufferInfo) {
     log::debug!("hello framebuffer");
 }
 
+/// Remaps the framebuffer write-combining, pixel writes are then buffered instead of going
+/// to the device one by one.
+///
+/// Does nothing if the framebuffer isn't initialized. Requires the PAT to be set up, see
+/// [`crate::mem::pat::init`].
+pub fn enable_write_combining() {
+    let Ok(fb) = FRAMEBUFFER.try_get() else {
+        return;
+    };
+    let fb = fb.lock_sync();
+
+    let mm = crate::mem::get_memory_manager();
+    let start = VirtAddr::from_ptr(fb.buf.as_ptr());
+    let pages = Page::<Size4KiB>::range_inclusive(
+        Page::containing_address(start),
+        Page::containing_address(start + (fb.buf.len() - 1)),
+    );
+    for page in pages {
+        if let Err(e) = mm.set_cache_mode(page, CacheMode::WriteCombining) {
+            log::warn!("can't map framebuffer write-combining: {:?}", e);
+            return;
+        }
+    }
+    crate::mem::pat::flush_caches();
+
+    #[cfg(feature = "dbg-mem")]
+    log::trace!("framebuffer at {:?} mapped write-combining", start);
+}
+
+/// Geometry and pixel format, `None` before [`init`]
+pub fn info() -> Option<FrameBufferInfo> {
+    let fb = FRAMEBUFFER.try_get().ok()?;
+    Some(interrupts::without_interrupts(|| fb.lock_sync().info))
+}
+
+/// Copies the raw pixel data from byte `offset` on into `buf`, returns the number of bytes
+/// copied. Copies nothing before [`init`].
+pub fn read_raw(offset: usize, buf: &mut [u8]) -> usize {
+    let Ok(fb) = FRAMEBUFFER.try_get() else {
+        return 0;
+    };
+    interrupts::without_interrupts(|| {
+        let fb = fb.lock_sync();
+        let start = offset.min(fb.buf.len());
+        let len = buf.len().min(fb.buf.len() - start);
+        buf[..len].copy_from_slice(&fb.buf[start..start + len]);
+        len
+    })
+}
+
+/// Overwrites the raw pixel data from byte `offset` on with `data`, returns the number of
+/// bytes written. Text printed afterwards draws over it.
+pub fn write_raw(offset: usize, data: &[u8]) -> usize {
+    let Ok(fb) = FRAMEBUFFER.try_get() else {
+        return 0;
+    };
+    interrupts::without_interrupts(|| {
+        let mut fb = fb.lock_sync();
+        let start = offset.min(fb.buf.len());
+        let len = data.len().min(fb.buf.len() - start);
+        fb.buf[start..start + len].copy_from_slice(&data[..len]);
+        len
+    })
+}
+
 pub(crate) fn draw_mouse(x: usize, y: usize) {
     let fb = FRAMEBUFFER.try_get().expect("framebuffer not initialized");
     let mut fb = fb.lock_sync();
@@ -129,7 +199,6 @@ impl Framebuffer {
         let bpp = self.info.bytes_per_pixel;
         let byte_offset = offset * bpp;
         self.buf[byte_offset..(byte_offset + bpp)].copy_from_slice(&color[..bpp]);
-        let _ = unsafe { core::ptr::read_volatile(&self.buf[byte_offset]) };
     }
 
     pub fn width(&self) -> usize {
@@ -155,7 +224,6 @@ unsafe impl Sync for Framebuffer {}
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
 
     interrupts::without_interrupts(|| {
         FRAMEBUFFER
@@ -0,0 +1,157 @@
+//! FPU, SSE and AVX state management
+//!
+//! [`init`] enables the x87 FPU, SSE and (when supported) AVX on the CPU it runs on.
+//! The extended register state can be saved and restored with [`ExtendedState`], using
+//! XSAVE if available and FXSAVE otherwise.
+//!
+//! The kernel itself is compiled without SIMD, so its own code never touches these registers.
+//! Code that does (interrupt handlers, future user threads) has to save the interrupted state
+//! first, for example with [`with_extended_state`].
+
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use raw_cpuid::CpuId;
+use x86_64::registers::{
+    control::{Cr0, Cr0Flags, Cr4, Cr4Flags},
+    xcontrol::{XCr0, XCr0Flags},
+};
+
+use crate::cpu::{self, Features};
+
+/// Size of the save area, large enough for the x87, SSE and AVX state components
+/// in the standard XSAVE format.
+pub const AREA_SIZE: usize = 1024;
+
+const DEFAULT_FCW: u16 = 0x037F;
+const DEFAULT_MXCSR: u32 = 0x1F80;
+
+static USE_XSAVE: AtomicBool = AtomicBool::new(false);
+
+/// Enables the FPU and SIMD extensions on the current CPU.
+///
+/// This should be called once on every CPU.
+pub fn init() {
+    unsafe {
+        Cr0::update(|f| {
+            f.remove(Cr0Flags::EMULATE_COPROCESSOR | Cr0Flags::TASK_SWITCHED);
+            f.insert(Cr0Flags::MONITOR_COPROCESSOR | Cr0Flags::NUMERIC_ERROR);
+        });
+        if cpu::has(Features::FXSR | Features::SSE) {
+            Cr4::update(|f| f.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE));
+        }
+    }
+
+    if cpu::has(Features::XSAVE) {
+        let mut xcr0 = XCr0Flags::X87 | XCr0Flags::SSE;
+        if cpu::has(Features::AVX) {
+            xcr0 |= XCr0Flags::AVX;
+        }
+        unsafe {
+            Cr4::update(|f| f.insert(Cr4Flags::OSXSAVE));
+            XCr0::write(xcr0);
+        }
+
+        let size = CpuId::new()
+            .get_extended_state_info()
+            .map(|s| s.xsave_area_size_enabled_features() as usize)
+            .unwrap_or(AREA_SIZE);
+        assert!(
+            size <= AREA_SIZE,
+            "XSAVE area of {} bytes doesn't fit into {} bytes",
+            size,
+            AREA_SIZE
+        );
+
+        USE_XSAVE.store(true, Ordering::SeqCst);
+    }
+
+    unsafe {
+        asm!("fninit", options(nomem, nostack));
+    }
+
+    log::debug!(
+        "enabled fpu, saving state with {}",
+        if USE_XSAVE.load(Ordering::SeqCst) {
+            "xsave"
+        } else {
+            "fxsave"
+        }
+    );
+}
+
+/// Saved x87, SSE and AVX register state
+#[derive(Clone)]
+#[repr(C, align(64))]
+pub struct ExtendedState {
+    area: [u8; AREA_SIZE],
+}
+
+impl ExtendedState {
+    /// Creates a save area holding the initial register state.
+    pub const fn new() -> Self {
+        let mut area = [0; AREA_SIZE];
+
+        // FCW at offset 0, MXCSR at offset 24 of the legacy region
+        let fcw = DEFAULT_FCW.to_le_bytes();
+        area[0] = fcw[0];
+        area[1] = fcw[1];
+        let mxcsr = DEFAULT_MXCSR.to_le_bytes();
+        area[24] = mxcsr[0];
+        area[25] = mxcsr[1];
+        area[26] = mxcsr[2];
+        area[27] = mxcsr[3];
+
+        Self { area }
+    }
+
+    /// Saves the register state of the current CPU into this area.
+    #[inline]
+    pub fn save(&mut self) {
+        let ptr = self.area.as_mut_ptr();
+        unsafe {
+            if USE_XSAVE.load(Ordering::Relaxed) {
+                asm!("xsave64 [{}]", in(reg) ptr, in("eax") u32::MAX, in("edx") u32::MAX, options(nostack, preserves_flags));
+            } else {
+                asm!("fxsave64 [{}]", in(reg) ptr, options(nostack, preserves_flags));
+            }
+        }
+    }
+
+    /// Loads the register state of the current CPU from this area.
+    #[inline]
+    pub fn restore(&self) {
+        let ptr = self.area.as_ptr();
+        unsafe {
+            if USE_XSAVE.load(Ordering::Relaxed) {
+                asm!("xrstor64 [{}]", in(reg) ptr, in("eax") u32::MAX, in("edx") u32::MAX, options(nostack, preserves_flags));
+            } else {
+                asm!("fxrstor64 [{}]", in(reg) ptr, options(nostack, preserves_flags));
+            }
+        }
+    }
+}
+
+impl Default for ExtendedState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl core::fmt::Debug for ExtendedState {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("ExtendedState").finish_non_exhaustive()
+    }
+}
+
+/// Runs `f` with the current FPU/SIMD register state saved, and restores it afterwards.
+///
+/// This doesn't allocate, so it is safe to use in interrupt handlers.
+pub fn with_extended_state<R>(f: impl FnOnce() -> R) -> R {
+    let mut saved = ExtendedState::new();
+    saved.save();
+    let ret = f();
+    saved.restore();
+    ret
+}
@@ -1,55 +1,153 @@
 //! Global Descriptor Table and Task State Segment
+//!
+//! Every CPU has its own GDT and TSS. The segments are laid out the way `SYSCALL`/`SYSRET`
+//! expect them: kernel code and data, then user data and user code. Loading the GDT also
+//! sets up the system call MSRs, see [`crate::syscall`].
+//!
+//! The last entry is a data segment that is never loaded, its limit is the logical ID of the
+//! CPU. [`current_cpu`] reads it with `LSL`, for CPUs without `RDTSCP`.
 
 use alloc::boxed::Box;
+use core::{
+    arch::asm,
+    ptr::{self, null_mut},
+    sync::atomic::{AtomicPtr, Ordering},
+};
 use lazy_static::lazy_static;
 use x86_64::instructions::interrupts::without_interrupts;
 use x86_64::instructions::segmentation::{Segment, CS, DS};
 use x86_64::instructions::tables::load_tss;
-use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
+use x86_64::registers::model_specific::{Efer, EferFlags, KernelGsBase, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::gdt::{
+    Descriptor, DescriptorFlags, GlobalDescriptorTable, SegmentSelector,
+};
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
+use crate::{
+    mem::stack::{self, StackKind},
+    smp::MAX_CPUS,
+};
+
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+/// Selector of the segment holding the CPU ID, the same in every GDT
+const CPU_SELECTOR: u16 = 7 << 3;
+const DOUBLE_FAULT_STACK_PAGES: u64 = 5;
+/// Size of the stack interrupts and exceptions from user mode arrive on, until a thread sets
+/// its own with [`set_kernel_stack`]
+const PRIVILEGE_STACK_PAGES: u64 = 8;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_TSS: AtomicPtr<TaskStateSegment> = AtomicPtr::new(null_mut());
+/// The TSS of every CPU, written to when switching kernel stacks
+static TSS_BY_CPU: [AtomicPtr<TaskStateSegment>; MAX_CPUS] = [NO_TSS; MAX_CPUS];
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_SYSCALL_STACK: AtomicPtr<SyscallStack> = AtomicPtr::new(null_mut());
+/// The system call stack of every CPU, its address is in `IA32_KERNEL_GS_BASE`
+static SYSCALL_STACKS: [AtomicPtr<SyscallStack>; MAX_CPUS] = [NO_SYSCALL_STACK; MAX_CPUS];
+
+/// Per CPU data for the system call entry, which finds it with `swapgs`. The layout is
+/// relied on by the entry stub.
+#[repr(C)]
+struct SyscallStack {
+    /// Stack pointer to switch to, the same as the privilege stack of the TSS
+    kernel_rsp: u64,
+    /// Scratch space for the user stack pointer
+    user_rsp: u64,
+}
 
 lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-
-            stack_start + STACK_SIZE
-        };
-        tss
-    };
+    static ref TSS: &'static TaskStateSegment = create_tss(0);
 }
 
 lazy_static! {
-    pub(crate) static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (
-            gdt,
-            Selectors {
-                code_selector,
-                data_selector,
-                tss_selector,
-            },
-        )
-    };
+    pub(crate) static ref GDT: (GlobalDescriptorTable, Selectors) = create_gdt(0, &TSS);
 }
 
 #[derive(Debug, Clone)]
 pub(crate) struct Selectors {
     pub code_selector: SegmentSelector,
     pub data_selector: SegmentSelector,
+    pub user_data_selector: SegmentSelector,
+    pub user_code_selector: SegmentSelector,
     pub tss_selector: SegmentSelector,
 }
 
+/// Creates a TSS for the CPU with the given logical ID, with its own guarded IST and privilege
+/// stacks.
+fn create_tss(cpu: u32) -> &'static TaskStateSegment {
+    let mut tss = TaskStateSegment::new();
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+        allocate_stack(cpu, StackKind::DoubleFault, DOUBLE_FAULT_STACK_PAGES);
+    tss.privilege_stack_table[0] = allocate_stack(cpu, StackKind::Privilege, PRIVILEGE_STACK_PAGES);
+
+    let syscall_stack = Box::into_raw(Box::new(SyscallStack {
+        kernel_rsp: tss.privilege_stack_table[0].as_u64(),
+        user_rsp: 0,
+    }));
+    SYSCALL_STACKS[cpu as usize].store(syscall_stack, Ordering::SeqCst);
+
+    let tss = Box::into_raw(Box::new(tss));
+    TSS_BY_CPU[cpu as usize].store(tss, Ordering::SeqCst);
+    unsafe { &*tss }
+}
+
+/// Enables `SYSCALL`/`SYSRET` on the CPU with the given logical ID.
+fn init_syscalls(cpu: u32, selectors: &Selectors) {
+    Star::write(
+        selectors.user_code_selector,
+        selectors.user_data_selector,
+        selectors.code_selector,
+        selectors.data_selector,
+    )
+    .expect("GDT layout doesn't fit SYSRET");
+    LStar::write(VirtAddr::new(crate::syscall::entry as *const () as u64));
+    // the entry stub runs with interrupts off until it switched stacks
+    SFMask::write(
+        RFlags::INTERRUPT_FLAG
+            | RFlags::TRAP_FLAG
+            | RFlags::DIRECTION_FLAG
+            | RFlags::ALIGNMENT_CHECK,
+    );
+    let syscall_stack = SYSCALL_STACKS[cpu as usize].load(Ordering::SeqCst);
+    KernelGsBase::write(VirtAddr::from_ptr(syscall_stack));
+    unsafe { Efer::update(|f| f.insert(EferFlags::SYSTEM_CALL_EXTENSIONS)) };
+}
+
+fn allocate_stack(cpu: u32, kind: StackKind, pages: u64) -> VirtAddr {
+    stack::allocate(cpu, kind, pages)
+        .unwrap_or_else(|e| {
+            panic!(
+                "failed to allocate {:?} stack for cpu {}: {:?}",
+                kind, cpu, e
+            )
+        })
+        .top()
+}
+
+fn create_gdt(cpu: u32, tss: &'static TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
+    let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+    let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+    let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+    let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+    let cpu_selector = gdt.add_entry(cpu_segment(cpu));
+    assert_eq!(cpu_selector.0, CPU_SELECTOR, "GDT layout changed");
+    (
+        gdt,
+        Selectors {
+            code_selector,
+            data_selector,
+            user_data_selector,
+            user_code_selector,
+            tss_selector,
+        },
+    )
+}
+
 pub fn init() {
     GDT.0.load();
     unsafe {
@@ -57,32 +155,18 @@ pub fn init() {
         CS::set_reg(GDT.1.code_selector);
         load_tss(GDT.1.tss_selector);
     }
+    init_syscalls(0, &GDT.1);
 
     #[cfg(feature = "dbg-mem")]
     log::trace!("loaded GDT at {:p}, {:x?}", &GDT, GDT.0);
 }
 
-pub fn init_ap() {
-    let tss = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-
-            stack_start + STACK_SIZE
-        };
-
-        let b = Box::new(tss);
-        Box::leak::<'static>(b)
-    };
+/// Loads a GDT and TSS for the AP with the given logical ID, see [`crate::smp::Cpu::id`].
+pub fn init_ap(cpu: u32) {
+    let tss = create_tss(cpu);
 
     let gdt = {
-        let mut gdt = GDT.clone();
-        gdt.0.add_entry(Descriptor::tss_segment(tss));
-
-        let b = Box::new(gdt);
+        let b = Box::new(create_gdt(cpu, tss));
         Box::leak::<'static>(b)
     };
 
@@ -91,6 +175,66 @@ pub fn init_ap() {
         unsafe {
             CS::set_reg(gdt.1.code_selector);
             DS::set_reg(gdt.1.data_selector);
+            load_tss(gdt.1.tss_selector);
         }
+        init_syscalls(cpu, &gdt.1);
+    });
+}
+
+/// A present data segment with `cpu` as its limit
+fn cpu_segment(cpu: u32) -> Descriptor {
+    let flags =
+        DescriptorFlags::USER_SEGMENT | DescriptorFlags::PRESENT | DescriptorFlags::ACCESSED;
+    let limit = (cpu as u64 & 0xffff) | (cpu as u64 >> 16 & 0xf) << 48;
+    Descriptor::UserSegment(flags.bits() | limit)
+}
+
+/// The logical ID of the current CPU, from the limit of its CPU segment. `None` until
+/// [`init`] or [`init_ap`] loaded the GDT of this CPU, unless another GDT has a segment at
+/// the same place.
+pub fn current_cpu() -> Option<u32> {
+    let limit: u32;
+    let valid: u8;
+    unsafe {
+        asm!(
+            "lsl {limit:e}, {selector:x}",
+            "setz {valid}",
+            selector = in(reg) CPU_SELECTOR,
+            limit = lateout(reg) limit,
+            valid = out(reg_byte) valid,
+            options(readonly, nostack)
+        );
+    }
+    (valid != 0).then_some(limit)
+}
+
+/// Code and data selectors for user mode, with a requested privilege level of 3.
+///
+/// These are the same on every CPU.
+pub fn user_selectors() -> (SegmentSelector, SegmentSelector) {
+    (GDT.1.user_code_selector, GDT.1.user_data_selector)
+}
+
+fn current<T>(per_cpu: &[AtomicPtr<T>; MAX_CPUS]) -> *mut T {
+    let ptr = per_cpu[crate::cpu::current_id() as usize].load(Ordering::SeqCst);
+    assert!(!ptr.is_null(), "GDT isn't initialized on this cpu");
+    ptr
+}
+
+fn current_tss() -> *mut TaskStateSegment {
+    current(&TSS_BY_CPU)
+}
+
+/// Sets the stack the current CPU switches to when an interrupt, exception or system call
+/// arrives while it runs user code. `top` is the initial stack pointer.
+pub fn set_kernel_stack(top: VirtAddr) {
+    without_interrupts(|| unsafe {
+        ptr::addr_of_mut!((*current_tss()).privilege_stack_table[0]).write_volatile(top);
+        ptr::addr_of_mut!((*current(&SYSCALL_STACKS)).kernel_rsp).write_volatile(top.as_u64());
     });
 }
+
+/// The stack the current CPU switches to when entering the kernel from user mode
+pub fn kernel_stack() -> VirtAddr {
+    unsafe { ptr::addr_of!((*current_tss()).privilege_stack_table[0]).read_volatile() }
+}
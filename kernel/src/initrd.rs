@@ -0,0 +1,301 @@
+//! Initial RAM disk
+//!
+//! The bootloader loads the archive `build.rs` packs from the `initrd/` directory and passes
+//! its address in [`BootInfo::ramdisk_addr`](bootloader_api::BootInfo::ramdisk_addr). It's an
+//! uncompressed ustar archive, [`Archive`] reads it in place as a read-only filesystem.
+//!
+//! Regular files, directories, symbolic and hard links are supported, other entries are
+//! skipped. Directories that only appear as part of a path are added as well.
+
+use alloc::{collections::BTreeMap, format, string::String};
+use conquer_once::spin::OnceCell;
+use thiserror_no_std::Error;
+use x86_64::VirtAddr;
+
+const BLOCK: usize = 512;
+
+static INITRD: OnceCell<Archive<'static>> = OnceCell::uninit();
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveError {
+    #[error("archive is truncated at {0:#x}")]
+    Truncated(usize),
+    #[error("invalid header at {0:#x}")]
+    BadHeader(usize),
+    #[error("header checksum mismatch at {0:#x}")]
+    BadChecksum(usize),
+    #[error("hard link at {0:#x} to a missing file")]
+    BadLink(usize),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
+    /// A symbolic link to the given path, which isn't resolved
+    Symlink(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct Entry<'a> {
+    pub kind: EntryKind,
+    /// Number of the entry in path order, starting at 1 for the root
+    pub inode: u64,
+    /// Permission bits
+    pub mode: u32,
+    /// Modification time in seconds since the Unix epoch
+    pub mtime: u64,
+    data: &'a [u8],
+}
+
+impl<'a> Entry<'a> {
+    fn directory() -> Self {
+        Self {
+            kind: EntryKind::Directory,
+            inode: 0,
+            mode: 0o755,
+            mtime: 0,
+            data: &[],
+        }
+    }
+
+    /// Contents of a file, empty for other entries
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    pub fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.kind == EntryKind::Directory
+    }
+}
+
+/// A ustar archive, indexed by path
+#[derive(Debug)]
+pub struct Archive<'a> {
+    /// Entries by path relative to the root, without leading or trailing slashes. The root is
+    /// the empty path.
+    entries: BTreeMap<String, Entry<'a>>,
+}
+
+/// Removes leading `/` and `./` and trailing `/` from `path`.
+fn normalize(path: &str) -> &str {
+    let mut path = path.trim_matches('/');
+    while let Some(rest) = path.strip_prefix("./") {
+        path = rest.trim_start_matches('/');
+    }
+    if path == "." {
+        ""
+    } else {
+        path
+    }
+}
+
+/// A NUL terminated string field of a header
+fn field(header: &[u8], range: core::ops::Range<usize>) -> &[u8] {
+    let bytes = &header[range];
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    &bytes[..end]
+}
+
+/// A numeric field of a header, octal digits padded with spaces or NULs
+fn octal(header: &[u8], range: core::ops::Range<usize>) -> Option<u64> {
+    let digits = field(header, range);
+    let digits = core::str::from_utf8(digits).ok()?.trim_matches(' ');
+    if digits.is_empty() {
+        return Some(0);
+    }
+    u64::from_str_radix(digits, 8).ok()
+}
+
+fn text(
+    header: &[u8],
+    range: core::ops::Range<usize>,
+    offset: usize,
+) -> Result<&str, ArchiveError> {
+    core::str::from_utf8(field(header, range)).map_err(|_| ArchiveError::BadHeader(offset))
+}
+
+impl<'a> Archive<'a> {
+    /// Indexes the archive in `data`.
+    pub fn parse(data: &'a [u8]) -> Result<Self, ArchiveError> {
+        let mut entries = BTreeMap::new();
+        entries.insert(String::new(), Entry::directory());
+
+        let mut offset = 0;
+        while offset + BLOCK <= data.len() {
+            let header = &data[offset..offset + BLOCK];
+            // the archive ends with two zero blocks, one is enough
+            if header.iter().all(|&b| b == 0) {
+                break;
+            }
+            if &header[257..262] != b"ustar" {
+                return Err(ArchiveError::BadHeader(offset));
+            }
+
+            let checksum = octal(header, 148..156).ok_or(ArchiveError::BadHeader(offset))?;
+            let sum: u64 = header
+                .iter()
+                .enumerate()
+                .map(|(i, &b)| if (148..156).contains(&i) { b' ' } else { b } as u64)
+                .sum();
+            if sum != checksum {
+                return Err(ArchiveError::BadChecksum(offset));
+            }
+
+            let bad_header = ArchiveError::BadHeader(offset);
+            let size = octal(header, 124..136).ok_or(bad_header)? as usize;
+            let mode = octal(header, 100..108).ok_or(bad_header)? as u32;
+            let mtime = octal(header, 136..148).ok_or(bad_header)?;
+            let prefix = text(header, 345..500, offset)?;
+            let name = text(header, 0..100, offset)?;
+            let link = text(header, 157..257, offset)?;
+            let path = if prefix.is_empty() {
+                String::from(normalize(name))
+            } else {
+                String::from(normalize(&format!("{}/{}", prefix, name)))
+            };
+
+            let start = offset + BLOCK;
+            let contents = start
+                .checked_add(size)
+                .and_then(|end| data.get(start..end))
+                .ok_or(ArchiveError::Truncated(start))?;
+
+            let entry = match header[156] {
+                b'0' | 0 => Some(Entry {
+                    kind: EntryKind::File,
+                    inode: 0,
+                    mode,
+                    mtime,
+                    data: contents,
+                }),
+                b'1' => {
+                    let target: &Entry = entries
+                        .get(normalize(link))
+                        .ok_or(ArchiveError::BadLink(offset))?;
+                    Some(Entry {
+                        mode,
+                        mtime,
+                        ..target.clone()
+                    })
+                }
+                b'2' => Some(Entry {
+                    kind: EntryKind::Symlink(link.into()),
+                    inode: 0,
+                    mode,
+                    mtime,
+                    data: &[],
+                }),
+                b'5' => Some(Entry {
+                    mode,
+                    mtime,
+                    ..Entry::directory()
+                }),
+                _ => None,
+            };
+
+            if let Some(entry) = entry {
+                // parents that aren't in the archive themselves
+                let mut parent = path.as_str();
+                while let Some((dir, _)) = parent.rsplit_once('/') {
+                    entries
+                        .entry(String::from(dir))
+                        .or_insert_with(Entry::directory);
+                    parent = dir;
+                }
+                entries.insert(path, entry);
+            } else {
+                log::warn!(
+                    "initrd: skipping {} of type {:?}",
+                    path,
+                    header[156] as char
+                );
+            }
+
+            offset = start + x86_64::align_up(size as u64, BLOCK as u64) as usize;
+        }
+
+        for (entry, inode) in entries.values_mut().zip(1..) {
+            entry.inode = inode;
+        }
+        Ok(Self { entries })
+    }
+
+    /// The entry at `path`, symbolic links aren't followed
+    pub fn get(&self, path: &str) -> Option<&Entry<'a>> {
+        self.entries.get(normalize(path))
+    }
+
+    /// Contents of the file at `path`
+    pub fn read(&self, path: &str) -> Option<&'a [u8]> {
+        self.get(path)
+            .filter(|entry| entry.kind == EntryKind::File)
+            .map(Entry::data)
+    }
+
+    /// Names and entries in the directory `path`, `None` if it isn't a directory
+    pub fn read_dir<'s>(
+        &'s self,
+        path: &str,
+    ) -> Option<impl Iterator<Item = (&'s str, &'s Entry<'a>)> + 's> {
+        let dir = normalize(path);
+        if !self.entries.get(dir)?.is_dir() {
+            return None;
+        }
+        let dir = String::from(dir);
+        Some(self.entries.iter().filter_map(move |(path, entry)| {
+            let name = if dir.is_empty() {
+                path.as_str()
+            } else {
+                path.strip_prefix(dir.as_str())?.strip_prefix('/')?
+            };
+            (!name.is_empty() && !name.contains('/')).then_some((name, entry))
+        }))
+    }
+
+    /// All paths and entries, sorted by path
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Entry<'a>)> {
+        self.entries
+            .iter()
+            .map(|(path, entry)| (path.as_str(), entry))
+    }
+
+    /// Number of entries, including the root
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Indexes the RAM disk of `len` bytes at `addr`.
+///
+/// # Safety
+///
+/// The memory has to be mapped and never be written or freed.
+pub unsafe fn init(addr: VirtAddr, len: u64) {
+    let data = core::slice::from_raw_parts(addr.as_ptr::<u8>(), len as usize);
+    match Archive::parse(data) {
+        Ok(archive) => {
+            log::info!(
+                "initrd at {:?}: {} bytes, {} entries",
+                addr,
+                len,
+                archive.len()
+            );
+            INITRD.init_once(|| archive);
+        }
+        Err(e) => log::error!("can't read initrd: {}", e),
+    }
+}
+
+/// The initial RAM disk, if the bootloader loaded one
+pub fn get() -> Option<&'static Archive<'static>> {
+    INITRD.get()
+}
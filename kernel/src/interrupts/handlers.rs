@@ -1,4 +1,22 @@
-use x86_64::structures::idt::{InterruptStackFrame, PageFaultErrorCode};
+use x86_64::{
+    structures::idt::{InterruptStackFrame, PageFaultErrorCode},
+    VirtAddr,
+};
+
+use super::{count, exception, InterruptIndex};
+use crate::{
+    mem::protection,
+    user::{self, Exit},
+};
+
+/// Runs first in every handler: counts the interrupt at `vector` and, if it arrived from user
+/// mode, makes sure SMAP applies to the handler.
+fn enter(vector: u8, stack_frame: &InterruptStackFrame) {
+    if user::from_user_mode(stack_frame) {
+        protection::clear_user_access();
+    }
+    count(vector);
+}
 
 #[inline(always)]
 /// Signal End of Interrupt to the local APIC
@@ -12,7 +30,37 @@ fn eoi() {
     }
 }
 
+/// Panics with a clear message if `addr` is inside the guard page of a kernel stack.
+fn check_stack_overflow(addr: VirtAddr, stack_frame: &InterruptStackFrame) {
+    if let Some(stack) = crate::mem::stack::find_by_guard_page(addr) {
+        panic!(
+            "EXCEPTION: stack overflow on CPU {} ({:?} stack, bottom at {:?}), accessed address: {:?}\n{:#?}",
+            stack.cpu,
+            stack.kind,
+            stack.bottom(),
+            addr,
+            stack_frame
+        );
+    }
+}
+
+/// Ends the user program that caused an exception, if it came from user mode.
+fn exit_user_program(
+    name: &'static str,
+    stack_frame: &InterruptStackFrame,
+    error_code: Option<u64>,
+) {
+    if user::from_user_mode(stack_frame) {
+        user::exit(Exit::Exception {
+            name,
+            instruction_pointer: stack_frame.instruction_pointer,
+            error_code,
+        });
+    }
+}
+
 pub extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
+    enter(exception::BREAKPOINT, &stack_frame);
     log::warn!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
@@ -22,17 +70,29 @@ pub extern "x86-interrupt" fn page_fault_handler(
 ) {
     use x86_64::registers::control::Cr2;
 
-    log::error!("EXCEPTION: PAGE FAULT");
-    log::trace!("Accessed Address: {:?}", Cr2::read());
-    log::trace!("Error Code: {:?}", error_code);
-    log::trace!("{:#?}", stack_frame);
-    panic!("Unhandled page fault");
+    enter(exception::PAGE_FAULT, &stack_frame);
+
+    let addr = Cr2::read();
+    if !user::from_user_mode(&stack_frame) {
+        check_stack_overflow(addr, &stack_frame);
+    }
+
+    if let Err(report) =
+        crate::mem::fault::handle(addr, error_code, stack_frame.instruction_pointer)
+    {
+        if report.user_mode() {
+            user::exit(Exit::PageFault(report));
+        }
+        panic!("EXCEPTION: PAGE FAULT\n{}\n{:#?}", report, stack_frame);
+    }
 }
 
 pub extern "x86-interrupt" fn general_protection_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: u64,
 ) {
+    enter(exception::GENERAL_PROTECTION_FAULT, &stack_frame);
+    exit_user_program("general protection fault", &stack_frame, Some(error_code));
     panic!(
         "EXCEPTION: GENERAL PROTECTION FAULT\nerror code: {}, {:#?}",
         error_code, stack_frame
@@ -43,6 +103,8 @@ pub extern "x86-interrupt" fn stack_segment_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: u64,
 ) {
+    enter(exception::STACK_SEGMENT_FAULT, &stack_frame);
+    exit_user_program("stack segment fault", &stack_frame, Some(error_code));
     panic!(
         "EXCEPTION: SATCK SEGMENT FAULT\nerror code: {}, {:#?}",
         error_code, stack_frame
@@ -50,6 +112,7 @@ pub extern "x86-interrupt" fn stack_segment_fault_handler(
 }
 
 pub extern "x86-interrupt" fn non_maskable_interrupt_handler(stack_frame: InterruptStackFrame) {
+    enter(exception::NON_MASKABLE_INTERRUPT, &stack_frame);
     if crate::PANICKING.load(core::sync::atomic::Ordering::SeqCst) {
         x86_64::instructions::interrupts::disable();
         loop {
@@ -68,6 +131,8 @@ pub extern "x86-interrupt" fn segment_not_present_handler(
     stack_frame: InterruptStackFrame,
     error_code: u64,
 ) {
+    enter(exception::SEGMENT_NOT_PRESENT, &stack_frame);
+    exit_user_program("segment not present", &stack_frame, Some(error_code));
     panic!(
         "EXCEPTION: SEGMENT NOT PRESENT\nerror code: {}, {:#?}",
         error_code, stack_frame
@@ -75,6 +140,8 @@ pub extern "x86-interrupt" fn segment_not_present_handler(
 }
 
 pub extern "x86-interrupt" fn divide_error_handler(stack_frame: InterruptStackFrame) {
+    enter(exception::DIVIDE_ERROR, &stack_frame);
+    exit_user_program("divide error", &stack_frame, None);
     panic!(
         "EXCEPTION: DIVIDE ERROR
 
@@ -84,6 +151,8 @@ pub extern "x86-interrupt" fn divide_error_handler(stack_frame: InterruptStackFr
 }
 
 pub extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
+    enter(exception::INVALID_OPCODE, &stack_frame);
+    exit_user_program("invalid opcode", &stack_frame, None);
     panic!(
         "EXCEPTION: INVALID OPCODE
 
@@ -92,17 +161,56 @@ pub extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStack
     );
 }
 
+pub extern "x86-interrupt" fn device_not_available_handler(stack_frame: InterruptStackFrame) {
+    enter(exception::DEVICE_NOT_AVAILABLE, &stack_frame);
+    exit_user_program("device not available", &stack_frame, None);
+    panic!(
+        "EXCEPTION: DEVICE NOT AVAILABLE
+
+{:#?}",
+        stack_frame
+    );
+}
+
+pub extern "x86-interrupt" fn x87_floating_point_handler(stack_frame: InterruptStackFrame) {
+    enter(exception::X87_FLOATING_POINT, &stack_frame);
+    exit_user_program("x87 floating point", &stack_frame, None);
+    panic!(
+        "EXCEPTION: x87 FLOATING POINT
+
+{:#?}",
+        stack_frame
+    );
+}
+
+pub extern "x86-interrupt" fn simd_floating_point_handler(stack_frame: InterruptStackFrame) {
+    enter(exception::SIMD_FLOATING_POINT, &stack_frame);
+    exit_user_program("SIMD floating point", &stack_frame, None);
+    panic!(
+        "EXCEPTION: SIMD FLOATING POINT
+
+{:#?}",
+        stack_frame
+    );
+}
+
 pub extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: u64,
 ) -> ! {
+    enter(exception::DOUBLE_FAULT, &stack_frame);
+    // a page fault on a guard page can't push its frame onto the overflowed stack,
+    // so stack overflows usually end up here
+    check_stack_overflow(x86_64::registers::control::Cr2::read(), &stack_frame);
+
     panic!(
         "EXCEPTION: DOUBLE FAULT\nerror code: {}, {:#?}",
         error_code, stack_frame
     );
 }
 
-pub extern "x86-interrupt" fn apic_error_handler(_stack_frame: InterruptStackFrame) {
+pub extern "x86-interrupt" fn apic_error_handler(stack_frame: InterruptStackFrame) {
+    enter(InterruptIndex::ApicError.into(), &stack_frame);
     unsafe {
         let lapic = super::LAPIC
             .try_get()
@@ -113,14 +221,17 @@ pub extern "x86-interrupt" fn apic_error_handler(_stack_frame: InterruptStackFra
     }
 }
 
-pub extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
+pub extern "x86-interrupt" fn timer_interrupt_handler(stack_frame: InterruptStackFrame) {
+    enter(InterruptIndex::Timer.into(), &stack_frame);
     crate::time::increment();
     eoi();
 }
 
-pub extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
+pub extern "x86-interrupt" fn keyboard_interrupt_handler(stack_frame: InterruptStackFrame) {
     use x86_64::instructions::port::Port;
 
+    enter(InterruptIndex::Keyboard.into(), &stack_frame);
+
     let mut port = Port::new(0x60);
     let scancode: u8 = unsafe { port.read() };
     crate::task::keyboard::write(scancode);
@@ -128,9 +239,11 @@ pub extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: Interrupt
     eoi();
 }
 
-pub extern "x86-interrupt" fn mouse_interrupt_handler(_stack_frame: InterruptStackFrame) {
+pub extern "x86-interrupt" fn mouse_interrupt_handler(stack_frame: InterruptStackFrame) {
     use x86_64::instructions::port::Port;
 
+    enter(InterruptIndex::Mouse.into(), &stack_frame);
+
     let mut port = Port::new(0x60);
     let packet: u8 = unsafe { port.read() };
     crate::task::mouse::write(packet);
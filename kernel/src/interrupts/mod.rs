@@ -3,18 +3,31 @@
 use acpi::InterruptModel;
 use alloc::boxed::Box;
 use conquer_once::spin::OnceCell;
+use core::sync::atomic::{AtomicU64, Ordering};
 use lazy_static::lazy_static;
 use x2apic::{
     ioapic::{IoApic, IrqFlags, RedirectionTableEntry},
-    lapic::LocalApic,
+    lapic::{LocalApic, TimerDivide},
+};
+use x86_64::{
+    structures::{
+        idt::InterruptDescriptorTable,
+        paging::{PageSize, PageTableFlags, Size4KiB},
+    },
+    PhysAddr,
 };
-use x86_64::structures::idt::InterruptDescriptorTable;
 
-use crate::util::Spinlock;
+use crate::{
+    mem::{mmio, pat::CacheMode, vmalloc::VmallocError},
+    smp::MAX_CPUS,
+    time,
+    util::Spinlock,
+};
 
 mod handlers;
 use handlers::*;
 
+/// Physical address of the local APIC registers
 pub static LAPIC_BASE: OnceCell<u64> = OnceCell::uninit();
 pub static LAPIC: OnceCell<Spinlock<LocalApic>> = OnceCell::uninit();
 pub static IOAPIC: OnceCell<Spinlock<IoApic>> = OnceCell::uninit();
@@ -32,6 +45,9 @@ lazy_static! {
         idt.segment_not_present.set_handler_fn(segment_not_present_handler);
         idt.divide_error.set_handler_fn(divide_error_handler);
         idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+        idt.device_not_available.set_handler_fn(device_not_available_handler);
+        idt.x87_floating_point.set_handler_fn(x87_floating_point_handler);
+        idt.simd_floating_point.set_handler_fn(simd_floating_point_handler);
 
         unsafe {
             idt.double_fault
@@ -88,21 +104,113 @@ impl From<IoApicTableIndex> for usize {
     }
 }
 
+/// Vectors of the exceptions with a handler
+mod exception {
+    pub const DIVIDE_ERROR: u8 = 0;
+    pub const NON_MASKABLE_INTERRUPT: u8 = 2;
+    pub const BREAKPOINT: u8 = 3;
+    pub const INVALID_OPCODE: u8 = 6;
+    pub const DEVICE_NOT_AVAILABLE: u8 = 7;
+    pub const DOUBLE_FAULT: u8 = 8;
+    pub const SEGMENT_NOT_PRESENT: u8 = 11;
+    pub const STACK_SEGMENT_FAULT: u8 = 12;
+    pub const GENERAL_PROTECTION_FAULT: u8 = 13;
+    pub const PAGE_FAULT: u8 = 14;
+    pub const X87_FLOATING_POINT: u8 = 16;
+    pub const SIMD_FLOATING_POINT: u8 = 19;
+}
+
+/// The vectors with a handler and what they are for, in ascending order
+pub const HANDLED: [(u8, &str); 16] = [
+    (exception::DIVIDE_ERROR, "divide error"),
+    (exception::NON_MASKABLE_INTERRUPT, "non-maskable interrupt"),
+    (exception::BREAKPOINT, "breakpoint"),
+    (exception::INVALID_OPCODE, "invalid opcode"),
+    (exception::DEVICE_NOT_AVAILABLE, "device not available"),
+    (exception::DOUBLE_FAULT, "double fault"),
+    (exception::SEGMENT_NOT_PRESENT, "segment not present"),
+    (exception::STACK_SEGMENT_FAULT, "stack segment fault"),
+    (
+        exception::GENERAL_PROTECTION_FAULT,
+        "general protection fault",
+    ),
+    (exception::PAGE_FAULT, "page fault"),
+    (exception::X87_FLOATING_POINT, "x87 floating point"),
+    (exception::SIMD_FLOATING_POINT, "SIMD floating point"),
+    (InterruptIndex::Keyboard as u8, "keyboard"),
+    (InterruptIndex::Mouse as u8, "mouse"),
+    (InterruptIndex::ApicError as u8, "APIC error"),
+    (InterruptIndex::Timer as u8, "timer"),
+];
+
+const VECTORS: usize = 256;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NONE: AtomicU64 = AtomicU64::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_INTERRUPTS: [AtomicU64; VECTORS] = [NONE; VECTORS];
+/// Interrupts handled so far, by CPU and vector
+static COUNTS: [[AtomicU64; VECTORS]; MAX_CPUS] = [NO_INTERRUPTS; MAX_CPUS];
+
+/// Counts an interrupt at `vector` on this CPU. Called first thing by every handler.
+///
+/// Interrupts arriving before the CPU can tell its ID cheaply aren't counted.
+fn count(vector: u8) {
+    if let Some(cpu) = crate::cpu::try_current_id() {
+        COUNTS[cpu as usize][vector as usize].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// The number of interrupts at `vector` handled by the CPU `cpu`
+pub fn interrupt_count(cpu: u32, vector: u8) -> u64 {
+    COUNTS
+        .get(cpu as usize)
+        .map_or(0, |counts| counts[vector as usize].load(Ordering::Relaxed))
+}
+
+/// Maps the register page of an APIC uncached, returns its virtual address.
+fn map_registers(base_address: u64) -> Result<u64, VmallocError> {
+    let registers = mmio::map(
+        PhysAddr::new(base_address),
+        Size4KiB::SIZE as usize,
+        PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        CacheMode::Uncacheable,
+    )?;
+    Ok(registers.as_u64())
+}
+
+/// The count the local APIC timer needs to fire [`time::TICKS_PER_SECOND`] times a second
+/// with a divider of 16, if the CPU reports the frequency of the timer.
+fn timer_initial_count() -> Option<u32> {
+    let frequency = crate::cpu::get(0)?.apic_timer_frequency?;
+    let count = frequency / 16 / time::TICKS_PER_SECOND;
+    u32::try_from(count).ok().filter(|&count| count != 0)
+}
+
 unsafe fn init_lapic(base_address: u64) {
     LAPIC_BASE.init_once(|| base_address);
     LAPIC
         .try_init_once(|| {
-            let mm = crate::mem::get_memory_manager();
-            mm.identity_map_address(base_address, None)
+            let registers = map_registers(base_address)
                 .unwrap_or_else(|e| panic!("can't map APIC base address: {:#?}", e));
 
-            let mut lapic = x2apic::lapic::LocalApicBuilder::new()
-                .set_xapic_base(base_address)
+            let mut builder = x2apic::lapic::LocalApicBuilder::new();
+            builder
+                .set_xapic_base(registers)
                 .spurious_vector(0xff)
                 .error_vector(InterruptIndex::ApicError.into())
-                .timer_vector(InterruptIndex::Timer.into())
-                .build()
-                .unwrap_or_else(|e| panic!("{}", e));
+                .timer_vector(InterruptIndex::Timer.into());
+            match timer_initial_count() {
+                Some(initial) => {
+                    builder
+                        .timer_divide(TimerDivide::Div16)
+                        .timer_initial(initial);
+                }
+                None => log::warn!(
+                    "the cpu doesn't report the apic timer frequency, timer ticks are uncalibrated"
+                ),
+            }
+            let mut lapic = builder.build().unwrap_or_else(|e| panic!("{}", e));
             lapic.enable();
 
             #[cfg(feature = "dbg-interrupts")]
@@ -131,11 +239,10 @@ unsafe fn init_io_apic(base_address: u64) {
                 .expect("should have the LAPIC initialized")
                 .lock_sync();
 
-            let mm = crate::mem::get_memory_manager();
-            mm.identity_map_address(base_address, None)
+            let registers = map_registers(base_address)
                 .unwrap_or_else(|e| panic!("can't map IO-APIC base address: {:#?}", e));
 
-            let mut ioapic = x2apic::ioapic::IoApic::new(base_address);
+            let mut ioapic = x2apic::ioapic::IoApic::new(registers);
             ioapic.init(IOAPIC_INTERRUPT_INDEX_OFFSET);
 
             #[cfg(feature = "dbg-interrupts")]
@@ -212,6 +319,12 @@ pub fn init_ap() {
             .set_handler_fn(segment_not_present_handler);
         idt.divide_error.set_handler_fn(divide_error_handler);
         idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+        idt.device_not_available
+            .set_handler_fn(device_not_available_handler);
+        idt.x87_floating_point
+            .set_handler_fn(x87_floating_point_handler);
+        idt.simd_floating_point
+            .set_handler_fn(simd_floating_point_handler);
 
         unsafe {
             idt.double_fault
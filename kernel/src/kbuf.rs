@@ -3,6 +3,7 @@
 //! Used to print with [`print!`](crate::print) and [`println!`](crate::println).
 
 use alloc::{
+    collections::VecDeque,
     string::{String, ToString},
     vec::Vec,
 };
@@ -13,9 +14,13 @@ use core::{
 use crossbeam_queue::SegQueue;
 use futures_util::{task::AtomicWaker, Stream, StreamExt};
 use heapless::{HistoryBuffer, String as StaticString};
+use x86_64::instructions::interrupts;
 
 use crate::util::Spinlock;
 
+/// Once the heap is used, the kernel buffer keeps about this many bytes of what was printed
+pub const HISTORY: usize = 64 * 1024;
+
 static mut KBUF: KernelBuffer = KernelBuffer::new();
 static KBUF_LOCK: Spinlock<()> = Spinlock::new(());
 static WAKER: AtomicWaker = AtomicWaker::new();
@@ -28,6 +33,14 @@ pub fn read_all() -> impl Iterator<Item = &'static str> {
     unsafe { KBUF.iter() }
 }
 
+/// A copy of what is in the kernel buffer, the last [`HISTORY`] bytes printed
+pub fn contents() -> String {
+    interrupts::without_interrupts(|| {
+        let _guard = KBUF_LOCK.lock_sync();
+        read_all().collect()
+    })
+}
+
 /// Convert the kernel buffer to heap-allocated
 ///
 /// # Safety
@@ -117,7 +130,9 @@ impl<const LEN: usize, const CAP: usize> core::fmt::Write for StaticKernelBuffer
 
 #[derive(Default)]
 struct HeapKernelBuffer {
-    buf: Vec<String>,
+    /// What was printed last, at most [`HISTORY`] bytes in total
+    buf: VecDeque<String>,
+    len: usize,
     queue: SegQueue<String>,
 }
 impl HeapKernelBuffer {
@@ -127,6 +142,12 @@ impl HeapKernelBuffer {
 }
 impl core::fmt::Write for HeapKernelBuffer {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.buf.push_back(s.to_string());
+        self.len += s.len();
+        while self.len > HISTORY {
+            let oldest = self.buf.pop_front().expect("history is empty");
+            self.len -= oldest.len();
+        }
         self.queue.push(s.to_string());
         WAKER.wake();
         Ok(())
@@ -17,8 +17,13 @@ use mem::MemoryManager;
 extern crate alloc;
 
 pub mod acpi;
+pub mod block;
+pub mod cpu;
+pub mod elf;
 pub mod fb;
+pub mod fpu;
 pub mod gdt;
+pub mod initrd;
 pub mod interrupts;
 pub mod kbuf;
 pub mod logger;
@@ -26,11 +31,16 @@ pub mod mem;
 pub mod pci;
 pub mod peripheral;
 pub mod pit;
+pub mod process;
+pub mod random;
 pub mod serial;
 pub mod smp;
+pub mod syscall;
 pub mod task;
 pub mod time;
+pub mod user;
 pub mod util;
+pub mod vfs;
 
 #[cfg(feature = "test")]
 pub mod test;
@@ -41,15 +51,22 @@ pub use test::*;
 static PANICKING: AtomicBool = AtomicBool::new(false);
 
 pub fn init(acpi_tables: Option<AcpiTables<MemoryManager>>) {
+    cpu::init(0);
+    mem::protection::enable();
+    mem::protection::protect_kernel_image();
+    mem::pat::init();
+    mem::address_space::init();
+    fb::enable_write_combining();
+    fpu::init();
     gdt::init();
     if let Some(tables) = acpi_tables {
         let interrupt_model = tables.platform_info().map(|p| p.interrupt_model).ok();
         interrupts::init(interrupt_model);
         smp::init(&tables).ok();
-        pci::init(&tables).ok();
     } else {
         interrupts::init(None);
     }
+    pci::init();
     peripheral::init();
 }
 
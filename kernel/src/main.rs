@@ -10,6 +10,10 @@ use bootloader_api::{config::Mapping, entry_point, BootInfo, BootloaderConfig};
 pub static BOOTLOADER_CONFIG: BootloaderConfig = {
     let mut config = BootloaderConfig::new_default();
     config.mappings.physical_memory = Some(Mapping::Dynamic);
+    // randomize where the bootloader puts its mappings, but keep them in the upper half, away
+    // from user space and the kernel regions placed by `mem::layout`
+    config.mappings.aslr = true;
+    config.mappings.dynamic_range_start = Some(0xffff_8000_0000_0000);
     //config.frame_buffer.minimum_framebuffer_height = Some(1024);
     //config.frame_buffer.minimum_framebuffer_width = Some(768);
     config
@@ -51,6 +55,12 @@ fn main(boot_info: &'static mut BootInfo) -> ! {
     );
     unsafe { mem::init(physical_memory_offset, &boot_info.memory_regions) };
 
+    if let Some(addr) = boot_info.ramdisk_addr.into_option() {
+        unsafe { lib::initrd::init(VirtAddr::new(addr), boot_info.ramdisk_len) };
+    } else {
+        log::warn!("no initrd loaded by the bootloader");
+    }
+
     let acpi_info = boot_info.rsdp_addr.into_option().map(lib::acpi::init);
     if acpi_info.is_none() {
         log::warn!("no RSDP address provided for the kernel, ACPI initialization not possible");
@@ -58,9 +68,31 @@ fn main(boot_info: &'static mut BootInfo) -> ! {
 
     lib::init(acpi_info);
 
+    mount_filesystems();
+
     lib::task::executor::run();
 }
 
+/// Mounts the initrd on `/` with a tmpfs on `/tmp`, or only a tmpfs on `/` without an initrd,
+/// the devices on `/dev` and the kernel state on `/proc`.
+#[cfg(not(feature = "test"))]
+fn mount_filesystems() {
+    use alloc::sync::Arc;
+    use lib::{task::block_on, vfs};
+
+    let tmp = Arc::new(vfs::TmpFs::default());
+    match lib::initrd::get() {
+        Some(initrd) => {
+            let fs = Arc::new(vfs::ArchiveFs::new(initrd));
+            block_on(vfs::mount("/", fs)).expect("failed to mount initrd");
+            block_on(vfs::mount("/tmp", tmp)).expect("failed to mount /tmp");
+        }
+        None => block_on(vfs::mount("/", tmp)).expect("failed to mount tmpfs"),
+    }
+    block_on(vfs::mount("/dev", Arc::new(vfs::DevFs))).expect("failed to mount /dev");
+    block_on(vfs::mount("/proc", Arc::new(vfs::ProcFs))).expect("failed to mount /proc");
+}
+
 #[cfg(feature = "test")]
 static TEST_BOOTLOADER_CONFIG: BootloaderConfig = {
     let mut config = BootloaderConfig::new_default();
@@ -0,0 +1,844 @@
+//! Isolated address spaces
+//!
+//! An [`AddressSpace`] has its own level 4 table. All entries the kernel's table uses are
+//! copied into it, so the kernel (including the heap, stacks, vmalloc and the physical memory
+//! mapping) stays mapped in every address space. The remaining slots below [`USER_END`] are
+//! private to the address space and hold user mappings.
+//!
+//! User memory is described by [`Vma`]s. Anonymous areas are populated on demand by
+//! [`AddressSpace::handle_fault`], and [`AddressSpace::clone_cow`] shares all pages
+//! copy-on-write with the new address space.
+//!
+//! [`init`] enables process-context identifiers if the CPU has them. Every address space then
+//! gets its own PCID and switching to it only flushes the TLB if a mapping was removed or
+//! restricted somewhere since this CPU last used it.
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::{
+    arch::asm,
+    ops::{DerefMut, Range},
+    ptr,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicU16, AtomicU64, Ordering},
+};
+use thiserror_no_std::Error;
+use x86_64::{
+    registers::control::{Cr3, Cr4, Cr4Flags},
+    structures::idt::PageFaultErrorCode,
+    structures::paging::{
+        mapper::{FlagUpdateError, MapToError, TranslateResult, UnmapError},
+        page::PageRange,
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTable,
+        PageTableFlags, PhysFrame, Size4KiB, Translate,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use super::{
+    fault::FaultKind,
+    stats::{self, Tag},
+    vma::{self, Vma, VmaFlags, VmaKind, COPY_ON_WRITE},
+    MemoryManager,
+};
+use crate::{
+    cpu::{self, Features},
+    smp::MAX_CPUS,
+    util::Spinlock,
+};
+
+/// Lowest address user mappings may use, the first page is never mapped
+pub const USER_START: u64 = 0x1000;
+/// End of the user part of an address space, the level 4 slots above belong to the kernel
+pub const USER_END: u64 = 0x_4000_0000_0000;
+
+/// Software bit marking leaf entries whose frame is owned, and freed, by the address space
+pub(super) const OWNED: PageTableFlags = PageTableFlags::BIT_9;
+
+const MAX_PCID: u16 = 4095;
+/// Never loaded on a CPU
+const NOT_LOADED: u64 = u64::MAX;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NOT_LOADED_ON_CPU: AtomicU64 = AtomicU64::new(NOT_LOADED);
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_ADDRESS_SPACE: AtomicPtr<AddressSpace> = AtomicPtr::new(ptr::null_mut());
+
+static PCID_ENABLED: AtomicBool = AtomicBool::new(false);
+static NEXT_PCID: AtomicU16 = AtomicU16::new(1);
+static FREE_PCIDS: Spinlock<Vec<u16>> = Spinlock::new(Vec::new());
+/// Incremented whenever a mapping is removed or restricted in any address space
+static TLB_GENERATION: AtomicU64 = AtomicU64::new(0);
+/// TLB generation the kernel's own table was last loaded with, per CPU
+static KERNEL_LOADED: [AtomicU64; MAX_CPUS] = [NOT_LOADED_ON_CPU; MAX_CPUS];
+/// Address space active on each CPU, null while the kernel's table is loaded
+static CURRENT: [AtomicPtr<AddressSpace>; MAX_CPUS] = [NO_ADDRESS_SPACE; MAX_CPUS];
+
+#[derive(Error, Debug)]
+pub enum AddressSpaceError {
+    #[error("out of physical memory")]
+    OutOfMemory,
+    #[error("{0:?} is not in the user part of the address space")]
+    NotUser(VirtAddr),
+    #[error("failed to map page: {0:?}")]
+    Map(MapToError<Size4KiB>),
+    #[error("failed to unmap page: {0:?}")]
+    Unmap(UnmapError),
+    #[error("failed to update page flags: {0:?}")]
+    Flags(FlagUpdateError),
+    #[error("{0:?} overlaps an existing memory area")]
+    Overlap(VirtAddr),
+    #[error("{0:?} isn't mapped")]
+    NotMapped(VirtAddr),
+    #[error("address space is active on another CPU")]
+    ActiveElsewhere,
+}
+
+/// Enables PCIDs on the current CPU if they are supported.
+///
+/// This should be called once on every CPU, while the kernel's table is loaded.
+pub fn init() {
+    if !cpu::has(Features::PCID) {
+        return;
+    }
+    unsafe { Cr4::update(|f| f.insert(Cr4Flags::PCID)) };
+    PCID_ENABLED.store(true, Ordering::SeqCst);
+
+    #[cfg(feature = "dbg-mem")]
+    log::trace!("PCIDs enabled");
+}
+
+/// Records that a mapping was removed or restricted, so no CPU may reuse cached translations
+/// of another address space without flushing.
+pub(super) fn mappings_changed() {
+    TLB_GENERATION.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Loads `pml4` into CR3, flushing the TLB entries of `pcid` unless `loaded` shows this CPU
+/// used it since the last change.
+unsafe fn load(pml4: PhysFrame, pcid: u16, loaded: &[AtomicU64; MAX_CPUS]) {
+    if !PCID_ENABLED.load(Ordering::Relaxed) {
+        Cr3::write(pml4, Cr3::read().1);
+        return;
+    }
+
+    let generation = TLB_GENERATION.load(Ordering::SeqCst);
+    let cpu = cpu::current_id() as usize;
+    let is_kernel = ptr::eq(loaded, &KERNEL_LOADED);
+    if pcid == 0 && !is_kernel {
+        // an address space without its own PCID leaves user entries in the kernel's
+        if let Some(kernel) = KERNEL_LOADED.get(cpu) {
+            kernel.store(NOT_LOADED, Ordering::SeqCst);
+        }
+    }
+    let no_flush = (pcid != 0 || is_kernel)
+        && loaded
+            .get(cpu)
+            .map(|l| l.swap(generation, Ordering::SeqCst) == generation)
+            .unwrap_or(false);
+
+    let value = pml4.start_address().as_u64() | pcid as u64 | (no_flush as u64) << 63;
+    asm!("mov cr3, {}", in(reg) value, options(nostack, preserves_flags));
+}
+
+/// Switches the current CPU back to the kernel's own table.
+pub fn activate_kernel() {
+    let pml4 = PhysFrame::containing_address(super::get_memory_manager().lvl4_table_addr());
+    if Cr3::read().0 != pml4 {
+        set_current(ptr::null_mut());
+        unsafe { load(pml4, 0, &KERNEL_LOADED) };
+    }
+}
+
+fn set_current(space: *mut AddressSpace) {
+    if let Some(current) = CURRENT.get(cpu::current_id() as usize) {
+        current.store(space, Ordering::SeqCst);
+    }
+}
+
+/// Runs `f` with the address space active on the current CPU, if any.
+pub fn with_current<R>(f: impl FnOnce(&AddressSpace) -> R) -> Option<R> {
+    let current = CURRENT
+        .get(cpu::current_id() as usize)?
+        .load(Ordering::SeqCst);
+    // activate requires the address space to stay in place while it is active
+    unsafe { current.as_ref() }.map(f)
+}
+
+fn allocate_pcid() -> u16 {
+    if !PCID_ENABLED.load(Ordering::Relaxed) {
+        return 0;
+    }
+    if let Some(pcid) = FREE_PCIDS.lock_sync().pop() {
+        return pcid;
+    }
+    NEXT_PCID
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |p| {
+            (p <= MAX_PCID).then_some(p + 1)
+        })
+        // out of PCIDs, share the kernel's and always flush
+        .unwrap_or(0)
+}
+
+pub struct AddressSpace {
+    pml4: PhysFrame,
+    page_table: Spinlock<OffsetPageTable<'static>>,
+    /// Level 4 slots copied from the kernel
+    kernel_slots: [bool; 512],
+    /// 0 if PCIDs are disabled or exhausted
+    pcid: u16,
+    loaded: [AtomicU64; MAX_CPUS],
+    /// Memory areas by start address
+    vmas: Spinlock<BTreeMap<VirtAddr, Vma>>,
+}
+
+impl AddressSpace {
+    /// Creates an address space with only the kernel mapped.
+    pub fn new() -> Result<Self, AddressSpaceError> {
+        let mm = super::get_memory_manager();
+        let pml4 = allocate_zeroed(&mm).ok_or(AddressSpaceError::OutOfMemory)?;
+
+        let mut kernel_table = mm.page_table.lock_sync();
+        let phys_offset = kernel_table.phys_offset();
+        let table = unsafe { table_at(phys_offset, pml4) };
+
+        let mut kernel_slots = [false; 512];
+        for (i, entry) in kernel_table.level_4_table().iter().enumerate() {
+            if !entry.is_unused() {
+                table[i] = entry.clone();
+                kernel_slots[i] = true;
+            }
+        }
+        drop(kernel_table);
+
+        #[cfg(feature = "dbg-mem")]
+        log::trace!("new address space with level 4 table at {:?}", pml4);
+
+        Ok(Self {
+            pml4,
+            page_table: Spinlock::new(unsafe { OffsetPageTable::new(table, phys_offset) }),
+            kernel_slots,
+            pcid: allocate_pcid(),
+            loaded: [NOT_LOADED_ON_CPU; MAX_CPUS],
+            vmas: Spinlock::new(BTreeMap::new()),
+        })
+    }
+
+    /// Physical frame of the level 4 table
+    pub fn pml4(&self) -> PhysFrame {
+        self.pml4
+    }
+
+    /// Whether this address space is loaded on the current CPU
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.pml4
+    }
+
+    /// Whether a CPU other than the current one has this address space loaded
+    fn active_elsewhere(&self) -> bool {
+        let cpu = cpu::current_id() as usize;
+        CURRENT
+            .iter()
+            .enumerate()
+            .any(|(i, current)| i != cpu && ptr::eq(current.load(Ordering::SeqCst), self))
+    }
+
+    /// Switches the current CPU to this address space.
+    ///
+    /// # Safety
+    ///
+    /// The address space must not be moved or dropped while it is active on any CPU, switch
+    /// away with [`activate_kernel`] or by activating another address space first.
+    pub unsafe fn activate(&self) {
+        set_current(self as *const _ as *mut _);
+        if !self.is_active() {
+            load(self.pml4, self.pcid, &self.loaded);
+        }
+    }
+
+    fn check_user(&self, pages: PageRange) -> Result<(), AddressSpaceError> {
+        if pages.is_empty() {
+            return Ok(());
+        }
+        let last = pages.end - 1;
+        for page in [pages.start, last] {
+            let addr = page.start_address();
+            if addr.as_u64() < USER_START || addr.as_u64() >= USER_END {
+                return Err(AddressSpaceError::NotUser(addr));
+            }
+        }
+        let slots = u16::from(pages.start.p4_index())..=u16::from(last.p4_index());
+        match slots.into_iter().find(|&i| self.kernel_slots[i as usize]) {
+            // the slot is in the lower half, so its address is canonical
+            Some(i) => Err(AddressSpaceError::NotUser(VirtAddr::new((i as u64) << 39))),
+            None => Ok(()),
+        }
+    }
+
+    /// Maps `pages` to newly allocated, zeroed frames which are freed with the mapping.
+    ///
+    /// `USER_ACCESSIBLE` is always added to `flags`.
+    pub fn map(&self, pages: PageRange, flags: PageTableFlags) -> Result<(), AddressSpaceError> {
+        self.check_user(pages)?;
+        let mm = super::get_memory_manager();
+
+        for (i, page) in pages.enumerate() {
+            let result = allocate_zeroed(&mm)
+                .ok_or(AddressSpaceError::OutOfMemory)
+                .and_then(|frame| {
+                    let result = self.map_to(&mm, page, frame, flags | OWNED);
+                    if result.is_err() {
+                        unsafe { mm.frame_allocator.lock_sync().deallocate_frame(frame) };
+                    }
+                    result
+                });
+            if let Err(e) = result {
+                self.unmap(Page::range(pages.start, pages.start + i as u64))
+                    .ok();
+                return Err(e);
+            }
+            stats::account(Tag::User, Size4KiB::SIZE);
+        }
+        Ok(())
+    }
+
+    /// Maps `page` to an existing `frame`, which stays owned by the caller.
+    ///
+    /// `USER_ACCESSIBLE` is always added to `flags`.
+    pub fn map_frame(
+        &self,
+        page: Page,
+        frame: PhysFrame,
+        flags: PageTableFlags,
+    ) -> Result<(), AddressSpaceError> {
+        self.check_user(Page::range(page, page + 1))?;
+        self.map_to(&super::get_memory_manager(), page, frame, flags - OWNED)
+    }
+
+    fn map_to(
+        &self,
+        mm: &MemoryManager,
+        page: Page,
+        frame: PhysFrame,
+        flags: PageTableFlags,
+    ) -> Result<(), AddressSpaceError> {
+        let flags = super::supported_flags(
+            flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE,
+        );
+        let parent_flags =
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+
+        let mut page_table = self.page_table.lock_sync();
+        let flush = unsafe {
+            page_table.map_to_with_table_flags(
+                page,
+                frame,
+                flags,
+                parent_flags,
+                mm.frame_allocator.lock_sync().deref_mut(),
+            )
+        }
+        .map_err(AddressSpaceError::Map)?;
+
+        // not present entries are never cached, so only the active table needs a flush
+        if self.is_active() {
+            flush.flush();
+        } else {
+            flush.ignore();
+        }
+        Ok(())
+    }
+
+    /// Unmaps `pages`, freeing the frames owned by the address space.
+    ///
+    /// Pages that aren't mapped are skipped.
+    pub fn unmap(&self, pages: PageRange) -> Result<(), AddressSpaceError> {
+        self.check_user(pages)?;
+        let mm = super::get_memory_manager();
+
+        let mut page_table = self.page_table.lock_sync();
+        for page in pages {
+            let flags = match page_table.translate(page.start_address()) {
+                TranslateResult::Mapped { flags, .. } => flags,
+                _ => continue,
+            };
+            let (frame, flush) = page_table.unmap(page).map_err(AddressSpaceError::Unmap)?;
+            if self.is_active() {
+                flush.flush();
+            } else {
+                flush.ignore();
+            }
+            if flags.contains(OWNED) && vma::unshare(frame) {
+                unsafe { mm.frame_allocator.lock_sync().deallocate_frame(frame) };
+                stats::release(Tag::User, Size4KiB::SIZE);
+            }
+        }
+        mappings_changed();
+        Ok(())
+    }
+
+    /// Replaces the flags of a mapped user page.
+    pub fn update_flags(&self, page: Page, flags: PageTableFlags) -> Result<(), AddressSpaceError> {
+        self.check_user(Page::range(page, page + 1))?;
+
+        let mut page_table = self.page_table.lock_sync();
+        let software = match page_table.translate(page.start_address()) {
+            TranslateResult::Mapped { flags, .. } => flags & (OWNED | COPY_ON_WRITE),
+            _ => return Err(AddressSpaceError::Flags(FlagUpdateError::PageNotMapped)),
+        };
+        let mut flags = (flags - OWNED - COPY_ON_WRITE) | software;
+        if software.contains(COPY_ON_WRITE) {
+            // stays read-only until the next write fault copies it
+            flags.remove(PageTableFlags::WRITABLE);
+        }
+        let flags = super::supported_flags(
+            flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE,
+        );
+        let flush =
+            unsafe { page_table.update_flags(page, flags) }.map_err(AddressSpaceError::Flags)?;
+        if self.is_active() {
+            flush.flush();
+        } else {
+            flush.ignore();
+        }
+        mappings_changed();
+        Ok(())
+    }
+
+    /// Adds a memory area, its pages are populated by [`Self::handle_fault`].
+    pub fn add_vma(&self, vma: Vma) -> Result<(), AddressSpaceError> {
+        self.check_user(vma.pages)?;
+        let range = vma.range();
+
+        let mut vmas = self.vmas.lock_sync();
+        if let Some(other) = vmas.values().find(|other| other.overlaps(&range)) {
+            return Err(AddressSpaceError::Overlap(other.range().start));
+        }
+        vmas.insert(range.start, vma);
+        Ok(())
+    }
+
+    /// Adds an anonymous memory area with the given access rights.
+    pub fn map_anonymous(
+        &self,
+        pages: PageRange,
+        flags: VmaFlags,
+    ) -> Result<(), AddressSpaceError> {
+        self.add_vma(Vma::anonymous(pages, flags))
+    }
+
+    /// Reserves `pages` as a guard area, e.g. below a stack.
+    pub fn map_guard(&self, pages: PageRange) -> Result<(), AddressSpaceError> {
+        self.add_vma(Vma::guard(pages))
+    }
+
+    /// Whether `pages` are in the user part of the address space and no memory area overlaps
+    /// them.
+    pub fn is_free(&self, pages: PageRange) -> bool {
+        let range = pages.start.start_address()..pages.end.start_address();
+        self.check_user(pages).is_ok()
+            && !self
+                .vmas
+                .lock_sync()
+                .values()
+                .any(|vma| vma.overlaps(&range))
+    }
+
+    /// Finds `count` free pages for a new memory area, as high up as possible.
+    ///
+    /// Only memory areas are taken into account, not pages mapped without one.
+    pub fn find_free(&self, count: u64) -> Option<PageRange> {
+        let size = count.checked_mul(Size4KiB::SIZE)?;
+        let mut end = USER_END;
+        while size > 0 && end >= USER_START + size {
+            let start = end - size;
+            let pages = Page::range(
+                Page::containing_address(VirtAddr::new(start)),
+                Page::containing_address(VirtAddr::new(end)),
+            );
+            if let Err(AddressSpaceError::NotUser(slot)) = self.check_user(pages) {
+                // below the lowest kernel slot in the way
+                end = slot.as_u64();
+                continue;
+            }
+            match self
+                .vmas
+                .lock_sync()
+                .values()
+                .find(|vma| vma.overlaps(&(VirtAddr::new(start)..VirtAddr::new(end))))
+            {
+                Some(vma) => end = vma.range().start.as_u64(),
+                None => return Some(pages),
+            }
+        }
+        None
+    }
+
+    /// Removes the memory area starting at `start` and unmaps its pages.
+    pub fn remove_vma(&self, start: VirtAddr) -> Result<Vma, AddressSpaceError> {
+        let vma = self
+            .vmas
+            .lock_sync()
+            .remove(&start)
+            .ok_or(AddressSpaceError::Unmap(UnmapError::PageNotMapped))?;
+        self.unmap(vma.pages)?;
+        Ok(vma)
+    }
+
+    /// The memory area containing `addr`
+    pub fn find_vma(&self, addr: VirtAddr) -> Option<Vma> {
+        let vmas = self.vmas.lock_sync();
+        let (_, vma) = vmas.range(..=addr).next_back()?;
+        vma.contains(addr).then(|| vma.clone())
+    }
+
+    /// All memory areas, ordered by address
+    pub fn vmas(&self) -> Vec<Vma> {
+        self.vmas.lock_sync().values().cloned().collect()
+    }
+
+    /// Resolves a page fault at `addr` by populating an anonymous page or copying a
+    /// copy-on-write page. Returns why the fault is invalid otherwise.
+    pub fn handle_fault(&self, addr: VirtAddr, error: PageFaultErrorCode) -> Result<(), FaultKind> {
+        let page = Page::containing_address(addr);
+        let write = error.contains(PageFaultErrorCode::CAUSED_BY_WRITE);
+
+        if error.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            return match self.translate(addr) {
+                Some((phys, flags)) if write && flags.contains(COPY_ON_WRITE) => {
+                    self.copy_on_write(page, PhysFrame::containing_address(phys))
+                }
+                _ => Err(FaultKind::PermissionViolation),
+            };
+        }
+
+        let vma = self.find_vma(addr).ok_or(FaultKind::NotMapped)?;
+        match vma.kind {
+            VmaKind::Guard => Err(FaultKind::GuardPage),
+            VmaKind::Anonymous => {
+                let allowed = if write {
+                    vma.flags.contains(VmaFlags::WRITE)
+                } else if error.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+                    vma.flags.contains(VmaFlags::EXECUTE)
+                } else {
+                    vma.flags.contains(VmaFlags::READ)
+                };
+                if !allowed {
+                    return Err(FaultKind::PermissionViolation);
+                }
+
+                let mm = super::get_memory_manager();
+                let frame = allocate_zeroed(&mm).ok_or(FaultKind::OutOfMemory)?;
+                match self.map_to(&mm, page, frame, vma.flags.page_table_flags() | OWNED) {
+                    Ok(()) => stats::account(Tag::User, Size4KiB::SIZE),
+                    Err(error) => {
+                        unsafe { mm.frame_allocator.lock_sync().deallocate_frame(frame) };
+                        match error {
+                            // another CPU populated it first
+                            AddressSpaceError::Map(MapToError::PageAlreadyMapped(_)) => {}
+                            AddressSpaceError::Map(MapToError::FrameAllocationFailed) => {
+                                return Err(FaultKind::OutOfMemory)
+                            }
+                            _ => return Err(FaultKind::PermissionViolation),
+                        }
+                    }
+                }
+
+                #[cfg(feature = "dbg-mem")]
+                log::trace!("populated {:?} on demand", page);
+
+                Ok(())
+            }
+        }
+    }
+
+    fn copy_on_write(&self, page: Page, frame: PhysFrame) -> Result<(), FaultKind> {
+        let mm = super::get_memory_manager();
+        let mut page_table = self.page_table.lock_sync();
+
+        // another CPU may have resolved the fault since `frame` was looked up
+        let flags = match page_table.translate(page.start_address()) {
+            TranslateResult::Mapped {
+                frame: mapped,
+                flags,
+                ..
+            } if flags.contains(COPY_ON_WRITE)
+                && mapped.start_address() == frame.start_address() =>
+            {
+                (flags - COPY_ON_WRITE) | PageTableFlags::WRITABLE
+            }
+            _ => return Ok(()),
+        };
+
+        if !vma::is_shared(frame) {
+            // every other address space already made its own copy
+            let flush = unsafe { page_table.update_flags(page, flags) }
+                .map_err(|_| FaultKind::NotMapped)?;
+            flush.flush();
+            return Ok(());
+        }
+
+        let copy = allocate_zeroed(&mm).ok_or(FaultKind::OutOfMemory)?;
+        let phys_offset = page_table.phys_offset();
+        unsafe {
+            ptr::copy_nonoverlapping(
+                table_at(phys_offset, frame) as *const PageTable,
+                table_at(phys_offset, copy) as *mut PageTable,
+                1,
+            );
+        }
+        let (_, flush) = page_table.unmap(page).map_err(|_| FaultKind::NotMapped)?;
+        flush.flush();
+        unsafe {
+            page_table
+                .map_to(
+                    page,
+                    copy,
+                    flags,
+                    mm.frame_allocator.lock_sync().deref_mut(),
+                )
+                .expect("the page tables of a just unmapped page exist")
+                .flush();
+        }
+        stats::account(Tag::User, Size4KiB::SIZE);
+
+        if vma::unshare(frame) {
+            unsafe { mm.frame_allocator.lock_sync().deallocate_frame(frame) };
+            stats::release(Tag::User, Size4KiB::SIZE);
+        }
+
+        #[cfg(feature = "dbg-mem")]
+        log::trace!("copied {:?} on write", page);
+
+        Ok(())
+    }
+
+    /// Creates a copy of this address space, sharing all populated pages copy-on-write.
+    ///
+    /// Pages mapped with [`Self::map_frame`] are shared as they are. Fails with
+    /// [`AddressSpaceError::ActiveElsewhere`] if another CPU has this address space loaded, as
+    /// its TLB could still allow writes to the shared pages.
+    pub fn clone_cow(&self) -> Result<AddressSpace, AddressSpaceError> {
+        let child = AddressSpace::new()?;
+        *child.vmas.lock_sync() = self.vmas.lock_sync().clone();
+
+        let mm = super::get_memory_manager();
+        let mut pages = Vec::new();
+        {
+            let mut page_table = self.page_table.lock_sync();
+            let phys_offset = page_table.phys_offset();
+            for (i, entry) in page_table.level_4_table().iter().enumerate() {
+                if self.kernel_slots[i] || entry.is_unused() {
+                    continue;
+                }
+                let table = PhysFrame::containing_address(entry.addr());
+                let start = Page::containing_address(VirtAddr::new((i as u64) << 39));
+                unsafe { share_table(phys_offset, table, 3, start, &mut pages) };
+            }
+            if self.is_active() {
+                x86_64::instructions::tlb::flush_all();
+            }
+            // CPUs loading it from now on flush, the ones that already have it don't
+            mappings_changed();
+            if self.active_elsewhere() {
+                // pages left copy-on-write are made writable again on their next write
+                unshare_pages(&mm, &pages);
+                return Err(AddressSpaceError::ActiveElsewhere);
+            }
+        }
+
+        for (i, &(page, frame, flags)) in pages.iter().enumerate() {
+            if let Err(e) = child.map_to(&mm, page, frame, flags) {
+                // the pages already mapped are released with the child
+                unshare_pages(&mm, &pages[i..]);
+                return Err(e);
+            }
+        }
+        Ok(child)
+    }
+
+    /// Copies `data` to `addr` through the physical memory mapping, so this address space
+    /// needn't be active and read-only pages can be written to. Pages of anonymous memory
+    /// areas are populated and copy-on-write pages copied as needed.
+    pub fn write(&self, addr: VirtAddr, data: &[u8]) -> Result<(), AddressSpaceError> {
+        let phys_offset = self.page_table.lock_sync().phys_offset();
+        let mut written = 0;
+
+        while written < data.len() {
+            let addr = addr + written;
+            let phys = match self.translate(addr) {
+                Some((phys, flags)) if !flags.contains(COPY_ON_WRITE) => phys,
+                mapped => {
+                    let mut error = PageFaultErrorCode::CAUSED_BY_WRITE;
+                    if mapped.is_some() {
+                        error |= PageFaultErrorCode::PROTECTION_VIOLATION;
+                    }
+                    self.handle_fault(addr, error)
+                        .map_err(|_| AddressSpaceError::NotMapped(addr))?;
+                    self.translate(addr)
+                        .ok_or(AddressSpaceError::NotMapped(addr))?
+                        .0
+                }
+            };
+
+            let len = ((Size4KiB::SIZE - addr.as_u64() % Size4KiB::SIZE) as usize)
+                .min(data.len() - written);
+            unsafe {
+                ptr::copy_nonoverlapping(
+                    data[written..].as_ptr(),
+                    (phys_offset + phys.as_u64()).as_mut_ptr::<u8>(),
+                    len,
+                )
+            };
+            written += len;
+        }
+        Ok(())
+    }
+
+    /// Physical frame and flags `addr` is mapped to.
+    pub fn translate(&self, addr: VirtAddr) -> Option<(PhysAddr, PageTableFlags)> {
+        match self.page_table.lock_sync().translate(addr) {
+            TranslateResult::Mapped {
+                frame,
+                offset,
+                flags,
+            } => Some((frame.start_address() + offset, flags)),
+            _ => None,
+        }
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        if self.is_active() {
+            activate_kernel();
+        }
+
+        let mm = super::get_memory_manager();
+        let mut frame_allocator = mm.frame_allocator.lock_sync();
+        let mut page_table = self.page_table.lock_sync();
+        let phys_offset = page_table.phys_offset();
+        let mut owned = 0;
+
+        for (i, entry) in page_table.level_4_table().iter_mut().enumerate() {
+            if self.kernel_slots[i] || entry.is_unused() {
+                continue;
+            }
+            let frame = PhysFrame::containing_address(entry.addr());
+            unsafe { free_table(phys_offset, frame, 3, &mut *frame_allocator, &mut owned) };
+            entry.set_unused();
+        }
+        unsafe { frame_allocator.deallocate_frame(self.pml4) };
+        stats::release(Tag::User, owned * Size4KiB::SIZE);
+        mappings_changed();
+
+        if self.pcid != 0 {
+            FREE_PCIDS.lock_sync().push(self.pcid);
+        }
+
+        #[cfg(feature = "dbg-mem")]
+        log::trace!(
+            "dropped address space with level 4 table at {:?}",
+            self.pml4
+        );
+    }
+}
+
+/// Frees the table in `frame` at `level` (3 is a level 3 table) with all tables below it and
+/// the owned frames they map. `owned` is incremented by the number of owned frames.
+unsafe fn free_table<A: FrameDeallocator<Size4KiB>>(
+    phys_offset: VirtAddr,
+    frame: PhysFrame,
+    level: u8,
+    frame_allocator: &mut A,
+    owned: &mut u64,
+) {
+    let table = table_at(phys_offset, frame);
+    for entry in table.iter() {
+        if entry.is_unused() {
+            continue;
+        }
+        let child = PhysFrame::containing_address(entry.addr());
+        if level == 1 {
+            if entry.flags().contains(OWNED) && vma::unshare(child) {
+                frame_allocator.deallocate_frame(child);
+                *owned += 1;
+            }
+        } else {
+            // user mappings only use 4 KiB pages
+            free_table(phys_offset, child, level - 1, frame_allocator, owned);
+        }
+    }
+    frame_allocator.deallocate_frame(frame);
+}
+
+/// Marks the owned pages mapped by the table in `frame` at `level` copy-on-write, and adds
+/// everything it maps to `pages`. `start` is the first page the table covers.
+unsafe fn share_table(
+    phys_offset: VirtAddr,
+    frame: PhysFrame,
+    level: u8,
+    start: Page,
+    pages: &mut Vec<(Page, PhysFrame, PageTableFlags)>,
+) {
+    let table = table_at(phys_offset, frame);
+    let pages_per_entry = 1u64 << (9 * (level as u64 - 1));
+    for (i, entry) in table.iter_mut().enumerate() {
+        if entry.is_unused() {
+            continue;
+        }
+        let page = start + i as u64 * pages_per_entry;
+        let child = PhysFrame::containing_address(entry.addr());
+        if level > 1 {
+            share_table(phys_offset, child, level - 1, page, pages);
+            continue;
+        }
+
+        let mut flags = entry.flags();
+        if flags.contains(OWNED) {
+            if flags.contains(PageTableFlags::WRITABLE) {
+                flags = (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+                entry.set_flags(flags);
+            }
+            vma::share(child);
+        }
+        pages.push((page, child, flags));
+    }
+}
+
+/// Drops the references [`share_table`] took to the owned frames of `pages`, freeing the
+/// frames nothing maps anymore.
+fn unshare_pages(mm: &MemoryManager, pages: &[(Page, PhysFrame, PageTableFlags)]) {
+    for &(_, frame, flags) in pages {
+        if flags.contains(OWNED) && vma::unshare(frame) {
+            unsafe { mm.frame_allocator.lock_sync().deallocate_frame(frame) };
+            stats::release(Tag::User, Size4KiB::SIZE);
+        }
+    }
+}
+
+unsafe fn table_at(phys_offset: VirtAddr, frame: PhysFrame) -> &'static mut PageTable {
+    &mut *(phys_offset + frame.start_address().as_u64()).as_mut_ptr()
+}
+
+/// Allocates a zeroed frame
+fn allocate_zeroed(mm: &MemoryManager) -> Option<PhysFrame> {
+    let frame: PhysFrame = mm.frame_allocator.lock_sync().allocate_frame()?;
+    let phys_offset = mm.page_table.lock_sync().phys_offset();
+    unsafe { table_at(phys_offset, frame).zero() };
+    Some(frame)
+}
+
+/// Creates empty level 3 tables for all unused level 4 slots of the kernel's table in `range`,
+/// so kernel mappings created there later are visible in every address space.
+pub(super) fn reserve_kernel_slots(mm: &MemoryManager, range: Range<VirtAddr>) {
+    let first = u16::from(Page::<Size4KiB>::containing_address(range.start).p4_index());
+    let last = u16::from(Page::<Size4KiB>::containing_address(range.end - 1u64).p4_index());
+
+    for i in first..=last {
+        if !mm.page_table.lock_sync().level_4_table()[i as usize].is_unused() {
+            continue;
+        }
+        let frame = allocate_zeroed(mm).expect("out of memory for kernel page tables");
+        mm.page_table.lock_sync().level_4_table()[i as usize]
+            .set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+    }
+}
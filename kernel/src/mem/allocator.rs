@@ -1,29 +1,210 @@
-use alloc::alloc::Global;
-use core::alloc::Allocator;
+use alloc::{alloc::Global, vec::Vec};
+use core::{
+    alloc::{Allocator, GlobalAlloc, Layout},
+    ops::Range,
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
 use x86_64::{
     structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size2MiB, Size4KiB,
+        mapper::MapToError, FrameAllocator, Mapper, Page, PageSize, PageTableFlags, Size2MiB,
+        Size4KiB,
     },
     VirtAddr,
 };
 
 use linked_list_allocator::LockedHeap;
 
+use super::{
+    layout::{self, MAX_HEAP_SIZE},
+    pat::CacheMode,
+    slab::{SlabAllocator, SlabStats},
+    stats::{self, HeapStats, Tag},
+};
+use crate::util::Spinlock;
+
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: SlabAllocator<GrowingHeap> = SlabAllocator::new(&HEAP);
+static HEAP: GrowingHeap = GrowingHeap::new();
+
+/// Default for the maximum heap size, see [`set_heap_limit`]
+pub const DEFAULT_HEAP_LIMIT: usize = 512 * 1024 * 1024;
 
-/// This virtual address marks where the extended heap will start. It is aligned to 1 GiB.
+/// The heap grows at least by this much at once.
+const GROWTH_STEP: usize = Size2MiB::SIZE as usize;
+/// When less than this is free after an allocation, the heap grows ahead of time. This
+/// leaves room for the allocations the frame allocator makes while the heap is growing.
+const LOW_WATERMARK: usize = 64 * 1024;
+/// How often to retry taking the locks needed to grow the heap before giving up.
+const GROW_RETRIES: usize = 100_000;
+/// Maximum number of 2 MiB chunks [`shrink_heap`] can give back at once.
+const MAX_DECOMMITTED: usize = 64;
+
+/// Heap that grows by mapping more 2 MiB pages when it runs out of memory.
 ///
-/// Below this address is where the initial heap is, which should be small.
-pub const EXTENDED_HEAP_START: u64 = 0x_4446_0000_0000;
+/// The heap is one contiguous region, growing up from [`layout::heap_start`] with the small
+/// initial heap right below. Shrinking can't move the top of the heap, so instead free 2 MiB
+/// chunks anywhere in the heap are allocated and unmapped ("decommitted"), and mapped again
+/// first the next time the heap grows.
+struct GrowingHeap {
+    heap: LockedHeap,
+    /// Bytes of the heap that are backed by memory
+    committed: AtomicUsize,
+    limit: AtomicUsize,
+    /// Held while the heap grows or shrinks, guards the decommitted chunks
+    decommitted: Spinlock<[Option<Page<Size2MiB>>; MAX_DECOMMITTED]>,
+}
+
+impl GrowingHeap {
+    const fn new() -> Self {
+        Self {
+            heap: LockedHeap::empty(),
+            committed: AtomicUsize::new(0),
+            limit: AtomicUsize::new(DEFAULT_HEAP_LIMIT),
+            decommitted: Spinlock::new([None; MAX_DECOMMITTED]),
+        }
+    }
+
+    /// Tries to make at least `min` more bytes available, returns whether it did.
+    ///
+    /// This must not block: it can be called while the caller holds the page table or frame
+    /// allocator lock, so it only retries taking those a limited number of times if `retry`
+    /// is set, and gives up right away otherwise.
+    fn grow(&self, min: usize, retry: bool) -> bool {
+        let retries = if retry { GROW_RETRIES } else { 1 };
+
+        let mm = match super::MEMORY_MANAGER.try_get() {
+            Ok(mm) => mm,
+            Err(_) => return false,
+        };
+        let mut decommitted = match retry_while_none(retries, || self.decommitted.try_lock()) {
+            Some(decommitted) => decommitted,
+            None => return false,
+        };
+
+        let committed = self.committed.load(Ordering::SeqCst);
+        let available = self.limit.load(Ordering::SeqCst).saturating_sub(committed);
+        let size =
+            align_up(min.max(GROWTH_STEP), GROWTH_STEP).min(align_down(available, GROWTH_STEP));
+        if size < min || size == 0 {
+            #[cfg(feature = "dbg-mem")]
+            log::warn!("heap limit reached, can't grow by {} bytes", min);
+            return false;
+        }
+
+        // a decommitted chunk is enough for small requests and doesn't make the heap larger
+        if min <= GROWTH_STEP {
+            if let Some(slot) = decommitted.iter_mut().find(|c| c.is_some()) {
+                let page = slot.expect("slot is some");
+                if let Some(Ok(_)) =
+                    retry_while_none(retries, || mm.try_map_2m(page, CacheMode::WriteBack))
+                {
+                    *slot = None;
+                    unsafe {
+                        self.heap.lock().deallocate(
+                            NonNull::new_unchecked(page.start_address().as_mut_ptr()),
+                            chunk_layout(),
+                        );
+                    }
+                    self.committed.fetch_add(GROWTH_STEP, Ordering::SeqCst);
+                    stats::account(Tag::Heap, Size2MiB::SIZE);
+                    return true;
+                }
+                return false;
+            }
+        }
+
+        let top = VirtAddr::from_ptr(self.heap.lock().top());
+        let pages = Page::<Size2MiB>::range(
+            Page::containing_address(top),
+            Page::containing_address(top + size),
+        );
+
+        let mut grown = 0;
+        for page in pages {
+            match retry_while_none(retries, || mm.try_map_2m(page, CacheMode::WriteBack)) {
+                Some(Ok(_)) => grown += GROWTH_STEP,
+                _ => break,
+            }
+        }
+        if grown == 0 {
+            return false;
+        }
+
+        unsafe {
+            self.heap.lock().extend(grown);
+        }
+        self.committed.fetch_add(grown, Ordering::SeqCst);
+        stats::account(Tag::Heap, grown as u64);
+
+        #[cfg(feature = "dbg-mem")]
+        log::debug!("heap grew by {} KiB", grown / 1024);
+
+        grown >= min
+    }
+}
+
+unsafe impl GlobalAlloc for GrowingHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        loop {
+            let (result, free) = {
+                let mut heap = self.heap.lock();
+                (heap.allocate_first_fit(layout), heap.free())
+            };
+            match result {
+                Ok(ptr) => {
+                    if free < LOW_WATERMARK {
+                        self.grow(GROWTH_STEP, false);
+                    }
+                    return ptr.as_ptr();
+                }
+                Err(()) => {
+                    if !self.grow(layout.size() + layout.align(), true) {
+                        return ptr::null_mut();
+                    }
+                }
+            }
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.heap
+            .lock()
+            .deallocate(NonNull::new_unchecked(ptr), layout);
+    }
+}
+
+fn retry_while_none<T>(retries: usize, mut f: impl FnMut() -> Option<T>) -> Option<T> {
+    for _ in 0..retries {
+        if let Some(t) = f() {
+            return Some(t);
+        }
+        core::hint::spin_loop();
+    }
+    None
+}
+
+fn chunk_layout() -> Layout {
+    Layout::from_size_align(GROWTH_STEP, GROWTH_STEP).expect("valid layout")
+}
+
+#[inline]
+fn align_up(size: usize, align: usize) -> usize {
+    linked_list_allocator::align_up_size(size, align)
+}
+
+#[inline]
+fn align_down(size: usize, align: usize) -> usize {
+    linked_list_allocator::align_down_size(size, align)
+}
 
 pub fn init_heap(
     mapper: &mut (impl Mapper<Size4KiB> + '_),
     initial_size: u64,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
 ) -> Result<(), MapToError<Size4KiB>> {
-    let heap_start = VirtAddr::new(EXTENDED_HEAP_START - initial_size);
+    let heap_start = layout::heap_start() - initial_size;
     let page_range = {
         let heap_end = heap_start + initial_size;
         let heap_start_page = Page::containing_address(heap_start);
@@ -42,17 +223,22 @@ pub fn init_heap(
         let frame = frame_allocator
             .allocate_frame()
             .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        let flags = super::supported_flags(
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        );
         unsafe {
             mapper.map_to(page, frame, flags, frame_allocator)?.flush();
         }
     }
 
     unsafe {
-        ALLOCATOR
+        HEAP.heap
             .lock()
             .init(heap_start.as_mut_ptr(), initial_size as usize);
     }
+    HEAP.committed
+        .store(initial_size as usize, Ordering::SeqCst);
+    stats::account(Tag::Heap, initial_size);
 
     #[cfg(feature = "dbg-mem")]
     dump_heap_state();
@@ -64,7 +250,7 @@ pub fn init_heap(
 /// frame allocator, so we can use 2MiB pages.
 pub fn extend(extension_size: usize) -> Result<(), MapToError<Size2MiB>> {
     let page_range = {
-        let heap_extended_bottom = VirtAddr::new(ALLOCATOR.lock().top() as u64);
+        let heap_extended_bottom = VirtAddr::new(HEAP.heap.lock().top() as u64);
         let heap_extended_top = heap_extended_bottom + extension_size - 1u64;
         let heap_extended_bottom_page = Page::containing_address(heap_extended_bottom);
         let heap_extended_top_page = Page::containing_address(heap_extended_top);
@@ -80,12 +266,14 @@ pub fn extend(extension_size: usize) -> Result<(), MapToError<Size2MiB>> {
 
     let mm = super::get_memory_manager();
     for page in page_range {
-        mm.map_2m(page)?;
+        mm.map_2m(page, CacheMode::WriteBack)?;
     }
 
     unsafe {
-        ALLOCATOR.lock().extend(extension_size);
+        HEAP.heap.lock().extend(extension_size);
     }
+    HEAP.committed.fetch_add(extension_size, Ordering::SeqCst);
+    stats::account(Tag::Heap, extension_size as u64);
 
     #[cfg(feature = "dbg-mem")]
     dump_heap_state();
@@ -93,13 +281,78 @@ pub fn extend(extension_size: usize) -> Result<(), MapToError<Size2MiB>> {
     Ok(())
 }
 
+/// Sets the maximum size the heap may grow to.
+///
+/// Lowering the limit below the current size doesn't shrink the heap, see [`shrink_heap`].
+/// The limit can't exceed [`MAX_HEAP_SIZE`], the virtual space set aside for the heap.
+pub fn set_heap_limit(bytes: usize) {
+    HEAP.limit
+        .store(bytes.min(MAX_HEAP_SIZE as usize), Ordering::SeqCst);
+}
+
+/// Gives free 2 MiB chunks of the heap back to the frame allocator, as long as at least
+/// `keep_free` bytes stay free. Returns the number of bytes given back.
+pub fn shrink_heap(keep_free: usize) -> usize {
+    let mm = super::get_memory_manager();
+    let mut decommitted = HEAP.decommitted.lock_sync();
+
+    let mut released = 0;
+    for slot in decommitted.iter_mut().filter(|c| c.is_none()) {
+        let chunk = {
+            let mut heap = HEAP.heap.lock();
+            if heap.free() < keep_free + GROWTH_STEP {
+                break;
+            }
+            match heap.allocate_first_fit(chunk_layout()) {
+                Ok(chunk) => chunk,
+                Err(()) => break,
+            }
+        };
+
+        let page = Page::containing_address(VirtAddr::from_ptr(chunk.as_ptr()));
+        mm.unmap_2m(page)
+            .unwrap_or_else(|e| panic!("failed to unmap heap chunk {:?}: {:?}", page, e));
+        *slot = Some(page);
+        released += GROWTH_STEP;
+    }
+
+    HEAP.committed.fetch_sub(released, Ordering::SeqCst);
+    stats::release(Tag::Heap, released as u64);
+
+    #[cfg(feature = "dbg-mem")]
+    log::debug!("heap shrunk by {} KiB", released / 1024);
+
+    released
+}
+
+pub(super) fn heap_stats() -> HeapStats {
+    let a = HEAP.heap.lock();
+    let committed = HEAP.committed.load(Ordering::SeqCst) as u64;
+    let decommitted = a.size() as u64 - committed;
+    HeapStats {
+        start: VirtAddr::from_ptr(a.bottom()),
+        size: committed,
+        used: a.used() as u64 - decommitted,
+    }
+}
+
+/// Virtual range set aside for the heap, whatever its limit
+pub(super) fn reserved_region() -> Range<VirtAddr> {
+    let start = layout::heap_start();
+    start..start + MAX_HEAP_SIZE
+}
+
+pub(super) fn slab_stats() -> Vec<SlabStats> {
+    ALLOCATOR.stats()
+}
+
 pub fn dump_heap_state() {
     let mut level = log::Level::Debug;
-    const K: usize = 1024;
+    const K: u64 = 1024;
 
-    let a = ALLOCATOR.lock();
-    let used = a.used() / K;
-    let size = a.size() / K;
+    let stats = heap_stats();
+    let used = stats.used / K;
+    let size = stats.size / K;
     let ratio = used as f32 / size as f32;
     if ratio == 1. {
         level = log::Level::Error;
@@ -124,7 +377,7 @@ pub fn dump_heap_state() {
 /// This is needed to ensure that we can dump the allocator state while panicking, as an
 /// out-of-memory state panics for now.
 pub(crate) unsafe fn force_unlock_allocator() {
-    ALLOCATOR.force_unlock();
+    HEAP.heap.force_unlock();
 }
 
 pub struct AlignedAlloc<const N: usize>;
@@ -0,0 +1,163 @@
+//! Buddy allocator for physical memory
+//!
+//! Free memory is kept as blocks of `4 KiB << order` bytes, for orders from 0 (4 KiB) up to
+//! [`MAX_ORDER`] (1 GiB). Every block is aligned to its own size, so the buddy of a block is
+//! found by flipping a single address bit. Freed blocks are merged with their buddy whenever
+//! it is free as well.
+//!
+//! The free lists are ordered sets of block addresses, making allocation, deallocation and
+//! coalescing O(log n). The allocator only does address math, it never touches the memory it
+//! manages.
+
+use alloc::collections::BTreeSet;
+use core::ops::Range;
+use x86_64::{
+    align_up,
+    structures::paging::{PageSize, Size4KiB},
+};
+
+/// Size of an order 0 block
+pub const MIN_BLOCK_SIZE: u64 = Size4KiB::SIZE;
+/// Largest order, blocks of this order are 1 GiB
+pub const MAX_ORDER: usize = 18;
+
+const MIN_BLOCK_SHIFT: u32 = MIN_BLOCK_SIZE.trailing_zeros();
+
+/// Size in bytes of a block of the given order.
+#[inline]
+pub const fn block_size(order: usize) -> u64 {
+    MIN_BLOCK_SIZE << order
+}
+
+/// Smallest order whose blocks can hold `size` bytes, if there is one.
+pub fn order_for_size(size: u64) -> Option<usize> {
+    let frames = align_up(size.max(1), MIN_BLOCK_SIZE) / MIN_BLOCK_SIZE;
+    let order = frames.next_power_of_two().trailing_zeros() as usize;
+    (order <= MAX_ORDER).then_some(order)
+}
+
+/// Largest order of an aligned block that starts at `start` and fits below `end`.
+fn largest_fitting_order(start: u64, end: u64) -> usize {
+    let align = if start == 0 {
+        MAX_ORDER
+    } else {
+        (start.trailing_zeros() - MIN_BLOCK_SHIFT) as usize
+    };
+    let fit = ((end - start).ilog2() - MIN_BLOCK_SHIFT) as usize;
+    align.min(fit).min(MAX_ORDER)
+}
+
+#[derive(Debug)]
+pub struct BuddyAllocator {
+    free: [BTreeSet<u64>; MAX_ORDER + 1],
+    free_bytes: u64,
+}
+
+impl BuddyAllocator {
+    pub fn new() -> Self {
+        Self {
+            free: core::array::from_fn(|_| BTreeSet::new()),
+            free_bytes: 0,
+        }
+    }
+
+    /// Adds the memory in `range` to the free blocks.
+    ///
+    /// The range is shrunk to 4 KiB boundaries and split into the largest aligned blocks
+    /// possible, which are merged with adjacent free blocks.
+    pub fn free_range(&mut self, range: Range<u64>) {
+        let mut start = align_up(range.start, MIN_BLOCK_SIZE);
+        let end = range.end - range.end % MIN_BLOCK_SIZE;
+
+        while start < end {
+            let order = largest_fitting_order(start, end);
+            self.deallocate(start, order);
+            start += block_size(order);
+        }
+    }
+
+    /// Allocates a block of `4 KiB << order` bytes, aligned to its size.
+    ///
+    /// The lowest free address is preferred, larger blocks are split as needed.
+    pub fn allocate(&mut self, order: usize) -> Option<u64> {
+        let found = (order..=MAX_ORDER).find(|&o| !self.free[o].is_empty())?;
+        let addr = self.free[found].pop_first()?;
+
+        // give back the upper halves we don't need
+        for o in (order..found).rev() {
+            self.free[o].insert(addr + block_size(o));
+        }
+
+        self.free_bytes -= block_size(order);
+        Some(addr)
+    }
+
+    /// Allocates `size` bytes of contiguous memory, aligned to 4 KiB.
+    ///
+    /// Unlike [`Self::allocate`], only the needed frames are taken, the rest of the block is
+    /// given back right away.
+    pub fn allocate_contiguous(&mut self, size: u64) -> Option<Range<u64>> {
+        let order = order_for_size(size)?;
+        let start = self.allocate(order)?;
+        let end = start + align_up(size.max(1), MIN_BLOCK_SIZE);
+        self.free_range(end..start + block_size(order));
+        Some(start..end)
+    }
+
+    /// Returns a block previously handed out by [`Self::allocate`] with the same `order`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the block is misaligned or already free.
+    pub fn deallocate(&mut self, addr: u64, order: usize) {
+        assert!(
+            addr % block_size(order) == 0,
+            "block {:#x} is not aligned to order {}",
+            addr,
+            order
+        );
+        self.free_bytes += block_size(order);
+
+        let mut addr = addr;
+        let mut order = order;
+        while order < MAX_ORDER {
+            let buddy = addr ^ block_size(order);
+            if !self.free[order].remove(&buddy) {
+                break;
+            }
+            addr = addr.min(buddy);
+            order += 1;
+        }
+
+        assert!(
+            self.free[order].insert(addr),
+            "double free of block {:#x}, order {}",
+            addr,
+            order
+        );
+    }
+
+    /// Total number of free bytes.
+    pub fn free_bytes(&self) -> u64 {
+        self.free_bytes
+    }
+
+    /// Number of free blocks of the given order.
+    pub fn free_blocks(&self, order: usize) -> usize {
+        self.free[order].len()
+    }
+
+    /// Iterates over all free blocks as `(address, order)`, grouped by order.
+    pub fn blocks(&self) -> impl Iterator<Item = (u64, usize)> + '_ {
+        self.free
+            .iter()
+            .enumerate()
+            .flat_map(|(order, blocks)| blocks.iter().map(move |&addr| (addr, order)))
+    }
+}
+
+impl Default for BuddyAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,115 @@
+//! Page fault handling
+//!
+//! [`handle`] resolves faults in the user part of the active [`AddressSpace`] (demand paging
+//! and copy-on-write) and describes every other fault with a [`FaultReport`].
+
+use core::fmt;
+use x86_64::{structures::idt::PageFaultErrorCode, VirtAddr};
+
+use super::address_space::{self, AddressSpace, USER_END};
+
+/// Why a page fault couldn't be resolved
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultKind {
+    /// Access to the first page, almost certainly through a null pointer
+    NullDereference,
+    /// Access to the guard page of a kernel stack or a guard area of an address space
+    GuardPage,
+    /// The page is mapped, but not with the rights the access needs
+    PermissionViolation,
+    /// Nothing is, or will be, mapped at the address
+    NotMapped,
+    /// A page couldn't be populated because physical memory ran out
+    OutOfMemory,
+}
+
+impl fmt::Display for FaultKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FaultKind::NullDereference => "null pointer dereference",
+            FaultKind::GuardPage => "guard page hit",
+            FaultKind::PermissionViolation => "permission violation",
+            FaultKind::NotMapped => "access to unmapped memory",
+            FaultKind::OutOfMemory => "out of memory",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FaultReport {
+    pub addr: VirtAddr,
+    pub instruction_pointer: VirtAddr,
+    pub error_code: PageFaultErrorCode,
+    pub kind: FaultKind,
+}
+
+impl FaultReport {
+    pub fn user_mode(&self) -> bool {
+        self.error_code.contains(PageFaultErrorCode::USER_MODE)
+    }
+}
+
+impl fmt::Display for FaultReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let access = if self
+            .error_code
+            .contains(PageFaultErrorCode::INSTRUCTION_FETCH)
+        {
+            "executing"
+        } else if self
+            .error_code
+            .contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+        {
+            "writing"
+        } else {
+            "reading"
+        };
+        write!(
+            f,
+            "{}: {} {} {:?} at {:?} ({:?})",
+            self.kind,
+            if self.user_mode() { "user" } else { "kernel" },
+            access,
+            self.addr,
+            self.instruction_pointer,
+            self.error_code
+        )
+    }
+}
+
+/// Tries to resolve a page fault at `addr`, caused by the instruction at
+/// `instruction_pointer`.
+pub fn handle(
+    addr: VirtAddr,
+    error_code: PageFaultErrorCode,
+    instruction_pointer: VirtAddr,
+) -> Result<(), FaultReport> {
+    let report = |kind| FaultReport {
+        addr,
+        instruction_pointer,
+        error_code,
+        kind,
+    };
+
+    if addr.as_u64() < address_space::USER_START {
+        return Err(report(FaultKind::NullDereference));
+    }
+    if super::stack::find_by_guard_page(addr).is_some() {
+        return Err(report(FaultKind::GuardPage));
+    }
+    if addr.as_u64() < USER_END {
+        if let Some(result) =
+            address_space::with_current(|space: &AddressSpace| space.handle_fault(addr, error_code))
+        {
+            return result.map_err(report);
+        }
+    }
+
+    Err(report(
+        if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            FaultKind::PermissionViolation
+        } else {
+            FaultKind::NotMapped
+        },
+    ))
+}
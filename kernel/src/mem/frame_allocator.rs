@@ -1,12 +1,16 @@
-use alloc::collections::BTreeSet;
+use alloc::{collections::BTreeSet, vec::Vec};
 use bootloader_api::info::{
     MemoryRegionKind as BootMemoryRegionKind, MemoryRegions as BootMemoryRegions,
 };
 use x86_64::{
-    structures::paging::{FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size4KiB},
+    structures::paging::{
+        frame::PhysFrameRange, FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size4KiB,
+    },
     PhysAddr,
 };
 
+use super::{buddy::BuddyAllocator, stats::FrameStats};
+
 pub struct BootInfoFrameAllocator {
     memory_regions: &'static BootMemoryRegions,
     next: usize,
@@ -63,15 +67,10 @@ struct MemoryRegion {
     end: u64,
 }
 impl MemoryRegion {
+    /// Whether the regions share at least one byte or touch each other, so they can be merged.
     #[inline]
-    fn size(&self) -> u64 {
-        self.end - self.start
-    }
-
-    #[inline]
-    fn overlaps(&self, other: &Self) -> bool {
-        (self.start > other.start && self.end < other.end)
-            || (other.start > self.start && other.end < self.end)
+    fn overlaps_or_touches(&self, other: &Self) -> bool {
+        self.start <= other.end && other.start <= self.end
     }
 
     #[inline]
@@ -79,21 +78,13 @@ impl MemoryRegion {
         self.start <= other.start && self.end >= other.end
     }
 }
-impl core::ops::RangeBounds<u64> for MemoryRegion {
-    fn start_bound(&self) -> core::ops::Bound<&u64> {
-        core::ops::Bound::Included(&self.start)
-    }
-
-    fn end_bound(&self) -> core::ops::Bound<&u64> {
-        core::ops::Bound::Excluded(&self.end)
-    }
-}
 impl core::fmt::Debug for MemoryRegion {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.write_fmt(format_args!("0x{:x} - 0x{:x}", self.start, self.end))
     }
 }
 
+/// A set of non-overlapping memory regions, overlapping and adjacent regions are merged.
 #[derive(Debug)]
 struct MemoryRegions {
     regions: BTreeSet<MemoryRegion>,
@@ -105,82 +96,32 @@ impl MemoryRegions {
         }
     }
 
-    fn add(&mut self, region: MemoryRegion) {
-        if self.regions.is_empty() {
-            self.regions.insert(region);
-            return;
+    fn add(&mut self, mut region: MemoryRegion) {
+        let merged: Vec<_> = self
+            .regions
+            .iter()
+            .filter(|r| r.overlaps_or_touches(&region))
+            .copied()
+            .collect();
+        for r in merged {
+            self.regions.remove(&r);
+            region.start = region.start.min(r.start);
+            region.end = region.end.max(r.end);
         }
-
-        for r in self.regions.iter() {
-            if r.contains(&region) {
-                // already covered by larger existing region
-                return;
-            }
-            if r.overlaps(&region) {
-                if region.start < r.start {
-                    // we're under the existing region, extend it down
-                    self.regions.replace(MemoryRegion {
-                        start: region.start,
-                        end: r.end,
-                    });
-                } else if region.end > r.end {
-                    // we're over the existing region, extend it up
-                    self.regions.replace(MemoryRegion {
-                        start: r.start,
-                        end: region.end,
-                    });
-                }
-                return;
-            }
-        }
-
-        // at this point we found no intersections
         self.regions.insert(region);
     }
 
-    fn cut(&mut self, region: MemoryRegion) -> Result<(), ()> {
-        let mut containing_region = None;
-        for r in self.regions.iter() {
-            if r.contains(&region) {
-                containing_region = Some(*r);
-                break;
-            }
-        }
-
-        if let Some(containing_region) = containing_region {
-            self.regions.remove(&containing_region);
-
-            let lower = MemoryRegion {
-                start: containing_region.start,
-                end: region.start,
-            };
-            let upper = MemoryRegion {
-                start: region.end,
-                end: containing_region.end,
-            };
-            if lower.size() > 0 {
-                self.add(lower);
-            }
-            if upper.size() > 0 {
-                self.add(upper);
-            }
-
-            return Ok(());
-        }
-        Err(())
-    }
-
-    fn find_with_page_size<S: PageSize>(&self) -> Option<&MemoryRegion> {
-        self.regions.iter().find(|r| r.size() >= S::SIZE)
-    }
-
     fn contains(&self, region: MemoryRegion) -> bool {
-        for r in self.regions.iter() {
-            if r.contains(&region) {
-                return true;
-            }
-        }
-        false
+        // regions don't overlap, so only the last one starting at or below `region` can contain it
+        self.regions
+            .range(
+                ..=MemoryRegion {
+                    start: region.start,
+                    end: u64::MAX,
+                },
+            )
+            .next_back()
+            .map_or(false, |r| r.contains(&region))
     }
 
     #[cfg(feature = "dbg-mem")]
@@ -191,19 +132,37 @@ impl MemoryRegions {
     }
 }
 
+/// Physical frame allocator used once the heap is available.
+///
+/// Free frames are managed by a [`BuddyAllocator`]. Frames of reserved memory (e.g. ACPI
+/// tables) are never handed out, but are tracked separately if they get deallocated.
 #[derive(Debug)]
 pub struct KernelFrameAllocator {
-    free_usable_regions: MemoryRegions,
-    free_reserved_regions: MemoryRegions,
+    free_usable: BuddyAllocator,
+    free_reserved: BuddyAllocator,
     usable_regions: MemoryRegions,
     reserved_regions: MemoryRegions,
+    /// Bytes per boot memory map kind: usable, bootloader, firmware
+    boot_map_bytes: [u64; 3],
+    boot_allocated_frames: u64,
+    physical_memory_end: u64,
 }
 
 impl KernelFrameAllocator {
     pub fn init(boot_frame_allocator: &BootInfoFrameAllocator) -> Self {
         let mut usable_regions = MemoryRegions::new();
         let mut reserved_regions = MemoryRegions::new();
+        let mut boot_map_bytes = [0; 3];
+        let mut physical_memory_end = 0;
         for region in boot_frame_allocator.memory_regions.iter() {
+            let kind_index = match region.kind {
+                BootMemoryRegionKind::Usable => 0,
+                BootMemoryRegionKind::Bootloader => 1,
+                _ => 2,
+            };
+            boot_map_bytes[kind_index] += region.end - region.start;
+            physical_memory_end = physical_memory_end.max(region.end);
+
             match region.kind {
                 BootMemoryRegionKind::Usable => {
                     usable_regions.add(MemoryRegion {
@@ -226,81 +185,127 @@ impl KernelFrameAllocator {
             reserved_regions.dump_state();
         }
 
-        let mut free_usable_regions = MemoryRegions::new();
-        let mut iter = boot_frame_allocator
-            .usable_frames()
-            .skip(boot_frame_allocator.used_frame_count())
-            .peekable();
-        while let Some(frame) = iter.next() {
-            let start = frame.start_address().as_u64();
-            let mut end = start + frame.size();
-            while let Some(next) = iter.peek() {
-                let next_start = next.start_address().as_u64();
-                let next_end = next_start + next.size();
-                if end == next_start {
-                    end = next_end;
-                    iter.next();
-                    continue;
-                }
-                break;
+        // the boot allocator hands out usable frames in memory map order, skip the ones it used
+        let mut free_usable = BuddyAllocator::new();
+        let mut used = boot_frame_allocator.used_frame_count() as u64;
+        for region in boot_frame_allocator
+            .memory_regions
+            .iter()
+            .filter(|r| r.kind == BootMemoryRegionKind::Usable)
+        {
+            let frames = (region.end - region.start) / Size4KiB::SIZE;
+            if used >= frames {
+                used -= frames;
+                continue;
             }
-            free_usable_regions.add(MemoryRegion { start, end });
+            free_usable.free_range(region.start + used * Size4KiB::SIZE..region.end);
+            used = 0;
         }
 
         #[cfg(feature = "dbg-mem")]
         {
-            log::trace!("free usable memory regions:");
-            free_usable_regions.dump_state();
+            log::trace!("free usable memory blocks:");
+            for (addr, order) in free_usable.blocks() {
+                log::trace!(
+                    "0x{:x} - 0x{:x}",
+                    addr,
+                    addr + super::buddy::block_size(order)
+                );
+            }
         }
 
         Self {
-            free_usable_regions,
-            free_reserved_regions: MemoryRegions::new(),
+            free_usable,
+            free_reserved: BuddyAllocator::new(),
             usable_regions,
             reserved_regions,
+            boot_map_bytes,
+            boot_allocated_frames: boot_frame_allocator.used_frame_count() as u64,
+            physical_memory_end,
         }
     }
-}
 
-unsafe impl<S: PageSize> FrameAllocator<S> for KernelFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<PhysFrame<S>> {
-        if let Some(region) = self.free_usable_regions.find_with_page_size::<S>() {
-            let start = PhysAddr::new(region.start).align_up(S::SIZE);
-            let frame: PhysFrame<S> = PhysFrame::from_start_address(start).ok()?;
-
-            let frame_end = frame.start_address().as_u64() + frame.size();
-            let allocated_region = MemoryRegion {
-                start: region.start,
-                end: frame_end,
-            };
-            self.free_usable_regions.cut(allocated_region).ok()?;
+    pub(super) fn stats(&self) -> FrameStats {
+        let [usable, bootloader, firmware] = self.boot_map_bytes;
+        FrameStats {
+            usable,
+            bootloader,
+            firmware,
+            boot_allocated: self.boot_allocated_frames * Size4KiB::SIZE,
+            free: self.free_usable.free_bytes(),
+            used: usable - self.free_usable.free_bytes(),
+            reclaimed: self.free_reserved.free_bytes(),
+            physical_memory_end: self.physical_memory_end,
+        }
+    }
 
-            #[cfg(feature = "dbg-mem")]
-            log::trace!("allocated frame: {:x?}", frame);
+    /// Allocates `count` physically contiguous frames.
+    pub fn allocate_contiguous(&mut self, count: u64) -> Option<PhysFrameRange> {
+        let range = self
+            .free_usable
+            .allocate_contiguous(count * Size4KiB::SIZE)?;
 
-            return Some(frame);
-        }
-        None
+        #[cfg(feature = "dbg-mem")]
+        log::trace!("allocated contiguous frames: {:x?}", range);
+
+        Some(PhysFrame::range(
+            PhysFrame::containing_address(PhysAddr::new(range.start)),
+            PhysFrame::containing_address(PhysAddr::new(range.end)),
+        ))
     }
-}
 
-impl<S: PageSize> FrameDeallocator<S> for KernelFrameAllocator {
-    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<S>) {
-        let start = frame.start_address().as_u64();
-        let end = start + frame.size();
-        let region = MemoryRegion { start, end };
+    /// Returns a run of frames, e.g. one allocated with [`Self::allocate_contiguous`].
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that the frames are unused.
+    pub unsafe fn deallocate_contiguous(&mut self, frames: PhysFrameRange) {
+        self.free_region(MemoryRegion {
+            start: frames.start.start_address().as_u64(),
+            end: frames.end.start_address().as_u64(),
+        });
+    }
+
+    fn free_region(&mut self, region: MemoryRegion) {
         if self.usable_regions.contains(region) {
-            self.free_usable_regions.add(region);
+            self.free_usable.free_range(region.start..region.end);
 
             #[cfg(feature = "dbg-mem")]
-            log::trace!("deallocated frame: {:x?}", frame);
+            log::trace!("deallocated frames: {:?}", region);
         } else if self.reserved_regions.contains(region) {
-            self.free_reserved_regions.add(region);
+            self.free_reserved.free_range(region.start..region.end);
 
             #[cfg(feature = "dbg-mem")]
-            log::trace!("deallocated reserved frame: {:x?}", frame);
+            log::trace!("deallocated reserved frames: {:?}", region);
         } else {
-            panic!("couldn't deallocate frame: {:x?}", frame);
+            panic!("couldn't deallocate frames: {:?}", region);
         }
     }
 }
+
+/// Buddy order of a frame of size `S`
+fn order_of<S: PageSize>() -> usize {
+    (S::SIZE / Size4KiB::SIZE).trailing_zeros() as usize
+}
+
+unsafe impl<S: PageSize> FrameAllocator<S> for KernelFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<S>> {
+        let addr = self.free_usable.allocate(order_of::<S>())?;
+        let frame = PhysFrame::from_start_address(PhysAddr::new(addr)).ok()?;
+
+        #[cfg(feature = "dbg-mem")]
+        log::trace!("allocated frame: {:x?}", frame);
+
+        Some(frame)
+    }
+}
+
+impl<S: PageSize> FrameDeallocator<S> for KernelFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<S>) {
+        let start = frame.start_address().as_u64();
+        self.free_region(MemoryRegion {
+            start,
+            end: start + frame.size(),
+        });
+    }
+}
@@ -0,0 +1,108 @@
+//! Kernel virtual memory layout
+//!
+//! The heap, kernel stacks and the vmalloc region each live in a fixed band of the lower half,
+//! above [`USER_END`](super::address_space::USER_END). Where exactly in its band a region
+//! starts is chosen randomly at boot with [`crate::random::entropy`], so their addresses can't
+//! be hardcoded by an exploit. Level 4 slots the bootloader already uses are avoided.
+//!
+//! The kernel image itself stays at `KERNEL_OFFSET` from `linker.ld`, it isn't position
+//! independent.
+
+use core::{
+    ops::Range,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use x86_64::{
+    structures::paging::{PageSize, PageTable, Size2MiB},
+    VirtAddr,
+};
+
+use crate::random;
+
+pub const HEAP_BAND: Range<u64> = 0x_4000_0000_0000..0x_4800_0000_0000;
+pub const STACKS_BAND: Range<u64> = 0x_4800_0000_0000..0x_5000_0000_0000;
+pub const VMALLOC_BAND: Range<u64> = 0x_5000_0000_0000..0x_6000_0000_0000;
+
+/// The heap can't grow beyond this, regardless of its limit
+pub const MAX_HEAP_SIZE: u64 = 64 << 30;
+/// Virtual space for all kernel stacks
+pub const STACKS_SIZE: u64 = 64 << 30;
+/// Size of the vmalloc region
+pub const VMALLOC_SIZE: u64 = 1 << 40;
+
+/// Regions start at a multiple of this, which keeps 2 MiB heap pages aligned
+const ALIGN: u64 = Size2MiB::SIZE;
+/// Size of the address range a level 4 entry maps
+const SLOT_SIZE: u64 = 1 << 39;
+const ATTEMPTS: usize = 64;
+
+static HEAP_START: AtomicU64 = AtomicU64::new(0);
+static STACKS_START: AtomicU64 = AtomicU64::new(0);
+static VMALLOC_START: AtomicU64 = AtomicU64::new(0);
+
+/// Chooses where the kernel regions start.
+///
+/// This has to run before the heap is initialized.
+pub(super) fn randomize(level_4_table: &PageTable) {
+    // the initial heap lives right below the extended heap, keep one step free for it
+    let heap = choose(
+        level_4_table,
+        HEAP_BAND.start + ALIGN..HEAP_BAND.end,
+        MAX_HEAP_SIZE,
+    );
+    let stacks = choose(level_4_table, STACKS_BAND, STACKS_SIZE);
+    let vmalloc = choose(level_4_table, VMALLOC_BAND, VMALLOC_SIZE);
+
+    HEAP_START.store(heap, Ordering::SeqCst);
+    STACKS_START.store(stacks, Ordering::SeqCst);
+    VMALLOC_START.store(vmalloc, Ordering::SeqCst);
+
+    #[cfg(feature = "dbg-mem")]
+    log::debug!(
+        "kernel layout: heap at {:#x}, stacks at {:#x}, vmalloc at {:#x}",
+        heap,
+        stacks,
+        vmalloc
+    );
+}
+
+/// A random, aligned start for a region of `size` bytes in `band`, whose level 4 slots aren't
+/// used yet. Falls back to the start of the band.
+fn choose(level_4_table: &PageTable, band: Range<u64>, size: u64) -> u64 {
+    let positions = (band.end - band.start - size) / ALIGN + 1;
+    let slots_free = |start: u64| {
+        let first = (start / SLOT_SIZE) as usize;
+        let last = ((start + size - 1) / SLOT_SIZE) as usize;
+        (first..=last).all(|i| level_4_table[i].is_unused())
+    };
+
+    for _ in 0..ATTEMPTS {
+        let start = band.start + random::entropy() % positions * ALIGN;
+        if slots_free(start) {
+            return start;
+        }
+    }
+    log::warn!("no free space for a randomized region in {:#x?}", band);
+    band.start
+}
+
+fn load(start: &AtomicU64) -> VirtAddr {
+    let start = start.load(Ordering::Relaxed);
+    assert_ne!(start, 0, "kernel layout isn't initialized");
+    VirtAddr::new(start)
+}
+
+/// Where the heap grows up from, the initial heap is right below
+pub fn heap_start() -> VirtAddr {
+    load(&HEAP_START)
+}
+
+/// Where kernel stacks are allocated from
+pub fn stacks_start() -> VirtAddr {
+    load(&STACKS_START)
+}
+
+/// Start of the vmalloc region
+pub fn vmalloc_start() -> VirtAddr {
+    load(&VMALLOC_START)
+}
@@ -0,0 +1,110 @@
+//! Reference counted mappings of physical memory the kernel doesn't own
+//!
+//! MMIO registers and firmware tables (e.g. ACPI) are mapped into the vmalloc region. Mapping
+//! a range that is already covered by a mapping with the same flags reuses it, and the
+//! virtual range is only released after the last user unmapped it. The physical frames are
+//! never handed to the frame allocator.
+
+use alloc::vec::Vec;
+use x86_64::{
+    align_up,
+    structures::paging::{PageSize, PageTableFlags, PhysFrame, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+use super::{
+    pat::CacheMode,
+    vmalloc::{self, VmallocError},
+};
+use crate::util::Spinlock;
+
+static MAPPINGS: Spinlock<Vec<Mapping>> = Spinlock::new(Vec::new());
+
+#[derive(Debug)]
+struct Mapping {
+    /// Page aligned physical start address
+    phys: PhysAddr,
+    /// Page aligned virtual start address
+    virt: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+    cache: CacheMode,
+    refs: usize,
+}
+
+impl Mapping {
+    fn covers(&self, phys: PhysAddr, size: u64, flags: PageTableFlags, cache: CacheMode) -> bool {
+        self.flags == flags
+            && self.cache == cache
+            && self.phys <= phys
+            && phys + size <= self.phys + self.size
+    }
+
+    fn contains(&self, virt: VirtAddr) -> bool {
+        self.virt <= virt && virt < self.virt + self.size
+    }
+}
+
+/// Maps `size` bytes of physical memory at `phys` and returns the address of `phys`.
+///
+/// Device registers must be mapped [`CacheMode::Uncacheable`]. Every call must be paired with
+/// an [`unmap`] of the returned address.
+pub fn map(
+    phys: PhysAddr,
+    size: usize,
+    flags: PageTableFlags,
+    cache: CacheMode,
+) -> Result<VirtAddr, VmallocError> {
+    let flags = flags | PageTableFlags::PRESENT;
+    let start = PhysFrame::<Size4KiB>::containing_address(phys).start_address();
+    let size = align_up(phys - start + size.max(1) as u64, Size4KiB::SIZE);
+
+    let mut mappings = MAPPINGS.lock_sync();
+    if let Some(mapping) = mappings
+        .iter_mut()
+        .find(|m| m.covers(start, size, flags, cache))
+    {
+        mapping.refs += 1;
+
+        #[cfg(feature = "dbg-mem")]
+        log::trace!("reusing mmio mapping {:x?} for {:?}", mapping, phys);
+
+        return Ok(mapping.virt + (phys - mapping.phys));
+    }
+
+    let virt = vmalloc::map_physical(start, size as usize, flags, cache)?;
+    mappings.push(Mapping {
+        phys: start,
+        virt,
+        size,
+        flags,
+        cache,
+        refs: 1,
+    });
+    Ok(virt + (phys - start))
+}
+
+/// Drops a reference to the mapping containing `virt`, unmapping it if it was the last one.
+pub fn unmap(virt: VirtAddr) -> Result<(), VmallocError> {
+    let mut mappings = MAPPINGS.lock_sync();
+    let index = mappings
+        .iter()
+        .position(|m| m.contains(virt))
+        .ok_or(VmallocError::NotAllocated(virt))?;
+
+    let mapping = &mut mappings[index];
+    if mapping.refs > 1 {
+        mapping.refs -= 1;
+        return Ok(());
+    }
+    // still tracked, with its reference, if it can't be freed
+    vmalloc::free(mapping.virt)?;
+    mappings.swap_remove(index);
+    Ok(())
+}
+
+/// Number of live mappings and the sum of their reference counts
+pub fn mapping_count() -> (usize, usize) {
+    let mappings = MAPPINGS.lock_sync();
+    (mappings.len(), mappings.iter().map(|m| m.refs).sum())
+}
@@ -11,22 +11,45 @@ use core::{
     ptr,
 };
 use x86_64::{
+    align_up,
     structures::paging::{
-        mapper::{MapToError, UnmapError},
+        frame::PhysFrameRange,
+        mapper::{FlagUpdateError, MapToError, TranslateResult, UnmapError},
         FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTable,
         PageTableFlags, PhysFrame, Size1GiB, Size2MiB, Size4KiB, Translate,
     },
     PhysAddr, VirtAddr,
 };
 
-use self::frame_allocator::{BootInfoFrameAllocator, KernelFrameAllocator};
-use crate::util::Spinlock;
+use self::{
+    frame_allocator::{BootInfoFrameAllocator, KernelFrameAllocator},
+    pat::CacheMode,
+    stats::Tag,
+};
+use crate::{cpu::Features, util::Spinlock};
 
+pub mod address_space;
 mod allocator;
+pub mod buddy;
+pub mod fault;
 mod frame_allocator;
+pub mod layout;
+pub mod mmio;
+pub mod pat;
+pub mod protection;
+pub mod slab;
+pub mod stack;
+pub mod stats;
+pub mod user_copy;
+pub mod vma;
+pub mod vmalloc;
 
+pub use address_space::{AddressSpace, AddressSpaceError};
 pub(crate) use allocator::force_unlock_allocator;
-pub use allocator::{dump_heap_state, AlignedAlloc};
+pub use allocator::{
+    dump_heap_state, set_heap_limit, shrink_heap, AlignedAlloc, DEFAULT_HEAP_LIMIT,
+};
+pub use stats::{stats, MemoryStats};
 
 static MEMORY_MANAGER: OnceCell<MemoryManager> = OnceCell::uninit();
 
@@ -60,13 +83,15 @@ impl MemoryManager<'_> {
         &self,
         frame: PhysFrame,
         flags: Option<PageTableFlags>,
+        cache: CacheMode,
     ) -> Result<(), MapToError<Size4KiB>> {
         #[cfg(feature = "dbg-mem")]
-        log::trace!("identity mapping frame: {:x?}", frame);
+        log::trace!("identity mapping frame: {:x?} ({:?})", frame, cache);
 
         let flags = flags.unwrap_or_else(|| {
             PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE
         });
+        let flags = supported_flags((flags - CacheMode::mask()) | cache.flags());
         unsafe {
             self.page_table
                 .lock_sync()
@@ -79,10 +104,12 @@ impl MemoryManager<'_> {
         &self,
         physical_address: u64,
         flags: Option<PageTableFlags>,
+        cache: CacheMode,
     ) -> Result<(), MapToError<Size4KiB>> {
         self.identity_map(
             PhysFrame::containing_address(PhysAddr::new(physical_address)),
             flags,
+            cache,
         )
     }
 
@@ -90,29 +117,142 @@ impl MemoryManager<'_> {
         &self,
         range: Range<u64>,
         flags: Option<PageTableFlags>,
+        cache: CacheMode,
     ) -> Result<(), MapToError<Size4KiB>> {
         for frame in PhysFrame::range_inclusive(
             PhysFrame::containing_address(PhysAddr::new(range.start)),
             PhysFrame::containing_address(PhysAddr::new(range.end - 1)),
         ) {
-            self.identity_map(frame, flags)?;
+            self.identity_map(frame, flags, cache)?;
+        }
+        Ok(())
+    }
+
+    /// Maps `page` to an existing `frame` with the given cache mode, e.g. for MMIO.
+    ///
+    /// The frame stays owned by the caller, unmap it with [`Self::unmap_frame`].
+    pub fn map_frame(
+        &self,
+        page: Page<Size4KiB>,
+        frame: PhysFrame<Size4KiB>,
+        flags: PageTableFlags,
+        cache: CacheMode,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        #[cfg(feature = "dbg-mem")]
+        log::trace!(
+            "mapping page {:x?} to frame {:x?} ({:?})",
+            page,
+            frame,
+            cache
+        );
+
+        let flags = (flags - CacheMode::mask()) | cache.flags();
+        unsafe {
+            self.page_table
+                .lock_sync()
+                .map_to(
+                    page,
+                    frame,
+                    supported_flags(flags),
+                    self.frame_allocator.lock_sync().deref_mut(),
+                )?
+                .flush();
+        }
+        Ok(())
+    }
+
+    /// Unmaps `page` without giving its frame back to the frame allocator.
+    pub fn unmap_frame(&self, page: Page<Size4KiB>) -> Result<PhysFrame<Size4KiB>, UnmapError> {
+        #[cfg(feature = "dbg-mem")]
+        log::trace!("unmapping page {:x?}, keeping its frame", page);
+
+        let (frame, flush) = self.page_table.lock_sync().unmap(page)?;
+        flush.flush();
+        address_space::mappings_changed();
+        Ok(frame)
+    }
+
+    /// Replaces the flags of an already mapped page.
+    pub fn update_flags(
+        &self,
+        page: Page<Size4KiB>,
+        flags: PageTableFlags,
+    ) -> Result<(), FlagUpdateError> {
+        #[cfg(feature = "dbg-mem")]
+        log::trace!("updating flags of page {:x?} to {:?}", page, flags);
+
+        unsafe {
+            self.page_table
+                .lock_sync()
+                .update_flags(page, supported_flags(flags))?
+                .flush();
         }
+        address_space::mappings_changed();
         Ok(())
     }
+
+    /// Changes the cache mode of an already mapped page, keeping its other flags.
+    pub fn set_cache_mode(
+        &self,
+        page: Page<Size4KiB>,
+        cache: CacheMode,
+    ) -> Result<(), FlagUpdateError> {
+        let flags = match self.page_table.lock_sync().translate(page.start_address()) {
+            TranslateResult::Mapped { flags, .. } => flags,
+            _ => return Err(FlagUpdateError::PageNotMapped),
+        };
+        self.update_flags(page, (flags - CacheMode::mask()) | cache.flags())
+    }
+
+    /// Allocates `count` physically contiguous frames, e.g. for DMA buffers.
+    ///
+    /// The frames are not mapped.
+    pub fn allocate_contiguous(&self, count: u64) -> Option<PhysFrameRange> {
+        let frames = self
+            .frame_allocator
+            .lock_sync()
+            .allocate_contiguous(count)?;
+        stats::account(Tag::Contiguous, count * Size4KiB::SIZE);
+        Some(frames)
+    }
+
+    /// Returns frames allocated with [`Self::allocate_contiguous`].
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that the frames are no longer used or mapped anywhere.
+    pub unsafe fn deallocate_contiguous(&self, frames: PhysFrameRange) {
+        self.frame_allocator
+            .lock_sync()
+            .deallocate_contiguous(frames);
+        stats::release(Tag::Contiguous, frames.count() as u64 * Size4KiB::SIZE);
+    }
+}
+
+/// Removes page table flags the CPU doesn't support.
+///
+/// Setting `NO_EXECUTE` without NX support is a reserved bit violation on every access.
+pub(crate) fn supported_flags(mut flags: PageTableFlags) -> PageTableFlags {
+    if !crate::cpu::has(Features::NX) {
+        flags.remove(PageTableFlags::NO_EXECUTE);
+    }
+    flags
 }
 
 macro_rules! gen_map_impl {
-    ($Size:ident, $map_name:ident, $unmap_name:ident) => {
+    ($Size:ident, $map_name:ident, $try_map_name:ident, $unmap_name:ident, $cache_flags:ident) => {
         impl<'a> MemoryManager<'a>
         where
             OffsetPageTable<'a>: Mapper<$Size>,
         {
+            /// Maps `page` to a newly allocated frame with the given cache mode.
             pub fn $map_name(
                 &self,
                 page: Page<$Size>,
+                cache: CacheMode,
             ) -> Result<PhysFrame<$Size>, MapToError<$Size>> {
                 #[cfg(feature = "dbg-mem")]
-                log::trace!("mapping page: {:x?}", page);
+                log::trace!("mapping page: {:x?} ({:?})", page, cache);
 
                 let frame: PhysFrame<$Size> = self
                     .frame_allocator
@@ -125,13 +265,60 @@ macro_rules! gen_map_impl {
                         .map_to(
                             page,
                             frame,
-                            PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+                            supported_flags(
+                                PageTableFlags::PRESENT
+                                    | PageTableFlags::WRITABLE
+                                    | PageTableFlags::NO_EXECUTE
+                                    | cache.$cache_flags(),
+                            ),
                             self.frame_allocator.lock_sync().deref_mut(),
                         )?
                         .flush();
                 }
                 Ok(frame)
             }
+            /// Like the blocking variant, but returns `None` instead of waiting if the page
+            /// table or the frame allocator is locked, e.g. because the caller already holds
+            /// them further up the stack.
+            pub fn $try_map_name(
+                &self,
+                page: Page<$Size>,
+                cache: CacheMode,
+            ) -> Option<Result<PhysFrame<$Size>, MapToError<$Size>>> {
+                let mut page_table = self.page_table.try_lock()?;
+                let mut frame_allocator = self.frame_allocator.try_lock()?;
+
+                #[cfg(feature = "dbg-mem")]
+                log::trace!("mapping page: {:x?} ({:?})", page, cache);
+
+                let frame: PhysFrame<$Size> = match frame_allocator.allocate_frame() {
+                    Some(frame) => frame,
+                    None => return Some(Err(MapToError::FrameAllocationFailed)),
+                };
+                let result = unsafe {
+                    page_table.map_to(
+                        page,
+                        frame,
+                        supported_flags(
+                            PageTableFlags::PRESENT
+                                | PageTableFlags::WRITABLE
+                                | PageTableFlags::NO_EXECUTE
+                                | cache.$cache_flags(),
+                        ),
+                        frame_allocator.deref_mut(),
+                    )
+                };
+                Some(match result {
+                    Ok(flush) => {
+                        flush.flush();
+                        Ok(frame)
+                    }
+                    Err(e) => {
+                        unsafe { frame_allocator.deallocate_frame(frame) };
+                        Err(e)
+                    }
+                })
+            }
             pub fn $unmap_name(&self, page: Page<$Size>) -> Result<(), UnmapError> {
                 #[cfg(feature = "dbg-mem")]
                 log::trace!("unmapping page: {:x?}", page);
@@ -140,6 +327,7 @@ macro_rules! gen_map_impl {
                     p.1.flush();
                     Ok(p.0)
                 })?;
+                address_space::mappings_changed();
                 unsafe {
                     self.frame_allocator.lock_sync().deallocate_frame(frame);
                 }
@@ -149,9 +337,9 @@ macro_rules! gen_map_impl {
     };
 }
 
-gen_map_impl!(Size4KiB, map, unmap);
-gen_map_impl!(Size2MiB, map_2m, unmap_2m);
-gen_map_impl!(Size1GiB, map_1g, unmap_1g);
+gen_map_impl!(Size4KiB, map, try_map, unmap, flags);
+gen_map_impl!(Size2MiB, map_2m, try_map_2m, unmap_2m, huge_flags);
+gen_map_impl!(Size1GiB, map_1g, try_map_1g, unmap_1g, huge_flags);
 
 impl AcpiHandler for MemoryManager<'_> {
     unsafe fn map_physical_region<T>(
@@ -160,38 +348,29 @@ impl AcpiHandler for MemoryManager<'_> {
         size: usize,
     ) -> acpi::PhysicalMapping<Self, T> {
         let start_address = PhysAddr::new(physical_address as u64);
-        let end_address = PhysAddr::new((physical_address + size) as u64);
-        let range = PhysFrame::range_inclusive(
-            PhysFrame::containing_address(start_address),
-            PhysFrame::containing_address(end_address),
-        );
+        let virtual_start = mmio::map(
+            start_address,
+            size,
+            PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+            CacheMode::WriteBack,
+        )
+        .expect("failed to map page for acpi table parsing");
 
-        // TODO: don't necessarily identity map the requested addresses, this could be a bit
-        // more smarter. It also panics if it cannot map the address
-        for frame in range {
-            self.identity_map(frame, None)
-                .or_else(|e| match e {
-                    MapToError::PageAlreadyMapped(_) => Ok(()), // if the page is already mapped, we
-                    // leave it alone for now
-                    _ => Err(()),
-                })
-                .expect("failed to map page for acpi table parsing");
-        }
+        let offset = start_address.as_u64() % Size4KiB::SIZE;
+        let mapped_length = align_up(offset + size as u64, Size4KiB::SIZE) - offset;
         acpi::PhysicalMapping::new(
-            start_address.as_u64() as usize,
-            ptr::NonNull::new_unchecked(start_address.as_u64() as *mut _),
-            size,
+            physical_address,
+            ptr::NonNull::new_unchecked(virtual_start.as_mut_ptr()),
             size,
+            mapped_length as usize,
             self.clone(),
         )
     }
 
     fn unmap_physical_region<T>(region: &acpi::PhysicalMapping<Self, T>) {
-        let mm = region.handler();
-        mm.unmap(Page::containing_address(VirtAddr::new(
-            region.virtual_start().as_ptr() as u64,
-        )))
-        .expect("should be able to unmap");
+        // the frames belong to the firmware, only the mapping is released
+        mmio::unmap(VirtAddr::from_ptr(region.virtual_start().as_ptr()))
+            .expect("should be able to unmap");
     }
 }
 
@@ -205,6 +384,7 @@ impl AcpiHandler for MemoryManager<'_> {
 /// to avoid aliasing `&mut` references (which is undefined behavior).
 pub unsafe fn init(physical_memory_offset: VirtAddr, memory_regions: &'static MemoryRegions) {
     let level_4_table = active_level_4_table(physical_memory_offset);
+    layout::randomize(level_4_table);
     let mut page_table = OffsetPageTable::new(level_4_table, physical_memory_offset);
 
     let mut initial_frame_allocator = BootInfoFrameAllocator::init(memory_regions);
@@ -226,6 +406,16 @@ pub unsafe fn init(physical_memory_offset: VirtAddr, memory_regions: &'static Me
     allocator::extend(4 * Size2MiB::SIZE as usize)
         .unwrap_or_else(|e| panic!("failed to extend heap: {:#?}", e));
 
+    // kernel regions growing later must be shared with every address space
+    let mm = get_memory_manager();
+    for range in [
+        allocator::reserved_region(),
+        stack::reserved_region(),
+        vmalloc::region(),
+    ] {
+        address_space::reserve_kernel_slots(&mm, range);
+    }
+
     crate::kbuf::use_heap();
     crate::time::init();
 }
@@ -0,0 +1,109 @@
+//! Page attribute table and cache modes
+//!
+//! [`init`] programs the `IA32_PAT` MSR. The first four entries keep their power-on values, so
+//! mappings created by the bootloader keep their meaning, the upper ones add write-combining:
+//!
+//! | index | PAT | PCD | PWT | mode |
+//! |-------|-----|-----|-----|------|
+//! | 0     | 0   | 0   | 0   | WB   |
+//! | 1     | 0   | 0   | 1   | WT   |
+//! | 2     | 0   | 1   | 0   | UC-  |
+//! | 3     | 0   | 1   | 1   | UC   |
+//! | 4     | 1   | 0   | 0   | WB   |
+//! | 5     | 1   | 0   | 1   | WT   |
+//! | 6     | 1   | 1   | 0   | WC   |
+//! | 7     | 1   | 1   | 1   | UC   |
+
+use x86_64::{
+    instructions::tlb, registers::model_specific::Msr, structures::paging::PageTableFlags,
+};
+
+use crate::cpu::{self, Features};
+
+const IA32_PAT: u32 = 0x277;
+
+const UC: u64 = 0x00;
+const WC: u64 = 0x01;
+const WT: u64 = 0x04;
+const WB: u64 = 0x06;
+const UC_MINUS: u64 = 0x07;
+
+const PAT_VALUE: u64 =
+    WB | WT << 8 | UC_MINUS << 16 | UC << 24 | WB << 32 | WT << 40 | WC << 48 | UC << 56;
+
+/// The PAT bit of a 4 KiB page table entry, which is the huge page bit in the other levels
+const PAT_4KIB: PageTableFlags = PageTableFlags::HUGE_PAGE;
+/// The PAT bit of a 2 MiB or 1 GiB page table entry, the lowest address bit of a 4 KiB one
+const PAT_HUGE: u64 = 1 << 12;
+
+/// Memory type of a mapping
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CacheMode {
+    /// Normal cached memory
+    #[default]
+    WriteBack,
+    /// Writes are buffered and combined, reads are uncached. Meant for framebuffers.
+    WriteCombining,
+    /// Every access goes to the bus, in order. Required for MMIO registers.
+    Uncacheable,
+    /// Reads are cached, writes go to memory immediately
+    WriteThrough,
+}
+
+impl CacheMode {
+    /// Page table flags selecting this mode in a 4 KiB page table entry.
+    ///
+    /// Without PAT support write-combining falls back to uncacheable.
+    pub fn flags(self) -> PageTableFlags {
+        match self {
+            CacheMode::WriteBack => PageTableFlags::empty(),
+            CacheMode::WriteThrough => PageTableFlags::WRITE_THROUGH,
+            CacheMode::Uncacheable => PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH,
+            CacheMode::WriteCombining if cpu::has(Features::PAT) => {
+                PAT_4KIB | PageTableFlags::NO_CACHE
+            }
+            CacheMode::WriteCombining => PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH,
+        }
+    }
+
+    /// Page table flags selecting this mode in a 2 MiB or 1 GiB page table entry.
+    pub fn huge_flags(self) -> PageTableFlags {
+        let flags = self.flags();
+        if !flags.contains(PAT_4KIB) {
+            return flags;
+        }
+        // bit 12 has no flag, it's only kept when an entry is written, not when it's read
+        (flags - PAT_4KIB) | unsafe { PageTableFlags::from_bits_unchecked(PAT_HUGE) }
+    }
+
+    /// Flags of all cache mode bits, to clear them before applying a mode
+    pub fn mask() -> PageTableFlags {
+        PAT_4KIB | PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH
+    }
+}
+
+/// Programs the page attribute table of the current CPU.
+///
+/// This should be called once on every CPU, all of them must use the same table.
+pub fn init() {
+    if !cpu::has(Features::PAT) {
+        log::warn!("PAT not supported, write-combining mappings will be uncached");
+        return;
+    }
+
+    unsafe {
+        // cached lines of memory whose type changes must not survive the switch
+        core::arch::asm!("wbinvd", options(nostack, preserves_flags));
+        Msr::new(IA32_PAT).write(PAT_VALUE);
+        core::arch::asm!("wbinvd", options(nostack, preserves_flags));
+    }
+    tlb::flush_all();
+
+    #[cfg(feature = "dbg-mem")]
+    log::trace!("PAT set to {:#018x}", PAT_VALUE);
+}
+
+/// Flushes the caches after the cache mode of existing mappings was changed.
+pub fn flush_caches() {
+    unsafe { core::arch::asm!("wbinvd", options(nostack, preserves_flags)) };
+}
@@ -0,0 +1,173 @@
+//! Hardware memory protection
+//!
+//! [`enable`] turns on the paging related protection features of the CPU it runs on:
+//!
+//! - `EFER.NXE` so `NO_EXECUTE` mappings are honored
+//! - `CR0.WP` so the kernel can't write to read-only pages either
+//! - `CR4.SMEP` / `CR4.SMAP` to stop the kernel from executing or touching user pages
+//! - `CR4.UMIP` to hide descriptor table addresses from user mode
+//!
+//! Features the CPU doesn't report are skipped. [`protect_kernel_image`] then maps the
+//! sections of the kernel image W^X, using the boundaries exported by `linker.ld`.
+
+use core::{
+    arch::asm,
+    ops::Range,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use x86_64::{
+    registers::{
+        control::{Cr0, Cr0Flags, Cr4, Cr4Flags},
+        model_specific::{Efer, EferFlags},
+    },
+    structures::paging::{mapper::FlagUpdateError, Page, PageTableFlags},
+    VirtAddr,
+};
+
+use crate::cpu::{self, Features};
+
+extern "C" {
+    static __text_start: u8;
+    static __text_end: u8;
+    static __rodata_start: u8;
+    static __rodata_end: u8;
+    static __data_start: u8;
+    static __data_end: u8;
+}
+
+static SMAP_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Enables the supported protection features on the current CPU.
+///
+/// This should be called once on every CPU.
+pub fn enable() {
+    unsafe {
+        if cpu::has(Features::NX) {
+            Efer::update(|f| f.insert(EferFlags::NO_EXECUTE_ENABLE));
+        }
+        Cr0::update(|f| f.insert(Cr0Flags::WRITE_PROTECT));
+
+        let mut cr4 = Cr4Flags::empty();
+        if cpu::has(Features::SMEP) {
+            cr4 |= Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION;
+        }
+        if cpu::has(Features::SMAP) {
+            cr4 |= Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION;
+            SMAP_ENABLED.store(true, Ordering::SeqCst);
+        }
+        if cpu::has(Features::UMIP) {
+            cr4 |= Cr4Flags::USER_MODE_INSTRUCTION_PREVENTION;
+        }
+        Cr4::update(|f| f.insert(cr4));
+    }
+
+    #[cfg(feature = "dbg-mem")]
+    log::debug!(
+        "enabled memory protection, efer: {:?}, cr0: {:?}, cr4: {:?}",
+        Efer::read(),
+        Cr0::read(),
+        Cr4::read()
+    );
+}
+
+/// Runs `f` with SMAP temporarily lifted, so it can access user accessible pages.
+///
+/// Keep `f` as small as possible, everything it does can touch user memory.
+pub fn with_user_access<R>(f: impl FnOnce() -> R) -> R {
+    let _access = UserAccess::new();
+    f()
+}
+
+/// Puts SMAP back in place for an interrupt or exception from user mode.
+///
+/// Their entry keeps `RFLAGS.AC`, which user mode is free to set, and that would lift SMAP for
+/// the whole handler. `iretq` restores the flags of the program.
+pub fn clear_user_access() {
+    if SMAP_ENABLED.load(Ordering::Relaxed) {
+        unsafe { asm!("clac", options(nostack)) };
+    }
+}
+
+/// Lifts SMAP until it's dropped, so it's back in place however `f` of [`with_user_access`]
+/// ends.
+struct UserAccess {
+    smap: bool,
+}
+
+impl UserAccess {
+    fn new() -> Self {
+        let smap = SMAP_ENABLED.load(Ordering::Relaxed);
+        if smap {
+            // not `nomem`: memory accesses must not be moved across it by the compiler
+            unsafe { asm!("stac", options(nostack)) };
+        }
+        Self { smap }
+    }
+}
+
+impl Drop for UserAccess {
+    fn drop(&mut self) {
+        if self.smap {
+            unsafe { asm!("clac", options(nostack)) };
+        }
+    }
+}
+
+fn section(start: &u8, end: &u8) -> Range<VirtAddr> {
+    VirtAddr::from_ptr(start)..VirtAddr::from_ptr(end)
+}
+
+/// Remaps the kernel image so no page is both writable and executable.
+///
+/// `.text` becomes read-only and executable, `.rodata` read-only and `.data`, `.bss` and
+/// `.got` writable, both without execute permission.
+pub fn protect_kernel_image() {
+    let [text, rodata, data] = kernel_image_sections();
+
+    #[cfg(feature = "dbg-mem")]
+    log::debug!(
+        "kernel image: text {:x?}, rodata {:x?}, data {:x?}",
+        text,
+        rodata,
+        data
+    );
+
+    let result = update_range(text, PageTableFlags::PRESENT)
+        .and_then(|_| update_range(rodata, PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE))
+        .and_then(|_| {
+            update_range(
+                data,
+                PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+            )
+        });
+    if let Err(e) = result {
+        panic!("failed to protect kernel image: {:?}", e);
+    }
+}
+
+/// Address ranges of the `.text`, `.rodata` and `.data` parts of the kernel image
+pub(super) fn kernel_image_sections() -> [Range<VirtAddr>; 3] {
+    unsafe {
+        [
+            section(&__text_start, &__text_end),
+            section(&__rodata_start, &__rodata_end),
+            section(&__data_start, &__data_end),
+        ]
+    }
+}
+
+fn update_range(range: Range<VirtAddr>, flags: PageTableFlags) -> Result<(), FlagUpdateError> {
+    if range.is_empty() {
+        return Ok(());
+    }
+
+    let mm = super::get_memory_manager();
+    let pages = Page::range_inclusive(
+        Page::containing_address(range.start),
+        Page::containing_address(range.end - 1u64),
+    );
+    for page in pages {
+        mm.update_flags(page, flags)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,278 @@
+//! Slab allocator with per-CPU magazines
+//!
+//! Small allocations are served from power of two size classes between 16 and 2048 bytes.
+//! Every CPU keeps a small stack ("magazine") of free objects per size class, so most
+//! allocations and deallocations only touch CPU-local state. Empty magazines are refilled
+//! from, and full ones flushed to, a depot shared by all CPUs. When the depot runs dry, a new
+//! slab is carved out of the backing allocator.
+//!
+//! An allocation aligned to more than its size is served from the size class of its
+//! alignment, as objects are aligned to their size class. Everything larger than 2048 bytes,
+//! in size or alignment, goes straight to the backing allocator. Slabs are never given back
+//! to it.
+
+use alloc::vec::Vec;
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ptr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::{smp::MAX_CPUS, util::Spinlock};
+
+const MIN_CLASS_SHIFT: u32 = 4;
+const CLASSES: usize = 8;
+/// Largest object size served by the slab allocator
+pub const MAX_OBJECT_SIZE: usize = 1 << (MIN_CLASS_SHIFT as usize + CLASSES - 1);
+/// Size of the chunks requested from the backing allocator
+const SLAB_SIZE: usize = 16 * 1024;
+/// Alignment of slabs, all objects in a slab are aligned to their size class
+const SLAB_ALIGN: usize = 4096;
+const MAGAZINE_SIZE: usize = 32;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_MAGAZINE: Spinlock<Magazine> = Spinlock::new(Magazine::new());
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_MAGAZINES: [Spinlock<Magazine>; CLASSES] = [EMPTY_MAGAZINE; CLASSES];
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_DEPOT: Spinlock<Depot> = Spinlock::new(Depot::new());
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicUsize = AtomicUsize::new(0);
+
+/// Usage of one size class
+#[derive(Debug, Clone, Copy)]
+pub struct SlabStats {
+    pub object_size: usize,
+    /// Objects currently allocated
+    pub in_use: usize,
+    /// Bytes taken from the backing allocator for this class
+    pub slab_bytes: usize,
+}
+
+struct Magazine {
+    objects: [*mut u8; MAGAZINE_SIZE],
+    len: usize,
+}
+
+impl Magazine {
+    const fn new() -> Self {
+        Self {
+            objects: [ptr::null_mut(); MAGAZINE_SIZE],
+            len: 0,
+        }
+    }
+
+    fn pop(&mut self) -> Option<*mut u8> {
+        if self.len == 0 {
+            return None;
+        }
+        self.len -= 1;
+        Some(self.objects[self.len])
+    }
+
+    fn push(&mut self, object: *mut u8) -> Result<(), *mut u8> {
+        if self.len == MAGAZINE_SIZE {
+            return Err(object);
+        }
+        self.objects[self.len] = object;
+        self.len += 1;
+        Ok(())
+    }
+}
+
+/// Free objects of a size class shared by all CPUs, as an intrusive list
+struct Depot {
+    head: *mut FreeObject,
+}
+
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+impl Depot {
+    const fn new() -> Self {
+        Self {
+            head: ptr::null_mut(),
+        }
+    }
+
+    fn pop(&mut self) -> Option<*mut u8> {
+        if self.head.is_null() {
+            return None;
+        }
+        let object = self.head;
+        self.head = unsafe { (*object).next };
+        Some(object.cast())
+    }
+
+    /// # Safety
+    ///
+    /// `object` must be a free object of this depot's size class.
+    unsafe fn push(&mut self, object: *mut u8) {
+        let object = object.cast::<FreeObject>();
+        (*object).next = self.head;
+        self.head = object;
+    }
+}
+
+pub struct SlabAllocator<A: 'static> {
+    backing: &'static A,
+    depots: [Spinlock<Depot>; CLASSES],
+    magazines: [[Spinlock<Magazine>; CLASSES]; MAX_CPUS],
+    in_use: [AtomicUsize; CLASSES],
+    slab_bytes: [AtomicUsize; CLASSES],
+}
+
+impl<A: GlobalAlloc> SlabAllocator<A> {
+    pub const fn new(backing: &'static A) -> Self {
+        Self {
+            backing,
+            depots: [EMPTY_DEPOT; CLASSES],
+            magazines: [EMPTY_MAGAZINES; MAX_CPUS],
+            in_use: [ZERO; CLASSES],
+            slab_bytes: [ZERO; CLASSES],
+        }
+    }
+
+    pub fn stats(&self) -> Vec<SlabStats> {
+        (0..CLASSES)
+            .map(|class| SlabStats {
+                object_size: class_size(class),
+                in_use: self.in_use[class].load(Ordering::Relaxed),
+                slab_bytes: self.slab_bytes[class].load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    fn magazine(&self, class: usize) -> &Spinlock<Magazine> {
+        &self.magazines[crate::cpu::current_id() as usize][class]
+    }
+
+    /// Slow path of [`Self::alloc`]: refills the magazine from the depot, which is refilled
+    /// with a new slab first if necessary.
+    fn refill(&self, class: usize) -> *mut u8 {
+        loop {
+            let mut batch = [ptr::null_mut(); MAGAZINE_SIZE / 2];
+            let mut count = 0;
+            {
+                let mut depot = self.depots[class].lock_sync();
+                while count < batch.len() {
+                    match depot.pop() {
+                        Some(object) => {
+                            batch[count] = object;
+                            count += 1;
+                        }
+                        None => break,
+                    }
+                }
+            }
+
+            if count > 0 {
+                let mut magazine = self.magazine(class).lock_sync();
+                for &object in batch[1..count].iter() {
+                    if magazine.push(object).is_err() {
+                        unsafe { self.depots[class].lock_sync().push(object) };
+                    }
+                }
+                return batch[0];
+            }
+
+            // no locks are held here, the backing allocator may allocate through us
+            if !self.add_slab(class) {
+                return ptr::null_mut();
+            }
+        }
+    }
+
+    fn add_slab(&self, class: usize) -> bool {
+        let layout = Layout::from_size_align(SLAB_SIZE, SLAB_ALIGN).expect("valid layout");
+        let slab = unsafe { self.backing.alloc(layout) };
+        if slab.is_null() {
+            return false;
+        }
+
+        let mut depot = self.depots[class].lock_sync();
+        for offset in (0..SLAB_SIZE).step_by(class_size(class)).rev() {
+            unsafe { depot.push(slab.add(offset)) };
+        }
+        self.slab_bytes[class].fetch_add(SLAB_SIZE, Ordering::Relaxed);
+        true
+    }
+}
+
+/// Index of the size class serving `layout`, if any
+#[inline]
+fn class_of(layout: &Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align());
+    if size > MAX_OBJECT_SIZE {
+        return None;
+    }
+    let shift = size
+        .next_power_of_two()
+        .trailing_zeros()
+        .max(MIN_CLASS_SHIFT);
+    Some((shift - MIN_CLASS_SHIFT) as usize)
+}
+
+#[inline]
+const fn class_size(class: usize) -> usize {
+    1 << (class + MIN_CLASS_SHIFT as usize)
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for SlabAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let class = match class_of(&layout) {
+            Some(class) => class,
+            None => return self.backing.alloc(layout),
+        };
+
+        // an interrupt handler allocating on this CPU would deadlock on the magazine
+        let object = without_interrupts(|| {
+            let cached = self.magazine(class).lock_sync().pop();
+            cached.unwrap_or_else(|| self.refill(class))
+        });
+        if !object.is_null() {
+            self.in_use[class].fetch_add(1, Ordering::Relaxed);
+        }
+        object
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let class = match class_of(&layout) {
+            Some(class) => class,
+            None => return self.backing.dealloc(ptr, layout),
+        };
+
+        without_interrupts(|| {
+            let mut magazine = self.magazine(class).lock_sync();
+            if let Err(object) = magazine.push(ptr) {
+                // flush the older half of the full magazine, keeping the recently freed objects
+                let mut depot = self.depots[class].lock_sync();
+                depot.push(object);
+                for &flushed in magazine.objects[..MAGAZINE_SIZE / 2].iter() {
+                    depot.push(flushed);
+                }
+                magazine.objects.copy_within(MAGAZINE_SIZE / 2.., 0);
+                magazine.len -= MAGAZINE_SIZE / 2;
+            }
+        });
+        self.in_use[class].fetch_sub(1, Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        match (class_of(&layout), class_of(&new_layout)) {
+            (None, None) => self.backing.realloc(ptr, layout, new_size),
+            (Some(old), Some(new)) if old == new => ptr,
+            _ => {
+                let new_ptr = self.alloc(new_layout);
+                if !new_ptr.is_null() {
+                    ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+                    self.dealloc(ptr, layout);
+                }
+                new_ptr
+            }
+        }
+    }
+}
@@ -0,0 +1,129 @@
+//! Kernel stack allocation
+//!
+//! Kernel stacks live in a dedicated virtual region starting at
+//! [`layout::stacks_start`](super::layout::stacks_start). Every stack is preceded by an
+//! unmapped guard page, so running off the end of a stack faults instead of silently
+//! corrupting whatever is mapped below it. A random gap between stacks keeps their addresses
+//! from being predictable from each other.
+
+use alloc::vec::Vec;
+use core::{
+    ops::Range,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use x86_64::{
+    structures::paging::{mapper::MapToError, Page, PageSize, Size4KiB},
+    VirtAddr,
+};
+
+use super::{
+    layout::{self, STACKS_SIZE},
+    pat::CacheMode,
+    stats::{self, Tag},
+};
+use crate::{random, util::Spinlock};
+
+/// Up to this many unmapped pages are left between two stacks
+const MAX_GAP_PAGES: u64 = 16;
+
+/// Offset of the next stack from the start of the stack region
+static NEXT_STACK: AtomicU64 = AtomicU64::new(0);
+static STACKS: Spinlock<Vec<KernelStack>> = Spinlock::new(Vec::new());
+
+/// What a kernel stack is used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackKind {
+    /// The main stack a CPU runs the kernel on
+    Kernel,
+    /// Interrupt stack table entry for double faults
+    DoubleFault,
+    /// Stack the CPU switches to when entering the kernel from user mode, see
+    /// [`crate::gdt::set_kernel_stack`]
+    Privilege,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct KernelStack {
+    /// Logical ID of the CPU owning this stack, see [`crate::smp::Cpu::id`]
+    pub cpu: u32,
+    pub kind: StackKind,
+    guard: Page<Size4KiB>,
+    bottom: VirtAddr,
+    top: VirtAddr,
+}
+
+impl KernelStack {
+    /// Lowest usable address of the stack
+    pub fn bottom(&self) -> VirtAddr {
+        self.bottom
+    }
+
+    /// Initial stack pointer, the stack grows down from here
+    pub fn top(&self) -> VirtAddr {
+        self.top
+    }
+
+    pub fn guard_page(&self) -> Page<Size4KiB> {
+        self.guard
+    }
+}
+
+/// Allocate and map a new kernel stack with `pages` usable pages above an unmapped guard page.
+pub fn allocate(
+    cpu: u32,
+    kind: StackKind,
+    pages: u64,
+) -> Result<KernelStack, MapToError<Size4KiB>> {
+    let size = (pages + 1) * Size4KiB::SIZE;
+    let gap = random::below(MAX_GAP_PAGES + 1) * Size4KiB::SIZE;
+    let offset = NEXT_STACK.fetch_add(gap + size, Ordering::SeqCst) + gap;
+    if offset + size > STACKS_SIZE {
+        return Err(MapToError::FrameAllocationFailed);
+    }
+    let start = layout::stacks_start() + offset;
+
+    let guard = Page::containing_address(start);
+    let bottom = start + Size4KiB::SIZE;
+    let top = start + size;
+
+    let mm = super::get_memory_manager();
+    for page in Page::range(guard + 1, Page::containing_address(top)) {
+        mm.map(page, CacheMode::WriteBack)?;
+    }
+    stats::account(Tag::KernelStacks, pages * Size4KiB::SIZE);
+
+    let stack = KernelStack {
+        cpu,
+        kind,
+        guard,
+        bottom,
+        top,
+    };
+
+    #[cfg(feature = "dbg-mem")]
+    log::trace!("allocated kernel stack: {:x?}", stack);
+
+    STACKS.lock_sync().push(stack);
+    Ok(stack)
+}
+
+/// Returns the stack whose guard page contains `addr`, if any.
+///
+/// This is meant to be called from fault handlers, so it gives up instead of spinning if the
+/// stack list is locked.
+pub fn find_by_guard_page(addr: VirtAddr) -> Option<KernelStack> {
+    let page = Page::<Size4KiB>::containing_address(addr);
+    STACKS.try_lock()?.iter().find(|s| s.guard == page).copied()
+}
+
+/// Virtual address range set aside for stacks
+pub(super) fn reserved_region() -> Range<VirtAddr> {
+    let start = layout::stacks_start();
+    start..start + STACKS_SIZE
+}
+
+/// Virtual address range stacks have been allocated from so far
+pub(super) fn region() -> Range<VirtAddr> {
+    let start = layout::stacks_start();
+    start..start + NEXT_STACK.load(Ordering::Relaxed)
+}
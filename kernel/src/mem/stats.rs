@@ -0,0 +1,249 @@
+//! Memory usage statistics
+//!
+//! [`stats`] takes a snapshot of physical frame usage, the heap, page table overhead and the
+//! kernel's virtual memory layout. Its [`Display`](core::fmt::Display) implementation prints
+//! a `/proc/meminfo` style summary.
+//!
+//! Subsystems that allocate memory directly (not through the heap) report it with
+//! [`account`] and [`release`] under a [`Tag`].
+
+use alloc::vec::Vec;
+use core::{
+    fmt,
+    ops::Range,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use x86_64::{
+    structures::paging::{PageSize, PageTable, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+use super::{slab::SlabStats, MemoryManager};
+
+/// Subsystems memory is accounted to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tag {
+    /// Pages backing the kernel heap
+    Heap,
+    /// Kernel stacks, including interrupt stacks
+    KernelStacks,
+    /// Physically contiguous frames, see [`MemoryManager::allocate_contiguous`]
+    Contiguous,
+    /// Frames backing [`vmalloc`](super::vmalloc::vmalloc) allocations
+    Vmalloc,
+    /// Frames mapped into user address spaces, see [`AddressSpace`](super::AddressSpace)
+    User,
+}
+
+impl Tag {
+    pub const ALL: [Tag; 5] = [
+        Tag::Heap,
+        Tag::KernelStacks,
+        Tag::Contiguous,
+        Tag::Vmalloc,
+        Tag::User,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Tag::Heap => "Heap",
+            Tag::KernelStacks => "KernelStacks",
+            Tag::Contiguous => "Contiguous",
+            Tag::Vmalloc => "Vmalloc",
+            Tag::User => "User",
+        }
+    }
+}
+
+static TAGGED: [AtomicU64; Tag::ALL.len()] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+
+/// Records `bytes` of memory as allocated for `tag`.
+pub fn account(tag: Tag, bytes: u64) {
+    TAGGED[tag as usize].fetch_add(bytes, Ordering::Relaxed);
+}
+
+/// Records `bytes` of memory previously accounted to `tag` as freed.
+pub fn release(tag: Tag, bytes: u64) {
+    TAGGED[tag as usize].fetch_sub(bytes, Ordering::Relaxed);
+}
+
+/// Physical memory, in bytes
+#[derive(Debug, Clone, Copy)]
+pub struct FrameStats {
+    /// Memory the boot memory map reported as usable
+    pub usable: u64,
+    /// Memory the bootloader used for the kernel image, page tables, boot info etc.
+    pub bootloader: u64,
+    /// Memory reserved by the firmware
+    pub firmware: u64,
+    /// Usable memory consumed by the boot frame allocator before the kernel one took over
+    pub boot_allocated: u64,
+    /// Free usable memory
+    pub free: u64,
+    /// Usable memory that is allocated
+    pub used: u64,
+    /// Reserved memory that has been given back, e.g. after unmapping ACPI tables
+    pub reclaimed: u64,
+    /// End of the highest memory map region
+    pub physical_memory_end: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    pub start: VirtAddr,
+    /// Size of the heap in bytes
+    pub size: u64,
+    /// Bytes currently allocated
+    pub used: u64,
+}
+
+/// A named range of the kernel's virtual address space
+#[derive(Debug, Clone)]
+pub struct VirtualRange {
+    pub name: &'static str,
+    pub range: Range<VirtAddr>,
+}
+
+#[derive(Debug, Clone)]
+pub struct MemoryStats {
+    pub frames: FrameStats,
+    pub heap: HeapStats,
+    /// Bytes used by the active page table hierarchy
+    pub page_tables: u64,
+    pub ranges: Vec<VirtualRange>,
+    pub tags: Vec<(Tag, u64)>,
+    /// Size classes of the slab allocator, these are part of the used heap
+    pub slabs: Vec<SlabStats>,
+}
+
+/// Takes a snapshot of the current memory usage.
+pub fn stats() -> MemoryStats {
+    let mm = super::get_memory_manager();
+
+    let frames = mm.frame_allocator.lock_sync().stats();
+    let heap = super::allocator::heap_stats();
+    let page_tables = page_table_frames(&mm) * Size4KiB::SIZE;
+
+    let [text, rodata, data] = super::protection::kernel_image_sections();
+    let phys_offset = mm.page_table.lock_sync().phys_offset();
+    let ranges = alloc::vec![
+        VirtualRange {
+            name: "kernel text",
+            range: text,
+        },
+        VirtualRange {
+            name: "kernel rodata",
+            range: rodata,
+        },
+        VirtualRange {
+            name: "kernel data",
+            range: data,
+        },
+        VirtualRange {
+            name: "physical memory",
+            range: phys_offset..phys_offset + frames.physical_memory_end,
+        },
+        VirtualRange {
+            name: "heap",
+            range: heap.start..heap.start + heap.size,
+        },
+        VirtualRange {
+            name: "kernel stacks",
+            range: super::stack::region(),
+        },
+        VirtualRange {
+            name: "vmalloc",
+            range: super::vmalloc::region(),
+        },
+    ];
+
+    let tags = Tag::ALL
+        .iter()
+        .map(|&tag| (tag, TAGGED[tag as usize].load(Ordering::Relaxed)))
+        .collect();
+
+    MemoryStats {
+        frames,
+        heap,
+        page_tables,
+        ranges,
+        tags,
+        slabs: super::allocator::slab_stats(),
+    }
+}
+
+/// Counts the page tables reachable from the active level 4 table, including itself.
+fn page_table_frames(mm: &MemoryManager) -> u64 {
+    let mut page_table = mm.page_table.lock_sync();
+    let offset = page_table.phys_offset();
+
+    fn count(table: &PageTable, level: u8, offset: VirtAddr) -> u64 {
+        let mut tables = 1;
+        if level == 1 {
+            return tables;
+        }
+        for entry in table.iter() {
+            let flags = entry.flags();
+            if !flags.contains(PageTableFlags::PRESENT) || flags.contains(PageTableFlags::HUGE_PAGE)
+            {
+                continue;
+            }
+            let next: *const PageTable = (offset + entry.addr().as_u64()).as_ptr();
+            tables += count(unsafe { &*next }, level - 1, offset);
+        }
+        tables
+    }
+
+    count(page_table.level_4_table(), 4, offset)
+}
+
+impl fmt::Display for MemoryStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn line(f: &mut fmt::Formatter<'_>, name: &str, bytes: u64) -> fmt::Result {
+            writeln!(f, "{}:{:>w$} KiB", name, bytes / 1024, w = 24 - name.len())
+        }
+
+        line(f, "MemUsable", self.frames.usable)?;
+        line(f, "MemFree", self.frames.free)?;
+        line(f, "MemUsed", self.frames.used)?;
+        line(f, "BootAllocated", self.frames.boot_allocated)?;
+        line(f, "Bootloader", self.frames.bootloader)?;
+        line(f, "Firmware", self.frames.firmware)?;
+        line(f, "Reclaimed", self.frames.reclaimed)?;
+        line(f, "PageTables", self.page_tables)?;
+        line(f, "HeapSize", self.heap.size)?;
+        line(f, "HeapUsed", self.heap.used)?;
+        for (tag, bytes) in self.tags.iter() {
+            line(f, tag.name(), *bytes)?;
+        }
+        for slab in self.slabs.iter() {
+            writeln!(
+                f,
+                "Slab{}:{:w$}{:>10} objects, {:>6} KiB",
+                slab.object_size,
+                "",
+                slab.in_use,
+                slab.slab_bytes / 1024,
+                w = 12 - slab.object_size.ilog10() as usize
+            )?;
+        }
+        for r in self.ranges.iter() {
+            writeln!(
+                f,
+                "{}:{:w$}{:#018x} - {:#018x}",
+                r.name,
+                "",
+                r.range.start.as_u64(),
+                r.range.end.as_u64(),
+                w = 16 - r.name.len()
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,96 @@
+//! Copying between kernel and user memory
+//!
+//! Pointers from user mode, e.g. system call arguments, may point anywhere. These helpers only
+//! touch a range after checking that every page of it belongs to the user part of the active
+//! [`AddressSpace`] with the needed rights, either mapped already or populated on demand by
+//! an anonymous [`Vma`](super::vma::Vma).
+
+use thiserror_no_std::Error;
+use x86_64::{
+    structures::paging::{Page, PageTableFlags},
+    VirtAddr,
+};
+
+use super::{
+    address_space::{self, AddressSpace, USER_END, USER_START},
+    protection::with_user_access,
+    vma::{VmaFlags, VmaKind, COPY_ON_WRITE},
+};
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserCopyError {
+    #[error("no user address space is active")]
+    NoAddressSpace,
+    #[error("{0:?} isn't accessible from user mode")]
+    BadAddress(VirtAddr),
+}
+
+/// Whether the user can access `page` for reading, or for writing if `write` is set
+fn accessible(space: &AddressSpace, page: Page, write: bool) -> bool {
+    let addr = page.start_address();
+    if let Some((_, flags)) = space.translate(addr) {
+        return flags.contains(PageTableFlags::USER_ACCESSIBLE)
+            && (!write || flags.intersects(PageTableFlags::WRITABLE | COPY_ON_WRITE));
+    }
+    match space.find_vma(addr) {
+        Some(vma) if vma.kind == VmaKind::Anonymous => {
+            vma.flags.contains(VmaFlags::READ) && (!write || vma.flags.contains(VmaFlags::WRITE))
+        }
+        _ => false,
+    }
+}
+
+/// Checks that `len` bytes at `addr` can be accessed from user mode in the active address
+/// space, and runs `f` with SMAP lifted if so.
+fn with_checked<R>(
+    addr: VirtAddr,
+    len: usize,
+    write: bool,
+    f: impl FnOnce() -> R,
+) -> Result<R, UserCopyError> {
+    if len == 0 {
+        return Ok(f());
+    }
+    let end = addr
+        .as_u64()
+        .checked_add(len as u64)
+        .filter(|&end| addr.as_u64() >= USER_START && end <= USER_END)
+        .ok_or(UserCopyError::BadAddress(addr))?;
+
+    address_space::with_current(|space| {
+        let pages = Page::range_inclusive(
+            Page::containing_address(addr),
+            Page::containing_address(VirtAddr::new(end - 1)),
+        );
+        if let Some(page) = pages
+            .into_iter()
+            .find(|&page| !accessible(space, page, write))
+        {
+            return Err(UserCopyError::BadAddress(page.start_address().max(addr)));
+        }
+        // pages that aren't populated yet are faulted in by the copy
+        Ok(with_user_access(f))
+    })
+    .unwrap_or(Err(UserCopyError::NoAddressSpace))
+}
+
+/// Copies `dst.len()` bytes from user memory at `src` into `dst`.
+pub fn copy_from_user(dst: &mut [u8], src: VirtAddr) -> Result<(), UserCopyError> {
+    with_checked(src, dst.len(), false, || unsafe {
+        core::ptr::copy_nonoverlapping(src.as_ptr::<u8>(), dst.as_mut_ptr(), dst.len())
+    })
+}
+
+/// Copies `src` to user memory at `dst`.
+pub fn copy_to_user(dst: VirtAddr, src: &[u8]) -> Result<(), UserCopyError> {
+    with_checked(dst, src.len(), true, || unsafe {
+        core::ptr::copy_nonoverlapping(src.as_ptr(), dst.as_mut_ptr::<u8>(), src.len())
+    })
+}
+
+/// Fills `len` bytes of user memory at `dst` with zeros.
+pub fn clear_user(dst: VirtAddr, len: usize) -> Result<(), UserCopyError> {
+    with_checked(dst, len, true, || unsafe {
+        core::ptr::write_bytes(dst.as_mut_ptr::<u8>(), 0, len)
+    })
+}
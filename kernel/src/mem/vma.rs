@@ -0,0 +1,122 @@
+//! Virtual memory areas
+//!
+//! A [`Vma`] describes a range of an [`AddressSpace`](super::AddressSpace) and how to back it.
+//! Anonymous areas are populated lazily: their pages are only allocated (zeroed) on the first
+//! access, by the page fault handler. Guard areas are never backed and make faults in them
+//! easy to diagnose.
+//!
+//! Frames shared copy-on-write between address spaces are reference counted here. A frame is
+//! only tracked while at least two address spaces map it.
+
+use alloc::collections::BTreeMap;
+use bitflags::bitflags;
+use core::ops::Range;
+use x86_64::{
+    structures::paging::{page::PageRange, PageTableFlags, PhysFrame},
+    VirtAddr,
+};
+
+use crate::util::Spinlock;
+
+/// Software bit marking read-only leaf entries whose frame is shared copy-on-write
+pub(super) const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_10;
+
+/// Number of address spaces mapping a copy-on-write frame, if more than one
+static SHARED_FRAMES: Spinlock<BTreeMap<PhysFrame, usize>> = Spinlock::new(BTreeMap::new());
+
+bitflags! {
+    /// Access rights of a memory area
+    pub struct VmaFlags: u8 {
+        const READ = 1 << 0;
+        const WRITE = 1 << 1;
+        const EXECUTE = 1 << 2;
+    }
+}
+
+impl VmaFlags {
+    /// Flags of the page table entries backing an area with these rights
+    pub fn page_table_flags(self) -> PageTableFlags {
+        let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        if self.contains(VmaFlags::WRITE) {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if !self.contains(VmaFlags::EXECUTE) {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        flags
+    }
+}
+
+/// How a memory area is backed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaKind {
+    /// Zeroed pages, allocated on first access
+    Anonymous,
+    /// Never mapped, accesses are reported as guard page hits
+    Guard,
+}
+
+#[derive(Debug, Clone)]
+pub struct Vma {
+    pub pages: PageRange,
+    pub flags: VmaFlags,
+    pub kind: VmaKind,
+}
+
+impl Vma {
+    pub fn anonymous(pages: PageRange, flags: VmaFlags) -> Self {
+        Self {
+            pages,
+            flags,
+            kind: VmaKind::Anonymous,
+        }
+    }
+
+    pub fn guard(pages: PageRange) -> Self {
+        Self {
+            pages,
+            flags: VmaFlags::empty(),
+            kind: VmaKind::Guard,
+        }
+    }
+
+    pub fn range(&self) -> Range<VirtAddr> {
+        self.pages.start.start_address()..self.pages.end.start_address()
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.range().contains(&addr)
+    }
+
+    pub fn overlaps(&self, other: &Range<VirtAddr>) -> bool {
+        let range = self.range();
+        range.start < other.end && other.start < range.end
+    }
+}
+
+/// Records one more address space mapping `frame`, which already is mapped by at least one.
+pub(super) fn share(frame: PhysFrame) {
+    *SHARED_FRAMES.lock_sync().entry(frame).or_insert(1) += 1;
+}
+
+/// Drops an address space's reference to `frame`, returns whether it was the last one and
+/// the frame can be freed.
+pub(super) fn unshare(frame: PhysFrame) -> bool {
+    let mut shared = SHARED_FRAMES.lock_sync();
+    match shared.get_mut(&frame) {
+        Some(count) if *count > 2 => {
+            *count -= 1;
+            false
+        }
+        Some(_) => {
+            shared.remove(&frame);
+            false
+        }
+        None => true,
+    }
+}
+
+/// Whether `frame` is mapped by more than one address space
+pub(super) fn is_shared(frame: PhysFrame) -> bool {
+    SHARED_FRAMES.lock_sync().contains_key(&frame)
+}
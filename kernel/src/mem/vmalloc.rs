@@ -0,0 +1,248 @@
+//! Kernel virtual address space allocator
+//!
+//! Hands out non-overlapping ranges of the region starting at
+//! [`layout::vmalloc_start`](super::layout::vmalloc_start), either
+//! backed by newly allocated frames ([`vmalloc`]) or mapped to existing physical memory like
+//! MMIO registers or firmware tables ([`map_physical`]). Every range is followed by an
+//! unmapped guard page.
+
+use alloc::collections::BTreeMap;
+use lazy_static::lazy_static;
+use thiserror_no_std::Error;
+use x86_64::{
+    align_up,
+    structures::paging::{
+        mapper::{FlagUpdateError, MapToError, UnmapError},
+        Page, PageSize, PageTableFlags, PhysFrame, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use super::{
+    layout::{self, VMALLOC_SIZE},
+    pat::CacheMode,
+    stats::{self, Tag},
+};
+use crate::util::Spinlock;
+
+lazy_static! {
+    static ref VMALLOC: Spinlock<VirtualAllocator> = {
+        let region = region();
+        Spinlock::new(VirtualAllocator::new(
+            region.start.as_u64(),
+            region.end.as_u64(),
+        ))
+    };
+}
+
+#[derive(Error, Debug)]
+pub enum VmallocError {
+    #[error("kernel virtual address space exhausted")]
+    OutOfVirtualMemory,
+    #[error("failed to map page: {0:?}")]
+    Map(MapToError<Size4KiB>),
+    #[error("failed to update page flags: {0:?}")]
+    Flags(FlagUpdateError),
+    #[error("failed to unmap page: {0:?}")]
+    Unmap(UnmapError),
+    #[error("no allocation at {0:?}")]
+    NotAllocated(VirtAddr),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Backing {
+    /// Frames were allocated for this range and are freed with it
+    Anonymous,
+    /// Existing physical memory, the frames are not owned by the range
+    Physical,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Allocation {
+    pages: u64,
+    backing: Backing,
+}
+
+#[derive(Debug)]
+struct VirtualAllocator {
+    /// Free ranges, start -> end
+    free: BTreeMap<u64, u64>,
+    allocations: BTreeMap<u64, Allocation>,
+}
+
+impl VirtualAllocator {
+    fn new(start: u64, end: u64) -> Self {
+        let mut free = BTreeMap::new();
+        free.insert(start, end);
+        Self {
+            free,
+            allocations: BTreeMap::new(),
+        }
+    }
+
+    /// Reserves `pages` pages plus a guard page, first fit.
+    fn reserve(&mut self, pages: u64, backing: Backing) -> Option<VirtAddr> {
+        let size = (pages + 1) * Size4KiB::SIZE;
+        let (&start, &end) = self.free.iter().find(|(&s, &e)| e - s >= size)?;
+
+        self.free.remove(&start);
+        if end - start > size {
+            self.free.insert(start + size, end);
+        }
+        self.allocations
+            .insert(start, Allocation { pages, backing });
+        Some(VirtAddr::new(start))
+    }
+
+    fn release(&mut self, start: u64) -> Option<Allocation> {
+        let allocation = self.allocations.remove(&start)?;
+        let mut start = start;
+        let mut end = start + (allocation.pages + 1) * Size4KiB::SIZE;
+
+        // merge with the free ranges right below and above
+        if let Some((&below_start, &below_end)) = self.free.range(..start).next_back() {
+            if below_end == start {
+                self.free.remove(&below_start);
+                start = below_start;
+            }
+        }
+        if let Some(above_end) = self.free.remove(&end) {
+            end = above_end;
+        }
+        self.free.insert(start, end);
+        Some(allocation)
+    }
+}
+
+/// Number of pages needed for `size` bytes
+fn page_count(size: u64) -> u64 {
+    (align_up(size, Size4KiB::SIZE) / Size4KiB::SIZE).max(1)
+}
+
+fn pages(start: VirtAddr, count: u64) -> impl Iterator<Item = Page<Size4KiB>> {
+    let first = Page::containing_address(start);
+    Page::range(first, first + count)
+}
+
+/// Allocates `size` bytes of virtually contiguous, zeroed kernel memory.
+///
+/// The memory is writable but not executable. Free it with [`free`].
+pub fn vmalloc(size: usize) -> Result<VirtAddr, VmallocError> {
+    let count = page_count(size as u64);
+    let start = VMALLOC
+        .lock_sync()
+        .reserve(count, Backing::Anonymous)
+        .ok_or(VmallocError::OutOfVirtualMemory)?;
+
+    let mm = super::get_memory_manager();
+    for (i, page) in pages(start, count).enumerate() {
+        let result = mm
+            .map(page, CacheMode::WriteBack)
+            .map_err(VmallocError::Map)
+            .and_then(|_| {
+                mm.update_flags(
+                    page,
+                    PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+                )
+                .map_err(VmallocError::Flags)
+            });
+        if let Err(e) = result {
+            let mapped = if matches!(e, VmallocError::Flags(_)) {
+                i + 1
+            } else {
+                i
+            };
+            for page in pages(start, mapped as u64) {
+                mm.unmap(page).ok();
+            }
+            VMALLOC.lock_sync().release(start.as_u64());
+            return Err(e);
+        }
+        unsafe {
+            page.start_address()
+                .as_mut_ptr::<u8>()
+                .write_bytes(0, Size4KiB::SIZE as usize)
+        };
+    }
+    stats::account(Tag::Vmalloc, count * Size4KiB::SIZE);
+
+    #[cfg(feature = "dbg-mem")]
+    log::trace!("vmalloc: {} pages at {:?}", count, start);
+
+    Ok(start)
+}
+
+/// Maps `size` bytes of physical memory starting at `phys` with the given `flags` and cache
+/// mode.
+///
+/// Returns the virtual address corresponding to `phys`, which has the same offset into its
+/// page. The physical frames are not owned by the mapping, [`free`] only unmaps them.
+pub fn map_physical(
+    phys: PhysAddr,
+    size: usize,
+    flags: PageTableFlags,
+    cache: CacheMode,
+) -> Result<VirtAddr, VmallocError> {
+    let first_frame = PhysFrame::<Size4KiB>::containing_address(phys);
+    let offset = phys - first_frame.start_address();
+    let count = page_count(offset + size as u64);
+
+    let start = VMALLOC
+        .lock_sync()
+        .reserve(count, Backing::Physical)
+        .ok_or(VmallocError::OutOfVirtualMemory)?;
+
+    let mm = super::get_memory_manager();
+    for (i, page) in pages(start, count).enumerate() {
+        let frame = first_frame + i as u64;
+        if let Err(e) = mm.map_frame(page, frame, flags | PageTableFlags::PRESENT, cache) {
+            for page in pages(start, i as u64) {
+                mm.unmap_frame(page).ok();
+            }
+            VMALLOC.lock_sync().release(start.as_u64());
+            return Err(VmallocError::Map(e));
+        }
+    }
+
+    #[cfg(feature = "dbg-mem")]
+    log::trace!("mapped {:?} ({} pages) at {:?}", phys, count, start);
+
+    Ok(start + offset)
+}
+
+/// Virtual address range vmalloc hands out addresses from
+pub fn region() -> core::ops::Range<VirtAddr> {
+    let start = layout::vmalloc_start();
+    start..start + VMALLOC_SIZE
+}
+
+/// Frees a range returned by [`vmalloc`] or [`map_physical`], `addr` may point anywhere
+/// into its first page.
+pub fn free(addr: VirtAddr) -> Result<(), VmallocError> {
+    let start = addr.align_down(Size4KiB::SIZE);
+    let allocation = VMALLOC
+        .lock_sync()
+        .allocations
+        .get(&start.as_u64())
+        .copied()
+        .ok_or(VmallocError::NotAllocated(addr))?;
+
+    let mm = super::get_memory_manager();
+    for page in pages(start, allocation.pages) {
+        match allocation.backing {
+            Backing::Anonymous => mm.unmap(page),
+            Backing::Physical => mm.unmap_frame(page).map(|_| ()),
+        }
+        .map_err(VmallocError::Unmap)?;
+    }
+    if allocation.backing == Backing::Anonymous {
+        stats::release(Tag::Vmalloc, allocation.pages * Size4KiB::SIZE);
+    }
+
+    VMALLOC.lock_sync().release(start.as_u64());
+
+    #[cfg(feature = "dbg-mem")]
+    log::trace!("freed {} pages at {:?}", allocation.pages, start);
+
+    Ok(())
+}
@@ -1,12 +1,167 @@
-//! PCI subsystem (stub)
+//! PCI devices, found through the legacy configuration ports
 
-use acpi::{AcpiError, AcpiTables};
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use core::fmt;
+use x86_64::instructions::port::Port;
 
-use crate::mem::MemoryManager;
+use crate::util::Spinlock;
 
-pub fn init(acpi_tables: &AcpiTables<MemoryManager>) -> Result<(), AcpiError> {
-    let _regions = acpi::PciConfigRegions::new(acpi_tables)?;
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0xcfc;
 
-    // TODO
-    Ok(())
+/// Read by all functions without a device behind them
+const NO_VENDOR: u16 = 0xffff;
+/// Set in the header type of the first function of devices with more than one
+const MULTI_FUNCTION: u8 = 0x80;
+const CLASS_BRIDGE: u8 = 0x06;
+const SUBCLASS_PCI_BRIDGE: u8 = 0x04;
+
+/// The address and data ports, a configuration access takes both
+static PORTS: Spinlock<(Port<u32>, Port<u32>)> =
+    Spinlock::new((Port::new(CONFIG_ADDRESS), Port::new(CONFIG_DATA)));
+
+static DEVICES: OnceCell<Vec<PciDevice>> = OnceCell::uninit();
+
+/// A function of a device on a PCI bus
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciDevice {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub header_type: u8,
+}
+
+impl PciDevice {
+    fn probe(bus: u8, device: u8, function: u8) -> Option<Self> {
+        let id = read_config(bus, device, function, 0x00);
+        let vendor_id = id as u16;
+        if vendor_id == NO_VENDOR {
+            return None;
+        }
+        let class = read_config(bus, device, function, 0x08);
+        let header = read_config(bus, device, function, 0x0c);
+        Some(Self {
+            bus,
+            device,
+            function,
+            vendor_id,
+            device_id: (id >> 16) as u16,
+            class: (class >> 24) as u8,
+            subclass: (class >> 16) as u8,
+            prog_if: (class >> 8) as u8,
+            revision: class as u8,
+            header_type: (header >> 16) as u8,
+        })
+    }
+
+    /// Reads the configuration register at `offset` of this function
+    pub fn read_config(&self, offset: u8) -> u32 {
+        read_config(self.bus, self.device, self.function, offset)
+    }
+
+    /// A description of the class, the name of the subclass if it's a common one
+    pub fn class_name(&self) -> &'static str {
+        match (self.class, self.subclass) {
+            (0x01, 0x01) => "IDE controller",
+            (0x01, 0x06) => "SATA controller",
+            (0x01, 0x08) => "NVMe controller",
+            (0x01, _) => "mass storage controller",
+            (0x02, 0x00) => "ethernet controller",
+            (0x02, _) => "network controller",
+            (0x03, 0x00) => "VGA controller",
+            (0x03, _) => "display controller",
+            (0x04, _) => "multimedia controller",
+            (0x05, _) => "memory controller",
+            (0x06, 0x00) => "host bridge",
+            (0x06, 0x01) => "ISA bridge",
+            (0x06, 0x04) => "PCI bridge",
+            (0x06, _) => "bridge",
+            (0x07, _) => "communication controller",
+            (0x08, _) => "system peripheral",
+            (0x09, _) => "input device controller",
+            (0x0c, 0x03) => "USB controller",
+            (0x0c, 0x05) => "SMBus controller",
+            (0x0c, _) => "serial bus controller",
+            _ => "unknown device",
+        }
+    }
+
+    fn is_multi_function(&self) -> bool {
+        self.header_type & MULTI_FUNCTION != 0
+    }
+}
+
+impl fmt::Display for PciDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:02x}:{:02x}.{} {:04x}:{:04x} {}",
+            self.bus,
+            self.device,
+            self.function,
+            self.vendor_id,
+            self.device_id,
+            self.class_name()
+        )
+    }
+}
+
+/// Reads the configuration register at `offset`, rounded down to 4 bytes, of a function.
+pub fn read_config(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
+    let address = 1 << 31
+        | (bus as u32) << 16
+        | (device as u32 & 0x1f) << 11
+        | (function as u32 & 0x7) << 8
+        | (offset as u32 & 0xfc);
+    let mut ports = PORTS.lock_sync();
+    unsafe {
+        ports.0.write(address);
+        ports.1.read()
+    }
+}
+
+/// Enumerates the devices, starting at bus 0 and following PCI to PCI bridges.
+pub fn init() {
+    DEVICES.init_once(|| {
+        let mut devices = Vec::new();
+        scan_bus(0, &mut devices);
+        for device in devices.iter() {
+            log::info!("pci: {}", device);
+        }
+        devices
+    });
+}
+
+fn scan_bus(bus: u8, devices: &mut Vec<PciDevice>) {
+    for device in 0..32 {
+        let Some(first) = PciDevice::probe(bus, device, 0) else {
+            continue;
+        };
+        let functions = if first.is_multi_function() { 8 } else { 1 };
+        for function in 0..functions {
+            let Some(found) = PciDevice::probe(bus, device, function) else {
+                continue;
+            };
+            devices.push(found);
+            if found.class == CLASS_BRIDGE && found.subclass == SUBCLASS_PCI_BRIDGE {
+                let secondary = (found.read_config(0x18) >> 8) as u8;
+                // a secondary bus at or below this one would never end
+                if secondary > bus {
+                    scan_bus(secondary, devices);
+                }
+            }
+        }
+    }
+}
+
+/// The devices found by [`init`], empty before
+pub fn devices() -> &'static [PciDevice] {
+    DEVICES.get().map_or(&[], Vec::as_slice)
 }
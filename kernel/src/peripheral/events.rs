@@ -0,0 +1,103 @@
+//! Event streams of input devices
+//!
+//! Every [`Listener`] gets the events published after it started listening. Events are records
+//! of `N` bytes, a listener that doesn't keep up loses the newest ones.
+
+use alloc::{
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::{future::poll_fn, task::Poll};
+use crossbeam_queue::ArrayQueue;
+use futures_util::task::AtomicWaker;
+use x86_64::instructions::interrupts;
+
+use crate::util::Spinlock;
+
+/// Events a listener holds before it loses some
+const QUEUE_LEN: usize = 256;
+
+pub struct Events<const N: usize> {
+    listeners: Spinlock<Vec<Weak<Listener<N>>>>,
+}
+
+impl<const N: usize> Events<N> {
+    pub const fn new() -> Self {
+        Self {
+            listeners: Spinlock::new(Vec::new()),
+        }
+    }
+
+    /// Starts listening, until the listener is dropped.
+    pub fn listen(&self) -> Arc<Listener<N>> {
+        let listener = Arc::new(Listener {
+            queue: ArrayQueue::new(QUEUE_LEN),
+            waker: AtomicWaker::new(),
+        });
+        interrupts::without_interrupts(|| {
+            self.listeners.lock_sync().push(Arc::downgrade(&listener));
+        });
+        listener
+    }
+
+    pub fn publish(&self, event: [u8; N]) {
+        interrupts::without_interrupts(|| {
+            self.listeners
+                .lock_sync()
+                .retain(|listener| match listener.upgrade() {
+                    Some(listener) => {
+                        if listener.queue.push(event).is_ok() {
+                            listener.waker.wake();
+                        }
+                        true
+                    }
+                    None => false,
+                });
+        });
+    }
+}
+
+impl<const N: usize> Default for Events<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct Listener<const N: usize> {
+    queue: ArrayQueue<[u8; N]>,
+    waker: AtomicWaker,
+}
+
+impl<const N: usize> Listener<N> {
+    /// Waits for events and reads as many as fit into `buf`, returns the number of bytes read.
+    /// Returns 0 right away if `buf` can't hold a single event.
+    pub async fn read(&self, buf: &mut [u8]) -> usize {
+        if buf.len() < N {
+            return 0;
+        }
+        poll_fn(|cx| {
+            if self.queue.is_empty() {
+                self.waker.register(cx.waker());
+                if self.queue.is_empty() {
+                    return Poll::Pending;
+                }
+                self.waker.take();
+            }
+            let mut read = 0;
+            for chunk in buf.chunks_exact_mut(N) {
+                match self.queue.pop() {
+                    Some(event) => chunk.copy_from_slice(&event),
+                    None => break,
+                }
+                read += N;
+            }
+            Poll::Ready(read)
+        })
+        .await
+    }
+
+    /// Bytes of events waiting to be read
+    pub fn pending(&self) -> usize {
+        self.queue.len() * N
+    }
+}
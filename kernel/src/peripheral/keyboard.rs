@@ -8,10 +8,14 @@ use pc_keyboard::{
     Keyboard as KeyboardDevice, ScancodeSet1,
 };
 
+use super::events::Events;
 use crate::{print, util::Spinlock};
 
 static KEYBOARD: OnceCell<Keyboard> = OnceCell::uninit();
 
+/// Scancodes of scancode set 1 as they arrive, see [`crate::task::keyboard`]
+pub static EVENTS: Events<1> = Events::new();
+
 pub(super) fn init() {
     KEYBOARD.init_once(Keyboard::new);
     let mut cmd = x86_64::instructions::port::Port::<u8>::new(0x64);
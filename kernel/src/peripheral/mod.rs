@@ -1,5 +1,6 @@
 //! Simple peripehral drivers
 
+pub mod events;
 pub mod keyboard;
 pub mod mouse;
 
@@ -5,10 +5,15 @@ use conquer_once::spin::OnceCell;
 use crossbeam_utils::atomic::AtomicCell;
 use ps2_mouse::{Mouse as MouseDevice, MouseState};
 
+use super::events::Events;
 use crate::util::Spinlock;
 
 static MOUSE: OnceCell<Mouse> = OnceCell::uninit();
 
+/// Decoded packets: the buttons held down (bit 0 left, bit 1 right), then the movement right
+/// and up as little endian `i16`s
+pub static EVENTS: Events<5> = Events::new();
+
 pub(super) fn init() {
     MOUSE.init_once(Mouse::default);
 }
@@ -55,6 +60,11 @@ impl Mouse {
         let this: &Mouse = MOUSE.get().expect("mouse not initialized");
         this.state.store(Some(state));
 
+        let buttons = state.left_button_down() as u8 | (state.right_button_down() as u8) << 1;
+        let [x0, x1] = state.get_x().to_le_bytes();
+        let [y0, y1] = state.get_y().to_le_bytes();
+        EVENTS.publish([buttons, x0, x1, y0, y1]);
+
         this.set_pos();
 
         crate::fb::draw_mouse(
@@ -0,0 +1,97 @@
+//! Handles of a process
+//!
+//! Handles are what the file descriptors of system calls refer to. Processes spawned by the
+//! kernel start with the console on [`STDOUT`] and [`STDERR`], other processes with a copy of
+//! their parent's handles.
+
+use alloc::{collections::BTreeMap, sync::Arc};
+
+use crate::vfs::OpenFile;
+
+pub const STDOUT: u32 = 1;
+pub const STDERR: u32 = 2;
+
+/// A process can't have more handles open at once
+pub const MAX_HANDLES: usize = 1024;
+
+/// Something a process has open
+#[derive(Debug, Clone)]
+pub enum Handle {
+    /// The kernel console, writes go to the serial port and the framebuffer
+    Console,
+    /// A file, shared with the handles it was duplicated to
+    File(Arc<OpenFile>),
+}
+
+impl PartialEq for Handle {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Handle::Console, Handle::Console) => true,
+            (Handle::File(a), Handle::File(b)) => Arc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Handle {}
+
+/// The handles of a process by file descriptor
+#[derive(Debug, Clone, Default)]
+pub struct Handles {
+    table: BTreeMap<u32, Handle>,
+}
+
+impl Handles {
+    pub const fn new() -> Self {
+        Self {
+            table: BTreeMap::new(),
+        }
+    }
+
+    /// Handles with the console as standard output and error
+    pub fn console() -> Self {
+        let mut handles = Self::new();
+        handles.insert_at(STDOUT, Handle::Console);
+        handles.insert_at(STDERR, Handle::Console);
+        handles
+    }
+
+    pub fn get(&self, fd: u32) -> Option<&Handle> {
+        self.table.get(&fd)
+    }
+
+    /// Adds `handle` at the lowest free file descriptor, which is returned. Fails if there are
+    /// [`MAX_HANDLES`] already.
+    pub fn insert(&mut self, handle: Handle) -> Option<u32> {
+        if self.table.len() >= MAX_HANDLES {
+            return None;
+        }
+        let fd = (0..)
+            .zip(self.table.keys())
+            .find(|(expected, &fd)| *expected != fd)
+            .map_or(self.table.len() as u32, |(free, _)| free);
+        self.table.insert(fd, handle);
+        Some(fd)
+    }
+
+    /// Puts `handle` at `fd`, returns what was there before.
+    pub fn insert_at(&mut self, fd: u32, handle: Handle) -> Option<Handle> {
+        self.table.insert(fd, handle)
+    }
+
+    pub fn remove(&mut self, fd: u32) -> Option<Handle> {
+        self.table.remove(&fd)
+    }
+
+    pub fn len(&self) -> usize {
+        self.table.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.table.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u32, &Handle)> {
+        self.table.iter().map(|(&fd, handle)| (fd, handle))
+    }
+}
@@ -0,0 +1,403 @@
+//! Processes
+//!
+//! A process is a user program with its own address space and [`Handles`]. The process table
+//! keeps it from [`spawn`] until its parent collects the exit status with [`wait`],
+//! [`waitpid`] or [`try_wait`]. A process that exited but wasn't waited for yet is a zombie:
+//! only its exit status is kept, its memory and handles are released by [`exit`].
+//!
+//! The main thread of a process is a kernel [`Task`] which runs the program with
+//! [`user::start`] when polled and keeps its CPU until the program exits or has to wait for a
+//! system call. The main thread awaits the call like any other task and continues the program
+//! with [`user::resume`] afterwards, maybe on another CPU. Kernel tasks spawn
+//! processes as [`Pid::KERNEL`] and await them like any other parent. When a process exits,
+//! its children are reparented to [`Pid::INIT`], the first process, or to the kernel if init
+//! is gone. The kernel doesn't wait for orphans, they are reaped as soon as they exit.
+
+mod handles;
+
+pub use handles::{Handle, Handles, MAX_HANDLES, STDERR, STDOUT};
+
+use alloc::{collections::BTreeMap, string::String, sync::Arc, vec::Vec};
+use core::{
+    fmt,
+    future::{poll_fn, Future},
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
+};
+use thiserror_no_std::Error;
+use x86_64::VirtAddr;
+
+use crate::{
+    cpu,
+    elf::Program,
+    mem::{address_space::activate_kernel, AddressSpace},
+    smp::MAX_CPUS,
+    task::Task,
+    user::{self, Exit, Stop},
+    util::Spinlock,
+};
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_PROCESS: AtomicU64 = AtomicU64::new(0);
+/// The process running on every CPU, 0 if none
+static CURRENT: [AtomicU64; MAX_CPUS] = [NO_PROCESS; MAX_CPUS];
+
+static TABLE: Spinlock<Table> = Spinlock::new(Table {
+    entries: BTreeMap::new(),
+    next_pid: Pid::INIT.0,
+    waiters: BTreeMap::new(),
+});
+
+/// Process ID, they aren't reused
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Pid(pub u64);
+
+impl Pid {
+    /// The kernel, parent of the processes spawned by kernel tasks
+    pub const KERNEL: Pid = Pid(0);
+    /// The first process, which adopts orphans
+    pub const INIT: Pid = Pid(1);
+}
+
+impl fmt::Display for Pid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum State {
+    /// Spawned, but the main thread hasn't been polled yet
+    Ready,
+    Running,
+    /// Exited and waiting to be reaped by its parent
+    Zombie(Exit),
+}
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessError {
+    #[error("no process with pid {0}")]
+    NoSuchProcess(Pid),
+    #[error("process {0} isn't a child of the waiting process")]
+    NotAChild(Pid),
+    #[error("no children to wait for")]
+    NoChildren,
+}
+
+/// A snapshot of a process table entry
+#[derive(Debug, Clone)]
+pub struct ProcessInfo {
+    pub pid: Pid,
+    pub parent: Pid,
+    pub name: String,
+    pub state: State,
+}
+
+struct Entry {
+    name: String,
+    parent: Pid,
+    state: State,
+    /// Released when the process exits
+    space: Option<Arc<AddressSpace>>,
+    handles: Handles,
+    /// Reaped on exit instead of becoming a zombie, set for orphans adopted by the kernel
+    detached: bool,
+}
+
+impl Entry {
+    fn is_zombie(&self) -> bool {
+        matches!(self.state, State::Zombie(_))
+    }
+
+    fn info(&self, pid: Pid) -> ProcessInfo {
+        ProcessInfo {
+            pid,
+            parent: self.parent,
+            name: self.name.clone(),
+            state: self.state,
+        }
+    }
+}
+
+struct Table {
+    entries: BTreeMap<Pid, Entry>,
+    next_pid: u64,
+    /// Wakers of [`Wait`] futures by waiting parent
+    waiters: BTreeMap<Pid, Vec<Waker>>,
+}
+
+impl Table {
+    fn wake(&mut self, parent: Pid) {
+        for waker in self.waiters.remove(&parent).into_iter().flatten() {
+            waker.wake();
+        }
+    }
+
+    /// Removes an exited child of `parent`, `target` or any, and returns its status.
+    fn reap(
+        &mut self,
+        parent: Pid,
+        target: Option<Pid>,
+    ) -> Result<Option<(Pid, Exit)>, ProcessError> {
+        if let Some(pid) = target {
+            match self.entries.get(&pid) {
+                Some(entry) if entry.parent == parent => {}
+                Some(_) => return Err(ProcessError::NotAChild(pid)),
+                None => return Err(ProcessError::NoSuchProcess(pid)),
+            }
+        }
+
+        let mut children = self
+            .entries
+            .iter()
+            .filter(|(&pid, entry)| entry.parent == parent && target.unwrap_or(pid) == pid)
+            .peekable();
+        if children.peek().is_none() {
+            return Err(ProcessError::NoChildren);
+        }
+        let exited = children.find_map(|(&pid, entry)| match entry.state {
+            State::Zombie(status) => Some((pid, status)),
+            _ => None,
+        });
+        if let Some((pid, _)) = exited {
+            self.entries.remove(&pid);
+
+            #[cfg(feature = "dbg-process")]
+            log::trace!("process {} reaped by {}", pid, parent);
+        }
+        Ok(exited)
+    }
+}
+
+fn current_slot() -> &'static AtomicU64 {
+    &CURRENT[cpu::current_id() as usize]
+}
+
+/// The process running on this CPU, if any
+pub fn current() -> Option<Pid> {
+    let pid = current_slot().load(Ordering::SeqCst);
+    (pid != 0).then_some(Pid(pid))
+}
+
+/// Adds `program` to the process table as a child of `parent`. Returns its PID and the task of
+/// its main thread, which has to be spawned on an executor for the process to run.
+pub fn spawn(name: &str, program: Program, parent: Pid) -> Result<(Pid, Task), ProcessError> {
+    let mut table = TABLE.lock_sync();
+    let handles = if parent == Pid::KERNEL {
+        Handles::console()
+    } else {
+        table
+            .entries
+            .get(&parent)
+            .filter(|entry| !entry.is_zombie())
+            .ok_or(ProcessError::NoSuchProcess(parent))?
+            .handles
+            .clone()
+    };
+
+    let pid = Pid(table.next_pid);
+    table.next_pid += 1;
+    let space = Arc::new(program.space);
+    table.entries.insert(
+        pid,
+        Entry {
+            name: name.into(),
+            parent,
+            state: State::Ready,
+            space: Some(space.clone()),
+            handles,
+            detached: false,
+        },
+    );
+    drop(table);
+
+    #[cfg(feature = "dbg-process")]
+    log::trace!("process {} ({}) spawned by {}", pid, name, parent);
+
+    let thread = main_thread(pid, space, program.entry, program.stack_pointer);
+    Ok((pid, Task::new_with_name(name, thread)))
+}
+
+async fn main_thread(pid: Pid, space: Arc<AddressSpace>, entry: VirtAddr, stack: VirtAddr) {
+    {
+        let mut table = TABLE.lock_sync();
+        match table.entries.get_mut(&pid) {
+            Some(process) if matches!(process.state, State::Ready) => {
+                process.state = State::Running
+            }
+            // exited before it ran
+            _ => return,
+        }
+    }
+
+    // a user program only runs while its main thread is polled, so none is running here
+    let mut stop = unsafe { as_process(pid, &space, || user::start(entry, stack)) };
+    let status = loop {
+        match stop {
+            Stop::Exit(status) => break status,
+            Stop::Suspended(mut suspended) => {
+                // the call may access the program's memory
+                let result = poll_fn(|cx| unsafe {
+                    as_process(pid, &space, || suspended.call.as_mut().poll(cx))
+                })
+                .await;
+                stop = unsafe { as_process(pid, &space, || user::resume(suspended, result)) };
+            }
+        }
+    };
+
+    drop(space);
+    exit(pid, status);
+}
+
+/// Runs `f` as the process `pid`, with `space` active and `pid` as [`current`] process.
+///
+/// # Safety
+///
+/// `space` has to be the address space of `pid` and no user program may be running on this CPU.
+unsafe fn as_process<R>(pid: Pid, space: &AddressSpace, f: impl FnOnce() -> R) -> R {
+    current_slot().store(pid.0, Ordering::SeqCst);
+    space.activate();
+    let result = f();
+    activate_kernel();
+    current_slot().store(0, Ordering::SeqCst);
+    result
+}
+
+/// Ends the process `pid` with `status`: its memory and handles are released, its children
+/// reparented and its parent woken up. Does nothing if it already exited.
+///
+/// This doesn't stop a running main thread, it's called by the main thread once the program
+/// exited.
+pub fn exit(pid: Pid, status: Exit) {
+    let mut table = TABLE.lock_sync();
+    let Some(entry) = table
+        .entries
+        .get_mut(&pid)
+        .filter(|entry| !entry.is_zombie())
+    else {
+        return;
+    };
+    entry.state = State::Zombie(status);
+    let space = entry.space.take();
+    let handles = core::mem::take(&mut entry.handles);
+    let (parent, detached) = (entry.parent, entry.detached);
+
+    #[cfg(feature = "dbg-process")]
+    log::trace!("process {} exited: {:?}", pid, status);
+
+    let init_alive = pid != Pid::INIT
+        && matches!(table.entries.get(&Pid::INIT), Some(init) if !init.is_zombie());
+    let adopter = if init_alive { Pid::INIT } else { Pid::KERNEL };
+    let mut exited_orphans = false;
+    for child in table.entries.values_mut().filter(|e| e.parent == pid) {
+        child.parent = adopter;
+        child.detached = !init_alive;
+        exited_orphans |= child.is_zombie();
+    }
+    if !init_alive {
+        table
+            .entries
+            .retain(|_, entry| !(entry.detached && entry.is_zombie()));
+    } else if exited_orphans {
+        table.wake(Pid::INIT);
+    }
+
+    if detached {
+        table.entries.remove(&pid);
+    } else {
+        table.wake(parent);
+    }
+    // waits of the process itself fail now that it has no children
+    table.wake(pid);
+    drop(table);
+
+    // the address space may be freed here, outside of the lock
+    drop(space);
+    drop(handles);
+}
+
+/// Reaps an exited child of `parent`, `pid` or any if `None`, without waiting. Returns `None`
+/// if none exited yet.
+pub fn try_wait(parent: Pid, pid: Option<Pid>) -> Result<Option<(Pid, Exit)>, ProcessError> {
+    TABLE.lock_sync().reap(parent, pid)
+}
+
+/// Waits for any child of `parent` to exit and reaps it.
+pub fn wait(parent: Pid) -> Wait {
+    Wait {
+        parent,
+        target: None,
+    }
+}
+
+/// Waits for the child `pid` of `parent` to exit and reaps it.
+pub fn waitpid(parent: Pid, pid: Pid) -> Wait {
+    Wait {
+        parent,
+        target: Some(pid),
+    }
+}
+
+/// Future returned by [`wait`] and [`waitpid`], resolves to the PID and exit status of the
+/// reaped child
+#[derive(Debug)]
+pub struct Wait {
+    parent: Pid,
+    target: Option<Pid>,
+}
+
+impl Future for Wait {
+    type Output = Result<(Pid, Exit), ProcessError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut table = TABLE.lock_sync();
+        match table.reap(self.parent, self.target) {
+            Ok(Some(exited)) => Poll::Ready(Ok(exited)),
+            Err(e) => Poll::Ready(Err(e)),
+            Ok(None) => {
+                // registered under the lock, so an exit can't be missed
+                table
+                    .waiters
+                    .entry(self.parent)
+                    .or_default()
+                    .push(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+pub fn info(pid: Pid) -> Option<ProcessInfo> {
+    let table = TABLE.lock_sync();
+    table.entries.get(&pid).map(|entry| entry.info(pid))
+}
+
+/// All processes in the table, including zombies
+pub fn list() -> Vec<ProcessInfo> {
+    let table = TABLE.lock_sync();
+    table
+        .entries
+        .iter()
+        .map(|(&pid, entry)| entry.info(pid))
+        .collect()
+}
+
+/// Runs `f` with the handles of `pid`, `None` if there is no such process.
+pub fn with_handles<R>(pid: Pid, f: impl FnOnce(&mut Handles) -> R) -> Option<R> {
+    let mut table = TABLE.lock_sync();
+    table
+        .entries
+        .get_mut(&pid)
+        .map(|entry| f(&mut entry.handles))
+}
+
+/// The handle `fd` of the current process. User code running outside of a process only has
+/// the console.
+pub fn handle(fd: u32) -> Option<Handle> {
+    match current() {
+        Some(pid) => with_handles(pid, |handles| handles.get(fd).cloned()).flatten(),
+        None => Handles::console().get(fd).cloned(),
+    }
+}
@@ -0,0 +1,112 @@
+//! Random numbers
+//!
+//! [`entropy`] reads the CPU's hardware random number generator (`RDSEED`, then `RDRAND`) and
+//! falls back to timing jitter of the TSC on CPUs that have neither. [`u64`] and [`fill`]
+//! hand out numbers from the hardware generator if available, otherwise from a generator
+//! seeded with the jitter. None of this is meant for cryptography.
+
+use core::arch::{asm, x86_64::_rdtsc};
+
+use crate::{
+    cpu::{self, Features},
+    util::Spinlock,
+};
+
+/// How often to retry the hardware generators, which may fail when drained
+const RETRIES: usize = 16;
+
+/// SplitMix64 state for CPUs without a hardware generator, 0 until seeded
+static STATE: Spinlock<u64> = Spinlock::new(0);
+
+fn rdseed() -> Option<u64> {
+    for _ in 0..RETRIES {
+        let value: u64;
+        let ok: u8;
+        unsafe {
+            asm!("rdseed {}", "setc {}", out(reg) value, out(reg_byte) ok, options(nomem, nostack))
+        };
+        if ok == 1 {
+            return Some(value);
+        }
+        core::hint::spin_loop();
+    }
+    None
+}
+
+fn rdrand() -> Option<u64> {
+    for _ in 0..RETRIES {
+        let value: u64;
+        let ok: u8;
+        unsafe {
+            asm!("rdrand {}", "setc {}", out(reg) value, out(reg_byte) ok, options(nomem, nostack))
+        };
+        if ok == 1 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Random bits from the CPU, if it has a generator
+pub fn hardware() -> Option<u64> {
+    let seed = if cpu::has(Features::RDSEED) {
+        rdseed()
+    } else {
+        None
+    };
+    seed.or_else(|| cpu::has(Features::RDRAND).then(rdrand).flatten())
+}
+
+/// Collects entropy from how long a short, memory touching loop takes, measured with the TSC.
+fn tsc_jitter() -> u64 {
+    let mut scratch = [0u64; 64];
+    let mut result = 0u64;
+    for round in 0..64 {
+        let start = unsafe { _rdtsc() };
+        for (i, s) in scratch.iter_mut().enumerate() {
+            *s = core::hint::black_box(s.wrapping_add(i as u64 ^ round));
+        }
+        let delta = unsafe { _rdtsc() } - start;
+        result = result.rotate_left(7) ^ delta;
+    }
+    mix(result ^ unsafe { _rdtsc() })
+}
+
+/// SplitMix64 finalizer
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// 64 bits that are hard to predict, for seeding and address space layout randomization
+pub fn entropy() -> u64 {
+    hardware().unwrap_or_else(tsc_jitter)
+}
+
+/// A random number
+pub fn u64() -> u64 {
+    if let Some(value) = hardware() {
+        return value;
+    }
+
+    let mut state = STATE.lock_sync();
+    if *state == 0 {
+        *state = tsc_jitter() | 1;
+    }
+    *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    mix(*state)
+}
+
+/// A random number in `0..bound`, `bound` must not be 0
+pub fn below(bound: u64) -> u64 {
+    // the modulo bias is negligible for the small bounds this is used with
+    u64() % bound
+}
+
+/// Fills `buf` with random bytes.
+pub fn fill(buf: &mut [u8]) {
+    for chunk in buf.chunks_mut(8) {
+        chunk.copy_from_slice(&u64().to_ne_bytes()[..chunk.len()]);
+    }
+}
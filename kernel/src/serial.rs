@@ -3,9 +3,14 @@
 //! The kernel uses the serial port to print the [kernel buffer](`crate::kbuf`).
 
 use uart_16550::SerialPort;
+use x86_64::instructions::{interrupts, port::Port};
 
 use crate::util::Spinlock;
 
+/// I/O port of COM1
+const DATA: u16 = 0x3f8;
+const LINE_STATUS: u16 = DATA + 5;
+
 static SERIAL: Spinlock<Serial> = Spinlock::new(Serial::new());
 
 /// # Safety
@@ -16,6 +21,33 @@ pub(crate) unsafe fn force_unlock() {
     SERIAL.force_unlock();
 }
 
+/// Writes `bytes` to the serial port as they are.
+pub fn write_bytes(bytes: &[u8]) {
+    interrupts::without_interrupts(|| {
+        let mut serial = SERIAL.lock_sync();
+        for &byte in bytes {
+            serial.send_raw(byte);
+        }
+    });
+}
+
+/// Reads the bytes the serial port has received into `buf`, without waiting for more. Returns
+/// the number of bytes read.
+pub fn read_available(buf: &mut [u8]) -> usize {
+    interrupts::without_interrupts(|| {
+        let mut serial = SERIAL.lock_sync();
+        let mut read = 0;
+        while read < buf.len() {
+            match serial.try_receive() {
+                Some(byte) => buf[read] = byte,
+                None => break,
+            }
+            read += 1;
+        }
+        read
+    })
+}
+
 pub struct Serial {
     port: SerialPort,
 }
@@ -23,13 +55,34 @@ pub struct Serial {
 impl Serial {
     pub const fn new() -> Self {
         Self {
-            port: unsafe { SerialPort::new(0x3f8) },
+            port: unsafe { SerialPort::new(DATA) },
         }
     }
 
     pub fn write(&mut self, c: char) {
         self.port.send(c as u8);
     }
+
+    /// Sends `byte` without the translation of backspace `send` does
+    pub fn send_raw(&mut self, byte: u8) {
+        const TRANSMIT_EMPTY: u8 = 1 << 5;
+
+        while self.line_status() & TRANSMIT_EMPTY == 0 {
+            core::hint::spin_loop();
+        }
+        unsafe { Port::new(DATA).write(byte) };
+    }
+
+    /// The next received byte, if there is one
+    pub fn try_receive(&mut self) -> Option<u8> {
+        const DATA_READY: u8 = 1 << 0;
+
+        (self.line_status() & DATA_READY != 0).then(|| unsafe { Port::new(DATA).read() })
+    }
+
+    fn line_status(&mut self) -> u8 {
+        unsafe { Port::new(LINE_STATUS).read() }
+    }
 }
 
 impl core::fmt::Write for Serial {
@@ -47,7 +100,6 @@ unsafe impl Sync for Serial {}
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
 
     interrupts::without_interrupts(|| {
         SERIAL.lock_sync().write_fmt(args).expect("print failed");
@@ -1,20 +1,32 @@
-use core::sync::atomic::AtomicBool;
-
-pub static AP_READY: AtomicBool = AtomicBool::new(false);
+use super::CpuState;
 
 core::arch::global_asm!(include_str!("ap_startup.s"));
 
 #[no_mangle]
 pub extern "C" fn kernel_ap_main() -> ! {
-    crate::gdt::init_ap();
-    crate::interrupts::init_ap();
-
     let trampoline = unsafe { &*(super::TRAMPOLINE as *const super::ApTrampoline) };
+    let id = trampoline.ap_id;
+    let cpu = super::cpu(id).expect("AP started with unknown cpu id");
+
+    // the BSP may reuse the trampoline for the next AP from here on
+    if !cpu.transition(CpuState::Starting, CpuState::Started) {
+        super::park();
+    }
+
+    crate::cpu::init(id);
+    crate::gdt::init_ap(id);
+    crate::interrupts::init_ap();
+    crate::mem::protection::enable();
+    crate::mem::pat::init();
+    crate::mem::address_space::init();
+    crate::fpu::init();
 
     #[cfg(feature = "dbg-smp")]
-    log::debug!("hello from AP CPU {}", trampoline.ap_id);
+    log::debug!("hello from AP CPU {}", id);
 
-    AP_READY.store(true, core::sync::atomic::Ordering::SeqCst);
+    if !cpu.transition(CpuState::Started, CpuState::Online) {
+        super::park();
+    }
 
-    crate::task::executor::schedule(trampoline.ap_id);
+    crate::task::executor::schedule(id);
 }
@@ -12,131 +12,344 @@
 //! 1. The AP startup code is written to the physical address `0x10000`, copied from the kernel section `.text.init`.
 //! see [`ap_startup.s`](/src/ak_os_kernel/smp/ap_startup.s) for the startup code.
 //! 2. The BSP writes the trampoline data to the AP startup address `0xF000` which tells the AP where to put its stack and where the rust entry point is.
-//! 3. The BSP sends and INIT IPI, then two SIPI IPIs to the AP.
+//! 3. The BSP sends and INIT IPI, then up to two SIPI IPIs to the AP, addressed by its local APIC ID.
+//! CPUs beyond [`MAX_CPUS`] are never started.
 //! 4. The AP starts executing the startup code at `0x10000`, sets up paging, long mode, then jumps into rust code.
-//! 5. The AP signals the BSP that is is done initializing, while the BSP waits.
-//! 6. The BSP continues with the next AP, back to step 2.
-//! 7. Once all APs have been started, they get scheduled to run in the [Executor](crate::task::executor::Executor).
+//! 5. The AP copies what it needs out of the trampoline and marks itself [`CpuState::Started`].
+//! From this point the trampoline can be reused, so the BSP continues with the next AP (back to step 2)
+//! while this one finishes its own initialization in parallel.
+//! 6. The AP marks itself [`CpuState::Online`] once it is ready to run tasks.
+//! 7. The BSP waits for every started AP to come online. CPUs that don't respond in time are
+//! marked [`CpuState::Failed`], the rest of the system keeps running without them. The ones that never reached rust code
+//! are sent back to the wait-for-SIPI state, the others may hold locks and park themselves once they are done initializing.
+//! 8. Once all APs have been started, they get scheduled to run in the [Executor](crate::task::executor::Executor).
 
 use crate::{
-    mem::{AlignedAlloc, MemoryManager},
+    cpu::Features,
+    mem::{
+        pat::CacheMode,
+        stack::{self, StackKind},
+        MemoryManager,
+    },
     pit::pit_wait,
 };
-use acpi::{
-    platform::{Processor, ProcessorState},
-    AcpiError, AcpiTables,
-};
+use acpi::{platform::ProcessorState, AcpiError, AcpiTables};
 use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use crossbeam_utils::atomic::AtomicCell;
 use x86_64::{
     instructions::interrupts::without_interrupts,
+    registers::model_specific::Msr,
     structures::paging::{mapper::MapToError, PageSize, PageTableFlags, Size4KiB},
     PhysAddr, VirtAddr,
 };
 
 mod ap_startup;
 
+/// Most CPUs the kernel runs on, per CPU data is kept in arrays of this size indexed by
+/// [`Cpu::id`]. Further CPUs are left offline.
+pub const MAX_CPUS: usize = 32;
+
 const AP_STARTUP_DEST: u32 = 0x10000;
 const TRAMPOLINE: u32 = AP_STARTUP_DEST - Size4KiB::SIZE as u32;
+const AP_STACK_PAGES: u64 = 16;
+
+/// How many times we poll an AP after each SIPI, with [`SIPI_POLL_INTERVAL_US`] between polls.
+const SIPI_POLL_COUNT: usize = 10;
+const SIPI_POLL_INTERVAL_US: u32 = 200;
+/// How long the BSP waits for all started APs to come online, in timer ticks.
+const ONLINE_TIMEOUT_TICKS: u64 = 100;
+
+const IA32_APIC_BASE: u32 = 0x1B;
+const IA32_APIC_BASE_X2APIC_ENABLE: u64 = 1 << 10;
 
 extern "C" {
     static _init_section_start: u8;
     static _init_section_end: u8;
 }
 
+static CPUS: OnceCell<Vec<Cpu>> = OnceCell::uninit();
+
+/// Startup state of a CPU
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuState {
+    /// The CPU is disabled by the firmware or hasn't been started yet.
+    Offline,
+    /// INIT and SIPI IPIs have been sent, the BSP is waiting for the CPU to pick up the trampoline.
+    Starting,
+    /// The CPU has read the trampoline and is initializing itself.
+    Started,
+    /// The CPU is fully initialized and runs tasks.
+    Online,
+    /// The CPU failed to start in time and has been parked.
+    Failed,
+}
+
+/// A logical CPU in the system, as reported by ACPI
+#[derive(Debug)]
+pub struct Cpu {
+    /// Logical index of the CPU, the BSP is always `0`.
+    pub id: u32,
+    pub processor_uid: u32,
+    pub local_apic_id: u32,
+    pub is_bsp: bool,
+    state: AtomicCell<CpuState>,
+}
+
+impl Cpu {
+    pub fn state(&self) -> CpuState {
+        self.state.load()
+    }
+
+    fn set_state(&self, state: CpuState) {
+        self.state.store(state);
+    }
+
+    /// Moves the CPU from state `from` to `to`, unless another CPU changed its state first.
+    fn transition(&self, from: CpuState, to: CpuState) -> bool {
+        self.state.compare_exchange(from, to).is_ok()
+    }
+}
+
+/// Returns every CPU known to the kernel, indexed by their logical ID.
+///
+/// This is empty if SMP was not initialized.
+pub fn cpus() -> &'static [Cpu] {
+    CPUS.get().map(|c| c.as_slice()).unwrap_or(&[])
+}
+
+/// Returns the CPU with the given logical ID.
+pub fn cpu(id: u32) -> Option<&'static Cpu> {
+    cpus().get(id as usize)
+}
+
+/// Returns the number of CPUs that are up and running tasks, including the BSP.
+pub fn online_count() -> usize {
+    cpus()
+        .iter()
+        .filter(|c| c.state() == CpuState::Online)
+        .count()
+        .max(1)
+}
+
 pub fn init(acpi_tables: &AcpiTables<MemoryManager>) -> Result<(), AcpiError> {
     let platform_info = acpi_tables.platform_info()?;
     let cpu_info = platform_info.processor_info.expect("no processor info");
 
+    CPUS.init_once(|| {
+        let bsp = &cpu_info.boot_processor;
+        let mut cpus = Vec::with_capacity(cpu_info.application_processors.len() + 1);
+        cpus.push(Cpu {
+            id: 0,
+            processor_uid: bsp.processor_uid,
+            local_apic_id: bsp.local_apic_id,
+            is_bsp: true,
+            state: AtomicCell::new(CpuState::Online),
+        });
+        for (i, ap) in cpu_info.application_processors.iter().enumerate() {
+            cpus.push(Cpu {
+                id: i as u32 + 1,
+                processor_uid: ap.processor_uid,
+                local_apic_id: ap.local_apic_id,
+                is_bsp: false,
+                state: AtomicCell::new(CpuState::Offline),
+            });
+        }
+        cpus
+    });
+
     if cpu_info.application_processors.is_empty() {
         log::info!("system is single-processor, not starting additional cpus");
         return Ok(());
     }
 
     log::debug!(
-        "system BSP cpu is {}, starting {} AP cpus",
+        "system BSP cpu is {} (apic id {}), starting {} AP cpus",
         cpu_info.boot_processor.processor_uid,
+        cpu_info.boot_processor.local_apic_id,
         cpu_info.application_processors.len()
     );
 
     copy_init();
     copy_trampoline();
 
-    for ap in cpu_info.application_processors.iter() {
+    for (ap, cpu) in cpu_info
+        .application_processors
+        .iter()
+        .zip(cpus().iter().skip(1))
+    {
+        if cpu.id as usize >= MAX_CPUS {
+            log::warn!(
+                "cpu {} is beyond the {} supported cpus, leaving it offline",
+                ap.processor_uid,
+                MAX_CPUS
+            );
+            continue;
+        }
         match ap.state {
             ProcessorState::Disabled => log::warn!("cpu {} is disabled", ap.processor_uid),
             ProcessorState::WaitingForSipi => {
                 #[cfg(feature = "dbg-smp")]
                 log::debug!("cpu {} is waiting for SIPI", ap.processor_uid);
-                init_ap(ap);
+                init_ap(cpu);
             }
             ProcessorState::Running => log::warn!("cpu {} is already running", ap.processor_uid),
         }
     }
+
+    wait_for_online();
+
+    log::info!("{} out of {} cpus online", online_count(), cpus().len());
     Ok(())
 }
 
-fn init_ap(ap: &Processor) {
-    let dest = ap.processor_uid << 24;
+/// Returns whether the local APIC of the current CPU runs in x2APIC mode.
+fn x2apic_enabled() -> bool {
+    let apic_base = unsafe { Msr::new(IA32_APIC_BASE).read() };
+    apic_base & IA32_APIC_BASE_X2APIC_ENABLE != 0
+}
 
-    setup_trampoline(ap);
+/// Converts a local APIC ID to the destination field expected by [`x2apic::lapic::LocalApic`].
+///
+/// In xAPIC mode the destination lives in the top byte of the ICR high dword, so only IDs up
+/// to 255 can be addressed. In x2APIC mode the full 32-bit ID is used as is.
+fn ipi_destination(local_apic_id: u32) -> Option<u32> {
+    if crate::cpu::has(Features::X2APIC) && x2apic_enabled() {
+        Some(local_apic_id)
+    } else if local_apic_id <= 0xFF {
+        Some(local_apic_id << 24)
+    } else {
+        None
+    }
+}
 
-    // send INIT IPI
+fn send_init_ipi(dest: u32) {
     without_interrupts(|| {
         let mut lapic = crate::interrupts::LAPIC
             .get()
             .expect("LAPIC not initialized on BSP")
             .lock_sync();
         unsafe {
-            #[cfg(feature = "dbg-smp")]
-            log::trace!("INIT IPI to cpu {}", ap.processor_uid);
-
             // vector can be anything, it is ignored
             lapic.send_init_ipi(dest);
         }
     });
+}
+
+fn send_sipi(dest: u32) {
+    without_interrupts(|| {
+        let mut lapic = crate::interrupts::LAPIC
+            .get()
+            .expect("LAPIC not initialized on BSP")
+            .lock_sync();
+        unsafe {
+            let vector = (AP_STARTUP_DEST >> 12) & 0xFF;
+            lapic.send_sipi(vector as u8, dest);
+        }
+    });
+}
+
+/// Starts a single AP and waits until it has picked up the trampoline.
+///
+/// The rest of the AP initialization happens in parallel, see [`wait_for_online`].
+fn init_ap(cpu: &Cpu) {
+    let dest = match ipi_destination(cpu.local_apic_id) {
+        Some(dest) => dest,
+        None => {
+            log::error!(
+                "cpu {} has apic id {} which needs x2APIC mode, leaving it offline",
+                cpu.processor_uid,
+                cpu.local_apic_id
+            );
+            cpu.set_state(CpuState::Failed);
+            return;
+        }
+    };
+
+    setup_trampoline(cpu);
+    cpu.set_state(CpuState::Starting);
+
+    #[cfg(feature = "dbg-smp")]
+    log::trace!("INIT IPI to cpu {}", cpu.processor_uid);
+
+    send_init_ipi(dest);
     pit_wait(10_000).expect("failed to wait for INIT IPI");
 
     // send SIPI twice
     for _ in 1..=2 {
-        without_interrupts(|| {
-            let mut lapic = crate::interrupts::LAPIC
-                .get()
-                .expect("LAPIC not initialized on BSP")
-                .lock_sync();
-            unsafe {
-                let vector = (AP_STARTUP_DEST >> 12) & 0xFF;
+        #[cfg(feature = "dbg-smp")]
+        log::trace!(
+            "SIPI to AP#{} (apic id {})",
+            cpu.processor_uid,
+            cpu.local_apic_id
+        );
 
-                #[cfg(feature = "dbg-smp")]
-                log::trace!(
-                    "SIPI to AP#{} with vector 0x{:x}",
-                    ap.processor_uid,
-                    vector as u8
-                );
-
-                lapic.send_sipi(vector as u8, dest);
-            }
-        });
+        send_sipi(dest);
 
-        for _ in 1..=10 {
-            if ap_startup::AP_READY.load(core::sync::atomic::Ordering::SeqCst) {
-                pit_wait(300).expect("failed to wait for SIPI");
-                ap_startup::AP_READY.store(false, core::sync::atomic::Ordering::SeqCst);
+        for _ in 1..=SIPI_POLL_COUNT {
+            if cpu.state() != CpuState::Starting {
                 return;
             }
-            pit_wait(200).expect("failed to wait for SIPI");
+            pit_wait(SIPI_POLL_INTERVAL_US).expect("failed to wait for SIPI");
         }
     }
 
-    for _ in 1..=10 {
-        if ap_startup::AP_READY.load(core::sync::atomic::Ordering::SeqCst) {
-            crate::time::sleep_sync(10);
-            ap_startup::AP_READY.store(false, core::sync::atomic::Ordering::SeqCst);
-            return;
+    if park_ap(cpu, dest) {
+        log::error!(
+            "AP#{} (apic id {}) failed to start, marking it offline",
+            cpu.processor_uid,
+            cpu.local_apic_id
+        );
+    }
+}
+
+/// Waits until every started AP is online, parking the ones that time out.
+fn wait_for_online() {
+    let start = crate::time::boot_elapsed();
+    let pending = || {
+        cpus()
+            .iter()
+            .any(|c| matches!(c.state(), CpuState::Starting | CpuState::Started))
+    };
+    while pending() && crate::time::boot_elapsed() < start + ONLINE_TIMEOUT_TICKS {
+        core::hint::spin_loop();
+    }
+
+    for cpu in cpus()
+        .iter()
+        .filter(|c| matches!(c.state(), CpuState::Starting | CpuState::Started))
+    {
+        let Some(dest) = ipi_destination(cpu.local_apic_id) else {
+            continue;
+        };
+        if park_ap(cpu, dest) {
+            log::error!(
+                "AP#{} (apic id {}) did not come online in time, marking it offline",
+                cpu.processor_uid,
+                cpu.local_apic_id
+            );
         }
-        crate::time::sleep_sync(1);
     }
+}
+
+/// Gives up on an AP, returns `false` if it came online meanwhile.
+///
+/// An AP that hasn't picked up the trampoline yet is sent back to the wait-for-SIPI state, so
+/// it can't pick up one meant for another CPU later on. One that is already initializing
+/// itself may hold locks an INIT would leave locked forever, it parks itself when it sees it
+/// was given up on, see [`park`].
+fn park_ap(cpu: &Cpu, dest: u32) -> bool {
+    if cpu.transition(CpuState::Starting, CpuState::Failed) {
+        send_init_ipi(dest);
+        return true;
+    }
+    cpu.transition(CpuState::Started, CpuState::Failed)
+}
 
-    panic!("AP#{} failed to start", ap.processor_uid);
+/// Stops the current AP for good, after the BSP gave up on it.
+fn park() -> ! {
+    x86_64::instructions::interrupts::disable();
+    crate::halt()
 }
 
 fn copy_init() {
@@ -150,6 +363,7 @@ fn copy_init() {
     if let Err(e) = mm.identity_map_range(
         range,
         Some(PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::GLOBAL),
+        CacheMode::WriteBack,
     ) {
         match e {
             MapToError::PageAlreadyMapped(_) => {
@@ -176,7 +390,7 @@ fn copy_init() {
 
 fn copy_trampoline() {
     let mm = crate::mem::get_memory_manager();
-    if let Err(e) = mm.identity_map_address(TRAMPOLINE as u64, None) {
+    if let Err(e) = mm.identity_map_address(TRAMPOLINE as u64, None, CacheMode::WriteBack) {
         match e {
             MapToError::PageAlreadyMapped(_) => {
                 log::warn!("AP trampoline already mapped, skipping");
@@ -197,13 +411,15 @@ fn copy_trampoline() {
     }
 
     // temporary GDT
-    mm.identity_map_address(0x800, None).unwrap();
+    mm.identity_map_address(0x800, None, CacheMode::WriteBack)
+        .unwrap();
 }
 
 #[derive(Debug, Clone)]
 #[repr(C)]
 struct ApTrampoline {
-    ap_id: u8,
+    /// Logical CPU ID, see [`Cpu::id`]
+    ap_id: u32,
     ap_page_table: PhysAddr,
     ap_stack_start: VirtAddr,
     ap_stack_end: VirtAddr,
@@ -221,20 +437,16 @@ impl Default for ApTrampoline {
     }
 }
 
-fn setup_trampoline(ap: &Processor) {
+fn setup_trampoline(cpu: &Cpu) {
     let mm = crate::mem::get_memory_manager();
 
-    let stack = {
-        const STACK_SIZE: usize = 4096 * 16;
-        let mut v = Vec::with_capacity_in(STACK_SIZE, AlignedAlloc::<4096>);
-        v.resize(STACK_SIZE, 0);
-        Vec::leak::<'static>(v)
-    };
-    let ap_stack_start = VirtAddr::new(stack.as_ptr() as u64);
-    let ap_stack_end = ap_stack_start + stack.len() as u64;
+    let stack = stack::allocate(cpu.id, StackKind::Kernel, AP_STACK_PAGES)
+        .unwrap_or_else(|e| panic!("failed to allocate stack for AP#{}: {:?}", cpu.id, e));
+    let ap_stack_start = stack.bottom();
+    let ap_stack_end = stack.top();
 
     let tmp_trampoline = ApTrampoline {
-        ap_id: ap.processor_uid as u8,
+        ap_id: cpu.id,
         ap_page_table: mm.lvl4_table_addr(),
         ap_stack_start,
         ap_stack_end,
@@ -0,0 +1,211 @@
+use alloc::{boxed::Box, string::String, sync::Arc, vec};
+use core::task::{Context, Poll};
+use futures_util::task::noop_waker_ref;
+use x86_64::{
+    align_down, align_up,
+    structures::paging::{Page, PageSize, Size4KiB},
+    VirtAddr,
+};
+
+use super::{Syscall, SyscallError, SyscallFrame, SyscallFuture};
+use crate::{
+    mem::{address_space, user_copy, vma::VmaFlags},
+    process::{self, Handle, Pid},
+    user::{self, Exit},
+    vfs::{self, OpenFile, OpenFlags, SeekFrom},
+};
+
+/// At most this many bytes are read or written at once, larger transfers are short
+const MAX_TRANSFER: u64 = 4096;
+/// Longest path `open` accepts
+const MAX_PATH: u64 = 4096;
+
+pub(super) fn handle(
+    frame: &SyscallFrame,
+    syscall: Syscall,
+    args: [u64; 6],
+) -> Result<u64, SyscallError> {
+    match syscall {
+        Syscall::Write => write(frame, args[0], args[1], args[2]),
+        Syscall::Exit => exit(args[0]),
+        Syscall::Sleep => sleep(frame, args[0]),
+        Syscall::Mmap => mmap(args[0], args[1], args[2]),
+        Syscall::Yield => Ok(0),
+        Syscall::GetPid => Ok(process::current().unwrap_or(Pid::KERNEL).0),
+        Syscall::GetPpid => Ok(parent().0),
+        Syscall::Read => read(frame, args[0], args[1], args[2]),
+        Syscall::Open => open(frame, args[0], args[1], args[2]),
+        Syscall::Close => close(args[0]),
+        Syscall::Seek => seek(frame, args[0], args[1] as i64, args[2]),
+        Syscall::Ioctl => ioctl(frame, args[0], args[1], args[2]),
+    }
+}
+
+/// Completes `call` right away if it's ready, otherwise the program is suspended until it is,
+/// see [`user::suspend`].
+fn wait(frame: &SyscallFrame, mut call: SyscallFuture) -> Result<u64, SyscallError> {
+    let mut context = Context::from_waker(noop_waker_ref());
+    match call.as_mut().poll(&mut context) {
+        Poll::Ready(result) => result,
+        Poll::Pending => user::suspend(frame.registers(), call),
+    }
+}
+
+fn handle_of(fd: u64) -> Result<Handle, SyscallError> {
+    u32::try_from(fd)
+        .ok()
+        .and_then(process::handle)
+        .ok_or(SyscallError::BadFileDescriptor)
+}
+
+fn file(fd: u64) -> Result<Arc<OpenFile>, SyscallError> {
+    match handle_of(fd)? {
+        Handle::File(file) => Ok(file),
+        _ => Err(SyscallError::NotSupported),
+    }
+}
+
+fn write(frame: &SyscallFrame, fd: u64, buf: u64, len: u64) -> Result<u64, SyscallError> {
+    let handle = handle_of(fd)?;
+    let buf = VirtAddr::try_new(buf).map_err(|_| SyscallError::BadAddress)?;
+    let len = len.min(MAX_TRANSFER);
+
+    let mut bytes = vec![0; len as usize];
+    user_copy::copy_from_user(&mut bytes, buf)?;
+    match handle {
+        Handle::Console => {
+            crate::print!("{}", String::from_utf8_lossy(&bytes));
+            Ok(len)
+        }
+        Handle::File(file) => wait(
+            frame,
+            Box::pin(async move { Ok(file.write(&bytes).await? as u64) }),
+        ),
+    }
+}
+
+fn read(frame: &SyscallFrame, fd: u64, buf: u64, len: u64) -> Result<u64, SyscallError> {
+    let file = file(fd)?;
+    let buf = VirtAddr::try_new(buf).map_err(|_| SyscallError::BadAddress)?;
+    let len = len.min(MAX_TRANSFER) as usize;
+
+    wait(
+        frame,
+        Box::pin(async move {
+            let mut bytes = vec![0; len];
+            let read = file
+                .read_with(&mut bytes, |read| {
+                    user_copy::copy_to_user(buf, read).map_err(SyscallError::from)
+                })
+                .await?;
+            Ok(read as u64)
+        }),
+    )
+}
+
+/// Opens the file at the UTF-8 path of `len` bytes at `path` with [`OpenFlags`] `flags`.
+fn open(frame: &SyscallFrame, path: u64, len: u64, flags: u64) -> Result<u64, SyscallError> {
+    let pid = process::current().ok_or(SyscallError::NoSuchProcess)?;
+    let flags = u32::try_from(flags)
+        .ok()
+        .and_then(OpenFlags::from_bits)
+        .ok_or(SyscallError::InvalidArgument)?;
+    if len > MAX_PATH {
+        return Err(SyscallError::InvalidArgument);
+    }
+    let path = VirtAddr::try_new(path).map_err(|_| SyscallError::BadAddress)?;
+    let mut bytes = vec![0; len as usize];
+    user_copy::copy_from_user(&mut bytes, path)?;
+    let path = String::from_utf8(bytes).map_err(|_| SyscallError::InvalidArgument)?;
+
+    wait(
+        frame,
+        Box::pin(async move {
+            let file = Arc::new(vfs::open(&path, flags).await?);
+            let fd = process::with_handles(pid, |handles| handles.insert(Handle::File(file)))
+                .ok_or(SyscallError::NoSuchProcess)?
+                .ok_or(SyscallError::TooManyOpenFiles)?;
+            Ok(fd as u64)
+        }),
+    )
+}
+
+fn close(fd: u64) -> Result<u64, SyscallError> {
+    let pid = process::current().ok_or(SyscallError::NoSuchProcess)?;
+    let fd = u32::try_from(fd).map_err(|_| SyscallError::BadFileDescriptor)?;
+    // the file is closed once the last handle to it is dropped, outside of the process table
+    let closed = process::with_handles(pid, |handles| handles.remove(fd))
+        .ok_or(SyscallError::NoSuchProcess)?
+        .ok_or(SyscallError::BadFileDescriptor)?;
+    drop(closed);
+    Ok(0)
+}
+
+fn seek(frame: &SyscallFrame, fd: u64, offset: i64, from: u64) -> Result<u64, SyscallError> {
+    let pos = match from {
+        0 => SeekFrom::Start(u64::try_from(offset).map_err(|_| SyscallError::InvalidArgument)?),
+        1 => SeekFrom::Current(offset),
+        2 => SeekFrom::End(offset),
+        _ => return Err(SyscallError::InvalidArgument),
+    };
+    let file = file(fd)?;
+    wait(frame, Box::pin(async move { Ok(file.seek(pos).await?) }))
+}
+
+fn ioctl(frame: &SyscallFrame, fd: u64, request: u64, arg: u64) -> Result<u64, SyscallError> {
+    let file = file(fd)?;
+    wait(
+        frame,
+        Box::pin(async move { Ok(file.ioctl(request, arg).await?) }),
+    )
+}
+
+fn exit(status: u64) -> Result<u64, SyscallError> {
+    user::exit(Exit::Exited(status as i32));
+    unreachable!("exit called without a running user program");
+}
+
+/// Parent of the current process, the kernel for code running outside of a process
+fn parent() -> Pid {
+    process::current()
+        .and_then(process::info)
+        .map_or(Pid::KERNEL, |info| info.parent)
+}
+
+/// Suspends the program for `ticks` timer ticks, the CPU is free for others in the meantime.
+fn sleep(frame: &SyscallFrame, ticks: u64) -> Result<u64, SyscallError> {
+    wait(
+        frame,
+        Box::pin(async move {
+            crate::time::sleep(ticks).await;
+            Ok(0)
+        }),
+    )
+}
+
+/// Maps anonymous memory with the rights in `prot` ([`VmaFlags`]) at `addr`, or anywhere if
+/// `addr` is 0. Pages are populated on first access.
+fn mmap(addr: u64, len: u64, prot: u64) -> Result<u64, SyscallError> {
+    let flags = VmaFlags::from_bits(prot as u8)
+        .filter(|_| prot <= u8::MAX as u64)
+        .ok_or(SyscallError::InvalidArgument)?;
+    let end = addr
+        .checked_add(len)
+        .filter(|&end| end <= address_space::USER_END);
+    if len == 0 || align_down(addr, Size4KiB::SIZE) != addr || end.is_none() {
+        return Err(SyscallError::InvalidArgument);
+    }
+    let count = align_up(len, Size4KiB::SIZE) / Size4KiB::SIZE;
+
+    address_space::with_current(|space| {
+        let pages = if addr == 0 {
+            space.find_free(count).ok_or(SyscallError::OutOfMemory)?
+        } else {
+            let start = Page::containing_address(VirtAddr::new(addr));
+            Page::range(start, start + count)
+        };
+        space.map_anonymous(pages, flags)?;
+        Ok(pages.start.start_address().as_u64())
+    })
+    .unwrap_or(Err(SyscallError::BadAddress))
+}
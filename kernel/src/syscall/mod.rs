@@ -0,0 +1,311 @@
+//! System calls
+//!
+//! User programs enter the kernel with `SYSCALL`, the MSRs for it are set up per CPU by
+//! [`crate::gdt`]. [`entry`] switches to the kernel stack of the CPU, see
+//! [`crate::gdt::set_kernel_stack`], and calls the handler for the number in `rax`. Arguments
+//! are passed in `rdi`, `rsi`, `rdx`, `r10`, `r8` and `r9`, the result is returned in `rax`.
+//! All other registers except `rcx` and `r11`, which `SYSCALL` clobbers, are preserved.
+//!
+//! System calls that have to wait, like reading from a device without data, don't block the
+//! CPU: they suspend the program with [`user::suspend`](crate::user::suspend) and its main
+//! thread awaits them like any other task, see [`crate::process`].
+//!
+//! Negative results are errors, see [`SyscallError`]. The numbers are stable:
+//!
+//! | number | name      | arguments        | result                 |
+//! |--------|-----------|------------------|------------------------|
+//! | 0      | `write`   | fd, buffer, len  | bytes written          |
+//! | 1      | `exit`    | status           | doesn't return         |
+//! | 2      | `sleep`   | timer ticks      | 0                      |
+//! | 3      | `mmap`    | addr, len, prot  | address of the mapping |
+//! | 4      | `yield`   |                  | 0                      |
+//! | 5      | `getpid`  |                  | process ID             |
+//! | 6      | `getppid` |                  | parent's process ID    |
+//! | 7      | `read`    | fd, buffer, len  | bytes read             |
+//! | 8      | `open`    | path, len, flags | file descriptor        |
+//! | 9      | `close`   | fd               | 0                      |
+//! | 10     | `seek`    | fd, offset, from | new offset             |
+//! | 11     | `ioctl`   | fd, request, arg | depends on the request |
+//!
+//! `open` takes the bits of [`OpenFlags`](crate::vfs::OpenFlags), `seek` counts from the start,
+//! the current position or the end for `from` 0, 1 and 2. The requests of `ioctl` are in
+//! [`vfs::ioctl`](crate::vfs::ioctl).
+
+mod handlers;
+
+use alloc::boxed::Box;
+use core::{arch::global_asm, future::Future, pin::Pin};
+use thiserror_no_std::Error;
+
+use crate::{
+    mem::{user_copy::UserCopyError, AddressSpaceError},
+    user::Registers,
+    vfs::FsError,
+};
+
+/// A system call that has to wait, see [`user::suspend`](crate::user::suspend)
+pub type SyscallFuture = Pin<Box<dyn Future<Output = Result<u64, SyscallError>>>>;
+
+/// System call numbers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum Syscall {
+    Write = 0,
+    Exit = 1,
+    Sleep = 2,
+    Mmap = 3,
+    Yield = 4,
+    GetPid = 5,
+    GetPpid = 6,
+    Read = 7,
+    Open = 8,
+    Close = 9,
+    Seek = 10,
+    Ioctl = 11,
+}
+
+impl TryFrom<u64> for Syscall {
+    type Error = SyscallError;
+
+    fn try_from(value: u64) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0 => Syscall::Write,
+            1 => Syscall::Exit,
+            2 => Syscall::Sleep,
+            3 => Syscall::Mmap,
+            4 => Syscall::Yield,
+            5 => Syscall::GetPid,
+            6 => Syscall::GetPpid,
+            7 => Syscall::Read,
+            8 => Syscall::Open,
+            9 => Syscall::Close,
+            10 => Syscall::Seek,
+            11 => Syscall::Ioctl,
+            _ => return Err(SyscallError::NoSuchSyscall),
+        })
+    }
+}
+
+/// Errors returned to user mode as the negated discriminant, which follow Linux' `errno`
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i64)]
+pub enum SyscallError {
+    #[error("no such file or directory")]
+    NotFound = 2,
+    #[error("no such process")]
+    NoSuchProcess = 3,
+    #[error("i/o error")]
+    Io = 5,
+    #[error("bad file descriptor")]
+    BadFileDescriptor = 9,
+    #[error("out of memory")]
+    OutOfMemory = 12,
+    #[error("bad address")]
+    BadAddress = 14,
+    #[error("resource busy")]
+    Busy = 16,
+    #[error("file exists")]
+    AlreadyExists = 17,
+    #[error("cross-device link")]
+    CrossDevice = 18,
+    #[error("not a directory")]
+    NotADirectory = 20,
+    #[error("is a directory")]
+    IsADirectory = 21,
+    #[error("invalid argument")]
+    InvalidArgument = 22,
+    #[error("too many open files")]
+    TooManyOpenFiles = 24,
+    #[error("inappropriate ioctl for device")]
+    UnknownRequest = 25,
+    #[error("no space left on device")]
+    NoSpace = 28,
+    #[error("read-only filesystem")]
+    ReadOnly = 30,
+    #[error("no such system call")]
+    NoSuchSyscall = 38,
+    #[error("directory not empty")]
+    NotEmpty = 39,
+    #[error("too many levels of symbolic links")]
+    TooManyLinks = 40,
+    #[error("operation not supported")]
+    NotSupported = 95,
+}
+
+impl SyscallError {
+    /// The value returned in `rax`
+    pub fn code(self) -> i64 {
+        -(self as i64)
+    }
+}
+
+/// The value returned in `rax` for `result`
+pub fn result_value(result: Result<u64, SyscallError>) -> i64 {
+    match result {
+        Ok(value) => value as i64,
+        Err(e) => e.code(),
+    }
+}
+
+impl From<UserCopyError> for SyscallError {
+    fn from(_: UserCopyError) -> Self {
+        SyscallError::BadAddress
+    }
+}
+
+impl From<AddressSpaceError> for SyscallError {
+    fn from(e: AddressSpaceError) -> Self {
+        match e {
+            AddressSpaceError::OutOfMemory => SyscallError::OutOfMemory,
+            _ => SyscallError::InvalidArgument,
+        }
+    }
+}
+
+impl From<FsError> for SyscallError {
+    fn from(e: FsError) -> Self {
+        match e {
+            FsError::NotFound => SyscallError::NotFound,
+            FsError::NotADirectory => SyscallError::NotADirectory,
+            FsError::IsADirectory => SyscallError::IsADirectory,
+            FsError::ReadOnly => SyscallError::ReadOnly,
+            FsError::NotSupported => SyscallError::NotSupported,
+            FsError::InvalidArgument | FsError::NotMounted => SyscallError::InvalidArgument,
+            FsError::AccessDenied => SyscallError::BadFileDescriptor,
+            FsError::TooManyLinks => SyscallError::TooManyLinks,
+            FsError::AlreadyMounted | FsError::Busy => SyscallError::Busy,
+            FsError::Io => SyscallError::Io,
+            FsError::AlreadyExists => SyscallError::AlreadyExists,
+            FsError::NotEmpty => SyscallError::NotEmpty,
+            FsError::NoSpace => SyscallError::NoSpace,
+            FsError::CrossDevice => SyscallError::CrossDevice,
+            FsError::UnknownRequest => SyscallError::UnknownRequest,
+        }
+    }
+}
+
+/// User registers saved by [`entry`], in the order they are pushed onto the kernel stack
+#[repr(C)]
+#[derive(Debug)]
+pub struct SyscallFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub rbp: u64,
+    pub rbx: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub r10: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    /// The system call number
+    pub rax: u64,
+    /// Return address, saved in `rcx` by `SYSCALL`
+    pub rip: u64,
+    /// Saved in `r11` by `SYSCALL`
+    pub rflags: u64,
+    pub rsp: u64,
+}
+
+impl SyscallFrame {
+    /// The registers of the program, as `SYSRET` would return to it
+    pub fn registers(&self) -> Registers {
+        Registers {
+            rax: self.rax,
+            rbx: self.rbx,
+            rcx: self.rip,
+            rdx: self.rdx,
+            rsi: self.rsi,
+            rdi: self.rdi,
+            rbp: self.rbp,
+            r8: self.r8,
+            r9: self.r9,
+            r10: self.r10,
+            r11: self.rflags,
+            r12: self.r12,
+            r13: self.r13,
+            r14: self.r14,
+            r15: self.r15,
+            rip: self.rip,
+            rflags: self.rflags,
+            rsp: self.rsp,
+        }
+    }
+}
+
+extern "sysv64" {
+    /// Entry point of `SYSCALL`, its address is written to `IA32_LSTAR`
+    #[link_name = "syscall_entry"]
+    pub fn entry();
+}
+
+// Interrupts are masked by `IA32_FMASK` until the stack is switched. `gs` points to the CPU's
+// system call stack while swapped, see `gdt::SyscallStack`, the kernel doesn't use `gs`
+// otherwise.
+global_asm!(
+    r#"
+.global syscall_entry
+syscall_entry:
+    swapgs
+    mov gs:[8], rsp
+    mov rsp, gs:[0]
+    push qword ptr gs:[8]
+    swapgs
+
+    push r11
+    push rcx
+    push rax
+    push rdi
+    push rsi
+    push rdx
+    push r10
+    push r8
+    push r9
+    // the rest is only needed to suspend the program
+    push rbx
+    push rbp
+    push r12
+    push r13
+    push r14
+    push r15
+
+    mov rdi, rsp
+    sti
+    call {dispatch}
+    cli
+
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbp
+    pop rbx
+    pop r9
+    pop r8
+    pop r10
+    pop rdx
+    pop rsi
+    pop rdi
+    add rsp, 8
+    pop rcx
+    pop r11
+    pop rsp
+    sysretq
+"#,
+    dispatch = sym dispatch
+);
+
+extern "sysv64" fn dispatch(frame: &mut SyscallFrame) -> i64 {
+    let args = [
+        frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8, frame.r9,
+    ];
+    let result = Syscall::try_from(frame.rax).and_then(|syscall| {
+        #[cfg(feature = "dbg-syscall")]
+        log::trace!("syscall {:?}{:x?}", syscall, args);
+
+        handlers::handle(frame, syscall, args)
+    });
+    result_value(result)
+}
@@ -8,7 +8,7 @@
 use crate::util::Spinlock;
 
 use super::{Task, TaskId};
-use alloc::{collections::BTreeMap, sync::Arc, task::Wake};
+use alloc::{collections::BTreeMap, string::String, sync::Arc, task::Wake, vec::Vec};
 use conquer_once::spin::OnceCell;
 use core::{
     fmt::Debug,
@@ -24,22 +24,20 @@ static mut EXECUTOR: OnceCell<Executor> = OnceCell::uninit();
 
 /// This should be called from the main thread to initialize the kernel executor.
 pub fn run() -> ! {
-    unsafe {
-        EXECUTOR
-            .try_init_once(|| {
-                let executor = Executor::default();
-                executor.spawn(Task::new_with_name("logger", super::logger::process()));
-                executor.spawn(Task::new_with_name("keyboard", super::keyboard::process()));
-                executor.spawn(Task::new_with_name("mouse", super::mouse::process()));
-                executor
-            })
-            .expect("executor already initialized");
-        EXECUTOR.get().unwrap().run()
-    }
+    assert!(!running(), "executor already running");
+    spawn(Task::new_with_name("logger", super::logger::process()));
+    spawn(Task::new_with_name("keyboard", super::keyboard::process()));
+    spawn(Task::new_with_name("mouse", super::mouse::process()));
+    unsafe { EXECUTOR.get().unwrap().run() }
+}
+
+/// Spawns `task` on the kernel executor. Tasks spawned before [`run`] start with it.
+pub fn spawn(task: Task) {
+    unsafe { EXECUTOR.get_or_init(Executor::default).spawn(task) }
 }
 
 /// This should be called from additional cores to signal that they are ready to run tasks.
-pub fn schedule(id: u8) -> ! {
+pub fn schedule(id: u32) -> ! {
     while !CAN_SCHEDULE.load(core::sync::atomic::Ordering::SeqCst) {
         core::hint::spin_loop()
     }
@@ -51,56 +49,116 @@ pub fn schedule(id: u8) -> ! {
     }
 }
 
+/// The tasks of the kernel executor, empty before anything was spawned
+pub fn tasks() -> Vec<TaskInfo> {
+    unsafe {
+        EXECUTOR
+            .try_get()
+            .map_or_else(|_| Vec::new(), Executor::tasks)
+    }
+}
+
 pub fn running() -> bool {
     CAN_SCHEDULE.load(core::sync::atomic::Ordering::SeqCst)
 }
 
+/// A task that has not finished yet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TaskInfo {
+    pub id: u64,
+    pub name: Option<String>,
+}
+
 #[derive(Debug)]
 pub struct Executor {
-    tasks: Spinlock<BTreeMap<TaskId, Task>>,
+    tasks: Spinlock<BTreeMap<TaskId, Entry>>,
     task_queue: Arc<ArrayQueue<TaskId>>,
     waker_cache: Spinlock<BTreeMap<TaskId, Waker>>,
 }
 
+/// A task of an [`Executor`]
+///
+/// The task is taken out while it is polled, so that no lock is held while it runs: it may spawn
+/// tasks itself, or run a user program for a while.
+#[derive(Debug)]
+struct Entry {
+    name: Option<String>,
+    /// `None` while the task is polled
+    task: Option<Task>,
+    /// Set when the task is woken while it's polled, it's queued again afterwards.
+    woken: bool,
+}
+
 impl Executor {
     pub fn spawn(&self, task: Task) {
         let task_id = task.id;
-        if self.tasks.lock_sync().insert(task.id, task).is_some() {
+        let entry = Entry {
+            name: task.name.clone(),
+            task: Some(task),
+            woken: false,
+        };
+        if self.tasks.lock_sync().insert(task_id, entry).is_some() {
             panic!("task with same ID already exists");
         }
         self.task_queue.push(task_id).expect("task queue full");
     }
 
+    pub fn tasks(&self) -> Vec<TaskInfo> {
+        self.tasks
+            .lock_sync()
+            .iter()
+            .map(|(id, entry)| TaskInfo {
+                id: id.0,
+                name: entry.name.clone(),
+            })
+            .collect()
+    }
+
     pub fn run_ready_tasks(&self) {
-        // destructive self
-        let Self {
-            tasks,
-            task_queue,
-            waker_cache,
-        } = self;
-
-        while let Some(task_id) = task_queue.pop() {
-            let mut tasks = tasks.lock_sync();
-            let task = match tasks.get_mut(&task_id) {
-                Some(task) => task,
-                None => continue, // task no longer exists
+        while let Some(task_id) = self.task_queue.pop() {
+            let mut task = {
+                let mut tasks = self.tasks.lock_sync();
+                let entry = match tasks.get_mut(&task_id) {
+                    Some(entry) => entry,
+                    None => continue, // task no longer exists
+                };
+                match entry.task.take() {
+                    Some(task) => task,
+                    None => {
+                        // polled by another CPU
+                        entry.woken = true;
+                        continue;
+                    }
+                }
             };
 
-            let mut waker_cache = waker_cache.lock_sync();
-            let waker = waker_cache
+            let waker = self
+                .waker_cache
+                .lock_sync()
                 .entry(task_id)
-                .or_insert_with(|| TaskWaker::new_waker(task_id, task_queue.clone()));
+                .or_insert_with(|| TaskWaker::new_waker(task_id, self.task_queue.clone()))
+                .clone();
+
+            let mut context = Context::from_waker(&waker);
+            let poll = task.poll(&mut context);
 
-            let mut context = Context::from_waker(waker);
-            match task.poll(&mut context) {
+            let mut tasks = self.tasks.lock_sync();
+            match poll {
                 Poll::Ready(()) => {
                     #[cfg(feature = "dbg-executor")]
                     log::trace!("{:?} ready", task_id);
 
                     tasks.remove(&task_id);
-                    waker_cache.remove(&task_id);
+                    drop(tasks);
+                    self.waker_cache.lock_sync().remove(&task_id);
+                }
+                Poll::Pending => {
+                    let entry = tasks.get_mut(&task_id).expect("polled task was removed");
+                    entry.task = Some(task);
+                    if core::mem::take(&mut entry.woken) {
+                        self.task_queue.push(task_id).expect("task queue full");
+                    }
                 }
-                Poll::Pending => {}
             }
         }
     }
@@ -134,7 +192,7 @@ impl Executor {
         }
     }
 
-    fn schedule(&self, id: u8) -> ! {
+    fn schedule(&self, id: u32) -> ! {
         log::info!("core {} scheduled", id);
         loop {
             self.run_ready_tasks();
@@ -5,6 +5,7 @@ pub async fn process() {
         crate::peripheral::keyboard::get().expect("keyboard should be initialized by now");
     let mut stream = TaskStream::new();
     while let Some(sc) = stream.next().await {
+        crate::peripheral::keyboard::EVENTS.publish([sc]);
         keyboard.add(sc).await;
     }
 }
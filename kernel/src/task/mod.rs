@@ -12,8 +12,10 @@ pub use executor::Executor;
 
 use alloc::boxed::Box;
 use alloc::string::String;
-use core::sync::atomic::AtomicU64;
-use core::task::{Context, Poll};
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
 use core::{future::Future, pin::Pin};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
@@ -62,6 +64,47 @@ impl core::fmt::Debug for Task {
     }
 }
 
+/// Sets its flag when woken, for [`block_on`]
+struct FlagWaker(AtomicBool);
+
+impl Wake for FlagWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Runs `future` to completion on this CPU, halting until it's woken while it is pending.
+///
+/// This is for code that can't be async. Other tasks of this CPU's executor don't run in the
+/// meantime, so `future` must not wait for one of them or it never completes. System calls
+/// suspend the program instead, see [`crate::user::suspend`].
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    use x86_64::instructions::{hlt, interrupts};
+
+    let flag = Arc::new(FlagWaker(AtomicBool::new(false)));
+    let waker = Waker::from(flag.clone());
+    let mut context = Context::from_waker(&waker);
+    let mut future = core::pin::pin!(future);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output;
+        }
+        while !flag.0.swap(false, Ordering::SeqCst) {
+            // woken by an interrupt handler, or at the next timer tick if the wake up came
+            // right before halting
+            if interrupts::are_enabled() {
+                hlt();
+            } else {
+                core::hint::spin_loop();
+            }
+        }
+    }
+}
+
 /// This macro is used to create a stream that can be polled asynchronously for a given task.
 /// It implements a `write` function that can be used to write to the stream.
 /// To read from the stream, use the generated `TaskStream` struct which implements `Stream`.
@@ -1,4 +1,11 @@
-use crate::{exit_qemu, print, println, QemuExitCode};
+use alloc::rc::Rc;
+use core::{cell::RefCell, future::Future};
+
+use crate::{
+    exit_qemu, print, println,
+    task::{Executor, Task},
+    QemuExitCode,
+};
 
 pub trait Testable {
     fn run(&self);
@@ -30,6 +37,22 @@ pub fn test_main() -> ! {
     exit_qemu(QemuExitCode::Success);
 }
 
+/// Runs the tasks on `executor` until `future` completes.
+pub fn run_until<R: 'static>(executor: &Executor, future: impl Future<Output = R> + 'static) -> R {
+    let result = Rc::new(RefCell::new(None));
+    let output = result.clone();
+    executor.spawn(Task::new(async move {
+        *output.borrow_mut() = Some(future.await);
+    }));
+    loop {
+        executor.run_ready_tasks();
+        if let Some(result) = result.borrow_mut().take() {
+            return result;
+        }
+        core::hint::spin_loop();
+    }
+}
+
 #[test_case]
 fn trivial_assertion() {
     assert_eq!(1, 1);
@@ -13,6 +13,11 @@ use crate::util::Spinlock;
 
 static TIME: OnceCell<Time> = OnceCell::uninit();
 
+/// Timer ticks per second when the local APIC timer could be calibrated, see
+/// [`crate::cpu::CpuInfo::apic_timer_frequency`]. Otherwise a tick is however long the default
+/// count of the timer takes.
+pub const TICKS_PER_SECOND: u64 = 100;
+
 pub(crate) fn init() {
     TIME.init_once(Time::default);
 }
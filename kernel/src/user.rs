@@ -0,0 +1,402 @@
+//! Running code in user mode
+//!
+//! [`start`] enters ring 3 with `iretq` and returns once the program left user mode, either for
+//! good with the `exit` system call or by causing an exception the kernel can't resolve, or to
+//! wait for a system call. The first end up in [`exit`], the last in [`suspend`]. Both abandon
+//! the kernel stack of the system call or exception and return to the kernel stack [`start`]
+//! was called on, much like `longjmp`. A suspended program continues with [`resume`] once its
+//! system call completed, [`run`] does all of this on the current CPU.
+//!
+//! Interrupts, exceptions and system calls from user mode arrive on the privilege stack of the
+//! CPU, see [`gdt::set_kernel_stack`].
+
+use alloc::boxed::Box;
+use core::{
+    arch::global_asm,
+    fmt,
+    ptr::null_mut,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+use x86_64::{
+    instructions::interrupts, registers::rflags::RFlags, structures::idt::InterruptStackFrame,
+    VirtAddr,
+};
+
+use crate::{
+    cpu,
+    fpu::ExtendedState,
+    gdt,
+    mem::fault::FaultReport,
+    smp::MAX_CPUS,
+    syscall::{self, SyscallError, SyscallFuture},
+    task::block_on,
+};
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NOT_RUNNING: AtomicPtr<Context> = AtomicPtr::new(null_mut());
+/// The context of the user program running on every CPU, if any
+static RUNNING: [AtomicPtr<Context>; MAX_CPUS] = [NOT_RUNNING; MAX_CPUS];
+
+/// Why a user program stopped
+#[derive(Debug, Clone, Copy)]
+pub enum Exit {
+    /// The program exited with the given status
+    Exited(i32),
+    /// A page fault that couldn't be resolved
+    PageFault(FaultReport),
+    /// Any other exception
+    Exception {
+        name: &'static str,
+        instruction_pointer: VirtAddr,
+        error_code: Option<u64>,
+    },
+}
+
+/// Why [`start`] or [`resume`] returned
+#[derive(Debug)]
+pub enum Stop {
+    Exit(Exit),
+    /// The program waits for a system call, see [`suspend`]
+    Suspended(Suspended),
+}
+
+/// A user program waiting for a system call
+pub struct Suspended {
+    registers: Registers,
+    extended: Box<ExtendedState>,
+    /// Completes the system call, its result is passed to [`resume`]
+    pub call: SyscallFuture,
+}
+
+impl fmt::Debug for Suspended {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Suspended")
+            .field("registers", &self.registers)
+            .finish_non_exhaustive()
+    }
+}
+
+/// General purpose registers of a user program, in the order `resume_user_mode` expects them
+#[repr(C)]
+#[derive(Debug, Clone, Default)]
+pub struct Registers {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+}
+
+/// Where to return to when the program leaves user mode
+struct Context {
+    /// Kernel stack pointer with the callee saved registers on top, set by `enter_user_mode` or
+    /// `resume_user_mode`
+    kernel_rsp: u64,
+    stop: Option<Stop>,
+}
+
+extern "sysv64" {
+    fn enter_user_mode(
+        entry: u64,
+        stack: u64,
+        kernel_rsp: *mut u64,
+        code: u64,
+        data: u64,
+        rflags: u64,
+    );
+    fn resume_user_mode(
+        registers: *const Registers,
+        kernel_rsp: *mut u64,
+        code: u64,
+        data: u64,
+        rflags: u64,
+    );
+    fn return_to_kernel(kernel_rsp: u64) -> !;
+}
+
+global_asm!(
+    r#"
+.global enter_user_mode
+enter_user_mode:
+    push rbx
+    push rbp
+    push r12
+    push r13
+    push r14
+    push r15
+    mov [rdx], rsp
+
+    // interrupt frame for iretq: ss, rsp, rflags, cs, rip
+    push r8
+    push rsi
+    push r9
+    push rcx
+    push rdi
+
+    // don't leak kernel values to the program
+    xor eax, eax
+    xor ebx, ebx
+    xor ecx, ecx
+    xor edx, edx
+    xor esi, esi
+    xor edi, edi
+    xor ebp, ebp
+    xor r8d, r8d
+    xor r9d, r9d
+    xor r10d, r10d
+    xor r11d, r11d
+    xor r12d, r12d
+    xor r13d, r13d
+    xor r14d, r14d
+    xor r15d, r15d
+    iretq
+
+.global resume_user_mode
+resume_user_mode:
+    push rbx
+    push rbp
+    push r12
+    push r13
+    push r14
+    push r15
+    mov [rsi], rsp
+
+    // interrupt frame for iretq: ss, rsp, rflags, cs, rip
+    push rcx
+    push qword ptr [rdi + 136]
+    push r8
+    push rdx
+    push qword ptr [rdi + 120]
+
+    mov rax, [rdi]
+    mov rbx, [rdi + 8]
+    mov rcx, [rdi + 16]
+    mov rdx, [rdi + 24]
+    mov rsi, [rdi + 32]
+    mov rbp, [rdi + 48]
+    mov r8, [rdi + 56]
+    mov r9, [rdi + 64]
+    mov r10, [rdi + 72]
+    mov r11, [rdi + 80]
+    mov r12, [rdi + 88]
+    mov r13, [rdi + 96]
+    mov r14, [rdi + 104]
+    mov r15, [rdi + 112]
+    mov rdi, [rdi + 40]
+    iretq
+
+.global return_to_kernel
+return_to_kernel:
+    mov rsp, rdi
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbp
+    pop rbx
+    ret
+"#
+);
+
+fn running() -> &'static AtomicPtr<Context> {
+    &RUNNING[cpu::current_id() as usize]
+}
+
+/// Runs user code at `entry` with the stack pointer at `stack` until it exits.
+///
+/// System calls the program waits for are completed with [`block_on`], so nothing else runs
+/// on this CPU in the meantime. Interrupts are enabled in user mode if they are enabled now.
+///
+/// # Safety
+///
+/// See [`start`].
+pub unsafe fn run(entry: VirtAddr, stack: VirtAddr) -> Exit {
+    let mut stop = start(entry, stack);
+    loop {
+        match stop {
+            Stop::Exit(exit) => return exit,
+            Stop::Suspended(mut suspended) => {
+                let result = block_on(&mut suspended.call);
+                stop = resume(suspended, result);
+            }
+        }
+    }
+}
+
+/// Runs user code at `entry` with the stack pointer at `stack` until it exits or waits for a
+/// system call.
+///
+/// Interrupts are enabled in user mode if they are enabled now.
+///
+/// # Safety
+///
+/// The active address space must map `entry` and the stack user accessible, and no other user
+/// program may be running on this CPU.
+pub unsafe fn start(entry: VirtAddr, stack: VirtAddr) -> Stop {
+    #[cfg(feature = "dbg-mem")]
+    log::trace!("entering user mode at {:?}, stack at {:?}", entry, stack);
+
+    enter(|kernel_rsp, rflags| {
+        let (code, data) = gdt::user_selectors();
+        // don't hand over the x87/SSE registers of whatever ran last
+        ExtendedState::new().restore();
+        enter_user_mode(
+            entry.as_u64(),
+            stack.as_u64(),
+            kernel_rsp,
+            code.0 as u64,
+            data.0 as u64,
+            rflags.bits(),
+        )
+    })
+}
+
+/// Continues a program suspended by [`suspend`] with `result` as the result of its system
+/// call, until it exits or waits again.
+///
+/// This may be on another CPU than the one it was suspended on. Interrupts are enabled in user
+/// mode if they are enabled now.
+///
+/// # Safety
+///
+/// The active address space must be the one the program was suspended in, and no other user
+/// program may be running on this CPU.
+pub unsafe fn resume(suspended: Suspended, result: Result<u64, SyscallError>) -> Stop {
+    let Suspended {
+        mut registers,
+        extended,
+        call,
+    } = suspended;
+    drop(call);
+    registers.rax = syscall::result_value(result) as u64;
+
+    #[cfg(feature = "dbg-mem")]
+    log::trace!("resuming user mode at {:#x}", registers.rip);
+
+    enter(|kernel_rsp, rflags| {
+        // like `SYSRET`, which only keeps the flags user code may change
+        let rflags = rflags | (RFlags::from_bits_truncate(registers.rflags) & USER_FLAGS);
+        let (code, data) = gdt::user_selectors();
+        extended.restore();
+        resume_user_mode(
+            &registers,
+            kernel_rsp,
+            code.0 as u64,
+            data.0 as u64,
+            rflags.bits(),
+        )
+    })
+}
+
+/// Flags a user program keeps across a suspended system call
+const USER_FLAGS: RFlags = RFlags::from_bits_truncate(
+    RFlags::CARRY_FLAG.bits()
+        | RFlags::PARITY_FLAG.bits()
+        | RFlags::AUXILIARY_CARRY_FLAG.bits()
+        | RFlags::ZERO_FLAG.bits()
+        | RFlags::SIGN_FLAG.bits()
+        | RFlags::TRAP_FLAG.bits()
+        | RFlags::DIRECTION_FLAG.bits()
+        | RFlags::OVERFLOW_FLAG.bits()
+        | RFlags::ALIGNMENT_CHECK.bits()
+        | RFlags::ID.bits(),
+);
+
+/// Registers a context for this CPU and leaves the kernel with `enter`, which gets where to
+/// store the kernel stack pointer and the flags to start user mode with. Returns once the
+/// program left user mode.
+unsafe fn enter(enter: impl FnOnce(*mut u64, RFlags)) -> Stop {
+    let mut context = Context {
+        kernel_rsp: 0,
+        stop: None,
+    };
+    let enabled = interrupts::are_enabled();
+    let mut rflags = RFlags::from_bits_truncate(0x2);
+    if enabled {
+        rflags |= RFlags::INTERRUPT_FLAG;
+    }
+
+    interrupts::disable();
+    assert!(
+        running()
+            .compare_exchange(null_mut(), &mut context, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok(),
+        "a user program is already running on this cpu"
+    );
+
+    enter(&mut context.kernel_rsp, rflags);
+
+    // back from `exit` or `suspend`, which disabled interrupts
+    running().store(null_mut(), Ordering::SeqCst);
+    if enabled {
+        interrupts::enable();
+    }
+    context.stop.expect("left user mode without a reason")
+}
+
+/// Whether `stack_frame` was pushed by an interrupt or exception in user mode
+pub fn from_user_mode(stack_frame: &InterruptStackFrame) -> bool {
+    stack_frame.code_segment & 3 == 3
+}
+
+/// Stops the user program running on this CPU and returns to its [`start`] or [`resume`] call.
+///
+/// This is called by exception handlers and system calls and returns if no user program is
+/// running.
+pub fn exit(reason: Exit) {
+    let context = running().load(Ordering::SeqCst);
+    if context.is_null() {
+        return;
+    }
+
+    #[cfg(feature = "dbg-mem")]
+    log::trace!("user program exited: {:?}", reason);
+
+    interrupts::disable();
+    unsafe {
+        (*context).stop = Some(Stop::Exit(reason));
+        return_to_kernel((*context).kernel_rsp)
+    }
+}
+
+/// Suspends the user program running on this CPU until `call` completes and returns to its
+/// [`start`] or [`resume`] call with [`Stop::Suspended`]. `registers` are the ones the program
+/// made the system call with.
+///
+/// This is called by system calls that have to wait. Without a running user program, `call` is
+/// completed with [`block_on`] instead.
+pub fn suspend(registers: Registers, call: SyscallFuture) -> Result<u64, SyscallError> {
+    let context = running().load(Ordering::SeqCst);
+    if context.is_null() {
+        return block_on(call);
+    }
+
+    #[cfg(feature = "dbg-mem")]
+    log::trace!("user program suspended at {:#x}", registers.rip);
+
+    // other programs may run on this CPU until the call completes
+    let mut extended = Box::new(ExtendedState::new());
+    extended.save();
+    interrupts::disable();
+    unsafe {
+        (*context).stop = Some(Stop::Suspended(Suspended {
+            registers,
+            extended,
+            call,
+        }));
+        return_to_kernel((*context).kernel_rsp)
+    }
+}
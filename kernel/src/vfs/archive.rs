@@ -0,0 +1,139 @@
+//! The initial RAM disk as a read-only filesystem
+
+use alloc::{boxed::Box, format, string::String, sync::Arc, vec::Vec};
+
+use super::{
+    DirEntry, DirectoryFile, File, FileSystem, FsError, FsFuture, Metadata, Node, NodeKind,
+    OpenFlags,
+};
+use crate::initrd::{Archive, Entry, EntryKind};
+
+/// A filesystem over an [`Archive`], see [`crate::initrd`]
+pub struct ArchiveFs {
+    archive: &'static Archive<'static>,
+}
+
+impl ArchiveFs {
+    pub fn new(archive: &'static Archive<'static>) -> Self {
+        Self { archive }
+    }
+}
+
+impl FileSystem for ArchiveFs {
+    fn name(&self) -> &'static str {
+        "initrd"
+    }
+
+    fn root(&self) -> Arc<dyn Node> {
+        Arc::new(ArchiveNode {
+            archive: self.archive,
+            path: String::new(),
+            entry: self.archive.get("").expect("archive without root"),
+        })
+    }
+}
+
+struct ArchiveNode {
+    archive: &'static Archive<'static>,
+    /// Path in the archive
+    path: String,
+    entry: &'static Entry<'static>,
+}
+
+fn kind(entry: &Entry) -> NodeKind {
+    match entry.kind {
+        EntryKind::File => NodeKind::File,
+        EntryKind::Directory => NodeKind::Directory,
+        EntryKind::Symlink(_) => NodeKind::Symlink,
+    }
+}
+
+impl Node for ArchiveNode {
+    fn metadata(&self) -> FsFuture<'_, Metadata> {
+        let entry = self.entry;
+        Box::pin(async move {
+            Ok(Metadata {
+                kind: kind(entry),
+                inode: entry.inode,
+                size: entry.size() as u64,
+                mode: entry.mode,
+                mtime: entry.mtime,
+            })
+        })
+    }
+
+    fn lookup<'a>(&'a self, name: &'a str) -> FsFuture<'a, Arc<dyn Node>> {
+        Box::pin(async move {
+            if !self.entry.is_dir() {
+                return Err(FsError::NotADirectory);
+            }
+            let path = if self.path.is_empty() {
+                String::from(name)
+            } else {
+                format!("{}/{}", self.path, name)
+            };
+            let entry = self.archive.get(&path).ok_or(FsError::NotFound)?;
+            Ok(Arc::new(ArchiveNode {
+                archive: self.archive,
+                path,
+                entry,
+            }) as Arc<dyn Node>)
+        })
+    }
+
+    fn read_dir(&self) -> FsFuture<'_, Vec<DirEntry>> {
+        Box::pin(async move {
+            let entries = self
+                .archive
+                .read_dir(&self.path)
+                .ok_or(FsError::NotADirectory)?;
+            Ok(entries
+                .map(|(name, entry)| DirEntry {
+                    name: name.into(),
+                    kind: kind(entry),
+                    inode: entry.inode,
+                })
+                .collect())
+        })
+    }
+
+    fn read_link(&self) -> FsFuture<'_, String> {
+        Box::pin(async move {
+            match &self.entry.kind {
+                EntryKind::Symlink(target) => Ok(target.clone()),
+                _ => Err(FsError::InvalidArgument),
+            }
+        })
+    }
+
+    fn open(&self, flags: OpenFlags) -> FsFuture<'_, Box<dyn File>> {
+        Box::pin(async move {
+            if flags.intersects(OpenFlags::WRITE | OpenFlags::APPEND) {
+                return Err(FsError::ReadOnly);
+            }
+            match self.entry.kind {
+                EntryKind::Directory => Ok(Box::new(DirectoryFile) as Box<dyn File>),
+                _ => Ok(Box::new(ArchiveFile {
+                    data: self.entry.data(),
+                }) as Box<dyn File>),
+            }
+        })
+    }
+}
+
+struct ArchiveFile {
+    data: &'static [u8],
+}
+
+impl File for ArchiveFile {
+    fn read<'a>(&'a self, offset: u64, buf: &'a mut [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async move {
+            let start = usize::try_from(offset)
+                .unwrap_or(usize::MAX)
+                .min(self.data.len());
+            let len = buf.len().min(self.data.len() - start);
+            buf[..len].copy_from_slice(&self.data[start..start + len]);
+            Ok(len)
+        })
+    }
+}
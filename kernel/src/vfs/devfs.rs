@@ -0,0 +1,302 @@
+//! Kernel devices as files
+//!
+//! | name       | device                                                           |
+//! |------------|------------------------------------------------------------------|
+//! | `serial0`  | COM1, reads return what was received without waiting              |
+//! | `fb0`      | raw pixels of the framebuffer, the geometry is queried by [`ioctl`] |
+//! | `kmsg`     | the kernel buffer, writes are printed                            |
+//! | `keyboard` | scancodes, see [`keyboard::EVENTS`]                              |
+//! | `mouse`    | decoded packets, see [`mouse::EVENTS`]                           |
+//! | `null`     | reads nothing, discards writes                                   |
+//! | `zero`     | reads zeros, discards writes                                     |
+//! | `random`   | reads random bytes from [`random::fill`], discards writes        |
+//!
+//! Devices without a driver, like `fb0` when the bootloader found no framebuffer, are left out.
+
+use alloc::{boxed::Box, string::String, sync::Arc, vec::Vec};
+use bootloader_api::info::PixelFormat;
+
+use super::{
+    DirEntry, DirectoryFile, File, FileSystem, FsError, FsFuture, Metadata, Node, NodeKind,
+    OpenFlags,
+};
+use crate::{
+    fb, kbuf,
+    peripheral::{
+        events::Listener,
+        keyboard::{self, EVENTS as KEYBOARD_EVENTS},
+        mouse::{self, EVENTS as MOUSE_EVENTS},
+    },
+    random, serial,
+};
+
+/// Requests for [`File::ioctl`] on devices
+pub mod ioctl {
+    /// Width of `fb0` in pixels
+    pub const FB_WIDTH: u64 = 0x4600;
+    /// Height of `fb0` in pixels
+    pub const FB_HEIGHT: u64 = 0x4601;
+    /// Pixels from the start of one line of `fb0` to the next, at least its width
+    pub const FB_STRIDE: u64 = 0x4602;
+    pub const FB_BYTES_PER_PIXEL: u64 = 0x4603;
+    /// One of the `FB_FORMAT_*` constants
+    pub const FB_PIXEL_FORMAT: u64 = 0x4604;
+
+    pub const FB_FORMAT_RGB: u64 = 0;
+    pub const FB_FORMAT_BGR: u64 = 1;
+    /// One byte of intensity per pixel
+    pub const FB_FORMAT_GRAY: u64 = 2;
+    pub const FB_FORMAT_UNKNOWN: u64 = u64::MAX;
+
+    /// Bytes of events waiting to be read from `keyboard` or `mouse`
+    pub const EVENTS_PENDING: u64 = 0x4b00;
+}
+
+/// The filesystem of devices, usually mounted on `/dev`
+pub struct DevFs;
+
+impl FileSystem for DevFs {
+    fn name(&self) -> &'static str {
+        "devfs"
+    }
+
+    fn root(&self) -> Arc<dyn Node> {
+        Arc::new(Root)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Device {
+    Serial,
+    Framebuffer,
+    Kmsg,
+    Keyboard,
+    Mouse,
+    Null,
+    Zero,
+    Random,
+}
+
+impl Device {
+    const ALL: [Device; 8] = [
+        Device::Serial,
+        Device::Framebuffer,
+        Device::Kmsg,
+        Device::Keyboard,
+        Device::Mouse,
+        Device::Null,
+        Device::Zero,
+        Device::Random,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Device::Serial => "serial0",
+            Device::Framebuffer => "fb0",
+            Device::Kmsg => "kmsg",
+            Device::Keyboard => "keyboard",
+            Device::Mouse => "mouse",
+            Device::Null => "null",
+            Device::Zero => "zero",
+            Device::Random => "random",
+        }
+    }
+
+    fn mode(self) -> u32 {
+        match self {
+            Device::Null | Device::Zero | Device::Random => 0o666,
+            Device::Kmsg => 0o644,
+            Device::Serial | Device::Framebuffer | Device::Keyboard | Device::Mouse => 0o660,
+        }
+    }
+
+    /// The root is inode 1, devices follow
+    fn inode(self) -> u64 {
+        self as u64 + 2
+    }
+
+    fn available(self) -> bool {
+        match self {
+            Device::Framebuffer => fb::info().is_some(),
+            Device::Keyboard => keyboard::get().is_some(),
+            Device::Mouse => mouse::get().is_some(),
+            _ => true,
+        }
+    }
+
+    fn find(name: &str) -> Option<Device> {
+        Device::ALL
+            .into_iter()
+            .find(|device| device.name() == name && device.available())
+    }
+}
+
+struct Root;
+
+impl Node for Root {
+    fn metadata(&self) -> FsFuture<'_, Metadata> {
+        Box::pin(async {
+            Ok(Metadata {
+                kind: NodeKind::Directory,
+                inode: 1,
+                size: 0,
+                mode: 0o755,
+                mtime: 0,
+            })
+        })
+    }
+
+    fn lookup<'a>(&'a self, name: &'a str) -> FsFuture<'a, Arc<dyn Node>> {
+        Box::pin(async move {
+            let device = Device::find(name).ok_or(FsError::NotFound)?;
+            Ok(Arc::new(DeviceNode(device)) as Arc<dyn Node>)
+        })
+    }
+
+    fn read_dir(&self) -> FsFuture<'_, Vec<DirEntry>> {
+        Box::pin(async {
+            Ok(Device::ALL
+                .into_iter()
+                .filter(|device| device.available())
+                .map(|device| DirEntry {
+                    name: device.name().into(),
+                    kind: NodeKind::CharDevice,
+                    inode: device.inode(),
+                })
+                .collect())
+        })
+    }
+
+    fn open(&self, _flags: OpenFlags) -> FsFuture<'_, Box<dyn File>> {
+        Box::pin(async { Ok(Box::new(DirectoryFile) as Box<dyn File>) })
+    }
+}
+
+struct DeviceNode(Device);
+
+impl Node for DeviceNode {
+    fn metadata(&self) -> FsFuture<'_, Metadata> {
+        let device = self.0;
+        Box::pin(async move {
+            let size = match device {
+                Device::Framebuffer => fb::info().map_or(0, |info| info.byte_len),
+                _ => 0,
+            };
+            Ok(Metadata {
+                kind: NodeKind::CharDevice,
+                inode: device.inode(),
+                size: size as u64,
+                mode: device.mode(),
+                mtime: 0,
+            })
+        })
+    }
+
+    fn open(&self, _flags: OpenFlags) -> FsFuture<'_, Box<dyn File>> {
+        let device = self.0;
+        Box::pin(async move {
+            // input devices deliver the events from when they were opened on
+            let file = match device {
+                Device::Keyboard => DeviceFile::Keyboard(KEYBOARD_EVENTS.listen()),
+                Device::Mouse => DeviceFile::Mouse(MOUSE_EVENTS.listen()),
+                device => DeviceFile::Plain(device),
+            };
+            Ok(Box::new(file) as Box<dyn File>)
+        })
+    }
+}
+
+enum DeviceFile {
+    Plain(Device),
+    Keyboard(Arc<Listener<1>>),
+    Mouse(Arc<Listener<5>>),
+}
+
+impl File for DeviceFile {
+    fn read<'a>(&'a self, offset: u64, buf: &'a mut [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async move {
+            let offset = usize::try_from(offset).unwrap_or(usize::MAX);
+            Ok(match self {
+                DeviceFile::Keyboard(listener) => listener.read(buf).await,
+                DeviceFile::Mouse(listener) => listener.read(buf).await,
+                DeviceFile::Plain(Device::Serial) => serial::read_available(buf),
+                DeviceFile::Plain(Device::Framebuffer) => fb::read_raw(offset, buf),
+                DeviceFile::Plain(Device::Kmsg) => {
+                    let contents = kbuf::contents();
+                    let start = offset.min(contents.len());
+                    let len = buf.len().min(contents.len() - start);
+                    buf[..len].copy_from_slice(&contents.as_bytes()[start..start + len]);
+                    len
+                }
+                DeviceFile::Plain(Device::Zero) => {
+                    buf.fill(0);
+                    buf.len()
+                }
+                DeviceFile::Plain(Device::Random) => {
+                    random::fill(buf);
+                    buf.len()
+                }
+                DeviceFile::Plain(_) => 0,
+            })
+        })
+    }
+
+    fn write<'a>(&'a self, offset: u64, buf: &'a [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async move {
+            match self {
+                DeviceFile::Keyboard(_) | DeviceFile::Mouse(_) => Err(FsError::NotSupported),
+                DeviceFile::Plain(Device::Serial) => {
+                    serial::write_bytes(buf);
+                    Ok(buf.len())
+                }
+                DeviceFile::Plain(Device::Framebuffer) => {
+                    let offset = usize::try_from(offset).unwrap_or(usize::MAX);
+                    match fb::write_raw(offset, buf) {
+                        0 if !buf.is_empty() => Err(FsError::NoSpace),
+                        written => Ok(written),
+                    }
+                }
+                DeviceFile::Plain(Device::Kmsg) => {
+                    crate::print!("{}", String::from_utf8_lossy(buf));
+                    Ok(buf.len())
+                }
+                DeviceFile::Plain(_) => Ok(buf.len()),
+            }
+        })
+    }
+
+    fn ioctl(&self, request: u64, _arg: u64) -> FsFuture<'_, u64> {
+        Box::pin(async move {
+            match self {
+                DeviceFile::Plain(Device::Framebuffer) => framebuffer_ioctl(request),
+                DeviceFile::Keyboard(listener) if request == ioctl::EVENTS_PENDING => {
+                    Ok(listener.pending() as u64)
+                }
+                DeviceFile::Mouse(listener) if request == ioctl::EVENTS_PENDING => {
+                    Ok(listener.pending() as u64)
+                }
+                _ => Err(FsError::UnknownRequest),
+            }
+        })
+    }
+}
+
+fn framebuffer_ioctl(request: u64) -> Result<u64, FsError> {
+    let info = fb::info().ok_or(FsError::Io)?;
+    let value = match request {
+        ioctl::FB_WIDTH => info.width,
+        ioctl::FB_HEIGHT => info.height,
+        ioctl::FB_STRIDE => info.stride,
+        ioctl::FB_BYTES_PER_PIXEL => info.bytes_per_pixel,
+        ioctl::FB_PIXEL_FORMAT => {
+            return Ok(match info.pixel_format {
+                PixelFormat::Rgb => ioctl::FB_FORMAT_RGB,
+                PixelFormat::Bgr => ioctl::FB_FORMAT_BGR,
+                PixelFormat::U8 => ioctl::FB_FORMAT_GRAY,
+                _ => ioctl::FB_FORMAT_UNKNOWN,
+            })
+        }
+        _ => return Err(FsError::UnknownRequest),
+    };
+    Ok(value as u64)
+}
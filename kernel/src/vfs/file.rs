@@ -0,0 +1,172 @@
+//! Open files
+
+use alloc::{boxed::Box, string::String, sync::Arc, vec::Vec};
+use bitflags::bitflags;
+use core::fmt;
+
+use super::{DirEntry, File, FsError, FsFuture, Metadata, Node, NodeKind};
+use crate::util::Spinlock;
+
+bitflags! {
+    pub struct OpenFlags: u32 {
+        const READ = 1 << 0;
+        const WRITE = 1 << 1;
+        /// Every write goes to the end of the file
+        const APPEND = 1 << 2;
+        /// Creates the file if it doesn't exist
+        const CREATE = 1 << 3;
+        /// Empties the file, needs [`OpenFlags::WRITE`]
+        const TRUNCATE = 1 << 4;
+        /// With [`OpenFlags::CREATE`], fails if the file exists
+        const EXCLUSIVE = 1 << 5;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+/// The [`File`] of a directory, directories are read with [`OpenFile::read_dir`]
+pub struct DirectoryFile;
+
+impl File for DirectoryFile {
+    fn read<'a>(&'a self, _offset: u64, _buf: &'a mut [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async { Err(FsError::IsADirectory) })
+    }
+
+    fn write<'a>(&'a self, _offset: u64, _buf: &'a [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async { Err(FsError::IsADirectory) })
+    }
+}
+
+/// A file opened by a process or the kernel, reads and writes continue where the last ended
+pub struct OpenFile {
+    path: String,
+    node: Arc<dyn Node>,
+    file: Box<dyn File>,
+    flags: OpenFlags,
+    offset: Spinlock<u64>,
+}
+
+impl OpenFile {
+    /// Opens `node`, which was found at `path`.
+    pub async fn new(
+        node: Arc<dyn Node>,
+        path: &str,
+        flags: OpenFlags,
+    ) -> Result<OpenFile, FsError> {
+        let kind = node.metadata().await?.kind;
+        if kind == NodeKind::Directory && flags.contains(OpenFlags::WRITE) {
+            return Err(FsError::IsADirectory);
+        }
+        let file = node.open(flags).await?;
+        Ok(OpenFile {
+            path: path.into(),
+            node,
+            file,
+            flags,
+            offset: Spinlock::new(0),
+        })
+    }
+
+    /// The path the file was opened with
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn node(&self) -> &Arc<dyn Node> {
+        &self.node
+    }
+
+    pub fn flags(&self) -> OpenFlags {
+        self.flags
+    }
+
+    pub fn offset(&self) -> u64 {
+        *self.offset.lock_sync()
+    }
+
+    pub async fn metadata(&self) -> Result<Metadata, FsError> {
+        self.node.metadata().await
+    }
+
+    pub async fn read(&self, buf: &mut [u8]) -> Result<usize, FsError> {
+        self.read_with(buf, |_| Ok::<(), FsError>(())).await
+    }
+
+    /// Reads into `buf` and passes what was read to `consume`, like a copy to user memory. The
+    /// position only moves on if `consume` succeeds.
+    pub async fn read_with<E: From<FsError>>(
+        &self,
+        buf: &mut [u8],
+        consume: impl FnOnce(&[u8]) -> Result<(), E>,
+    ) -> Result<usize, E> {
+        if !self.flags.contains(OpenFlags::READ) {
+            return Err(FsError::AccessDenied.into());
+        }
+        let offset = self.offset();
+        let read = self.file.read(offset, buf).await?;
+        consume(&buf[..read])?;
+        *self.offset.lock().await = offset + read as u64;
+        Ok(read)
+    }
+
+    pub async fn write(&self, buf: &[u8]) -> Result<usize, FsError> {
+        if !self.flags.contains(OpenFlags::WRITE) {
+            return Err(FsError::AccessDenied);
+        }
+        let offset = if self.flags.contains(OpenFlags::APPEND) {
+            self.metadata().await?.size
+        } else {
+            self.offset()
+        };
+        let written = self.file.write(offset, buf).await?;
+        *self.offset.lock().await = offset + written as u64;
+        Ok(written)
+    }
+
+    /// Moves the position for the next read or write, returns the new position.
+    pub async fn seek(&self, pos: SeekFrom) -> Result<u64, FsError> {
+        let (base, delta) = match pos {
+            SeekFrom::Start(offset) => (0, offset as i64),
+            SeekFrom::Current(delta) => (self.offset(), delta),
+            SeekFrom::End(delta) => (self.metadata().await?.size, delta),
+        };
+        let offset = base
+            .checked_add_signed(delta)
+            .ok_or(FsError::InvalidArgument)?;
+        *self.offset.lock().await = offset;
+        Ok(offset)
+    }
+
+    /// Cuts the file off at `size` bytes or extends it with zeros, the position stays.
+    pub async fn truncate(&self, size: u64) -> Result<(), FsError> {
+        if !self.flags.contains(OpenFlags::WRITE) {
+            return Err(FsError::AccessDenied);
+        }
+        self.node.truncate(size).await
+    }
+
+    /// A device specific request, see [`super::ioctl`]
+    pub async fn ioctl(&self, request: u64, arg: u64) -> Result<u64, FsError> {
+        self.file.ioctl(request, arg).await
+    }
+
+    /// Entries of an opened directory
+    pub async fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        super::read_dir(&self.path).await
+    }
+}
+
+impl fmt::Debug for OpenFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OpenFile")
+            .field("path", &self.path)
+            .field("flags", &self.flags)
+            .field("offset", &self.offset())
+            .finish()
+    }
+}
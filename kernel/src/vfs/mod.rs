@@ -0,0 +1,317 @@
+//! Virtual filesystem
+//!
+//! Every filesystem implements [`FileSystem`], which hands out its root [`Node`]. Nodes are
+//! files, directories, links or devices, looked up by name in their directory. Opening a node
+//! gives a [`File`], which reads and writes at explicit offsets. [`OpenFile`] adds the current
+//! position on top of that and is what file descriptors refer to, see
+//! [`process::Handle`](crate::process::Handle).
+//!
+//! Filesystems are mounted on directories of the tree below `/`, see [`mount`]. Paths are
+//! resolved component by component from the root: `.` is skipped, `..` goes back to the
+//! previous directory, crossing mount points in both directions, and symbolic links are
+//! followed. All operations are asynchronous, so a filesystem can wait for a device.
+
+mod archive;
+mod devfs;
+mod file;
+mod mount;
+pub mod path;
+mod procfs;
+mod tmpfs;
+
+pub use archive::ArchiveFs;
+pub use devfs::{ioctl, DevFs};
+pub use file::{DirectoryFile, OpenFile, OpenFlags, SeekFrom};
+pub use mount::{mount, mounts, unmount};
+pub use procfs::ProcFs;
+pub use tmpfs::TmpFs;
+
+use alloc::{boxed::Box, string::String, sync::Arc, vec::Vec};
+use core::{fmt, future::Future, pin::Pin};
+use thiserror_no_std::Error;
+
+/// At most this many symbolic links are followed while resolving one path
+pub const MAX_SYMLINKS: usize = 8;
+
+/// The future returned by filesystem operations
+pub type FsFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, FsError>> + Send + 'a>>;
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    #[error("no such file or directory")]
+    NotFound,
+    #[error("not a directory")]
+    NotADirectory,
+    #[error("is a directory")]
+    IsADirectory,
+    #[error("read-only filesystem")]
+    ReadOnly,
+    #[error("operation not supported")]
+    NotSupported,
+    #[error("invalid argument")]
+    InvalidArgument,
+    #[error("file not opened with the required access")]
+    AccessDenied,
+    #[error("too many levels of symbolic links")]
+    TooManyLinks,
+    #[error("path is already a mount point")]
+    AlreadyMounted,
+    #[error("not a mount point")]
+    NotMounted,
+    #[error("i/o error")]
+    Io,
+    #[error("file exists")]
+    AlreadyExists,
+    #[error("directory not empty")]
+    NotEmpty,
+    #[error("no space left on filesystem")]
+    NoSpace,
+    #[error("mount point is busy")]
+    Busy,
+    #[error("can't move between filesystems")]
+    CrossDevice,
+    #[error("request not supported by the file")]
+    UnknownRequest,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeKind {
+    File,
+    Directory,
+    Symlink,
+    /// A device read and written a stream of bytes at a time
+    CharDevice,
+    /// A device read and written in blocks
+    BlockDevice,
+}
+
+/// What `stat` returns
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    pub kind: NodeKind,
+    /// Unique in its filesystem
+    pub inode: u64,
+    /// In bytes, 0 for directories and most devices
+    pub size: u64,
+    /// Permission bits
+    pub mode: u32,
+    /// Modification time in seconds since the Unix epoch, 0 if unknown
+    pub mtime: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub kind: NodeKind,
+    pub inode: u64,
+}
+
+pub trait FileSystem: Send + Sync {
+    /// Type of the filesystem, like `tmpfs`
+    fn name(&self) -> &'static str;
+
+    fn root(&self) -> Arc<dyn Node>;
+}
+
+impl fmt::Debug for dyn FileSystem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("FileSystem").field(&self.name()).finish()
+    }
+}
+
+/// A file, directory, link or device in a filesystem
+///
+/// Operations that don't apply to a kind of node fail by default.
+pub trait Node: Send + Sync {
+    fn metadata(&self) -> FsFuture<'_, Metadata>;
+
+    /// The entry `name` of this directory, `name` is never `.` or `..`
+    fn lookup<'a>(&'a self, _name: &'a str) -> FsFuture<'a, Arc<dyn Node>> {
+        Box::pin(async { Err(FsError::NotADirectory) })
+    }
+
+    /// Entries of this directory, without `.` and `..`
+    fn read_dir(&self) -> FsFuture<'_, Vec<DirEntry>> {
+        Box::pin(async { Err(FsError::NotADirectory) })
+    }
+
+    /// Target of this symbolic link
+    fn read_link(&self) -> FsFuture<'_, String> {
+        Box::pin(async { Err(FsError::InvalidArgument) })
+    }
+
+    /// Opens this node for reading and writing, access is checked by [`OpenFile`].
+    fn open(&self, flags: OpenFlags) -> FsFuture<'_, Box<dyn File>>;
+
+    /// Creates the empty file `name` in this directory.
+    fn create<'a>(&'a self, _name: &'a str) -> FsFuture<'a, Arc<dyn Node>> {
+        Box::pin(async { Err(FsError::ReadOnly) })
+    }
+
+    /// Creates the empty directory `name` in this directory.
+    fn create_dir<'a>(&'a self, _name: &'a str) -> FsFuture<'a, Arc<dyn Node>> {
+        Box::pin(async { Err(FsError::ReadOnly) })
+    }
+
+    /// Creates the symbolic link `name` to `target` in this directory.
+    fn symlink<'a>(&'a self, _name: &'a str, _target: &'a str) -> FsFuture<'a, Arc<dyn Node>> {
+        Box::pin(async { Err(FsError::ReadOnly) })
+    }
+
+    /// Removes the entry `name` of this directory, directories only if they are empty.
+    fn unlink<'a>(&'a self, _name: &'a str) -> FsFuture<'a, ()> {
+        Box::pin(async { Err(FsError::ReadOnly) })
+    }
+
+    /// Moves the entry `name` of this directory to `to_name` in the directory `to` of the same
+    /// filesystem, replacing what is there unless it's a non-empty directory.
+    fn rename<'a>(
+        &'a self,
+        _name: &'a str,
+        _to: &'a dyn Node,
+        _to_name: &'a str,
+    ) -> FsFuture<'a, ()> {
+        Box::pin(async { Err(FsError::ReadOnly) })
+    }
+
+    /// Cuts this file off at `size` bytes or extends it with zeros.
+    fn truncate(&self, _size: u64) -> FsFuture<'_, ()> {
+        Box::pin(async { Err(FsError::ReadOnly) })
+    }
+}
+
+/// An opened node
+pub trait File: Send + Sync {
+    /// Reads from `offset` into `buf`, returns how many bytes were read, 0 at the end.
+    fn read<'a>(&'a self, offset: u64, buf: &'a mut [u8]) -> FsFuture<'a, usize>;
+
+    /// Writes `buf` at `offset`, returns how many bytes were written.
+    fn write<'a>(&'a self, _offset: u64, _buf: &'a [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async { Err(FsError::ReadOnly) })
+    }
+
+    /// Answers a device specific `request` with argument `arg`, see [`ioctl`].
+    fn ioctl(&self, _request: u64, _arg: u64) -> FsFuture<'_, u64> {
+        Box::pin(async { Err(FsError::UnknownRequest) })
+    }
+}
+
+/// The node at `path`, following symbolic links
+pub async fn lookup(path: &str) -> Result<Arc<dyn Node>, FsError> {
+    mount::resolve(path, true).await
+}
+
+/// The node at `path`, a symbolic link itself if that's what is at `path`
+pub async fn lookup_link(path: &str) -> Result<Arc<dyn Node>, FsError> {
+    mount::resolve(path, false).await
+}
+
+/// The resolved directory `path` is in, and the last component of `path`
+async fn parent(path: &str) -> Result<(String, Arc<dyn Node>, &str), FsError> {
+    let (dir, name) = path::split(path).ok_or(FsError::InvalidArgument)?;
+    let (dir_path, dir) = mount::resolve_path(dir, true).await?;
+    Ok((dir_path, dir, name))
+}
+
+/// Opens the file at `path`, creating it with [`OpenFlags::CREATE`].
+pub async fn open(path: &str, flags: OpenFlags) -> Result<OpenFile, FsError> {
+    let (resolved, node) = match mount::resolve_path(path, true).await {
+        Ok(_) if flags.contains(OpenFlags::CREATE | OpenFlags::EXCLUSIVE) => {
+            return Err(FsError::AlreadyExists)
+        }
+        Err(FsError::NotFound) if flags.contains(OpenFlags::CREATE) => {
+            let (dir_path, dir, name) = parent(path).await?;
+            (path::join(&dir_path, name), dir.create(name).await?)
+        }
+        result => result?,
+    };
+    let file = OpenFile::new(node, &resolved, flags).await?;
+    if flags.contains(OpenFlags::TRUNCATE) {
+        file.truncate(0).await?;
+    }
+    Ok(file)
+}
+
+pub async fn create_dir(path: &str) -> Result<(), FsError> {
+    let (_, dir, name) = parent(path).await?;
+    dir.create_dir(name).await.map(drop)
+}
+
+/// Creates a symbolic link at `path` that points to `target`.
+pub async fn symlink(target: &str, path: &str) -> Result<(), FsError> {
+    let (_, dir, name) = parent(path).await?;
+    dir.symlink(name, target).await.map(drop)
+}
+
+/// Removes the file, link or empty directory at `path`.
+pub async fn remove(path: &str) -> Result<(), FsError> {
+    let (dir_path, dir, name) = parent(path).await?;
+    if mount::is_mount_point(&path::join(&dir_path, name)) {
+        return Err(FsError::Busy);
+    }
+    dir.unlink(name).await
+}
+
+/// Moves `from` to `to` within a filesystem.
+pub async fn rename(from: &str, to: &str) -> Result<(), FsError> {
+    let (from_path, from_dir, from_name) = parent(from).await?;
+    let (to_path, to_dir, to_name) = parent(to).await?;
+    if mount::is_mount_point(&path::join(&from_path, from_name))
+        || mount::is_mount_point(&path::join(&to_path, to_name))
+    {
+        return Err(FsError::Busy);
+    }
+    if mount::mount_point_of(&from_path) != mount::mount_point_of(&to_path) {
+        return Err(FsError::CrossDevice);
+    }
+    from_dir.rename(from_name, &*to_dir, to_name).await
+}
+
+/// Cuts the file at `path` off at `size` bytes or extends it with zeros.
+pub async fn truncate(path: &str, size: u64) -> Result<(), FsError> {
+    lookup(path).await?.truncate(size).await
+}
+
+pub async fn stat(path: &str) -> Result<Metadata, FsError> {
+    lookup(path).await?.metadata().await
+}
+
+/// Like [`stat`], but describes a symbolic link instead of its target
+pub async fn lstat(path: &str) -> Result<Metadata, FsError> {
+    lookup_link(path).await?.metadata().await
+}
+
+/// Entries of the directory at `path`, including filesystems mounted on it
+pub async fn read_dir(path: &str) -> Result<Vec<DirEntry>, FsError> {
+    let (dir_path, dir) = mount::resolve_path(path, true).await?;
+    let mut entries = dir.read_dir().await?;
+    for (name, fs) in mount::mounted_in(&dir_path) {
+        if entries.iter().all(|entry| entry.name != name) {
+            let inode = fs.root().metadata().await?.inode;
+            entries.push(DirEntry {
+                name,
+                kind: NodeKind::Directory,
+                inode,
+            });
+        }
+    }
+    Ok(entries)
+}
+
+pub async fn read_link(path: &str) -> Result<String, FsError> {
+    lookup_link(path).await?.read_link().await
+}
+
+/// Reads the whole file at `path`.
+pub async fn read_to_end(path: &str) -> Result<Vec<u8>, FsError> {
+    let file = open(path, OpenFlags::READ).await?;
+    let mut data = Vec::new();
+    let mut chunk = [0; 512];
+    loop {
+        let read = file.read(&mut chunk).await?;
+        if read == 0 {
+            return Ok(data);
+        }
+        data.extend_from_slice(&chunk[..read]);
+    }
+}
@@ -0,0 +1,138 @@
+//! Mount table and path resolution
+
+use alloc::{collections::BTreeMap, string::String, sync::Arc, vec, vec::Vec};
+
+use super::{
+    path::{self, components},
+    FileSystem, FsError, Node, NodeKind, MAX_SYMLINKS,
+};
+use crate::util::Spinlock;
+
+/// Mounted filesystems by normalized path
+static MOUNTS: Spinlock<BTreeMap<String, Arc<dyn FileSystem>>> = Spinlock::new(BTreeMap::new());
+
+fn mounted(path: &str) -> Option<Arc<dyn FileSystem>> {
+    MOUNTS.lock_sync().get(path).cloned()
+}
+
+/// Mounts `fs` on `path`.
+///
+/// The root has to be mounted first. Other mount points don't need to exist in the filesystem
+/// below, but their parent directory does.
+pub async fn mount(path: &str, fs: Arc<dyn FileSystem>) -> Result<(), FsError> {
+    let path = path::normalize(path);
+    if let Some((parent, _)) = path::split(&path) {
+        let (_, dir) = resolve_path(parent, true).await?;
+        if dir.metadata().await?.kind != NodeKind::Directory {
+            return Err(FsError::NotADirectory);
+        }
+    }
+
+    let mut mounts = MOUNTS.lock_sync();
+    if mounts.contains_key(&path) {
+        return Err(FsError::AlreadyMounted);
+    }
+    log::info!("mounted {} on {}", fs.name(), path);
+    mounts.insert(path, fs);
+    Ok(())
+}
+
+/// Removes the filesystem mounted on `path` and returns it. Files opened on it stay usable.
+pub fn unmount(path: &str) -> Result<Arc<dyn FileSystem>, FsError> {
+    MOUNTS
+        .lock_sync()
+        .remove(&path::normalize(path))
+        .ok_or(FsError::NotMounted)
+}
+
+/// Mount points and the names of the filesystems mounted on them
+pub fn mounts() -> Vec<(String, &'static str)> {
+    MOUNTS
+        .lock_sync()
+        .iter()
+        .map(|(path, fs)| (path.clone(), fs.name()))
+        .collect()
+}
+
+pub(super) fn is_mount_point(path: &str) -> bool {
+    MOUNTS.lock_sync().contains_key(path)
+}
+
+/// The mount point of the filesystem the resolved `path` is in
+pub(super) fn mount_point_of(path: &str) -> Option<String> {
+    let mounts = MOUNTS.lock_sync();
+    let mut path = path;
+    loop {
+        if mounts.contains_key(path) {
+            return Some(path.into());
+        }
+        path = path::split(path)?.0;
+    }
+}
+
+/// Mount points directly below the directory `dir`, by name
+pub(super) fn mounted_in(dir: &str) -> Vec<(String, Arc<dyn FileSystem>)> {
+    MOUNTS
+        .lock_sync()
+        .iter()
+        .filter_map(|(path, fs)| {
+            let (parent, name) = path::split(path)?;
+            (parent == dir).then(|| (String::from(name), fs.clone()))
+        })
+        .collect()
+}
+
+pub(super) async fn resolve(path: &str, follow_last: bool) -> Result<Arc<dyn Node>, FsError> {
+    resolve_path(path, follow_last).await.map(|(_, node)| node)
+}
+
+/// Walks `path` from the root. Returns the node and its absolute path, with links resolved.
+pub(super) async fn resolve_path(
+    path: &str,
+    follow_last: bool,
+) -> Result<(String, Arc<dyn Node>), FsError> {
+    let root = mounted("/").ok_or(FsError::NotFound)?.root();
+    // the directories walked through, `..` goes back to the previous one
+    let mut walked = vec![(String::from("/"), root)];
+    // components left, in reverse order so links can add theirs
+    let mut pending: Vec<String> = components(path).rev().map(String::from).collect();
+    let mut links = 0;
+
+    while let Some(name) = pending.pop() {
+        if name == ".." {
+            if walked.len() > 1 {
+                walked.pop();
+            }
+            continue;
+        }
+
+        let (dir_path, dir) = walked.last().expect("root is never left");
+        let node_path = path::join(dir_path, &name);
+        let node = match mounted(&node_path) {
+            Some(fs) => fs.root(),
+            None => dir.lookup(&name).await?,
+        };
+
+        let kind = node.metadata().await?.kind;
+        let follow = follow_last || !pending.is_empty();
+        if follow && kind == NodeKind::Symlink {
+            links += 1;
+            if links > MAX_SYMLINKS {
+                return Err(FsError::TooManyLinks);
+            }
+            let target = node.read_link().await?;
+            if target.starts_with('/') {
+                walked.truncate(1);
+            }
+            pending.extend(components(&target).rev().map(String::from));
+            continue;
+        }
+        // `..` after a file doesn't go back to its directory
+        if !pending.is_empty() && kind != NodeKind::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        walked.push((node_path, node));
+    }
+
+    Ok(walked.pop().expect("root is never left"))
+}
@@ -0,0 +1,54 @@
+//! Paths
+//!
+//! Paths are separated by `/`. There is no working directory yet, relative paths are
+//! resolved from the root like absolute ones.
+
+use alloc::{string::String, vec::Vec};
+
+/// The components of `path`, without empty ones and `.`
+pub fn components(path: &str) -> impl DoubleEndedIterator<Item = &str> {
+    path.split('/').filter(|c| !c.is_empty() && *c != ".")
+}
+
+/// The absolute path of `path` with `..` removed lexically, without following links
+pub fn normalize(path: &str) -> String {
+    let mut parts = Vec::new();
+    for component in components(path) {
+        if component == ".." {
+            parts.pop();
+        } else {
+            parts.push(component);
+        }
+    }
+    let mut normalized = String::new();
+    for part in parts {
+        normalized.push('/');
+        normalized.push_str(part);
+    }
+    if normalized.is_empty() {
+        normalized.push('/');
+    }
+    normalized
+}
+
+/// `name` in the directory `dir`, `dir` has to be normalized
+pub fn join(dir: &str, name: &str) -> String {
+    let mut path = String::from(dir);
+    if !path.ends_with('/') {
+        path.push('/');
+    }
+    path.push_str(name);
+    path
+}
+
+/// Splits `path` into its directory and last component, `None` if there is no last component
+/// like for `/`.
+pub fn split(path: &str) -> Option<(&str, &str)> {
+    let path = path.trim_end_matches('/');
+    let (dir, name) = match path.rsplit_once('/') {
+        Some(("", name)) => ("/", name),
+        Some(split) => split,
+        None => ("/", path),
+    };
+    (!name.is_empty() && name != "." && name != "..").then_some((dir, name))
+}
@@ -0,0 +1,282 @@
+//! Live kernel state as files
+//!
+//! | name         | contents                                                         |
+//! |--------------|------------------------------------------------------------------|
+//! | `cpuinfo`    | every CPU from ACPI with what CPUID tells about it               |
+//! | `tasks`      | the tasks of the kernel executor, one `id name` per line          |
+//! | `meminfo`    | frame and heap statistics, see [`mem::stats()`]                   |
+//! | `interrupts` | interrupts handled by every CPU, one line per vector with a handler |
+//! | `pci`        | the devices found by [`pci::init`]                               |
+//! | `uptime`     | timer ticks since boot, see [`time::boot_elapsed`]               |
+//! | `version`    | the kernel version and how it was built                          |
+//!
+//! The contents are generated when a file is opened, reads of the same open file see one
+//! snapshot. Sizes are reported as 0 since they aren't known before.
+
+use alloc::{
+    boxed::Box,
+    format,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use core::fmt::Write;
+
+use super::{
+    DirEntry, DirectoryFile, File, FileSystem, FsError, FsFuture, Metadata, Node, NodeKind,
+    OpenFlags,
+};
+use crate::{cpu, interrupts, mem, pci, smp, task::executor, time};
+
+/// The filesystem of kernel state, usually mounted on `/proc`
+pub struct ProcFs;
+
+impl FileSystem for ProcFs {
+    fn name(&self) -> &'static str {
+        "procfs"
+    }
+
+    fn root(&self) -> Arc<dyn Node> {
+        Arc::new(Root)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Entry {
+    CpuInfo,
+    Tasks,
+    MemInfo,
+    Interrupts,
+    Pci,
+    Uptime,
+    Version,
+}
+
+impl Entry {
+    const ALL: [Entry; 7] = [
+        Entry::CpuInfo,
+        Entry::Tasks,
+        Entry::MemInfo,
+        Entry::Interrupts,
+        Entry::Pci,
+        Entry::Uptime,
+        Entry::Version,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Entry::CpuInfo => "cpuinfo",
+            Entry::Tasks => "tasks",
+            Entry::MemInfo => "meminfo",
+            Entry::Interrupts => "interrupts",
+            Entry::Pci => "pci",
+            Entry::Uptime => "uptime",
+            Entry::Version => "version",
+        }
+    }
+
+    /// The root is inode 1, entries follow
+    fn inode(self) -> u64 {
+        self as u64 + 2
+    }
+
+    fn find(name: &str) -> Option<Entry> {
+        Entry::ALL.into_iter().find(|entry| entry.name() == name)
+    }
+
+    fn generate(self) -> String {
+        match self {
+            Entry::CpuInfo => cpu_info(),
+            Entry::Tasks => tasks(),
+            Entry::MemInfo => mem::stats().to_string(),
+            Entry::Interrupts => interrupt_counts(),
+            Entry::Pci => pci::devices()
+                .iter()
+                .map(|device| format!("{}\n", device))
+                .collect(),
+            Entry::Uptime => format!("{}\n", time::boot_elapsed()),
+            Entry::Version => format!(
+                "akOS kernel {} ({}) {} ({})\n{}\n",
+                env!("CARGO_PKG_VERSION"),
+                env!("PROFILE"),
+                env!("BUILD_TARGET"),
+                env!("BUILD_DATE"),
+                env!("RUSTC_VERSION"),
+            ),
+        }
+    }
+}
+
+/// The logical IDs of the CPUs, only the BSP without SMP
+fn cpu_ids() -> Vec<u32> {
+    match smp::cpus() {
+        [] => alloc::vec![0],
+        cpus => cpus.iter().map(|cpu| cpu.id).collect(),
+    }
+}
+
+fn cpu_info() -> String {
+    let mut out = String::new();
+    for id in cpu_ids() {
+        if !out.is_empty() {
+            out.push('\n');
+        }
+        writeln!(out, "processor       : {}", id).ok();
+        if let Some(cpu) = smp::cpu(id) {
+            writeln!(out, "processor uid   : {}", cpu.processor_uid).ok();
+            writeln!(out, "local apic id   : {}", cpu.local_apic_id).ok();
+            writeln!(out, "bsp             : {}", cpu.is_bsp).ok();
+            writeln!(out, "state           : {:?}", cpu.state()).ok();
+        }
+        // CPUs that haven't started yet didn't run CPUID
+        let Some(info) = cpu::get(id) else {
+            continue;
+        };
+        writeln!(out, "vendor          : {}", info.vendor).ok();
+        if let Some(brand) = &info.brand {
+            writeln!(out, "model name      : {}", brand).ok();
+        }
+        writeln!(out, "family          : {}", info.family).ok();
+        writeln!(out, "model           : {}", info.model).ok();
+        writeln!(out, "stepping        : {}", info.stepping).ok();
+        writeln!(out, "apic id         : {}", info.apic_id).ok();
+        writeln!(
+            out,
+            "topology        : package {}, core {}, thread {}",
+            info.topology.package, info.topology.core, info.topology.thread
+        )
+        .ok();
+        for cache in info.caches.iter() {
+            writeln!(
+                out,
+                "cache           : L{} {:?}, {} KiB, {} byte lines, {}-way, shared by {}",
+                cache.level,
+                cache.kind,
+                cache.size / 1024,
+                cache.line_size,
+                cache.ways,
+                cache.shared_by
+            )
+            .ok();
+        }
+        writeln!(
+            out,
+            "address sizes   : {} bits physical, {} bits virtual",
+            info.physical_address_bits, info.linear_address_bits
+        )
+        .ok();
+        if let Some(frequency) = info.tsc_frequency {
+            writeln!(out, "tsc frequency   : {} Hz", frequency).ok();
+        }
+        let features = format!("{:?}", info.features).to_lowercase();
+        writeln!(out, "flags           : {}", features.replace(" | ", " ")).ok();
+    }
+    out
+}
+
+fn tasks() -> String {
+    executor::tasks()
+        .into_iter()
+        .map(|task| format!("{} {}\n", task.id, task.name.as_deref().unwrap_or("-")))
+        .collect()
+}
+
+fn interrupt_counts() -> String {
+    let cpus = cpu_ids();
+    let mut out = String::from("    ");
+    for id in cpus.iter() {
+        write!(out, " {:>10}", format!("CPU{}", id)).ok();
+    }
+    out.push('\n');
+    for (vector, name) in interrupts::HANDLED {
+        write!(out, "{:>3}:", vector).ok();
+        for id in cpus.iter() {
+            write!(out, " {:>10}", interrupts::interrupt_count(*id, vector)).ok();
+        }
+        writeln!(out, "  {}", name).ok();
+    }
+    out
+}
+
+struct Root;
+
+impl Node for Root {
+    fn metadata(&self) -> FsFuture<'_, Metadata> {
+        Box::pin(async {
+            Ok(Metadata {
+                kind: NodeKind::Directory,
+                inode: 1,
+                size: 0,
+                mode: 0o555,
+                mtime: 0,
+            })
+        })
+    }
+
+    fn lookup<'a>(&'a self, name: &'a str) -> FsFuture<'a, Arc<dyn Node>> {
+        Box::pin(async move {
+            let entry = Entry::find(name).ok_or(FsError::NotFound)?;
+            Ok(Arc::new(EntryNode(entry)) as Arc<dyn Node>)
+        })
+    }
+
+    fn read_dir(&self) -> FsFuture<'_, Vec<DirEntry>> {
+        Box::pin(async {
+            Ok(Entry::ALL
+                .into_iter()
+                .map(|entry| DirEntry {
+                    name: entry.name().into(),
+                    kind: NodeKind::File,
+                    inode: entry.inode(),
+                })
+                .collect())
+        })
+    }
+
+    fn open(&self, _flags: OpenFlags) -> FsFuture<'_, Box<dyn File>> {
+        Box::pin(async { Ok(Box::new(DirectoryFile) as Box<dyn File>) })
+    }
+}
+
+struct EntryNode(Entry);
+
+impl Node for EntryNode {
+    fn metadata(&self) -> FsFuture<'_, Metadata> {
+        let entry = self.0;
+        Box::pin(async move {
+            Ok(Metadata {
+                kind: NodeKind::File,
+                inode: entry.inode(),
+                size: 0,
+                mode: 0o444,
+                mtime: 0,
+            })
+        })
+    }
+
+    fn open(&self, flags: OpenFlags) -> FsFuture<'_, Box<dyn File>> {
+        let entry = self.0;
+        Box::pin(async move {
+            if flags.intersects(OpenFlags::WRITE | OpenFlags::APPEND) {
+                return Err(FsError::ReadOnly);
+            }
+            Ok(Box::new(Snapshot(entry.generate().into_bytes())) as Box<dyn File>)
+        })
+    }
+}
+
+/// The contents of an entry when it was opened
+struct Snapshot(Vec<u8>);
+
+impl File for Snapshot {
+    fn read<'a>(&'a self, offset: u64, buf: &'a mut [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async move {
+            let start = usize::try_from(offset)
+                .unwrap_or(usize::MAX)
+                .min(self.0.len());
+            let len = buf.len().min(self.0.len() - start);
+            buf[..len].copy_from_slice(&self.0[start..start + len]);
+            Ok(len)
+        })
+    }
+}
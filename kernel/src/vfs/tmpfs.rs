@@ -0,0 +1,569 @@
+//! Filesystem in memory
+//!
+//! Files, directories and symbolic links live on the kernel heap. Everything a node takes up,
+//! its contents, the names in a directory and a fixed amount per node, is charged against the
+//! size limit of its [`TmpFs`]. Growing past the limit, or past what the heap can give, fails
+//! with [`FsError::NoSpace`]. Nodes are freed, and their memory given back, once they are
+//! unlinked and no longer open.
+
+use alloc::{
+    boxed::Box,
+    collections::BTreeMap,
+    string::String,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::{
+    mem::size_of,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+use super::{
+    DirEntry, DirectoryFile, File, FileSystem, FsError, FsFuture, Metadata, Node, NodeKind,
+    OpenFlags,
+};
+use crate::util::Spinlock;
+
+/// Size limit of [`TmpFs::default`] in bytes
+pub const DEFAULT_LIMIT: usize = 16 * 1024 * 1024;
+
+/// Longest name of a directory entry
+pub const MAX_NAME: usize = 255;
+
+/// Charged for every node on top of its contents
+const NODE_COST: usize = size_of::<Inode>();
+/// Charged for every directory entry on top of its name
+const ENTRY_COST: usize = size_of::<(String, Arc<Inode>)>();
+
+/// A filesystem in memory
+pub struct TmpFs {
+    shared: Arc<Shared>,
+    root: Arc<Inode>,
+}
+
+impl TmpFs {
+    /// An empty filesystem that holds at most `limit` bytes
+    pub fn new(limit: usize) -> Self {
+        let shared = Arc::new(Shared {
+            limit,
+            used: AtomicUsize::new(0),
+            next_inode: AtomicU64::new(1),
+            nodes: Spinlock::new(BTreeMap::new()),
+            namespace: Spinlock::new(()),
+        });
+        // the root is charged even if it doesn't fit
+        shared.used.fetch_add(NODE_COST, Ordering::SeqCst);
+        let root = shared.register(
+            NodeKind::Directory,
+            Content::Directory(BTreeMap::new()),
+            Weak::new(),
+            NODE_COST,
+        );
+        Self { shared, root }
+    }
+
+    /// Bytes charged against the limit
+    pub fn used(&self) -> usize {
+        self.shared.used.load(Ordering::SeqCst)
+    }
+
+    pub fn limit(&self) -> usize {
+        self.shared.limit
+    }
+}
+
+impl Default for TmpFs {
+    fn default() -> Self {
+        Self::new(DEFAULT_LIMIT)
+    }
+}
+
+impl FileSystem for TmpFs {
+    fn name(&self) -> &'static str {
+        "tmpfs"
+    }
+
+    fn root(&self) -> Arc<dyn Node> {
+        self.root.clone()
+    }
+}
+
+/// State of a [`TmpFs`] its nodes refer to
+struct Shared {
+    limit: usize,
+    used: AtomicUsize,
+    next_inode: AtomicU64,
+    /// Every node by inode number, to find the target directory of a rename
+    nodes: Spinlock<BTreeMap<u64, Weak<Inode>>>,
+    /// Held while adding, removing or moving entries, so what was checked about them holds
+    /// until they are changed, and directories can't be moved into themselves
+    namespace: Spinlock<()>,
+}
+
+impl Shared {
+    /// Charges `bytes` if they fit into the limit.
+    fn reserve(&self, bytes: usize) -> Result<(), FsError> {
+        self.used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                used.checked_add(bytes).filter(|&used| used <= self.limit)
+            })
+            .map(drop)
+            .map_err(|_| FsError::NoSpace)
+    }
+
+    fn release(&self, bytes: usize) {
+        self.used.fetch_sub(bytes, Ordering::SeqCst);
+    }
+
+    /// Sets what is charged for a node from `charged` to `cost`, regardless of the limit.
+    fn recharge(&self, charged: &mut usize, cost: usize) {
+        if cost > *charged {
+            self.used.fetch_add(cost - *charged, Ordering::SeqCst);
+        } else {
+            self.release(*charged - cost);
+        }
+        *charged = cost;
+    }
+
+    /// Bytes left below the limit
+    fn room(&self) -> usize {
+        self.limit.saturating_sub(self.used.load(Ordering::SeqCst))
+    }
+
+    /// Adds a node, whose `cost` was already charged.
+    fn register(
+        self: &Arc<Self>,
+        kind: NodeKind,
+        content: Content,
+        parent: Weak<Inode>,
+        cost: usize,
+    ) -> Arc<Inode> {
+        let inode = self.next_inode.fetch_add(1, Ordering::SeqCst);
+        let node = Arc::new(Inode {
+            shared: self.clone(),
+            inode,
+            kind,
+            parent: Spinlock::new(parent),
+            state: Spinlock::new(State {
+                content,
+                charged: cost,
+            }),
+        });
+        self.nodes.lock_sync().insert(inode, Arc::downgrade(&node));
+        node
+    }
+
+    /// Charges and adds a node.
+    fn create(
+        self: &Arc<Self>,
+        kind: NodeKind,
+        content: Content,
+        parent: Weak<Inode>,
+    ) -> Result<Arc<Inode>, FsError> {
+        let cost = NODE_COST + content.cost();
+        self.reserve(cost)?;
+        Ok(self.register(kind, content, parent, cost))
+    }
+
+    fn node(&self, inode: u64) -> Option<Arc<Inode>> {
+        self.nodes.lock_sync().get(&inode).and_then(Weak::upgrade)
+    }
+
+    /// Grows the capacity of `data` to at least `len` bytes. Like [`Vec`], it grows by more
+    /// than that to make appending cheap, as far as the limit allows.
+    fn grow(&self, data: &mut Vec<u8>, charged: &mut usize, len: usize) -> Result<(), FsError> {
+        let capacity = data.capacity();
+        if len <= capacity {
+            return Ok(());
+        }
+        let target = len.max(
+            capacity
+                .saturating_mul(2)
+                .min(capacity.saturating_add(self.room())),
+        );
+        // held until the allocation is charged, so others can't take the room meanwhile
+        self.reserve(target - capacity)?;
+        let grown = data.try_reserve_exact(target - data.len());
+        if grown.is_ok() {
+            self.recharge(charged, NODE_COST + data.capacity());
+        }
+        self.release(target - capacity);
+        grown.map_err(|_| FsError::NoSpace)
+    }
+
+    /// Moves the entry `name` of `from` to `to_name` in `to`.
+    fn rename(
+        &self,
+        from: &Arc<Inode>,
+        name: &str,
+        to: &Arc<Inode>,
+        to_name: &str,
+    ) -> Result<(), FsError> {
+        let _namespace = self.namespace.lock_sync();
+        let node = from.entry(name)?;
+        let replaced = match to.entry(to_name) {
+            Ok(replaced) if Arc::ptr_eq(&replaced, &node) => return Ok(()),
+            Ok(replaced) => Some(replaced),
+            Err(FsError::NotFound) => None,
+            Err(e) => return Err(e),
+        };
+
+        if node.kind == NodeKind::Directory {
+            // `to` must not be the directory itself or below it
+            let mut dir = Some(to.clone());
+            while let Some(ancestor) = dir {
+                if Arc::ptr_eq(&ancestor, &node) {
+                    return Err(FsError::InvalidArgument);
+                }
+                dir = ancestor.parent.lock_sync().upgrade();
+            }
+        }
+        match (node.kind, replaced.as_ref().map(|replaced| replaced.kind)) {
+            (NodeKind::Directory, Some(NodeKind::Directory)) => {
+                if !replaced.as_ref().expect("checked above").is_empty() {
+                    return Err(FsError::NotEmpty);
+                }
+            }
+            (NodeKind::Directory, Some(_)) => return Err(FsError::NotADirectory),
+            (_, Some(NodeKind::Directory)) => return Err(FsError::IsADirectory),
+            _ => {}
+        }
+
+        // the new name is charged before the old one is given back
+        let to_cost = entry_cost(to_name);
+        if replaced.is_none() {
+            self.reserve(to_cost)?;
+        }
+        if Arc::ptr_eq(from, to) {
+            let mut state = from.state.lock_sync();
+            let State { content, charged } = &mut *state;
+            let entries = content.entries()?;
+            entries.remove(name);
+            entries.insert(to_name.into(), node.clone());
+            *charged -= entry_cost(name);
+            if replaced.is_none() {
+                *charged += to_cost;
+            }
+        } else {
+            // directories are locked in inode order, like everywhere two are locked
+            let (mut from_state, mut to_state) = if from.inode < to.inode {
+                let from_state = from.state.lock_sync();
+                (from_state, to.state.lock_sync())
+            } else {
+                let to_state = to.state.lock_sync();
+                (from.state.lock_sync(), to_state)
+            };
+            from_state.content.entries()?.remove(name);
+            from_state.charged -= entry_cost(name);
+            to_state
+                .content
+                .entries()?
+                .insert(to_name.into(), node.clone());
+            if replaced.is_none() {
+                to_state.charged += to_cost;
+            }
+            if node.kind == NodeKind::Directory {
+                *node.parent.lock_sync() = Arc::downgrade(to);
+            }
+        }
+        self.release(entry_cost(name));
+        // dropped without holding locks, this may free the replaced node
+        drop(replaced);
+        Ok(())
+    }
+}
+
+fn entry_cost(name: &str) -> usize {
+    ENTRY_COST + name.len()
+}
+
+fn check_name(name: &str) -> Result<(), FsError> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME
+        && name != "."
+        && name != ".."
+        && !name.contains('/');
+    valid.then_some(()).ok_or(FsError::InvalidArgument)
+}
+
+enum Content {
+    File(Vec<u8>),
+    Directory(BTreeMap<String, Arc<Inode>>),
+    Symlink(String),
+}
+
+impl Content {
+    /// Bytes charged for the contents
+    fn cost(&self) -> usize {
+        match self {
+            Content::File(data) => data.capacity(),
+            Content::Directory(entries) => entries.keys().map(|name| entry_cost(name)).sum(),
+            Content::Symlink(target) => target.capacity(),
+        }
+    }
+
+    fn entries(&mut self) -> Result<&mut BTreeMap<String, Arc<Inode>>, FsError> {
+        match self {
+            Content::Directory(entries) => Ok(entries),
+            _ => Err(FsError::NotADirectory),
+        }
+    }
+}
+
+struct State {
+    content: Content,
+    /// Bytes charged to the filesystem for this node
+    charged: usize,
+}
+
+struct Inode {
+    shared: Arc<Shared>,
+    inode: u64,
+    kind: NodeKind,
+    /// The directory this one is in, only kept for directories
+    parent: Spinlock<Weak<Inode>>,
+    state: Spinlock<State>,
+}
+
+impl Inode {
+    /// This node as a strong reference, it is alive while `&self` is
+    fn this(&self) -> Arc<Inode> {
+        self.shared.node(self.inode).expect("node isn't registered")
+    }
+
+    fn entry(&self, name: &str) -> Result<Arc<Inode>, FsError> {
+        let mut state = self.state.lock_sync();
+        state
+            .content
+            .entries()?
+            .get(name)
+            .cloned()
+            .ok_or(FsError::NotFound)
+    }
+
+    /// Whether this is a directory without entries
+    fn is_empty(&self) -> bool {
+        matches!(&self.state.lock_sync().content, Content::Directory(entries) if entries.is_empty())
+    }
+
+    /// Adds the entry `name` for a new node.
+    fn add(&self, name: &str, kind: NodeKind, content: Content) -> Result<Arc<dyn Node>, FsError> {
+        check_name(name)?;
+        let parent = if kind == NodeKind::Directory {
+            Arc::downgrade(&self.this())
+        } else {
+            Weak::new()
+        };
+        let _namespace = self.shared.namespace.lock_sync();
+        let mut state = self.state.lock_sync();
+        let entries = state.content.entries()?;
+        if entries.contains_key(name) {
+            return Err(FsError::AlreadyExists);
+        }
+        let cost = entry_cost(name);
+        self.shared.reserve(cost)?;
+        let node = match self.shared.create(kind, content, parent) {
+            Ok(node) => node,
+            Err(e) => {
+                self.shared.release(cost);
+                return Err(e);
+            }
+        };
+        entries.insert(name.into(), node.clone());
+        state.charged += cost;
+        Ok(node)
+    }
+}
+
+impl Drop for Inode {
+    fn drop(&mut self) {
+        self.shared.release(self.state.lock_sync().charged);
+        self.shared.nodes.lock_sync().remove(&self.inode);
+    }
+}
+
+impl Node for Inode {
+    fn metadata(&self) -> FsFuture<'_, Metadata> {
+        Box::pin(async move {
+            let (size, mode) = match &self.state.lock_sync().content {
+                Content::File(data) => (data.len(), 0o644),
+                Content::Directory(_) => (0, 0o755),
+                Content::Symlink(target) => (target.len(), 0o777),
+            };
+            Ok(Metadata {
+                kind: self.kind,
+                inode: self.inode,
+                size: size as u64,
+                mode,
+                mtime: 0,
+            })
+        })
+    }
+
+    fn lookup<'a>(&'a self, name: &'a str) -> FsFuture<'a, Arc<dyn Node>> {
+        Box::pin(async move { self.entry(name).map(|node| node as Arc<dyn Node>) })
+    }
+
+    fn read_dir(&self) -> FsFuture<'_, Vec<DirEntry>> {
+        Box::pin(async move {
+            let mut state = self.state.lock_sync();
+            Ok(state
+                .content
+                .entries()?
+                .iter()
+                .map(|(name, node)| DirEntry {
+                    name: name.clone(),
+                    kind: node.kind,
+                    inode: node.inode,
+                })
+                .collect())
+        })
+    }
+
+    fn read_link(&self) -> FsFuture<'_, String> {
+        Box::pin(async move {
+            match &self.state.lock_sync().content {
+                Content::Symlink(target) => Ok(target.clone()),
+                _ => Err(FsError::InvalidArgument),
+            }
+        })
+    }
+
+    fn open(&self, _flags: OpenFlags) -> FsFuture<'_, Box<dyn File>> {
+        Box::pin(async move {
+            match self.kind {
+                NodeKind::Directory => Ok(Box::new(DirectoryFile) as Box<dyn File>),
+                _ => Ok(Box::new(TmpFile { node: self.this() }) as Box<dyn File>),
+            }
+        })
+    }
+
+    fn create<'a>(&'a self, name: &'a str) -> FsFuture<'a, Arc<dyn Node>> {
+        Box::pin(async move { self.add(name, NodeKind::File, Content::File(Vec::new())) })
+    }
+
+    fn create_dir<'a>(&'a self, name: &'a str) -> FsFuture<'a, Arc<dyn Node>> {
+        Box::pin(async move {
+            self.add(
+                name,
+                NodeKind::Directory,
+                Content::Directory(BTreeMap::new()),
+            )
+        })
+    }
+
+    fn symlink<'a>(&'a self, name: &'a str, target: &'a str) -> FsFuture<'a, Arc<dyn Node>> {
+        Box::pin(async move {
+            if target.is_empty() {
+                return Err(FsError::InvalidArgument);
+            }
+            self.add(name, NodeKind::Symlink, Content::Symlink(target.into()))
+        })
+    }
+
+    fn unlink<'a>(&'a self, name: &'a str) -> FsFuture<'a, ()> {
+        Box::pin(async move {
+            let removed = {
+                let _namespace = self.shared.namespace.lock_sync();
+                // checked without holding this directory, which is locked before its entries
+                // only in inode order
+                let node = self.entry(name)?;
+                if node.kind == NodeKind::Directory && !node.is_empty() {
+                    return Err(FsError::NotEmpty);
+                }
+                let mut state = self.state.lock_sync();
+                let removed = state.content.entries()?.remove(name);
+                state.charged -= entry_cost(name);
+                removed
+            };
+            self.shared.release(entry_cost(name));
+            // freed here unless it's still open
+            drop(removed);
+            Ok(())
+        })
+    }
+
+    fn rename<'a>(&'a self, name: &'a str, to: &'a dyn Node, to_name: &'a str) -> FsFuture<'a, ()> {
+        Box::pin(async move {
+            check_name(to_name)?;
+            let inode = to.metadata().await?.inode;
+            let to_node = self
+                .shared
+                .node(inode)
+                .filter(|node| {
+                    core::ptr::eq(
+                        Arc::as_ptr(node) as *const u8,
+                        to as *const dyn Node as *const u8,
+                    )
+                })
+                .ok_or(FsError::CrossDevice)?;
+            self.shared.rename(&self.this(), name, &to_node, to_name)
+        })
+    }
+
+    fn truncate(&self, size: u64) -> FsFuture<'_, ()> {
+        Box::pin(async move {
+            let size = usize::try_from(size).map_err(|_| FsError::NoSpace)?;
+            let mut state = self.state.lock_sync();
+            let State { content, charged } = &mut *state;
+            let data = match content {
+                Content::File(data) => data,
+                Content::Directory(_) => return Err(FsError::IsADirectory),
+                Content::Symlink(_) => return Err(FsError::InvalidArgument),
+            };
+            if size > data.len() {
+                self.shared.grow(data, charged, size)?;
+                data.resize(size, 0);
+            } else {
+                data.truncate(size);
+                data.shrink_to_fit();
+                self.shared.recharge(charged, NODE_COST + data.capacity());
+            }
+            Ok(())
+        })
+    }
+}
+
+/// An opened file, which keeps its node alive after it's unlinked
+struct TmpFile {
+    node: Arc<Inode>,
+}
+
+impl File for TmpFile {
+    fn read<'a>(&'a self, offset: u64, buf: &'a mut [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async move {
+            let state = self.node.state.lock_sync();
+            let data = match &state.content {
+                Content::File(data) => data.as_slice(),
+                Content::Symlink(target) => target.as_bytes(),
+                Content::Directory(_) => return Err(FsError::IsADirectory),
+            };
+            let start = usize::try_from(offset)
+                .unwrap_or(usize::MAX)
+                .min(data.len());
+            let len = buf.len().min(data.len() - start);
+            buf[..len].copy_from_slice(&data[start..start + len]);
+            Ok(len)
+        })
+    }
+
+    fn write<'a>(&'a self, offset: u64, buf: &'a [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async move {
+            let end = usize::try_from(offset)
+                .ok()
+                .and_then(|offset| offset.checked_add(buf.len()))
+                .ok_or(FsError::NoSpace)?;
+            let mut state = self.node.state.lock_sync();
+            let State { content, charged } = &mut *state;
+            let Content::File(data) = content else {
+                return Err(FsError::InvalidArgument);
+            };
+            if end > data.len() {
+                self.node.shared.grow(data, charged, end)?;
+                data.resize(end, 0);
+            }
+            data[end - buf.len()..end].copy_from_slice(buf);
+            Ok(buf.len())
+        })
+    }
+}
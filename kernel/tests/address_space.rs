@@ -0,0 +1,136 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ak_os_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use ak_os_kernel as lib;
+use alloc::boxed::Box;
+use bootloader_api::{config::Mapping, entry_point, BootInfo, BootloaderConfig};
+use lib::mem::{
+    address_space::{activate_kernel, USER_END},
+    protection::with_user_access,
+    AddressSpace, AddressSpaceError,
+};
+use x86_64::{
+    structures::paging::{Page, PageTableFlags},
+    VirtAddr,
+};
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::Dynamic);
+    config
+};
+
+entry_point!(kernel_main, config = &BOOTLOADER_CONFIG);
+
+pub fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
+    log::set_logger(&lib::logger::LOGGER).expect("failed to setup logger");
+    log::set_max_level(log::LevelFilter::Info);
+
+    let physical_memory_offset = VirtAddr::new(
+        boot_info
+            .physical_memory_offset
+            .into_option()
+            .expect("no physical_memory_offset"),
+    );
+    unsafe { lib::mem::init(physical_memory_offset, &boot_info.memory_regions) };
+
+    lib::init(None);
+
+    test_main();
+
+    lib::exit_qemu(lib::QemuExitCode::Success);
+}
+
+/// In the last user slot, far away from anything the bootloader mapped
+const USER_ADDR: u64 = USER_END - 0x10_0000;
+
+fn user_pages(count: u64) -> x86_64::structures::paging::page::PageRange {
+    let start = Page::containing_address(VirtAddr::new(USER_ADDR));
+    Page::range(start, start + count)
+}
+
+fn free_frames() -> u64 {
+    lib::mem::stats().frames.free
+}
+
+#[test_case]
+fn user_mappings_are_private() {
+    let a = AddressSpace::new().unwrap();
+    let b = AddressSpace::new().unwrap();
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    a.map(user_pages(2), flags).unwrap();
+    b.map(user_pages(1), flags).unwrap();
+
+    let ptr = USER_ADDR as *mut u64;
+    unsafe {
+        a.activate();
+        with_user_access(|| {
+            assert_eq!(ptr.read_volatile(), 0);
+            ptr.write_volatile(0xAAAA);
+        });
+        b.activate();
+        with_user_access(|| {
+            assert_eq!(ptr.read_volatile(), 0);
+            ptr.write_volatile(0xBBBB);
+        });
+        a.activate();
+        with_user_access(|| assert_eq!(ptr.read_volatile(), 0xAAAA));
+    }
+    activate_kernel();
+
+    let mm = lib::mem::get_memory_manager();
+    assert!(mm.translate_addr(VirtAddr::new(USER_ADDR)).is_none());
+    let (_, flags) = a.translate(VirtAddr::new(USER_ADDR)).unwrap();
+    assert!(flags.contains(PageTableFlags::USER_ACCESSIBLE));
+}
+
+#[test_case]
+fn kernel_stays_mapped() {
+    let space = AddressSpace::new().unwrap();
+    let on_heap = alloc_box();
+    unsafe { space.activate() };
+    assert_eq!(*on_heap, 42);
+    activate_kernel();
+}
+
+fn alloc_box() -> &'static u64 {
+    Box::leak(Box::new(42))
+}
+
+#[test_case]
+fn rejects_kernel_addresses() {
+    let space = AddressSpace::new().unwrap();
+    let heap = Page::containing_address(VirtAddr::from_ptr(alloc_box()));
+    assert!(matches!(
+        space.map(Page::range(heap, heap + 1), PageTableFlags::WRITABLE),
+        Err(AddressSpaceError::NotUser(_))
+    ));
+    let null = Page::containing_address(VirtAddr::new(0));
+    assert!(space
+        .map(Page::range(null, null + 1), PageTableFlags::WRITABLE)
+        .is_err());
+}
+
+#[test_case]
+fn drop_frees_all_frames() {
+    // the first address space may grow the heap, which isn't given back
+    drop(AddressSpace::new().unwrap());
+
+    let before = free_frames();
+    {
+        let space = AddressSpace::new().unwrap();
+        space.map(user_pages(64), PageTableFlags::WRITABLE).unwrap();
+        space.unmap(user_pages(16)).unwrap();
+        unsafe { space.activate() };
+        assert!(free_frames() < before);
+    }
+    assert!(lib::mem::get_memory_manager()
+        .translate_addr(VirtAddr::new(USER_ADDR))
+        .is_none());
+    assert_eq!(free_frames(), before);
+}
@@ -0,0 +1,272 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ak_os_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use ak_os_kernel as lib;
+use alloc::{format, sync::Arc, vec};
+use bootloader_api::{config::Mapping, entry_point, BootInfo, BootloaderConfig};
+use lib::{
+    block::{self, gpt, mbr, BlockDevice, BlockError, MemoryDisk, PartitionError, PartitionInfo},
+    task::block_on,
+};
+use x86_64::VirtAddr;
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::Dynamic);
+    config
+};
+
+entry_point!(kernel_main, config = &BOOTLOADER_CONFIG);
+
+pub fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
+    log::set_logger(&lib::logger::LOGGER).expect("failed to setup logger");
+    log::set_max_level(log::LevelFilter::Info);
+
+    let physical_memory_offset = VirtAddr::new(
+        boot_info
+            .physical_memory_offset
+            .into_option()
+            .expect("no physical_memory_offset"),
+    );
+    unsafe { lib::mem::init(physical_memory_offset, &boot_info.memory_regions) };
+
+    lib::init(None);
+
+    test_main();
+
+    lib::exit_qemu(lib::QemuExitCode::Success);
+}
+
+/// Written by `data/make-images.py`, like `MBR_IMAGE`
+///
+/// 128 sectors: an EFI system partition "boot" in 34..=63 and a Linux one "root" in 64..=93,
+/// the backup entries start at 95
+const GPT_IMAGE: &[u8] = include_bytes!("data/gpt.img");
+/// 64 sectors: a bootable FAT32 partition in 2..32 and a Linux one in 32..64
+const MBR_IMAGE: &[u8] = include_bytes!("data/mbr.img");
+
+const SECTOR: usize = 512;
+
+fn disk(image: &[u8]) -> Arc<MemoryDisk> {
+    Arc::new(MemoryDisk::from_bytes(SECTOR, image.to_vec()).unwrap())
+}
+
+/// Flips a byte of the disk image
+fn damage(image: &[u8], offset: usize) -> Arc<MemoryDisk> {
+    let mut image = image.to_vec();
+    image[offset] ^= 0xff;
+    Arc::new(MemoryDisk::from_bytes(SECTOR, image).unwrap())
+}
+
+#[test_case]
+fn memory_disk() {
+    let disk = MemoryDisk::new(SECTOR, 8);
+    assert_eq!(disk.sector_count(), 8);
+
+    let written = [0xab; 2 * SECTOR];
+    block_on(disk.write(6, &written)).unwrap();
+    block_on(disk.flush()).unwrap();
+    let mut read = [0; 2 * SECTOR];
+    block_on(disk.read(6, &mut read)).unwrap();
+    assert_eq!(read, written);
+
+    assert_eq!(
+        block_on(disk.read(7, &mut read)),
+        Err(BlockError::OutOfRange)
+    );
+    assert_eq!(
+        block_on(disk.read(u64::MAX, &mut read)),
+        Err(BlockError::OutOfRange)
+    );
+    assert_eq!(
+        block_on(disk.write(0, &written[..100])),
+        Err(BlockError::Unaligned)
+    );
+    assert_eq!(
+        MemoryDisk::from_bytes(SECTOR, vec![0; 1000]).err(),
+        Some(BlockError::Unaligned)
+    );
+}
+
+#[test_case]
+fn reads_gpt() {
+    let table = block_on(gpt::read(&*disk(GPT_IMAGE))).unwrap();
+    assert_eq!(table.header_lba, 1);
+    assert_eq!(
+        format!("{}", table.disk_guid),
+        "6F3A9E1C-2B4D-4E5F-8A7B-9C0D1E2F3A4B"
+    );
+    assert_eq!((table.first_usable_lba, table.last_usable_lba), (34, 94));
+    assert_eq!(table.partitions.len(), 2);
+
+    let boot = &table.partitions[0];
+    assert_eq!(boot.number, 1);
+    assert_eq!(boot.name, "boot");
+    assert_eq!(boot.type_guid, gpt::Guid::EFI_SYSTEM);
+    assert_eq!(
+        format!("{}", boot.unique_guid),
+        "8E4C2A5D-3B1F-4C6A-9D2E-7F1A0B3C5D6E"
+    );
+    assert_eq!(
+        (boot.first_lba, boot.last_lba, boot.sectors()),
+        (34, 63, 30)
+    );
+    assert_eq!(boot.attributes, 1);
+
+    let root = &table.partitions[1];
+    assert_eq!(root.number, 2);
+    assert_eq!(root.name, "root");
+    assert_eq!(root.type_guid, gpt::Guid::LINUX_FILESYSTEM);
+    assert_eq!((root.first_lba, root.last_lba), (64, 93));
+}
+
+#[test_case]
+fn falls_back_to_the_backup_gpt() {
+    // header
+    let table = block_on(gpt::read(&*damage(GPT_IMAGE, SECTOR + 40))).unwrap();
+    assert_eq!(table.header_lba, 127);
+    assert_eq!(table.partitions.len(), 2);
+    // entries
+    let table = block_on(gpt::read(&*damage(GPT_IMAGE, 2 * SECTOR + 56))).unwrap();
+    assert_eq!(table.header_lba, 127);
+    assert_eq!(table.partitions[0].name, "boot");
+}
+
+#[test_case]
+fn rejects_damaged_gpt() {
+    let mut image = GPT_IMAGE.to_vec();
+    image[SECTOR + 40] ^= 0xff;
+    image[127 * SECTOR + 40] ^= 0xff;
+    let disk = MemoryDisk::from_bytes(SECTOR, image).unwrap();
+    assert_eq!(
+        block_on(gpt::read(&disk)).unwrap_err(),
+        PartitionError::HeaderChecksum
+    );
+
+    let mut image = GPT_IMAGE.to_vec();
+    image[2 * SECTOR + 56] ^= 0xff;
+    image[95 * SECTOR + 56] ^= 0xff;
+    let disk = Arc::new(MemoryDisk::from_bytes(SECTOR, image).unwrap());
+    assert_eq!(
+        block_on(gpt::read(&*disk)).unwrap_err(),
+        PartitionError::EntriesChecksum
+    );
+    // a damaged GPT is not read as MBR
+    assert_eq!(
+        block_on(block::scan(disk)).unwrap_err(),
+        PartitionError::EntriesChecksum
+    );
+}
+
+#[test_case]
+fn reads_mbr() {
+    let bios = disk(MBR_IMAGE);
+    assert_eq!(
+        block_on(gpt::read(&*bios)).unwrap_err(),
+        PartitionError::NoTable
+    );
+
+    let partitions = block_on(mbr::read(&*bios)).unwrap();
+    assert_eq!(
+        partitions,
+        [
+            mbr::MbrPartition {
+                number: 1,
+                bootable: true,
+                system_id: 0x0c,
+                first_lba: 2,
+                sectors: 30,
+            },
+            mbr::MbrPartition {
+                number: 2,
+                bootable: false,
+                system_id: 0x83,
+                first_lba: 32,
+                sectors: 32,
+            },
+        ]
+    );
+
+    // the protective MBR of the GPT image
+    let partitions = block_on(mbr::read(&*disk(GPT_IMAGE))).unwrap();
+    assert_eq!(partitions.len(), 1);
+    assert_eq!(partitions[0].system_id, mbr::PROTECTIVE);
+}
+
+#[test_case]
+fn no_partition_table() {
+    let empty = Arc::new(MemoryDisk::new(SECTOR, 64));
+    assert_eq!(
+        block_on(block::scan(empty)).unwrap_err(),
+        PartitionError::NoTable
+    );
+
+    // both GPT headers gone, leaving the protective MBR
+    let mut image = GPT_IMAGE.to_vec();
+    image[SECTOR..2 * SECTOR].fill(0);
+    image[127 * SECTOR..].fill(0);
+    let disk = Arc::new(MemoryDisk::from_bytes(SECTOR, image).unwrap());
+    assert_eq!(
+        block_on(block::scan(disk)).unwrap_err(),
+        PartitionError::Invalid("protective MBR without a GPT")
+    );
+
+    let small = MemoryDisk::new(256, 64);
+    assert_eq!(
+        block_on(gpt::read(&small)).unwrap_err(),
+        PartitionError::UnsupportedSectorSize(256)
+    );
+}
+
+#[test_case]
+fn partitions_are_block_devices() {
+    let disk = disk(GPT_IMAGE);
+    let partitions = block_on(block::scan(disk.clone())).unwrap();
+    assert_eq!(partitions.len(), 2);
+    assert!(matches!(partitions[1].info(), PartitionInfo::Gpt(p) if p.name == "root"));
+
+    let root = &partitions[1];
+    assert_eq!(root.sector_size(), SECTOR);
+    assert_eq!(root.sector_count(), 30);
+    let mut sector = [0; SECTOR];
+    block_on(root.read(0, &mut sector)).unwrap();
+    assert_eq!(&sector[..14], b"root partition");
+
+    // writes land inside the partition on the disk
+    block_on(root.write(29, &[0x5a; SECTOR])).unwrap();
+    assert!(disk.contents()[93 * SECTOR..94 * SECTOR]
+        .iter()
+        .all(|&b| b == 0x5a));
+    assert_eq!(
+        block_on(root.write(30, &[0x5a; SECTOR])),
+        Err(BlockError::OutOfRange)
+    );
+    assert_eq!(
+        block_on(root.read(28, &mut [0; 3 * SECTOR])),
+        Err(BlockError::OutOfRange)
+    );
+    block_on(root.flush()).unwrap();
+}
+
+#[test_case]
+fn mbr_partitions_are_block_devices() {
+    let partitions = block_on(block::scan(disk(MBR_IMAGE))).unwrap();
+    assert_eq!(partitions.len(), 2);
+    assert!(matches!(
+        partitions[0].info(),
+        PartitionInfo::Mbr(p) if p.bootable && p.system_id == 0x0c
+    ));
+    assert_eq!(partitions[0].info().number(), 1);
+
+    let mut sector = [0; SECTOR];
+    block_on(partitions[0].read(0, &mut sector)).unwrap();
+    assert_eq!(&sector[..14], b"boot partition");
+    block_on(partitions[1].read(0, &mut sector)).unwrap();
+    assert_eq!(&sector[..14], b"data partition");
+    assert_eq!(partitions[1].sector_count(), 32);
+}
@@ -0,0 +1,173 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ak_os_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use ak_os_kernel as lib;
+use alloc::vec::Vec;
+use bootloader_api::{config::Mapping, entry_point, BootInfo, BootloaderConfig};
+use lib::mem::buddy::{block_size, order_for_size, BuddyAllocator, MAX_ORDER};
+use x86_64::VirtAddr;
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::Dynamic);
+    config
+};
+
+entry_point!(kernel_main, config = &BOOTLOADER_CONFIG);
+
+pub fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
+    log::set_logger(&lib::logger::LOGGER).expect("failed to setup logger");
+    log::set_max_level(log::LevelFilter::Trace);
+
+    let physical_memory_offset = VirtAddr::new(
+        boot_info
+            .physical_memory_offset
+            .into_option()
+            .expect("no physical_memory_offset"),
+    );
+    unsafe { lib::mem::init(physical_memory_offset, &boot_info.memory_regions) };
+
+    test_main();
+
+    lib::exit_qemu(lib::QemuExitCode::Success);
+}
+
+const KIB: u64 = 1024;
+const MIB: u64 = 1024 * KIB;
+const GIB: u64 = 1024 * MIB;
+
+#[test_case]
+fn order_for_sizes() {
+    assert_eq!(order_for_size(0), Some(0));
+    assert_eq!(order_for_size(1), Some(0));
+    assert_eq!(order_for_size(4 * KIB), Some(0));
+    assert_eq!(order_for_size(4 * KIB + 1), Some(1));
+    assert_eq!(order_for_size(12 * KIB), Some(2));
+    assert_eq!(order_for_size(2 * MIB), Some(9));
+    assert_eq!(order_for_size(GIB), Some(MAX_ORDER));
+    assert_eq!(order_for_size(GIB + 1), None);
+    assert_eq!(block_size(MAX_ORDER), GIB);
+}
+
+#[test_case]
+fn free_range_splits_into_aligned_blocks() {
+    let mut buddy = BuddyAllocator::new();
+    buddy.free_range(0x1800..0x5400);
+
+    // shrunk to 0x2000..0x5000
+    assert_eq!(buddy.free_bytes(), 0x3000);
+    let blocks: Vec<_> = buddy.blocks().collect();
+    assert_eq!(blocks, [(0x4000, 0), (0x2000, 1)]);
+}
+
+#[test_case]
+fn alignment_gap_is_not_lost() {
+    let mut buddy = BuddyAllocator::new();
+    buddy.free_range(0x1000..0x40_0000);
+    let total = buddy.free_bytes();
+
+    assert_eq!(buddy.allocate(9), Some(0x20_0000));
+    assert_eq!(buddy.free_bytes(), total - 2 * MIB);
+
+    // the frames below the 2 MiB block are still available
+    assert_eq!(buddy.allocate(0), Some(0x1000));
+    assert_eq!(buddy.allocate(1), Some(0x2000));
+}
+
+#[test_case]
+fn freed_blocks_coalesce() {
+    let mut buddy = BuddyAllocator::new();
+    buddy.free_range(0..2 * MIB);
+    assert_eq!(buddy.free_blocks(9), 1);
+
+    let frames: Vec<_> = (0..512).map(|_| buddy.allocate(0).unwrap()).collect();
+    assert_eq!(buddy.free_bytes(), 0);
+    assert_eq!(buddy.allocate(0), None);
+
+    // free even frames first, so nothing can merge until the odd ones come back
+    for frame in frames.iter().step_by(2) {
+        buddy.deallocate(*frame, 0);
+    }
+    assert_eq!(buddy.free_blocks(0), 256);
+    for frame in frames.iter().skip(1).step_by(2) {
+        buddy.deallocate(*frame, 0);
+    }
+
+    assert_eq!(buddy.free_bytes(), 2 * MIB);
+    assert_eq!(buddy.free_blocks(0), 0);
+    assert_eq!(buddy.free_blocks(9), 1);
+}
+
+#[test_case]
+fn adjacent_ranges_merge() {
+    let mut buddy = BuddyAllocator::new();
+    buddy.free_range(MIB..2 * MIB);
+    buddy.free_range(0..MIB);
+    assert_eq!(buddy.blocks().collect::<Vec<_>>(), [(0, 9)]);
+}
+
+#[test_case]
+fn contiguous_allocation_returns_tail() {
+    let mut buddy = BuddyAllocator::new();
+    buddy.free_range(0..MIB);
+
+    let range = buddy.allocate_contiguous(3 * 4 * KIB).unwrap();
+    assert_eq!(range, 0..0x3000);
+    assert_eq!(buddy.free_bytes(), MIB - 0x3000);
+    assert_eq!(buddy.allocate(0), Some(0x3000));
+
+    buddy.free_range(range);
+    buddy.deallocate(0x3000, 0);
+    assert_eq!(buddy.blocks().collect::<Vec<_>>(), [(0, 8)]);
+}
+
+#[test_case]
+fn kernel_contiguous_frames() {
+    let mm = lib::mem::get_memory_manager();
+    let frames = mm.allocate_contiguous(17).expect("out of memory");
+    assert_eq!(frames.count(), 17);
+
+    for (i, frame) in frames.enumerate() {
+        assert_eq!(
+            frame.start_address(),
+            frames.start.start_address() + i as u64 * 4 * KIB
+        );
+    }
+
+    unsafe { mm.deallocate_contiguous(frames) };
+}
+
+#[test_case]
+fn memory_stats() {
+    use lib::mem::stats::Tag;
+
+    let contiguous = |stats: &lib::mem::MemoryStats| {
+        stats
+            .tags
+            .iter()
+            .find(|(tag, _)| *tag == Tag::Contiguous)
+            .unwrap()
+            .1
+    };
+
+    let before = lib::mem::stats();
+    assert!(before.frames.free <= before.frames.usable);
+    assert!(before.heap.used <= before.heap.size);
+    assert!(before.page_tables >= 4 * 4 * KIB);
+
+    let mm = lib::mem::get_memory_manager();
+    let frames = mm.allocate_contiguous(4).expect("out of memory");
+    let during = lib::mem::stats();
+    assert_eq!(contiguous(&during), contiguous(&before) + 4 * 4 * KIB);
+    assert!(during.frames.free <= before.frames.free - 4 * 4 * KIB);
+
+    unsafe { mm.deallocate_contiguous(frames) };
+    assert_eq!(contiguous(&lib::mem::stats()), contiguous(&before));
+
+    log::info!("meminfo:\n{}", lib::mem::stats());
+}
@@ -0,0 +1,114 @@
+#!/usr/bin/env python3
+# Writes the partitioned disk images read by tests/block.rs, in 512 byte sectors:
+#
+#   gpt.img  128 sectors: a protective MBR and a GPT with two of its 128 entries used, an EFI
+#            system partition "boot" in 34..=63 and a Linux one "root" in 64..=93. The backup
+#            entries start at 95, the backup header is in the last sector.
+#   mbr.img  64 sectors: a bootable FAT32 partition in 2..32 and a Linux one in 32..64.
+#
+# Each partition starts with its name, so tests can tell them apart. Run it in this directory:
+#
+#   python3 make-images.py
+
+import struct
+import uuid
+import zlib
+
+SECTOR = 512
+
+
+def guid(text):
+    return uuid.UUID(text).bytes_le
+
+
+def mbr(entries):
+    """A boot sector with up to four (bootable, chs_start, system_id, chs_end, lba, sectors)"""
+    sector = bytearray(SECTOR)
+    for i, (bootable, chs_start, system_id, chs_end, lba, sectors) in enumerate(entries):
+        sector[446 + 16 * i : 462 + 16 * i] = struct.pack(
+            "<B3sB3sII", 0x80 if bootable else 0, chs_start, system_id, chs_end, lba, sectors
+        )
+    sector[510:512] = b"\x55\xaa"
+    return sector
+
+
+def gpt_header(lba, backup_lba, entries_lba, disk_guid, entries):
+    header = struct.pack(
+        "<8sIIIIQQQQ16sQIII",
+        b"EFI PART",
+        0x00010000,
+        92,
+        0,
+        0,
+        lba,
+        backup_lba,
+        34,
+        94,
+        disk_guid,
+        entries_lba,
+        len(entries) // 128,
+        128,
+        zlib.crc32(entries),
+    )
+    header = header[:16] + struct.pack("<I", zlib.crc32(header)) + header[20:]
+    return header.ljust(SECTOR, b"\0")
+
+
+def gpt_entry(type_guid, unique_guid, first_lba, last_lba, attributes, name):
+    return struct.pack(
+        "<16s16sQQQ72s",
+        guid(type_guid),
+        guid(unique_guid),
+        first_lba,
+        last_lba,
+        attributes,
+        name.encode("utf-16-le"),
+    )
+
+
+def write_gpt():
+    image = bytearray(128 * SECTOR)
+    image[:SECTOR] = mbr([(False, b"\x00\x02\x00", 0xEE, b"\xff\xff\xff", 1, 127)])
+
+    entries = gpt_entry(
+        "c12a7328-f81f-11d2-ba4b-00a0c93ec93b",
+        "8e4c2a5d-3b1f-4c6a-9d2e-7f1a0b3c5d6e",
+        34,
+        63,
+        1,
+        "boot",
+    ) + gpt_entry(
+        "0fc63daf-8483-4772-8e79-3d69d8477de4",
+        "1b2c3d4e-5f60-4718-a9ba-cbdcedfe0f10",
+        64,
+        93,
+        0,
+        "root",
+    )
+    entries = entries.ljust(128 * 128, b"\0")
+    disk_guid = guid("6f3a9e1c-2b4d-4e5f-8a7b-9c0d1e2f3a4b")
+    image[SECTOR : 2 * SECTOR] = gpt_header(1, 127, 2, disk_guid, entries)
+    image[2 * SECTOR : 2 * SECTOR + len(entries)] = entries
+    image[95 * SECTOR : 95 * SECTOR + len(entries)] = entries
+    image[127 * SECTOR :] = gpt_header(127, 1, 95, disk_guid, entries)
+
+    image[34 * SECTOR : 34 * SECTOR + 14] = b"boot partition"
+    image[64 * SECTOR : 64 * SECTOR + 14] = b"root partition"
+    with open("gpt.img", "wb") as f:
+        f.write(image)
+
+
+def write_mbr():
+    image = bytearray(64 * SECTOR)
+    no_chs = b"\0\0\0"
+    image[:SECTOR] = mbr(
+        [(True, no_chs, 0x0C, no_chs, 2, 30), (False, no_chs, 0x83, no_chs, 32, 32)]
+    )
+    image[2 * SECTOR : 2 * SECTOR + 14] = b"boot partition"
+    image[32 * SECTOR : 32 * SECTOR + 14] = b"data partition"
+    with open("mbr.img", "wb") as f:
+        f.write(image)
+
+
+write_gpt()
+write_mbr()
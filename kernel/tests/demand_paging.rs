@@ -0,0 +1,170 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ak_os_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use ak_os_kernel as lib;
+use bootloader_api::{config::Mapping, entry_point, BootInfo, BootloaderConfig};
+use lib::mem::{
+    address_space::{activate_kernel, USER_END},
+    fault::{self, FaultKind},
+    protection::with_user_access,
+    vma::VmaFlags,
+    AddressSpace,
+};
+use x86_64::{
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{page::PageRange, Page},
+    },
+    VirtAddr,
+};
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::Dynamic);
+    config
+};
+
+entry_point!(kernel_main, config = &BOOTLOADER_CONFIG);
+
+pub fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
+    log::set_logger(&lib::logger::LOGGER).expect("failed to setup logger");
+    log::set_max_level(log::LevelFilter::Info);
+
+    let physical_memory_offset = VirtAddr::new(
+        boot_info
+            .physical_memory_offset
+            .into_option()
+            .expect("no physical_memory_offset"),
+    );
+    unsafe { lib::mem::init(physical_memory_offset, &boot_info.memory_regions) };
+
+    lib::init(None);
+
+    test_main();
+
+    lib::exit_qemu(lib::QemuExitCode::Success);
+}
+
+const USER_ADDR: u64 = USER_END - 0x100_0000;
+const RW: VmaFlags = VmaFlags::from_bits_truncate(VmaFlags::READ.bits() | VmaFlags::WRITE.bits());
+
+fn pages(offset: u64, count: u64) -> PageRange {
+    let start = Page::containing_address(VirtAddr::new(USER_ADDR + offset * 4096));
+    Page::range(start, start + count)
+}
+
+fn addr(offset: u64) -> VirtAddr {
+    pages(offset, 1).start.start_address()
+}
+
+#[test_case]
+fn anonymous_pages_are_populated_on_access() {
+    let space = AddressSpace::new().unwrap();
+    space.map_anonymous(pages(0, 1024), RW).unwrap();
+    assert!(space.translate(addr(10)).is_none());
+
+    unsafe { space.activate() };
+    let value = with_user_access(|| unsafe {
+        let ptr = addr(10).as_mut_ptr::<u64>();
+        let before = ptr.read_volatile();
+        ptr.write_volatile(7);
+        before
+    });
+    activate_kernel();
+
+    assert_eq!(value, 0);
+    assert!(space.translate(addr(10)).is_some());
+    assert!(space.translate(addr(11)).is_none());
+}
+
+#[test_case]
+fn writes_after_clone_are_private() {
+    let parent = AddressSpace::new().unwrap();
+    parent.map_anonymous(pages(0, 4), RW).unwrap();
+    let ptr = addr(1).as_mut_ptr::<u64>();
+
+    unsafe { parent.activate() };
+    with_user_access(|| unsafe { ptr.write_volatile(1) });
+
+    let child = parent.clone_cow().unwrap();
+    assert_eq!(
+        parent.translate(addr(1)).unwrap().0,
+        child.translate(addr(1)).unwrap().0
+    );
+
+    unsafe { child.activate() };
+    with_user_access(|| unsafe {
+        assert_eq!(ptr.read_volatile(), 1);
+        ptr.write_volatile(2);
+    });
+    unsafe { parent.activate() };
+    with_user_access(|| unsafe {
+        assert_eq!(ptr.read_volatile(), 1);
+        // the last sharer takes the frame over without copying
+        ptr.write_volatile(3);
+    });
+    activate_kernel();
+
+    assert_ne!(
+        parent.translate(addr(1)).unwrap().0,
+        child.translate(addr(1)).unwrap().0
+    );
+}
+
+#[test_case]
+fn dropping_shared_spaces_frees_everything() {
+    drop(AddressSpace::new().unwrap());
+    let free = || lib::mem::stats().frames.free;
+    let before = free();
+    {
+        let parent = AddressSpace::new().unwrap();
+        parent.map_anonymous(pages(0, 16), RW).unwrap();
+        unsafe { parent.activate() };
+        with_user_access(|| {
+            for i in 0..16 {
+                unsafe { addr(i).as_mut_ptr::<u8>().write_volatile(i as u8) };
+            }
+        });
+        activate_kernel();
+        let child = parent.clone_cow().unwrap();
+        drop(parent);
+        assert!(child.translate(addr(15)).is_some());
+    }
+    assert_eq!(free(), before);
+}
+
+#[test_case]
+fn faults_are_classified() {
+    let space = AddressSpace::new().unwrap();
+    space.map_anonymous(pages(1, 2), VmaFlags::READ).unwrap();
+    space.map_guard(pages(0, 1)).unwrap();
+
+    let write = PageFaultErrorCode::CAUSED_BY_WRITE | PageFaultErrorCode::USER_MODE;
+    assert_eq!(
+        space.handle_fault(addr(1), write),
+        Err(FaultKind::PermissionViolation)
+    );
+    assert_eq!(
+        space.handle_fault(addr(0), PageFaultErrorCode::USER_MODE),
+        Err(FaultKind::GuardPage)
+    );
+    assert_eq!(
+        space.handle_fault(addr(8), PageFaultErrorCode::USER_MODE),
+        Err(FaultKind::NotMapped)
+    );
+    assert_eq!(
+        space.handle_fault(addr(2), PageFaultErrorCode::USER_MODE),
+        Ok(())
+    );
+
+    let report = fault::handle(
+        VirtAddr::new(8),
+        PageFaultErrorCode::empty(),
+        VirtAddr::new(0x1234),
+    )
+    .unwrap_err();
+    assert_eq!(report.kind, FaultKind::NullDereference);
+}
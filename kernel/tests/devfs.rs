@@ -0,0 +1,221 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ak_os_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use ak_os_kernel as lib;
+use alloc::{string::String, sync::Arc, vec::Vec};
+use bootloader_api::{config::Mapping, entry_point, BootInfo, BootloaderConfig};
+use lib::{
+    elf, fb,
+    peripheral::{keyboard, mouse},
+    process::{self, Pid, State},
+    run_until,
+    task::{block_on, Executor},
+    user::Exit,
+    vfs::{self, ioctl, DevFs, FsError, NodeKind, OpenFile, OpenFlags, TmpFs},
+};
+use x86_64::VirtAddr;
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::Dynamic);
+    config
+};
+
+entry_point!(kernel_main, config = &BOOTLOADER_CONFIG);
+
+pub fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
+    log::set_logger(&lib::logger::LOGGER).expect("failed to setup logger");
+    log::set_max_level(log::LevelFilter::Info);
+
+    if let Some(framebuffer) = boot_info.framebuffer.as_mut() {
+        let info = framebuffer.info();
+        let buf = framebuffer.buffer_mut();
+        let buf = unsafe { core::slice::from_raw_parts_mut(buf.as_mut_ptr(), buf.len()) };
+        fb::init(buf, info);
+    }
+
+    let physical_memory_offset = VirtAddr::new(
+        boot_info
+            .physical_memory_offset
+            .into_option()
+            .expect("no physical_memory_offset"),
+    );
+    unsafe { lib::mem::init(physical_memory_offset, &boot_info.memory_regions) };
+
+    lib::init(None);
+
+    block_on(vfs::mount("/", Arc::new(TmpFs::default()))).expect("failed to mount the root");
+    block_on(vfs::mount("/dev", Arc::new(DevFs))).expect("failed to mount /dev");
+
+    test_main();
+
+    lib::exit_qemu(lib::QemuExitCode::Success);
+}
+
+/// Exits with the first scancode it reads from `/dev/keyboard`
+static KEYS: &[u8] = include_bytes!("programs/keys.elf");
+
+const RW: OpenFlags =
+    OpenFlags::from_bits_truncate(OpenFlags::READ.bits() | OpenFlags::WRITE.bits());
+
+fn open(path: &str) -> OpenFile {
+    block_on(vfs::open(path, RW)).unwrap()
+}
+
+#[test_case]
+fn lists_devices() {
+    let names: Vec<String> = block_on(vfs::read_dir("/dev"))
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect();
+    let expected = [
+        "serial0", "kmsg", "keyboard", "mouse", "null", "zero", "random",
+    ];
+    for name in expected {
+        assert!(
+            names.iter().any(|n| n == name),
+            "no {} in {:?}",
+            name,
+            names
+        );
+    }
+    assert_eq!(names.iter().any(|n| n == "fb0"), fb::info().is_some());
+
+    let null = block_on(vfs::stat("/dev/null")).unwrap();
+    assert_eq!(null.kind, NodeKind::CharDevice);
+    assert_eq!(null.mode, 0o666);
+    assert_eq!(
+        block_on(vfs::stat("/dev/nothing")).unwrap_err(),
+        FsError::NotFound
+    );
+}
+
+#[test_case]
+fn null_zero_and_random() {
+    let mut buf = [0xff; 64];
+    let null = open("/dev/null");
+    assert_eq!(block_on(null.read(&mut buf)), Ok(0));
+    assert_eq!(block_on(null.write(b"gone")), Ok(4));
+
+    let zero = open("/dev/zero");
+    assert_eq!(block_on(zero.read(&mut buf)), Ok(64));
+    assert!(buf.iter().all(|&b| b == 0));
+
+    let random = open("/dev/random");
+    let mut other = [0; 64];
+    assert_eq!(block_on(random.read(&mut buf)), Ok(64));
+    assert_eq!(block_on(random.read(&mut other)), Ok(64));
+    assert_ne!(buf, other);
+
+    assert_eq!(
+        block_on(null.ioctl(ioctl::FB_WIDTH, 0)),
+        Err(FsError::UnknownRequest)
+    );
+}
+
+#[test_case]
+fn kmsg_is_the_kernel_buffer() {
+    let kmsg = open("/dev/kmsg");
+    assert_eq!(block_on(kmsg.write(b"written to kmsg\n")), Ok(16));
+    lib::println!("printed to the kernel buffer");
+
+    let contents = block_on(vfs::read_to_end("/dev/kmsg")).unwrap();
+    let contents = String::from_utf8(contents).unwrap();
+    assert!(contents.contains("written to kmsg\n"));
+    assert!(contents.contains("printed to the kernel buffer\n"));
+}
+
+#[test_case]
+fn writes_to_serial() {
+    let serial = open("/dev/serial0");
+    assert_eq!(block_on(serial.write(b"written to serial0\n")), Ok(19));
+    // nothing was sent to us, reads don't wait
+    assert!(block_on(serial.read(&mut [0; 16])).unwrap() <= 16);
+}
+
+#[test_case]
+fn framebuffer_geometry() {
+    let Some(info) = fb::info() else {
+        return;
+    };
+    let fb0 = open("/dev/fb0");
+    let query = |request| block_on(fb0.ioctl(request, 0)).unwrap() as usize;
+    assert_eq!(query(ioctl::FB_WIDTH), info.width);
+    assert_eq!(query(ioctl::FB_HEIGHT), info.height);
+    assert_eq!(query(ioctl::FB_STRIDE), info.stride);
+    assert_eq!(query(ioctl::FB_BYTES_PER_PIXEL), info.bytes_per_pixel);
+    assert_ne!(
+        query(ioctl::FB_PIXEL_FORMAT),
+        ioctl::FB_FORMAT_UNKNOWN as usize
+    );
+    assert_eq!(
+        block_on(fb0.ioctl(ioctl::EVENTS_PENDING, 0)),
+        Err(FsError::UnknownRequest)
+    );
+    assert_eq!(
+        block_on(vfs::stat("/dev/fb0")).unwrap().size,
+        info.byte_len as u64
+    );
+
+    let pixels = [0x12, 0x34, 0x56, 0x78];
+    assert_eq!(block_on(fb0.write(&pixels)), Ok(4));
+    let mut read = [0; 4];
+    assert_eq!(block_on(fb0.seek(vfs::SeekFrom::Start(0))), Ok(0));
+    assert_eq!(block_on(fb0.read(&mut read)), Ok(4));
+    assert_eq!(read, pixels);
+
+    // nothing past the end
+    block_on(fb0.seek(vfs::SeekFrom::End(0))).unwrap();
+    assert_eq!(block_on(fb0.read(&mut read)), Ok(0));
+    assert_eq!(block_on(fb0.write(&pixels)), Err(FsError::NoSpace));
+}
+
+#[test_case]
+fn input_events() {
+    keyboard::EVENTS.publish([0x01]);
+    let keys = open("/dev/keyboard");
+    assert_eq!(block_on(keys.ioctl(ioctl::EVENTS_PENDING, 0)), Ok(0));
+    keyboard::EVENTS.publish([0x1e]);
+    keyboard::EVENTS.publish([0x9e]);
+    assert_eq!(block_on(keys.ioctl(ioctl::EVENTS_PENDING, 0)), Ok(2));
+    let mut buf = [0; 8];
+    assert_eq!(block_on(keys.read(&mut buf)), Ok(2));
+    assert_eq!(&buf[..2], [0x1e, 0x9e]);
+    assert_eq!(block_on(keys.write(&[0x1e])), Err(FsError::NotSupported));
+
+    let mice = open("/dev/mouse");
+    mouse::EVENTS.publish([1, 2, 0, 0xfe, 0xff]);
+    mouse::EVENTS.publish([0, 1, 0, 1, 0]);
+    assert_eq!(block_on(mice.ioctl(ioctl::EVENTS_PENDING, 0)), Ok(10));
+    // events are never split
+    assert_eq!(block_on(mice.read(&mut buf[..4])), Ok(0));
+    assert_eq!(block_on(mice.read(&mut buf)), Ok(5));
+    assert_eq!(buf[..5], [1, 2, 0, 0xfe, 0xff]);
+    assert_eq!(block_on(mice.read(&mut buf)), Ok(5));
+    assert_eq!(buf[..5], [0, 1, 0, 1, 0]);
+}
+
+#[test_case]
+fn programs_wait_for_input() {
+    let executor = Executor::default();
+    let program = elf::load(KEYS, &["keys"], &[]).unwrap();
+    let (pid, thread) = process::spawn("keys", program, Pid::KERNEL).unwrap();
+    executor.spawn(thread);
+
+    // the program waits for a key without keeping the executor from running other tasks
+    executor.run_ready_tasks();
+    assert!(matches!(
+        process::info(pid).map(|info| info.state),
+        Some(State::Running)
+    ));
+
+    keyboard::EVENTS.publish([0x1e]);
+    let (_, status) = run_until(&executor, process::waitpid(Pid::KERNEL, pid)).unwrap();
+    assert!(matches!(status, Exit::Exited(0x1e)), "{:?}", status);
+}
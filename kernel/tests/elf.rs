@@ -0,0 +1,157 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ak_os_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use ak_os_kernel as lib;
+use bootloader_api::{config::Mapping, entry_point, BootInfo, BootloaderConfig};
+use lib::{
+    elf::{self, ElfError},
+    mem::{address_space::USER_END, fault::FaultKind},
+    user::Exit,
+};
+use x86_64::{
+    structures::{idt::PageFaultErrorCode, paging::PageTableFlags},
+    VirtAddr,
+};
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::Dynamic);
+    config
+};
+
+entry_point!(kernel_main, config = &BOOTLOADER_CONFIG);
+
+pub fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
+    log::set_logger(&lib::logger::LOGGER).expect("failed to setup logger");
+    log::set_max_level(log::LevelFilter::Info);
+
+    let physical_memory_offset = VirtAddr::new(
+        boot_info
+            .physical_memory_offset
+            .into_option()
+            .expect("no physical_memory_offset"),
+    );
+    unsafe { lib::mem::init(physical_memory_offset, &boot_info.memory_regions) };
+
+    lib::init(None);
+
+    test_main();
+
+    lib::exit_qemu(lib::QemuExitCode::Success);
+}
+
+/// Built from `programs/hello.S`, linked to 0x400000
+static HELLO: &[u8] = include_bytes!("programs/hello.elf");
+/// The same program as a static PIE
+static HELLO_PIE: &[u8] = include_bytes!("programs/hello-pie.elf");
+
+/// Exit status of `hello` with two arguments and an environment starting with `X`
+const STATUS: i32 = 2 * 256 + b'X' as i32;
+
+fn flags(program: &elf::Program, addr: u64) -> PageTableFlags {
+    program
+        .space
+        .translate(VirtAddr::new(program.base + addr))
+        .expect("segment not mapped")
+        .1
+}
+
+#[test_case]
+fn runs_a_static_executable() {
+    let program = elf::load(HELLO, &["hello", "world"], &["X=1"]).unwrap();
+    assert_eq!(program.base, 0);
+    assert_eq!(program.entry, VirtAddr::new(0x40_1000));
+    assert_eq!(program.stack_pointer.as_u64() % 16, 0);
+
+    let exit = unsafe { program.run() };
+    assert!(matches!(exit, Exit::Exited(STATUS)), "{:?}", exit);
+}
+
+#[test_case]
+fn maps_segments_with_their_rights() {
+    let program = elf::load(HELLO, &[], &[]).unwrap();
+    let text = flags(&program, 0x40_1000);
+    let rodata = flags(&program, 0x40_2000);
+    let data = flags(&program, 0x40_3000);
+
+    assert!(text.contains(PageTableFlags::USER_ACCESSIBLE));
+    assert!(!text.intersects(PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE));
+    assert!(rodata.contains(PageTableFlags::NO_EXECUTE));
+    assert!(!rodata.contains(PageTableFlags::WRITABLE));
+    assert!(data.contains(PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE));
+    assert!(program.space.vmas().len() >= 4);
+}
+
+#[test_case]
+fn relocates_position_independent_executables() {
+    let a = elf::load(HELLO_PIE, &["hello", "world"], &["X=1"]).unwrap();
+    let b = elf::load(HELLO_PIE, &["hello"], &["X=1"]).unwrap();
+    assert_ne!(a.base, 0);
+    assert_eq!(a.base % 4096, 0);
+    assert!(a.base < USER_END);
+    assert_eq!(a.entry, VirtAddr::new(a.base + 0x1000));
+    assert_ne!(a.base, b.base, "load address isn't randomized");
+
+    // the program writes its message through a pointer that needs to be relocated
+    let exit = unsafe { a.run() };
+    assert!(matches!(exit, Exit::Exited(STATUS)), "{:?}", exit);
+}
+
+#[test_case]
+fn rejects_invalid_files() {
+    assert!(matches!(
+        elf::load(b"MZ", &[], &[]),
+        Err(ElfError::Truncated)
+    ));
+    assert!(matches!(
+        elf::load(&[0; 64], &[], &[]),
+        Err(ElfError::BadMagic)
+    ));
+
+    let mut truncated = HELLO.to_vec();
+    truncated.truncate(0x1010);
+    assert!(matches!(
+        elf::load(&truncated, &[], &[]),
+        Err(ElfError::BadSegment(_))
+    ));
+
+    // a segment pointing into kernel space
+    let mut kernel = HELLO.to_vec();
+    let text_vaddr = 64 + 56 + 16;
+    kernel[text_vaddr..text_vaddr + 8].copy_from_slice(&0xffff_8000_0000_1000u64.to_le_bytes());
+    assert!(elf::load(&kernel, &[], &[]).is_err());
+
+    // an alignment that isn't a power of two
+    let mut misaligned = HELLO_PIE.to_vec();
+    let text_align = 64 + 56 + 48;
+    misaligned[text_align..text_align + 8].copy_from_slice(&0x3000u64.to_le_bytes());
+    assert!(matches!(
+        elf::load(&misaligned, &[], &[]),
+        Err(ElfError::BadSegment(1))
+    ));
+}
+
+#[test_case]
+fn stack_has_a_guard_area() {
+    let program = elf::load(HELLO, &["hello"], &[]).unwrap();
+    let guard = program
+        .space
+        .vmas()
+        .into_iter()
+        .find(|vma| vma.flags.is_empty())
+        .expect("no guard area");
+    let stack = program.space.find_vma(program.stack_pointer).unwrap();
+    assert_eq!(guard.pages.end, stack.pages.start);
+    assert!(matches!(
+        program.space.handle_fault(
+            guard.pages.start.start_address(),
+            PageFaultErrorCode::empty()
+        ),
+        Err(FaultKind::GuardPage)
+    ));
+}
@@ -0,0 +1,89 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ak_os_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use ak_os_kernel as lib;
+use alloc::{boxed::Box, vec::Vec};
+use bootloader_api::{config::Mapping, entry_point, BootInfo, BootloaderConfig};
+use x86_64::VirtAddr;
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::Dynamic);
+    config
+};
+
+entry_point!(kernel_main, config = &BOOTLOADER_CONFIG);
+
+pub fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
+    log::set_logger(&lib::logger::LOGGER).expect("failed to setup logger");
+    log::set_max_level(log::LevelFilter::Trace);
+
+    let physical_memory_offset = VirtAddr::new(
+        boot_info
+            .physical_memory_offset
+            .into_option()
+            .expect("no physical_memory_offset"),
+    );
+    unsafe { lib::mem::init(physical_memory_offset, &boot_info.memory_regions) };
+
+    test_main();
+
+    lib::exit_qemu(lib::QemuExitCode::Success);
+}
+
+const MIB: usize = 1024 * 1024;
+
+fn heap_size() -> u64 {
+    lib::mem::stats().heap.size
+}
+
+#[test_case]
+fn grows_for_large_allocation() {
+    let before = heap_size();
+
+    let mut v: Vec<u8> = Vec::with_capacity(before as usize + 4 * MIB);
+    v.resize(v.capacity(), 0xAA);
+    assert!(heap_size() > before);
+    assert!(v.iter().all(|b| *b == 0xAA));
+}
+
+#[test_case]
+fn grows_for_many_small_allocations() {
+    let before = heap_size();
+
+    let boxes: Vec<Box<[u64; 64]>> = (0..(before as usize / 512 + 1024))
+        .map(|i| Box::new([i as u64; 64]))
+        .collect();
+    assert!(heap_size() > before);
+    for (i, b) in boxes.iter().enumerate() {
+        assert_eq!(b[63], i as u64);
+    }
+}
+
+#[test_case]
+fn shrinks_and_grows_again() {
+    let released = lib::mem::shrink_heap(MIB);
+    assert!(released > 0);
+    let shrunk = heap_size();
+
+    // decommitted chunks are mapped again before the heap is extended
+    let v: Vec<u8> = alloc::vec![1; 6 * MIB];
+    assert!(heap_size() > shrunk);
+    assert_eq!(v.iter().map(|b| *b as usize).sum::<usize>(), 6 * MIB);
+}
+
+#[test_case]
+fn respects_limit() {
+    lib::mem::set_heap_limit(heap_size() as usize);
+
+    let mut v: Vec<u8> = Vec::new();
+    assert!(v.try_reserve_exact(heap_size() as usize + MIB).is_err());
+
+    lib::mem::set_heap_limit(lib::mem::DEFAULT_HEAP_LIMIT);
+    assert!(v.try_reserve_exact(2 * MIB).is_ok());
+}
@@ -0,0 +1,130 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ak_os_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use ak_os_kernel as lib;
+use alloc::vec::Vec;
+use bootloader_api::{config::Mapping, entry_point, BootInfo, BootloaderConfig};
+use lib::initrd::{Archive, ArchiveError, EntryKind};
+use x86_64::VirtAddr;
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::Dynamic);
+    config
+};
+
+entry_point!(kernel_main, config = &BOOTLOADER_CONFIG);
+
+pub fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
+    log::set_logger(&lib::logger::LOGGER).expect("failed to setup logger");
+    log::set_max_level(log::LevelFilter::Info);
+
+    let physical_memory_offset = VirtAddr::new(
+        boot_info
+            .physical_memory_offset
+            .into_option()
+            .expect("no physical_memory_offset"),
+    );
+    unsafe { lib::mem::init(physical_memory_offset, &boot_info.memory_regions) };
+
+    lib::init(None);
+
+    test_main();
+
+    lib::exit_qemu(lib::QemuExitCode::Success);
+}
+
+/// Created with GNU tar from a directory with `bin/hello` (`programs/hello.elf`), a symbolic
+/// link `bin/motd -> ../etc/motd`, `etc/issue`, `etc/motd` as a hard link to it and a file
+/// whose path needs the ustar prefix field:
+///
+/// ```sh
+/// tar --format=ustar --sort=name --owner=0 --group=0 --numeric-owner --mtime=@0 -cf initrd.tar .
+/// ```
+static ARCHIVE: &[u8] = include_bytes!("data/initrd.tar");
+static HELLO: &[u8] = include_bytes!("programs/hello.elf");
+const MOTD: &[u8] = b"Welcome to akOS!\n";
+
+#[test_case]
+fn reads_files() {
+    let archive = Archive::parse(ARCHIVE).unwrap();
+    assert_eq!(archive.read("/bin/hello"), Some(HELLO));
+    assert_eq!(archive.read("etc/issue"), Some(MOTD));
+    let hello = archive.get("./bin/hello").unwrap();
+    assert_eq!(hello.mode, 0o755);
+    assert_eq!(hello.size(), HELLO.len());
+    assert_eq!(archive.read("bin"), None);
+    assert!(archive.get("etc/passwd").is_none());
+}
+
+#[test_case]
+fn resolves_links() {
+    let archive = Archive::parse(ARCHIVE).unwrap();
+    assert_eq!(archive.read("etc/motd"), Some(MOTD));
+    assert_eq!(
+        archive.get("bin/motd").unwrap().kind,
+        EntryKind::Symlink("../etc/motd".into())
+    );
+    assert_eq!(archive.read("bin/motd"), None);
+}
+
+#[test_case]
+fn lists_directories() {
+    let archive = Archive::parse(ARCHIVE).unwrap();
+    let root: Vec<&str> = archive
+        .read_dir("/")
+        .unwrap()
+        .map(|(name, _)| name)
+        .collect();
+    assert_eq!(root.len(), 3);
+    assert_eq!(&root[..2], ["bin", "etc"]);
+
+    let etc: Vec<&str> = archive
+        .read_dir("etc/")
+        .unwrap()
+        .map(|(name, _)| name)
+        .collect();
+    assert_eq!(etc, ["issue", "motd"]);
+    assert!(archive.read_dir("etc/motd").is_none());
+}
+
+#[test_case]
+fn joins_long_paths() {
+    let archive = Archive::parse(ARCHIVE).unwrap();
+    let (path, entry) = archive
+        .iter()
+        .find(|(path, _)| path.ends_with("/file"))
+        .unwrap();
+    assert!(path.len() > 100);
+    assert_eq!(entry.data(), b"deep\n");
+    let (dir, _) = path.rsplit_once('/').unwrap();
+    assert!(archive.get(dir).unwrap().is_dir());
+}
+
+#[test_case]
+fn rejects_corrupt_archives() {
+    let mut corrupt = ARCHIVE.to_vec();
+    corrupt[512] ^= 1;
+    assert_eq!(
+        Archive::parse(&corrupt).unwrap_err(),
+        ArchiveError::BadChecksum(512)
+    );
+
+    let truncated = &ARCHIVE[..3 * 512 + 100];
+    assert!(matches!(
+        Archive::parse(truncated),
+        Err(ArchiveError::Truncated(_))
+    ));
+
+    assert!(Archive::parse(&[0; 1024])
+        .unwrap()
+        .read_dir("")
+        .unwrap()
+        .next()
+        .is_none());
+}
@@ -0,0 +1,81 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ak_os_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use ak_os_kernel as lib;
+use alloc::boxed::Box;
+use bootloader_api::{config::Mapping, entry_point, BootInfo, BootloaderConfig};
+use lib::{
+    mem::{
+        layout::{self, HEAP_BAND, STACKS_BAND, VMALLOC_BAND},
+        stack::{self, StackKind},
+        vmalloc,
+    },
+    random,
+};
+use x86_64::VirtAddr;
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::Dynamic);
+    config.mappings.aslr = true;
+    config.mappings.dynamic_range_start = Some(0xffff_8000_0000_0000);
+    config
+};
+
+entry_point!(kernel_main, config = &BOOTLOADER_CONFIG);
+
+pub fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
+    log::set_logger(&lib::logger::LOGGER).expect("failed to setup logger");
+    log::set_max_level(log::LevelFilter::Info);
+
+    let physical_memory_offset = VirtAddr::new(
+        boot_info
+            .physical_memory_offset
+            .into_option()
+            .expect("no physical_memory_offset"),
+    );
+    unsafe { lib::mem::init(physical_memory_offset, &boot_info.memory_regions) };
+
+    test_main();
+
+    lib::exit_qemu(lib::QemuExitCode::Success);
+}
+
+#[test_case]
+fn random_numbers_differ() {
+    let a = random::u64();
+    let b = random::u64();
+    assert_ne!(a, b);
+    assert_ne!(random::entropy(), random::entropy());
+
+    let mut buf = [0u8; 61];
+    random::fill(&mut buf);
+    assert!(buf.iter().any(|b| *b != 0));
+}
+
+#[test_case]
+fn regions_lie_in_their_bands() {
+    let heap = VirtAddr::from_ptr(Box::into_raw(Box::new(0u64)));
+    assert!(HEAP_BAND.contains(&heap.as_u64()));
+    assert!(HEAP_BAND.contains(&layout::heap_start().as_u64()));
+
+    let region = vmalloc::region();
+    assert!(VMALLOC_BAND.contains(&region.start.as_u64()));
+    assert!(region.end.as_u64() <= VMALLOC_BAND.end);
+    assert_eq!(region.start.as_u64() % 0x20_0000, 0);
+    unsafe { drop(Box::from_raw(heap.as_mut_ptr::<u64>())) };
+}
+
+#[test_case]
+fn stacks_lie_in_their_band() {
+    let a = stack::allocate(0, StackKind::Kernel, 4).unwrap();
+    let b = stack::allocate(0, StackKind::Kernel, 4).unwrap();
+    assert!(STACKS_BAND.contains(&a.bottom().as_u64()));
+    assert!(a.bottom() >= layout::stacks_start());
+    assert!(b.guard_page().start_address() >= a.top());
+}
@@ -0,0 +1,203 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ak_os_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use ak_os_kernel as lib;
+use bootloader_api::{config::Mapping, entry_point, BootInfo, BootloaderConfig};
+use lib::{
+    elf,
+    process::{self, Handle, Pid, ProcessError, State},
+    run_until,
+    task::{Executor, Task},
+    user::Exit,
+};
+use x86_64::VirtAddr;
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::Dynamic);
+    config
+};
+
+entry_point!(kernel_main, config = &BOOTLOADER_CONFIG);
+
+pub fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
+    log::set_logger(&lib::logger::LOGGER).expect("failed to setup logger");
+    log::set_max_level(log::LevelFilter::Info);
+
+    let physical_memory_offset = VirtAddr::new(
+        boot_info
+            .physical_memory_offset
+            .into_option()
+            .expect("no physical_memory_offset"),
+    );
+    unsafe { lib::mem::init(physical_memory_offset, &boot_info.memory_regions) };
+
+    lib::init(None);
+
+    test_main();
+
+    lib::exit_qemu(lib::QemuExitCode::Success);
+}
+
+/// Exits with `argc * 256 + envp[0][0]`, see `programs/hello.S`
+static HELLO: &[u8] = include_bytes!("programs/hello.elf");
+/// Exits with `getpid() * 256 + getppid()`, see `programs/pid.S`
+static PID: &[u8] = include_bytes!("programs/pid.elf");
+
+/// Exit status of `hello` with the arguments from [`spawn`]
+const STATUS: i32 = 256 + b'X' as i32;
+
+fn spawn(program: &[u8], parent: Pid) -> (Pid, Task) {
+    let program = elf::load(program, &["test"], &["X=1"]).unwrap();
+    process::spawn("test", program, parent).unwrap()
+}
+
+fn state(pid: Pid) -> Option<State> {
+    process::info(pid).map(|info| info.state)
+}
+
+fn parent(pid: Pid) -> Pid {
+    process::info(pid).expect("no such process").parent
+}
+
+// runs first, the first process is init
+#[test_case]
+fn orphans_are_adopted_by_init() {
+    let executor = Executor::default();
+    let (init, init_thread) = spawn(HELLO, Pid::KERNEL);
+    assert_eq!(init, Pid::INIT);
+    let (parent_pid, _) = spawn(HELLO, Pid::KERNEL);
+    let (child, child_thread) = spawn(HELLO, parent_pid);
+
+    process::exit(parent_pid, Exit::Exited(0));
+    assert_eq!(parent(child), Pid::INIT);
+    executor.spawn(child_thread);
+    let (reaped, status) = run_until(&executor, process::wait(Pid::INIT)).unwrap();
+    assert_eq!(reaped, child);
+    assert!(matches!(status, Exit::Exited(STATUS)), "{:?}", status);
+
+    executor.spawn(init_thread);
+    run_until(&executor, process::waitpid(Pid::KERNEL, init)).unwrap();
+    assert!(matches!(
+        process::try_wait(Pid::KERNEL, Some(parent_pid)),
+        Ok(Some((_, Exit::Exited(0))))
+    ));
+}
+
+#[test_case]
+fn waits_for_the_main_thread() {
+    let executor = Executor::default();
+    let (pid, thread) = spawn(HELLO, Pid::KERNEL);
+    assert!(matches!(state(pid), Some(State::Ready)));
+
+    executor.spawn(thread);
+    let (reaped, status) = run_until(&executor, process::waitpid(Pid::KERNEL, pid)).unwrap();
+    assert_eq!(reaped, pid);
+    assert!(matches!(status, Exit::Exited(STATUS)), "{:?}", status);
+    assert!(state(pid).is_none(), "process wasn't reaped");
+}
+
+#[test_case]
+fn zombies_keep_their_status() {
+    let executor = Executor::default();
+    let (pid, thread) = spawn(HELLO, Pid::KERNEL);
+    executor.spawn(thread);
+    executor.run_ready_tasks();
+
+    assert!(matches!(
+        state(pid),
+        Some(State::Zombie(Exit::Exited(STATUS)))
+    ));
+    assert!(matches!(
+        process::try_wait(Pid::KERNEL, Some(pid)),
+        Ok(Some((reaped, Exit::Exited(STATUS)))) if reaped == pid
+    ));
+    assert_eq!(
+        process::try_wait(Pid::KERNEL, Some(pid)).unwrap_err(),
+        ProcessError::NoSuchProcess(pid)
+    );
+}
+
+#[test_case]
+fn getpid_returns_the_process_id() {
+    let executor = Executor::default();
+    let (pid, thread) = spawn(PID, Pid::KERNEL);
+    executor.spawn(thread);
+    let (_, status) = run_until(&executor, process::wait(Pid::KERNEL)).unwrap();
+    let expected = (pid.0 * 256 + Pid::KERNEL.0) as i32;
+    assert!(
+        matches!(status, Exit::Exited(s) if s == expected),
+        "{:?}",
+        status
+    );
+    assert_eq!(process::current(), None);
+}
+
+#[test_case]
+fn waiting_needs_children() {
+    let (pid, _) = spawn(HELLO, Pid::KERNEL);
+    let (other, _) = spawn(HELLO, Pid::KERNEL);
+    assert_eq!(
+        process::try_wait(pid, None).unwrap_err(),
+        ProcessError::NoChildren
+    );
+    assert_eq!(
+        process::try_wait(pid, Some(other)).unwrap_err(),
+        ProcessError::NotAChild(other)
+    );
+    assert!(matches!(
+        process::try_wait(Pid::KERNEL, Some(pid)),
+        Ok(None)
+    ));
+
+    process::exit(pid, Exit::Exited(1));
+    process::exit(other, Exit::Exited(2));
+    let mut reaped = [
+        process::try_wait(Pid::KERNEL, None).unwrap().unwrap().0,
+        process::try_wait(Pid::KERNEL, None).unwrap().unwrap().0,
+    ];
+    reaped.sort();
+    assert_eq!(reaped, [pid, other]);
+}
+
+#[test_case]
+fn children_inherit_handles() {
+    let (parent_pid, _) = spawn(HELLO, Pid::KERNEL);
+    let fd = process::with_handles(parent_pid, |handles| handles.insert(Handle::Console))
+        .unwrap()
+        .unwrap();
+    assert_eq!(fd, 0);
+
+    let (child, _) = spawn(HELLO, parent_pid);
+    let handles = process::with_handles(child, |handles| handles.clone()).unwrap();
+    assert_eq!(handles.get(fd), Some(&Handle::Console));
+    assert_eq!(handles.len(), 3);
+
+    process::exit(child, Exit::Exited(0));
+    process::exit(parent_pid, Exit::Exited(0));
+    process::try_wait(Pid::KERNEL, Some(parent_pid)).unwrap();
+}
+
+// init exited in the first test, orphans are adopted by the kernel now
+#[test_case]
+fn orphans_without_init_are_reaped_on_exit() {
+    let executor = Executor::default();
+    let (parent_pid, _) = spawn(HELLO, Pid::KERNEL);
+    let (exited, _) = spawn(HELLO, parent_pid);
+    let (child, thread) = spawn(HELLO, parent_pid);
+    process::exit(exited, Exit::Exited(0));
+
+    process::exit(parent_pid, Exit::Exited(0));
+    assert!(state(exited).is_none(), "exited orphan wasn't reaped");
+    assert_eq!(parent(child), Pid::KERNEL);
+
+    executor.spawn(thread);
+    executor.run_ready_tasks();
+    assert!(state(child).is_none(), "orphan wasn't reaped");
+    process::try_wait(Pid::KERNEL, Some(parent_pid)).unwrap();
+}
@@ -0,0 +1,163 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ak_os_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use ak_os_kernel as lib;
+use alloc::{string::String, sync::Arc, vec::Vec};
+use bootloader_api::{config::Mapping, entry_point, BootInfo, BootloaderConfig};
+use lib::{
+    pci,
+    task::{block_on, executor, Task},
+    vfs::{self, FsError, NodeKind, OpenFlags, ProcFs, TmpFs},
+};
+use x86_64::VirtAddr;
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::Dynamic);
+    config
+};
+
+entry_point!(kernel_main, config = &BOOTLOADER_CONFIG);
+
+pub fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
+    log::set_logger(&lib::logger::LOGGER).expect("failed to setup logger");
+    log::set_max_level(log::LevelFilter::Info);
+
+    let physical_memory_offset = VirtAddr::new(
+        boot_info
+            .physical_memory_offset
+            .into_option()
+            .expect("no physical_memory_offset"),
+    );
+    unsafe { lib::mem::init(physical_memory_offset, &boot_info.memory_regions) };
+
+    lib::init(None);
+
+    block_on(vfs::mount("/", Arc::new(TmpFs::default()))).expect("failed to mount the root");
+    block_on(vfs::mount("/proc", Arc::new(ProcFs))).expect("failed to mount /proc");
+
+    test_main();
+
+    lib::exit_qemu(lib::QemuExitCode::Success);
+}
+
+fn read(path: &str) -> String {
+    String::from_utf8(block_on(vfs::read_to_end(path)).unwrap()).unwrap()
+}
+
+#[test_case]
+fn lists_entries() {
+    let names: Vec<String> = block_on(vfs::read_dir("/proc"))
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect();
+    let expected = [
+        "cpuinfo",
+        "tasks",
+        "meminfo",
+        "interrupts",
+        "pci",
+        "uptime",
+        "version",
+    ];
+    assert_eq!(names, expected);
+
+    let version = block_on(vfs::stat("/proc/version")).unwrap();
+    assert_eq!(version.kind, NodeKind::File);
+    assert_eq!(version.mode, 0o444);
+    assert_eq!(
+        block_on(vfs::open("/proc/version", OpenFlags::WRITE)).unwrap_err(),
+        FsError::ReadOnly
+    );
+    assert_eq!(
+        block_on(vfs::create_dir("/proc/new")).unwrap_err(),
+        FsError::ReadOnly
+    );
+}
+
+#[test_case]
+fn build_info() {
+    let version = read("/proc/version");
+    assert!(version.starts_with("akOS kernel "));
+    for info in [env!("BUILD_DATE"), env!("RUSTC_VERSION"), env!("PROFILE")] {
+        assert!(version.contains(info), "no {} in {:?}", info, version);
+    }
+}
+
+#[test_case]
+fn cpu_and_memory() {
+    let cpuinfo = read("/proc/cpuinfo");
+    assert!(cpuinfo.starts_with("processor       : 0\n"));
+    assert!(cpuinfo.contains("vendor          : "));
+    assert!(cpuinfo.contains("flags           : "));
+
+    let meminfo = read("/proc/meminfo");
+    for name in ["MemUsable:", "MemFree:", "HeapSize:", "HeapUsed:"] {
+        assert!(meminfo.contains(name), "no {} in {:?}", name, meminfo);
+    }
+}
+
+#[test_case]
+fn tasks_are_listed() {
+    executor::spawn(Task::new_with_name("procfs test", async {}));
+    let tasks = read("/proc/tasks");
+    assert!(
+        tasks.lines().any(|line| line.ends_with(" procfs test")),
+        "{:?}",
+        tasks
+    );
+}
+
+#[test_case]
+fn counts_interrupts() {
+    let breakpoints = || {
+        read("/proc/interrupts")
+            .lines()
+            .find(|line| line.ends_with("  breakpoint"))
+            .and_then(|line| line.split_whitespace().nth(1))
+            .and_then(|count| count.parse::<u64>().ok())
+            .expect("no breakpoint count")
+    };
+
+    let before = breakpoints();
+    x86_64::instructions::interrupts::int3();
+    x86_64::instructions::interrupts::int3();
+    assert_eq!(breakpoints(), before + 2);
+    // counted by the ID the GDT holds, which needs no CPUID
+    assert_eq!(lib::gdt::current_cpu(), Some(0));
+    assert_eq!(lib::cpu::try_current_id(), Some(0));
+}
+
+#[test_case]
+fn pci_devices_and_uptime() {
+    let listed = read("/proc/pci");
+    assert_eq!(listed.lines().count(), pci::devices().len());
+    // QEMU always has a host bridge
+    assert!(listed.contains("00:00.0 "), "{:?}", listed);
+
+    let uptime = read("/proc/uptime");
+    assert!(uptime.trim_end().parse::<u64>().is_ok(), "{:?}", uptime);
+}
+
+#[test_case]
+fn contents_are_snapshots() {
+    let file = block_on(vfs::open("/proc/interrupts", OpenFlags::READ)).unwrap();
+    x86_64::instructions::interrupts::int3();
+
+    let mut opened = Vec::new();
+    let mut chunk = [0; 64];
+    loop {
+        match block_on(file.read(&mut chunk)).unwrap() {
+            0 => break,
+            read => opened.extend_from_slice(&chunk[..read]),
+        }
+    }
+    assert!(!opened.is_empty());
+    assert_ne!(opened, read("/proc/interrupts").into_bytes());
+}
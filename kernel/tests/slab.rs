@@ -0,0 +1,160 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ak_os_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use ak_os_kernel as lib;
+use alloc::{
+    alloc::{alloc, dealloc, Layout},
+    boxed::Box,
+    vec::Vec,
+};
+use bootloader_api::{config::Mapping, entry_point, BootInfo, BootloaderConfig};
+use core::arch::x86_64::_rdtsc;
+use lib::mem::slab::MAX_OBJECT_SIZE;
+use x86_64::VirtAddr;
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::Dynamic);
+    config
+};
+
+entry_point!(kernel_main, config = &BOOTLOADER_CONFIG);
+
+pub fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
+    log::set_logger(&lib::logger::LOGGER).expect("failed to setup logger");
+    log::set_max_level(log::LevelFilter::Info);
+
+    let physical_memory_offset = VirtAddr::new(
+        boot_info
+            .physical_memory_offset
+            .into_option()
+            .expect("no physical_memory_offset"),
+    );
+    unsafe { lib::mem::init(physical_memory_offset, &boot_info.memory_regions) };
+
+    lib::init(None);
+
+    test_main();
+
+    lib::exit_qemu(lib::QemuExitCode::Success);
+}
+
+const ITERATIONS: usize = 10_000;
+
+#[test_case]
+fn objects_are_aligned_and_distinct() {
+    for size in [1, 8, 16, 24, 100, 512, 1000, MAX_OBJECT_SIZE] {
+        for align in [1, 8, 64, 256] {
+            let layout = Layout::from_size_align(size, align).unwrap();
+            let ptrs: Vec<*mut u8> = (0..100).map(|_| unsafe { alloc(layout) }).collect();
+
+            for (i, &ptr) in ptrs.iter().enumerate() {
+                assert!(!ptr.is_null());
+                assert_eq!(ptr as usize % align, 0);
+                unsafe { ptr.write_bytes(i as u8, size) };
+            }
+            for (i, &ptr) in ptrs.iter().enumerate() {
+                let bytes = unsafe { core::slice::from_raw_parts(ptr, size) };
+                assert!(bytes.iter().all(|b| *b == i as u8));
+                unsafe { dealloc(ptr, layout) };
+            }
+        }
+    }
+}
+
+#[test_case]
+fn freed_objects_are_reused() {
+    let first = Box::new([0u64; 8]);
+    let addr = &*first as *const _ as usize;
+    drop(first);
+
+    let second = Box::new([1u64; 8]);
+    assert_eq!(&*second as *const _ as usize, addr);
+}
+
+#[test_case]
+fn realloc_across_classes() {
+    let mut v: Vec<u32> = Vec::new();
+    for i in 0..(4 * MAX_OBJECT_SIZE as u32) {
+        v.push(i);
+    }
+    assert!(v.iter().enumerate().all(|(i, x)| *x == i as u32));
+    v.truncate(3);
+    v.shrink_to_fit();
+    assert_eq!(v, [0, 1, 2]);
+}
+
+#[test_case]
+fn in_use_counts() {
+    let in_use = || -> usize {
+        lib::mem::stats()
+            .slabs
+            .iter()
+            .find(|s| s.object_size == 64)
+            .unwrap()
+            .in_use
+    };
+
+    let before = in_use();
+    let boxes: Vec<Box<[u8; 64]>> = (0..100).map(|_| Box::new([0; 64])).collect();
+    // the vector itself is too large for a 64 byte object
+    assert_eq!(in_use(), before + 100);
+    drop(boxes);
+    assert_eq!(in_use(), before);
+}
+
+/// Average cycles per allocation and deallocation of `layout`, in a batch of `ITERATIONS`
+fn bench(layout: Layout) -> (u64, u64) {
+    let mut ptrs = Vec::with_capacity(ITERATIONS);
+
+    let start = unsafe { _rdtsc() };
+    for _ in 0..ITERATIONS {
+        ptrs.push(unsafe { alloc(layout) });
+    }
+    let allocated = unsafe { _rdtsc() };
+    for &ptr in ptrs.iter() {
+        unsafe { dealloc(ptr, layout) };
+    }
+    let end = unsafe { _rdtsc() };
+
+    let n = ITERATIONS as u64;
+    ((allocated - start) / n, (end - allocated) / n)
+}
+
+/// Average cycles per allocation immediately followed by its deallocation
+fn bench_pairs(layout: Layout) -> u64 {
+    let start = unsafe { _rdtsc() };
+    for _ in 0..ITERATIONS {
+        unsafe { dealloc(core::hint::black_box(alloc(layout)), layout) };
+    }
+    (unsafe { _rdtsc() } - start) / ITERATIONS as u64
+}
+
+#[test_case]
+fn benchmark() {
+    for size in [16, 64, 256, 1024, MAX_OBJECT_SIZE, 4096, 16384] {
+        let layout = Layout::from_size_align(size, 8).unwrap();
+        // warm up, so slabs and heap growth aren't measured
+        bench(layout);
+
+        let (alloc_cycles, dealloc_cycles) = bench(layout);
+        let pair_cycles = bench_pairs(layout);
+        log::info!(
+            "{:>5} bytes ({}): alloc {} cycles, dealloc {} cycles, alloc+dealloc {} cycles",
+            size,
+            if size <= MAX_OBJECT_SIZE {
+                "slab"
+            } else {
+                "linked list"
+            },
+            alloc_cycles,
+            dealloc_cycles,
+            pair_cycles
+        );
+    }
+}
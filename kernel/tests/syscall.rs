@@ -0,0 +1,186 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ak_os_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use ak_os_kernel as lib;
+use alloc::{boxed::Box, vec::Vec};
+use bootloader_api::{config::Mapping, entry_point, BootInfo, BootloaderConfig};
+use lib::{
+    mem::{
+        address_space::{activate_kernel, USER_END},
+        protection::with_user_access,
+        user_copy::{copy_from_user, copy_to_user, UserCopyError},
+        vma::VmaFlags,
+        AddressSpace,
+    },
+    syscall::SyscallError,
+    user::{self, Exit},
+};
+use x86_64::{
+    structures::paging::{page::PageRange, Page, PageTableFlags},
+    VirtAddr,
+};
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::Dynamic);
+    config
+};
+
+entry_point!(kernel_main, config = &BOOTLOADER_CONFIG);
+
+pub fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
+    log::set_logger(&lib::logger::LOGGER).expect("failed to setup logger");
+    log::set_max_level(log::LevelFilter::Info);
+
+    let physical_memory_offset = VirtAddr::new(
+        boot_info
+            .physical_memory_offset
+            .into_option()
+            .expect("no physical_memory_offset"),
+    );
+    unsafe { lib::mem::init(physical_memory_offset, &boot_info.memory_regions) };
+
+    lib::init(None);
+
+    test_main();
+
+    lib::exit_qemu(lib::QemuExitCode::Success);
+}
+
+const CODE: u64 = USER_END - 0x100_0000;
+const STACK_TOP: u64 = CODE + 0x10_0000;
+
+/// mov rdi, rax; mov eax, 1 (exit); syscall
+const EXIT_WITH_RAX: [u8; 10] = [0x48, 0x89, 0xc7, 0xb8, 0x01, 0, 0, 0, 0x0f, 0x05];
+
+fn pages(start: u64, count: u64) -> PageRange {
+    let start = Page::containing_address(VirtAddr::new(start));
+    Page::range(start, start + count)
+}
+
+fn user_space() -> AddressSpace {
+    let space = AddressSpace::new().unwrap();
+    space
+        .map_anonymous(
+            pages(STACK_TOP - 4 * 4096, 4),
+            VmaFlags::READ | VmaFlags::WRITE,
+        )
+        .unwrap();
+    space
+}
+
+/// Runs `code` followed by [`EXIT_WITH_RAX`] in a fresh address space.
+fn run(code: &[u8]) -> Exit {
+    let code: Vec<u8> = code.iter().chain(EXIT_WITH_RAX.iter()).copied().collect();
+    let space = user_space();
+    let user = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    space
+        .map(pages(CODE, 1), user | PageTableFlags::WRITABLE)
+        .unwrap();
+
+    unsafe { space.activate() };
+    with_user_access(|| unsafe {
+        core::ptr::copy_nonoverlapping(code.as_ptr(), CODE as *mut u8, code.len())
+    });
+    space.update_flags(pages(CODE, 1).start, user).unwrap();
+
+    let exit = unsafe { user::run(VirtAddr::new(CODE), VirtAddr::new(STACK_TOP)) };
+    activate_kernel();
+    exit
+}
+
+fn exit_status(exit: Exit) -> i32 {
+    match exit {
+        Exit::Exited(status) => status,
+        exit => panic!("unexpected exit: {:?}", exit),
+    }
+}
+
+#[test_case]
+fn write_copies_from_user_memory() {
+    let mut code = Vec::new();
+    // lea rsi, [rip + 24]; mov edi, 1; mov edx, 6; xor eax, eax; syscall
+    code.extend_from_slice(&[0x48, 0x8d, 0x35, 24, 0, 0, 0]);
+    code.extend_from_slice(&[0xbf, 1, 0, 0, 0, 0xba, 6, 0, 0, 0, 0x31, 0xc0, 0x0f, 0x05]);
+    code.extend_from_slice(&EXIT_WITH_RAX);
+    code.extend_from_slice(b"hello\n");
+    assert_eq!(exit_status(run(&code)), 6);
+}
+
+#[test_case]
+fn write_rejects_kernel_pointers() {
+    let secret = Box::new(0u64);
+    let addr = &*secret as *const u64 as u64;
+
+    // mov edi, 1; movabs rsi, addr; mov edx, 8; xor eax, eax; syscall
+    let mut code = Vec::new();
+    code.extend_from_slice(&[0xbf, 1, 0, 0, 0, 0x48, 0xbe]);
+    code.extend_from_slice(&addr.to_le_bytes());
+    code.extend_from_slice(&[0xba, 8, 0, 0, 0, 0x31, 0xc0, 0x0f, 0x05]);
+    assert_eq!(
+        exit_status(run(&code)),
+        SyscallError::BadAddress.code() as i32
+    );
+}
+
+#[test_case]
+fn registers_are_preserved() {
+    // mov esi, 0x40; mov eax, 5 (getpid); syscall; lea rax, [rsi + rax]
+    let code = [
+        0xbe, 0x40, 0, 0, 0, 0xb8, 5, 0, 0, 0, 0x0f, 0x05, 0x48, 0x8d, 0x04, 0x06,
+    ];
+    // outside of a process the pid is the kernel's, 0
+    assert_eq!(exit_status(run(&code)), 0x40);
+}
+
+#[test_case]
+fn unknown_syscalls_fail() {
+    // mov eax, 99; syscall
+    let code = [0xb8, 99, 0, 0, 0, 0x0f, 0x05];
+    assert_eq!(
+        exit_status(run(&code)),
+        SyscallError::NoSuchSyscall.code() as i32
+    );
+}
+
+#[test_case]
+fn mmap_maps_anonymous_memory() {
+    // xor edi, edi; mov esi, 0x2000; mov edx, 3 (read | write); mov eax, 3 (mmap); syscall
+    // mov qword [rax + 0x1000], 9; mov rax, [rax + 0x1000]
+    let code = [
+        0x31, 0xff, 0xbe, 0, 0x20, 0, 0, 0xba, 3, 0, 0, 0, 0xb8, 3, 0, 0, 0, 0x0f, 0x05, 0x48,
+        0xc7, 0x80, 0, 0x10, 0, 0, 9, 0, 0, 0, 0x48, 0x8b, 0x80, 0, 0x10, 0, 0,
+    ];
+    assert_eq!(exit_status(run(&code)), 9);
+}
+
+#[test_case]
+fn copies_check_the_active_address_space() {
+    let mut buf = [0u8; 4];
+    let stack = VirtAddr::new(STACK_TOP - 8);
+    assert_eq!(
+        copy_from_user(&mut buf, stack),
+        Err(UserCopyError::NoAddressSpace)
+    );
+
+    let space = user_space();
+    unsafe { space.activate() };
+    copy_to_user(stack, &[1, 2, 3, 4]).unwrap();
+    copy_from_user(&mut buf, stack).unwrap();
+    let kernel = VirtAddr::from_ptr(&buf);
+    let result = copy_to_user(kernel, &[0; 4]);
+    let overflow = copy_from_user(&mut buf, VirtAddr::new(STACK_TOP - 2));
+    activate_kernel();
+
+    assert_eq!(buf, [1, 2, 3, 4]);
+    assert_eq!(result, Err(UserCopyError::BadAddress(kernel)));
+    assert_eq!(
+        overflow,
+        Err(UserCopyError::BadAddress(VirtAddr::new(STACK_TOP)))
+    );
+}
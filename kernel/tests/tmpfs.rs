@@ -0,0 +1,292 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ak_os_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use ak_os_kernel as lib;
+use alloc::{string::String, sync::Arc, vec, vec::Vec};
+use bootloader_api::{config::Mapping, entry_point, BootInfo, BootloaderConfig};
+use lib::{
+    task::block_on,
+    vfs::{self, FsError, NodeKind, OpenFile, OpenFlags, SeekFrom, TmpFs},
+};
+use x86_64::VirtAddr;
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::Dynamic);
+    config
+};
+
+entry_point!(kernel_main, config = &BOOTLOADER_CONFIG);
+
+pub fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
+    log::set_logger(&lib::logger::LOGGER).expect("failed to setup logger");
+    log::set_max_level(log::LevelFilter::Info);
+
+    let physical_memory_offset = VirtAddr::new(
+        boot_info
+            .physical_memory_offset
+            .into_option()
+            .expect("no physical_memory_offset"),
+    );
+    unsafe { lib::mem::init(physical_memory_offset, &boot_info.memory_regions) };
+
+    lib::init(None);
+
+    block_on(vfs::mount("/", Arc::new(TmpFs::default()))).expect("failed to mount the root");
+
+    test_main();
+
+    lib::exit_qemu(lib::QemuExitCode::Success);
+}
+
+const RW: OpenFlags = OpenFlags::from_bits_truncate(
+    OpenFlags::READ.bits() | OpenFlags::WRITE.bits() | OpenFlags::CREATE.bits(),
+);
+
+fn open(path: &str, flags: OpenFlags) -> Result<OpenFile, FsError> {
+    block_on(vfs::open(path, flags))
+}
+
+fn write_file(path: &str, data: &[u8]) {
+    let file = open(path, RW | OpenFlags::TRUNCATE).unwrap();
+    assert_eq!(block_on(file.write(data)), Ok(data.len()));
+}
+
+fn read_file(path: &str) -> Result<Vec<u8>, FsError> {
+    block_on(vfs::read_to_end(path))
+}
+
+fn names(path: &str) -> Vec<String> {
+    block_on(vfs::read_dir(path))
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect()
+}
+
+#[test_case]
+fn writes_files() {
+    let file = open("/file", RW).unwrap();
+    assert_eq!(block_on(file.write(b"hello")), Ok(5));
+    assert_eq!(block_on(file.seek(SeekFrom::Start(8))), Ok(8));
+    assert_eq!(block_on(file.write(b"world")), Ok(5));
+    assert_eq!(read_file("/file").unwrap(), b"hello\0\0\0world");
+    assert_eq!(block_on(vfs::stat("/file")).unwrap().size, 13);
+
+    let append = open("/file", OpenFlags::WRITE | OpenFlags::APPEND).unwrap();
+    assert_eq!(block_on(append.write(b"!")), Ok(1));
+    assert_eq!(read_file("/file").unwrap(), b"hello\0\0\0world!");
+
+    assert_eq!(
+        open("/file", RW | OpenFlags::EXCLUSIVE).unwrap_err(),
+        FsError::AlreadyExists
+    );
+    assert_eq!(
+        open("/file", OpenFlags::READ | OpenFlags::TRUNCATE).unwrap_err(),
+        FsError::AccessDenied
+    );
+    open("/file", OpenFlags::WRITE | OpenFlags::TRUNCATE).unwrap();
+    assert_eq!(read_file("/file").unwrap(), b"");
+    assert_eq!(
+        open("/missing", OpenFlags::READ).unwrap_err(),
+        FsError::NotFound
+    );
+    block_on(vfs::remove("/file")).unwrap();
+}
+
+#[test_case]
+fn truncates_files() {
+    write_file("/truncated", b"0123456789");
+    block_on(vfs::truncate("/truncated", 4)).unwrap();
+    assert_eq!(read_file("/truncated").unwrap(), b"0123");
+    block_on(vfs::truncate("/truncated", 6)).unwrap();
+    assert_eq!(read_file("/truncated").unwrap(), b"0123\0\0");
+
+    block_on(vfs::create_dir("/dir")).unwrap();
+    assert_eq!(
+        block_on(vfs::truncate("/dir", 0)).unwrap_err(),
+        FsError::IsADirectory
+    );
+    block_on(vfs::remove("/dir")).unwrap();
+    block_on(vfs::remove("/truncated")).unwrap();
+}
+
+#[test_case]
+fn creates_and_removes_directories() {
+    block_on(vfs::create_dir("/a")).unwrap();
+    block_on(vfs::create_dir("/a/b")).unwrap();
+    write_file("/a/b/c", b"c");
+    assert_eq!(
+        block_on(vfs::stat("/a/b")).unwrap().kind,
+        NodeKind::Directory
+    );
+    assert_eq!(names("/a"), ["b"]);
+    assert_eq!(
+        block_on(vfs::create_dir("/a/b")).unwrap_err(),
+        FsError::AlreadyExists
+    );
+    assert_eq!(
+        block_on(vfs::create_dir("/a/b/c/d")).unwrap_err(),
+        FsError::NotADirectory
+    );
+    assert_eq!(
+        block_on(vfs::create_dir("/a/..")).unwrap_err(),
+        FsError::InvalidArgument
+    );
+
+    assert_eq!(
+        block_on(vfs::remove("/a/b")).unwrap_err(),
+        FsError::NotEmpty
+    );
+    block_on(vfs::remove("/a/b/c")).unwrap();
+    block_on(vfs::remove("/a/b")).unwrap();
+    assert_eq!(
+        block_on(vfs::remove("/a/b")).unwrap_err(),
+        FsError::NotFound
+    );
+    block_on(vfs::remove("/a")).unwrap();
+    assert!(names("/").is_empty());
+}
+
+#[test_case]
+fn follows_symbolic_links() {
+    block_on(vfs::create_dir("/links")).unwrap();
+    write_file("/links/target", b"target");
+    block_on(vfs::symlink("target", "/links/relative")).unwrap();
+    block_on(vfs::symlink("/links", "/links/absolute")).unwrap();
+    block_on(vfs::symlink("missing", "/links/dangling")).unwrap();
+
+    assert_eq!(read_file("/links/relative").unwrap(), b"target");
+    assert_eq!(
+        read_file("/links/absolute/absolute/target").unwrap(),
+        b"target"
+    );
+    assert_eq!(
+        block_on(vfs::read_link("/links/relative")).unwrap(),
+        "target"
+    );
+    assert_eq!(
+        block_on(vfs::lstat("/links/relative")).unwrap().kind,
+        NodeKind::Symlink
+    );
+    assert_eq!(read_file("/links/dangling").unwrap_err(), FsError::NotFound);
+
+    block_on(vfs::symlink("loop", "/links/loop")).unwrap();
+    assert_eq!(read_file("/links/loop").unwrap_err(), FsError::TooManyLinks);
+
+    for name in ["relative", "absolute", "dangling", "loop", "target"] {
+        block_on(vfs::remove(&alloc::format!("/links/{}", name))).unwrap();
+    }
+    block_on(vfs::remove("/links")).unwrap();
+}
+
+#[test_case]
+fn renames() {
+    block_on(vfs::create_dir("/from")).unwrap();
+    block_on(vfs::create_dir("/to")).unwrap();
+    write_file("/from/file", b"moved");
+    write_file("/to/old", b"replaced");
+
+    block_on(vfs::rename("/from/file", "/to/old")).unwrap();
+    assert!(names("/from").is_empty());
+    assert_eq!(read_file("/to/old").unwrap(), b"moved");
+    block_on(vfs::rename("/to/old", "/to/new")).unwrap();
+    assert_eq!(names("/to"), ["new"]);
+
+    assert_eq!(
+        block_on(vfs::rename("/to", "/to/below")).unwrap_err(),
+        FsError::InvalidArgument
+    );
+    assert_eq!(
+        block_on(vfs::rename("/from", "/to")).unwrap_err(),
+        FsError::NotEmpty
+    );
+    assert_eq!(
+        block_on(vfs::rename("/to/new", "/from")).unwrap_err(),
+        FsError::IsADirectory
+    );
+    assert_eq!(
+        block_on(vfs::rename("/from/missing", "/to/missing")).unwrap_err(),
+        FsError::NotFound
+    );
+
+    // directories move with their entries
+    block_on(vfs::rename("/to", "/from/to")).unwrap();
+    assert_eq!(read_file("/from/to/new").unwrap(), b"moved");
+    assert_eq!(names("/from/to/.."), ["to"]);
+
+    block_on(vfs::remove("/from/to/new")).unwrap();
+    block_on(vfs::remove("/from/to")).unwrap();
+    block_on(vfs::remove("/from")).unwrap();
+}
+
+#[test_case]
+fn renames_only_within_a_filesystem() {
+    block_on(vfs::mount("/other", Arc::new(TmpFs::default()))).unwrap();
+    write_file("/file", b"");
+    assert_eq!(
+        block_on(vfs::rename("/file", "/other/file")).unwrap_err(),
+        FsError::CrossDevice
+    );
+    assert_eq!(
+        block_on(vfs::rename("/other", "/moved")).unwrap_err(),
+        FsError::Busy
+    );
+    assert_eq!(block_on(vfs::remove("/other")).unwrap_err(), FsError::Busy);
+    vfs::unmount("/other").unwrap();
+    block_on(vfs::remove("/file")).unwrap();
+}
+
+#[test_case]
+fn keeps_unlinked_files_while_open() {
+    write_file("/unlinked", b"still here");
+    let file = open("/unlinked", OpenFlags::READ).unwrap();
+    block_on(vfs::remove("/unlinked")).unwrap();
+    assert_eq!(
+        block_on(vfs::stat("/unlinked")).unwrap_err(),
+        FsError::NotFound
+    );
+    let mut buf = [0; 16];
+    assert_eq!(block_on(file.read(&mut buf)), Ok(10));
+    assert_eq!(&buf[..10], b"still here");
+}
+
+#[test_case]
+fn enforces_the_size_limit() {
+    let fs = Arc::new(TmpFs::new(64 * 1024));
+    let empty = fs.used();
+    block_on(vfs::mount("/small", fs.clone())).unwrap();
+
+    let file = open("/small/big", RW).unwrap();
+    let chunk = vec![0xaa; 4096];
+    let mut written = 0;
+    loop {
+        match block_on(file.write(&chunk)) {
+            Ok(len) => written += len,
+            Err(e) => {
+                assert_eq!(e, FsError::NoSpace);
+                break;
+            }
+        }
+    }
+    assert!(written >= 48 * 1024, "only {} bytes fit", written);
+    assert!(fs.used() <= fs.limit());
+    assert_eq!(
+        block_on(vfs::create_dir("/small/dir")),
+        Err(FsError::NoSpace)
+    );
+
+    // memory is given back
+    block_on(vfs::truncate("/small/big", 0)).unwrap();
+    block_on(vfs::create_dir("/small/dir")).unwrap();
+    block_on(vfs::remove("/small/dir")).unwrap();
+    drop(file);
+    block_on(vfs::remove("/small/big")).unwrap();
+    assert_eq!(fs.used(), empty);
+    vfs::unmount("/small").unwrap();
+}
@@ -0,0 +1,159 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ak_os_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use ak_os_kernel as lib;
+use alloc::boxed::Box;
+use bootloader_api::{config::Mapping, entry_point, BootInfo, BootloaderConfig};
+use lib::{
+    gdt,
+    mem::{
+        address_space::{activate_kernel, USER_END},
+        fault::FaultKind,
+        protection::with_user_access,
+        vma::VmaFlags,
+        AddressSpace,
+    },
+    user::{self, Exit},
+};
+use x86_64::{
+    structures::paging::{page::PageRange, Page, PageTableFlags},
+    VirtAddr,
+};
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::Dynamic);
+    config
+};
+
+entry_point!(kernel_main, config = &BOOTLOADER_CONFIG);
+
+pub fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
+    log::set_logger(&lib::logger::LOGGER).expect("failed to setup logger");
+    log::set_max_level(log::LevelFilter::Info);
+
+    let physical_memory_offset = VirtAddr::new(
+        boot_info
+            .physical_memory_offset
+            .into_option()
+            .expect("no physical_memory_offset"),
+    );
+    unsafe { lib::mem::init(physical_memory_offset, &boot_info.memory_regions) };
+
+    lib::init(None);
+
+    test_main();
+
+    lib::exit_qemu(lib::QemuExitCode::Success);
+}
+
+const CODE: u64 = USER_END - 0x100_0000;
+const STACK_TOP: u64 = CODE + 0x10_0000;
+
+fn pages(start: u64, count: u64) -> PageRange {
+    let start = Page::containing_address(VirtAddr::new(start));
+    Page::range(start, start + count)
+}
+
+/// Runs `code` in a fresh address space with 4 pages of stack, then calls `inspect` while the
+/// address space is still active.
+fn run(code: &[u8], inspect: impl FnOnce()) -> Exit {
+    let space = AddressSpace::new().unwrap();
+    let user = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    space
+        .map(pages(CODE, 1), user | PageTableFlags::WRITABLE)
+        .unwrap();
+    space
+        .map_anonymous(
+            pages(STACK_TOP - 4 * 4096, 4),
+            VmaFlags::READ | VmaFlags::WRITE,
+        )
+        .unwrap();
+
+    unsafe { space.activate() };
+    with_user_access(|| unsafe {
+        core::ptr::copy_nonoverlapping(code.as_ptr(), CODE as *mut u8, code.len())
+    });
+    space.update_flags(pages(CODE, 1).start, user).unwrap();
+
+    let exit = unsafe { user::run(VirtAddr::new(CODE), VirtAddr::new(STACK_TOP)) };
+    inspect();
+    activate_kernel();
+    exit
+}
+
+#[test_case]
+fn privileged_instructions_fault_back_to_the_kernel() {
+    // hlt
+    match run(&[0xf4], || {}) {
+        Exit::Exception {
+            name,
+            instruction_pointer,
+            error_code,
+        } => {
+            assert_eq!(name, "general protection fault");
+            assert_eq!(instruction_pointer, VirtAddr::new(CODE));
+            assert_eq!(error_code, Some(0));
+        }
+        exit => panic!("unexpected exit: {:?}", exit),
+    }
+}
+
+#[test_case]
+fn kernel_memory_is_out_of_reach() {
+    let secret = Box::new(0x1234u64);
+    let addr = &*secret as *const u64 as u64;
+
+    // movabs rax, addr; mov rbx, [rax]
+    let mut code = [0x48, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0x48, 0x8b, 0x18];
+    code[2..10].copy_from_slice(&addr.to_le_bytes());
+    match run(&code, || {}) {
+        Exit::PageFault(report) => {
+            assert!(report.user_mode());
+            assert_eq!(report.addr, VirtAddr::new(addr));
+            assert_eq!(report.instruction_pointer, VirtAddr::new(CODE + 10));
+            assert_eq!(report.kind, FaultKind::PermissionViolation);
+        }
+        exit => panic!("unexpected exit: {:?}", exit),
+    }
+    assert_eq!(*secret, 0x1234);
+}
+
+#[test_case]
+fn resolvable_faults_resume_the_program() {
+    // push 42 (populates the stack on demand); ud2
+    let mut pushed = 0;
+    let exit = run(&[0x6a, 0x2a, 0x0f, 0x0b], || {
+        pushed = with_user_access(|| unsafe { ((STACK_TOP - 8) as *const u64).read_volatile() });
+    });
+    match exit {
+        Exit::Exception {
+            name,
+            instruction_pointer,
+            ..
+        } => {
+            assert_eq!(name, "invalid opcode");
+            assert_eq!(instruction_pointer, VirtAddr::new(CODE + 2));
+        }
+        exit => panic!("unexpected exit: {:?}", exit),
+    }
+    assert_eq!(pushed, 42);
+}
+
+#[test_case]
+fn kernel_stack_can_be_replaced() {
+    let original = gdt::kernel_stack();
+    assert!(!original.is_null());
+
+    gdt::set_kernel_stack(original - 0x1000u64);
+    assert_eq!(gdt::kernel_stack(), original - 0x1000u64);
+    // exceptions from user mode arrive on the new stack
+    assert!(matches!(run(&[0xf4], || {}), Exit::Exception { .. }));
+
+    gdt::set_kernel_stack(original);
+}
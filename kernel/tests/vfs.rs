@@ -0,0 +1,234 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ak_os_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use ak_os_kernel as lib;
+use alloc::{boxed::Box, string::String, sync::Arc, vec::Vec};
+use bootloader_api::{config::Mapping, entry_point, BootInfo, BootloaderConfig};
+use lib::{
+    elf,
+    initrd::Archive,
+    process::{self, Pid},
+    run_until,
+    task::{block_on, Executor},
+    user::Exit,
+    vfs::{self, path, ArchiveFs, FsError, NodeKind, OpenFlags, SeekFrom},
+};
+use x86_64::VirtAddr;
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::Dynamic);
+    config
+};
+
+entry_point!(kernel_main, config = &BOOTLOADER_CONFIG);
+
+pub fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
+    log::set_logger(&lib::logger::LOGGER).expect("failed to setup logger");
+    log::set_max_level(log::LevelFilter::Info);
+
+    let physical_memory_offset = VirtAddr::new(
+        boot_info
+            .physical_memory_offset
+            .into_option()
+            .expect("no physical_memory_offset"),
+    );
+    unsafe { lib::mem::init(physical_memory_offset, &boot_info.memory_regions) };
+
+    lib::init(None);
+
+    block_on(vfs::mount("/", archive_fs())).expect("failed to mount the root");
+
+    test_main();
+
+    lib::exit_qemu(lib::QemuExitCode::Success);
+}
+
+/// The archive of `initrd.rs`, with `bin/motd -> ../etc/motd` and `etc/motd` a hard link to
+/// `etc/issue`
+static ARCHIVE: &[u8] = include_bytes!("data/initrd.tar");
+/// Prints `/bin/motd` and exits with its length, see `programs/cat.S`
+static CAT: &[u8] = include_bytes!("programs/cat.elf");
+const MOTD: &[u8] = b"Welcome to akOS!\n";
+
+fn archive_fs() -> Arc<ArchiveFs> {
+    let archive = Box::leak(Box::new(Archive::parse(ARCHIVE).unwrap()));
+    Arc::new(ArchiveFs::new(archive))
+}
+
+fn names(path: &str) -> Vec<String> {
+    let mut names: Vec<_> = block_on(vfs::read_dir(path))
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect();
+    names.sort();
+    names
+}
+
+#[test_case]
+fn normalizes_paths() {
+    assert_eq!(path::normalize("a//b/./c/"), "/a/b/c");
+    assert_eq!(path::normalize("/../a/../../b"), "/b");
+    assert_eq!(path::normalize(""), "/");
+    assert_eq!(path::join("/", "etc"), "/etc");
+    assert_eq!(path::join("/etc", "motd"), "/etc/motd");
+    assert_eq!(path::split("/etc/motd"), Some(("/etc", "motd")));
+    assert_eq!(path::split("/etc"), Some(("/", "etc")));
+    assert_eq!(path::split("/"), None);
+}
+
+#[test_case]
+fn resolves_paths() {
+    let hello = block_on(vfs::stat("/etc/../bin/./hello")).unwrap();
+    assert_eq!(hello.kind, NodeKind::File);
+    assert_eq!(hello.mode, 0o755);
+    assert_eq!(
+        block_on(vfs::stat("/..")).unwrap().kind,
+        NodeKind::Directory
+    );
+    assert_eq!(block_on(vfs::read_to_end("//etc///motd")).unwrap(), MOTD);
+    assert_eq!(
+        block_on(vfs::stat("/etc/nothing")).unwrap_err(),
+        FsError::NotFound
+    );
+    assert_eq!(
+        block_on(vfs::stat("/etc/motd/..")).unwrap_err(),
+        FsError::NotADirectory
+    );
+}
+
+#[test_case]
+fn follows_symbolic_links() {
+    assert_eq!(
+        block_on(vfs::lstat("/bin/motd")).unwrap().kind,
+        NodeKind::Symlink
+    );
+    assert_eq!(
+        block_on(vfs::read_link("/bin/motd")).unwrap(),
+        "../etc/motd"
+    );
+    assert_eq!(
+        block_on(vfs::read_link("/etc/motd")).unwrap_err(),
+        FsError::InvalidArgument
+    );
+    let motd = block_on(vfs::stat("/bin/motd")).unwrap();
+    assert_eq!(motd.kind, NodeKind::File);
+    assert_eq!(motd.size, MOTD.len() as u64);
+
+    // the open file has the path of the target
+    let file = block_on(vfs::open("/bin/motd", OpenFlags::READ)).unwrap();
+    assert_eq!(file.path(), "/etc/motd");
+}
+
+#[test_case]
+fn reads_and_seeks() {
+    let file = block_on(vfs::open("/etc/issue", OpenFlags::READ)).unwrap();
+    let mut buf = [0; 8];
+    assert_eq!(block_on(file.read(&mut buf)), Ok(8));
+    assert_eq!(&buf, b"Welcome ");
+    assert_eq!(block_on(file.read(&mut buf)), Ok(8));
+    assert_eq!(&buf, b"to akOS!");
+    assert_eq!(block_on(file.read(&mut buf)), Ok(1));
+    assert_eq!(block_on(file.read(&mut buf)), Ok(0));
+
+    assert_eq!(block_on(file.seek(SeekFrom::End(-6))), Ok(11));
+    assert_eq!(block_on(file.read(&mut buf)), Ok(6));
+    assert_eq!(&buf[..6], b"akOS!\n");
+    assert_eq!(block_on(file.seek(SeekFrom::Current(-9))), Ok(8));
+    assert_eq!(block_on(file.seek(SeekFrom::Start(100))), Ok(100));
+    assert_eq!(block_on(file.read(&mut buf)), Ok(0));
+    assert_eq!(
+        block_on(file.seek(SeekFrom::Current(-101))),
+        Err(FsError::InvalidArgument)
+    );
+    assert_eq!(file.offset(), 100);
+}
+
+#[test_case]
+fn failed_reads_keep_the_position() {
+    let file = block_on(vfs::open("/etc/issue", OpenFlags::READ)).unwrap();
+    let mut buf = [0; 8];
+    assert_eq!(
+        block_on(file.read_with(&mut buf, |_| Err(FsError::Io))),
+        Err(FsError::Io)
+    );
+    assert_eq!(file.offset(), 0);
+    assert_eq!(block_on(file.read(&mut buf)), Ok(8));
+    assert_eq!(&buf, b"Welcome ");
+}
+
+#[test_case]
+fn checks_access() {
+    assert_eq!(
+        block_on(vfs::open("/etc/issue", OpenFlags::READ | OpenFlags::WRITE)).unwrap_err(),
+        FsError::ReadOnly
+    );
+    assert_eq!(
+        block_on(vfs::open("/etc", OpenFlags::WRITE)).unwrap_err(),
+        FsError::IsADirectory
+    );
+    let file = block_on(vfs::open("/etc/issue", OpenFlags::empty())).unwrap();
+    assert_eq!(block_on(file.read(&mut [0; 4])), Err(FsError::AccessDenied));
+    assert_eq!(block_on(file.write(b"no")), Err(FsError::AccessDenied));
+}
+
+#[test_case]
+fn reads_directories() {
+    assert_eq!(names("/etc"), ["issue", "motd"]);
+    let dir = block_on(vfs::open("/bin/../etc", OpenFlags::READ)).unwrap();
+    assert_eq!(block_on(dir.read(&mut [0; 4])), Err(FsError::IsADirectory));
+    assert_eq!(block_on(dir.read_dir()).unwrap().len(), 2);
+    assert_eq!(
+        block_on(vfs::read_dir("/etc/issue")).unwrap_err(),
+        FsError::NotADirectory
+    );
+}
+
+#[test_case]
+fn mounts_filesystems() {
+    let root = names("/");
+    block_on(vfs::mount("/mnt", archive_fs())).unwrap();
+    assert!(vfs::mounts().contains(&(String::from("/mnt"), "initrd")));
+    assert!(names("/").iter().any(|name| name == "mnt"));
+    assert_eq!(names("/mnt"), root);
+    assert_eq!(block_on(vfs::read_to_end("/mnt/bin/motd")).unwrap(), MOTD);
+    // `..` leaves the mounted filesystem
+    assert_eq!(names("/mnt/etc/../.."), names("/"));
+
+    assert_eq!(
+        block_on(vfs::mount("/mnt/", archive_fs())).unwrap_err(),
+        FsError::AlreadyMounted
+    );
+    assert_eq!(
+        block_on(vfs::mount("/etc/issue/mnt", archive_fs())).unwrap_err(),
+        FsError::NotADirectory
+    );
+    assert_eq!(
+        block_on(vfs::mount("/none/mnt", archive_fs())).unwrap_err(),
+        FsError::NotFound
+    );
+
+    assert_eq!(vfs::unmount("/mnt").unwrap().name(), "initrd");
+    assert_eq!(vfs::unmount("/mnt").unwrap_err(), FsError::NotMounted);
+    assert_eq!(names("/"), root);
+}
+
+#[test_case]
+fn system_calls_use_files() {
+    let executor = Executor::default();
+    let program = elf::load(CAT, &["cat"], &[]).unwrap();
+    let (pid, thread) = process::spawn("cat", program, Pid::KERNEL).unwrap();
+    executor.spawn(thread);
+    let (_, status) = run_until(&executor, process::waitpid(Pid::KERNEL, pid)).unwrap();
+    assert!(
+        matches!(status, Exit::Exited(len) if len == MOTD.len() as i32),
+        "{:?}",
+        status
+    );
+}
@@ -0,0 +1,165 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ak_os_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use ak_os_kernel as lib;
+use bootloader_api::{config::Mapping, entry_point, BootInfo, BootloaderConfig};
+use lib::mem::{mmio, pat::CacheMode, vmalloc};
+use x86_64::{structures::paging::PageTableFlags, VirtAddr};
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::Dynamic);
+    config
+};
+
+entry_point!(kernel_main, config = &BOOTLOADER_CONFIG);
+
+static mut PHYSICAL_MEMORY_OFFSET: u64 = 0;
+
+pub fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
+    log::set_logger(&lib::logger::LOGGER).expect("failed to setup logger");
+    log::set_max_level(log::LevelFilter::Trace);
+
+    let physical_memory_offset = VirtAddr::new(
+        boot_info
+            .physical_memory_offset
+            .into_option()
+            .expect("no physical_memory_offset"),
+    );
+    unsafe { PHYSICAL_MEMORY_OFFSET = physical_memory_offset.as_u64() };
+    unsafe { lib::mem::init(physical_memory_offset, &boot_info.memory_regions) };
+
+    test_main();
+
+    lib::exit_qemu(lib::QemuExitCode::Success);
+}
+
+const PAGE: u64 = 4096;
+
+#[test_case]
+fn allocations_are_zeroed_and_separated_by_guard_pages() {
+    let a = vmalloc::vmalloc(3 * PAGE as usize).unwrap();
+    let b = vmalloc::vmalloc(1).unwrap();
+    assert!(vmalloc::region().contains(&a));
+    assert!(b >= a + 4 * PAGE || a >= b + 2 * PAGE);
+
+    let bytes = unsafe { core::slice::from_raw_parts_mut(a.as_mut_ptr::<u8>(), 3 * PAGE as usize) };
+    assert!(bytes.iter().all(|b| *b == 0));
+    bytes.fill(0x55);
+
+    let mm = lib::mem::get_memory_manager();
+    assert!(mm.translate_addr(a + 3 * PAGE).is_none());
+
+    vmalloc::free(a).unwrap();
+    vmalloc::free(b).unwrap();
+    assert!(mm.translate_addr(a).is_none());
+}
+
+#[test_case]
+fn freed_ranges_are_reused() {
+    let a = vmalloc::vmalloc(PAGE as usize).unwrap();
+    vmalloc::free(a).unwrap();
+    let b = vmalloc::vmalloc(PAGE as usize).unwrap();
+    assert_eq!(a, b);
+    vmalloc::free(b).unwrap();
+}
+
+#[test_case]
+fn maps_physical_memory_without_freeing_it() {
+    let mm = lib::mem::get_memory_manager();
+    let frames = mm.allocate_contiguous(2).unwrap();
+    let phys = frames.start.start_address() + 0x123u64;
+    let direct = VirtAddr::new(unsafe { PHYSICAL_MEMORY_OFFSET } + phys.as_u64());
+    unsafe { direct.as_mut_ptr::<u64>().write_unaligned(0xDEAD_BEEF) };
+
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let mapped =
+        vmalloc::map_physical(phys, 2 * PAGE as usize - 0x123, flags, CacheMode::WriteBack)
+            .unwrap();
+    assert_eq!(mapped.as_u64() % PAGE, 0x123);
+    assert_eq!(mm.translate_addr(mapped), Some(phys));
+    assert_eq!(
+        unsafe { mapped.as_ptr::<u64>().read_unaligned() },
+        0xDEAD_BEEF
+    );
+
+    vmalloc::free(mapped).unwrap();
+    // the frames are still owned by us and keep their contents
+    assert_eq!(
+        unsafe { direct.as_ptr::<u64>().read_unaligned() },
+        0xDEAD_BEEF
+    );
+    unsafe { mm.deallocate_contiguous(frames) };
+}
+
+#[test_case]
+fn free_rejects_unknown_addresses() {
+    assert!(vmalloc::free(vmalloc::region().start + 0x1000_0000u64).is_err());
+}
+
+#[test_case]
+fn mmio_mappings_are_shared_and_reference_counted() {
+    let mm = lib::mem::get_memory_manager();
+    let frames = mm.allocate_contiguous(3).unwrap();
+    let phys = frames.start.start_address();
+    let (mappings, refs) = mmio::mapping_count();
+
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let whole = mmio::map(phys, 3 * PAGE as usize, flags, CacheMode::WriteBack).unwrap();
+    let inner = mmio::map(phys + PAGE + 8u64, 16, flags, CacheMode::WriteBack).unwrap();
+    assert_eq!(inner, whole + PAGE + 8u64);
+    assert_eq!(mmio::mapping_count(), (mappings + 1, refs + 2));
+
+    mmio::unmap(whole).unwrap();
+    assert_eq!(mm.translate_addr(inner), Some(phys + PAGE + 8u64));
+    mmio::unmap(inner).unwrap();
+    assert!(mm.translate_addr(whole).is_none());
+    assert_eq!(mmio::mapping_count(), (mappings, refs));
+
+    // unmapping never returns the frames, so they can still be freed by their owner
+    unsafe { mm.deallocate_contiguous(frames) };
+}
+
+#[test_case]
+fn acpi_mappings_are_released_completely() {
+    use acpi::AcpiHandler;
+
+    let mm = lib::mem::get_memory_manager();
+    let frames = mm.allocate_contiguous(2).unwrap();
+    let (mappings, refs) = mmio::mapping_count();
+
+    let region = unsafe {
+        mm.map_physical_region::<u8>(
+            frames.start.start_address().as_u64() as usize + 0x800,
+            PAGE as usize,
+        )
+    };
+    let virt = VirtAddr::from_ptr(region.virtual_start().as_ptr());
+    assert!(region.mapped_length() >= PAGE as usize);
+    drop(region);
+
+    assert!(mm.translate_addr(virt).is_none());
+    assert!(mm.translate_addr(virt + PAGE).is_none());
+    assert_eq!(mmio::mapping_count(), (mappings, refs));
+    unsafe { mm.deallocate_contiguous(frames) };
+}
+
+#[test_case]
+fn mmio_mappings_are_not_shared_across_cache_modes() {
+    let mm = lib::mem::get_memory_manager();
+    let frames = mm.allocate_contiguous(1).unwrap();
+    let phys = frames.start.start_address();
+
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let cached = mmio::map(phys, PAGE as usize, flags, CacheMode::WriteBack).unwrap();
+    let uncached = mmio::map(phys, PAGE as usize, flags, CacheMode::Uncacheable).unwrap();
+    assert_ne!(cached, uncached);
+    assert_eq!(mm.translate_addr(uncached), Some(phys));
+
+    mmio::unmap(cached).unwrap();
+    mmio::unmap(uncached).unwrap();
+    unsafe { mm.deallocate_contiguous(frames) };
+}
@@ -0,0 +1,111 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+#![feature(custom_test_frameworks)]
+#![test_runner(ak_os_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use ak_os_kernel as lib;
+use alloc::boxed::Box;
+use bootloader_api::{config::Mapping, entry_point, BootInfo, BootloaderConfig};
+use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use x86_64::{
+    registers::control::{Cr2, Cr3},
+    structures::{
+        idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
+        paging::{mapper::TranslateResult, OffsetPageTable, PageTable, PageTableFlags, Translate},
+    },
+    VirtAddr,
+};
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::Dynamic);
+    config
+};
+
+entry_point!(kernel_main, config = &BOOTLOADER_CONFIG);
+
+static TARGET: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.page_fault.set_handler_fn(test_page_fault_handler);
+        idt
+    };
+}
+
+pub fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
+    log::set_logger(&lib::logger::LOGGER).expect("failed to setup logger");
+    log::set_max_level(log::LevelFilter::Trace);
+
+    let physical_memory_offset = VirtAddr::new(
+        boot_info
+            .physical_memory_offset
+            .into_option()
+            .expect("no physical_memory_offset"),
+    );
+    unsafe { lib::mem::init(physical_memory_offset, &boot_info.memory_regions) };
+
+    lib::init(None);
+
+    let heap = Box::new(0u64);
+    assert_no_execute(physical_memory_offset, VirtAddr::from_ptr(&*heap), "heap");
+    assert_no_execute(
+        physical_memory_offset,
+        lib::gdt::kernel_stack() - 1u64,
+        "kernel stack",
+    );
+
+    x86_64::instructions::interrupts::disable();
+    TEST_IDT.load();
+
+    let target = kernel_main as *const () as *mut u8;
+    TARGET.store(target as u64, Ordering::SeqCst);
+    log::info!("writing to kernel text at {:p}", target);
+    unsafe { core::ptr::write_volatile(target, 0xC3) };
+
+    panic!("write to kernel text did not fault");
+}
+
+/// Panics unless `addr` is mapped writable but not executable in the active table.
+fn assert_no_execute(physical_memory_offset: VirtAddr, addr: VirtAddr, what: &str) {
+    let level_4 = physical_memory_offset + Cr3::read().0.start_address().as_u64();
+    let table = unsafe {
+        OffsetPageTable::new(
+            &mut *level_4.as_mut_ptr::<PageTable>(),
+            physical_memory_offset,
+        )
+    };
+    let TranslateResult::Mapped { flags, .. } = table.translate(addr) else {
+        panic!("{} at {:?} isn't mapped", what, addr);
+    };
+    assert!(
+        flags.contains(PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE),
+        "{} at {:?} is mapped {:?}",
+        what,
+        addr,
+        flags
+    );
+}
+
+extern "x86-interrupt" fn test_page_fault_handler(
+    _stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    let addr = Cr2::read();
+    log::info!("page fault at {:?}, error code: {:?}", addr, error_code);
+
+    if addr.as_u64() == TARGET.load(Ordering::SeqCst)
+        && error_code.contains(
+            PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE,
+        )
+    {
+        lib::exit_qemu(lib::QemuExitCode::Success);
+    }
+    lib::exit_qemu(lib::QemuExitCode::Failed);
+}